@@ -0,0 +1,109 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use ecosystem::shortener::bench::{candidates, new_id, redirect_response, validate_url, Misses};
+use http::{Request, StatusCode};
+use tokio::runtime::Runtime;
+use tower::ServiceExt;
+
+// the hot path of the shortener without postgres: id generation, url checks on
+// shorten, a redirect served from an in-memory store standing in for the db, and
+// the miss cache answering scans of unknown ids
+
+const URLS: &[&str] = &[
+    "https://example.com",
+    "https://www.rust-lang.org/learn/get-started?utm_source=bench#install",
+    "http://A.Very.Deep.Sub.Domain.Example.co.uk./some/long/path/index.html",
+    "https://127.0.0.1:8080/health",
+];
+
+fn ids(c: &mut Criterion) {
+    let mut group = c.benchmark_group("new_id");
+    for len in [6, 8, 12] {
+        group.bench_function(len.to_string(), |b| b.iter(|| new_id(black_box(len))));
+    }
+    group.finish();
+}
+
+fn urls(c: &mut Criterion) {
+    c.bench_function("validate_url", |b| {
+        b.iter(|| {
+            for url in URLS {
+                let _ = validate_url(black_box(url));
+            }
+        })
+    });
+    c.bench_function("domain_candidates", |b| {
+        b.iter(|| {
+            for url in URLS {
+                black_box(candidates(black_box(url)));
+            }
+        })
+    });
+}
+
+type Store = Arc<HashMap<String, String>>;
+
+async fn redirect(State(store): State<Store>, Path(id): Path<String>) -> Response {
+    match store.get(&id) {
+        Some(url) => redirect_response(url.clone(), StatusCode::PERMANENT_REDIRECT, true, 3600),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+fn redirects(c: &mut Criterion) {
+    let store: Store = Arc::new(
+        (0..10_000)
+            .map(|i| (new_id(6), format!("https://example.com/{i}")))
+            .collect(),
+    );
+    let id = store.keys().next().unwrap().clone();
+    let app = Router::new().route("/:id", get(redirect)).with_state(store);
+    let rt = Runtime::new().unwrap();
+
+    c.bench_function("redirect_response", |b| {
+        b.iter(|| {
+            redirect_response(
+                black_box("https://example.com/".to_string()),
+                StatusCode::PERMANENT_REDIRECT,
+                true,
+                3600,
+            )
+        })
+    });
+    c.bench_function("redirect_cache_hit", |b| {
+        b.to_async(&rt).iter_batched(
+            || {
+                let req = Request::get(format!("/{id}")).body(Body::empty()).unwrap();
+                (app.clone(), req)
+            },
+            |(app, req)| async move {
+                let res = app.oneshot(req).await.unwrap();
+                assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn misses(c: &mut Criterion) {
+    let misses = Misses::new(10_000, Duration::from_secs(3600));
+    let ids: Vec<String> = (0..10_000).map(|_| new_id(6)).collect();
+    for id in &ids {
+        misses.insert("default", "127.0.0.1", id);
+    }
+    let id = &ids[ids.len() / 2];
+    c.bench_function("miss_cache_hit", |b| {
+        b.iter(|| misses.contains("default", black_box("127.0.0.1"), black_box(id)))
+    });
+}
+
+criterion_group!(benches, ids, urls, redirects, misses);
+criterion_main!(benches);
@@ -0,0 +1,135 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use anyhow::Result;
+use ecosystem::chat::{self, admin_channel, shutdown_signal, AdminCommand, Config, State};
+use tokio::net::TcpListener;
+use tokio::signal;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+// the full chat server, configured by `CHAT_*` env vars and the config file they name
+#[tokio::main]
+async fn main() -> Result<()> {
+    // let layer = Layer::new().pretty().with_filter(LevelFilter::INFO);
+    // tracing_subscriber::registry().with(layer).init();
+    console_subscriber::init();
+
+    let addr = "0.0.0.0:8080";
+    let listener = TcpListener::bind(addr).await?;
+    info!(addr, "listening for tcp clients");
+    let config = Config::from_env()?;
+    let db = std::env::var("CHAT_DB").unwrap_or_else(|_| "chat.db".to_string());
+    let state = State::open(config, &db).await?;
+
+    // a thread of its own, a pending read of stdin would hold up the runtime at shutdown
+    let admin = admin_channel(Arc::clone(&state));
+    tokio::spawn(reload_signal(admin.clone()));
+    std::thread::spawn(move || {
+        if let Err(e) = read_console(admin) {
+            warn!(error = ?e, "admin console failed");
+        }
+    });
+    // scraped by prometheus, apart from the clients
+    let metrics_addr =
+        std::env::var("CHAT_METRICS_ADDR").unwrap_or_else(|_| "0.0.0.0:9090".to_string());
+    let metrics_listener = TcpListener::bind(&metrics_addr).await?;
+    info!(addr = %metrics_addr, "serving metrics");
+    spawn(
+        "metrics server",
+        chat::serve_metrics(Arc::clone(&state), metrics_listener),
+    );
+    let cloned_state = Arc::clone(&state);
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        cloned_state.shut_down().await;
+    });
+
+    // files shared with /send are uploaded and downloaded here
+    if state.shares_files() {
+        let upload_addr =
+            std::env::var("CHAT_UPLOAD_ADDR").unwrap_or_else(|_| "0.0.0.0:8082".to_string());
+        let upload_listener = TcpListener::bind(&upload_addr).await?;
+        info!(addr = %upload_addr, "serving uploads");
+        spawn(
+            "upload server",
+            chat::serve_uploads(Arc::clone(&state), upload_listener),
+        );
+    }
+
+    // irc clients, in the same rooms as everyone else
+    let irc_addr = std::env::var("CHAT_IRC_ADDR").unwrap_or_else(|_| "0.0.0.0:6667".to_string());
+    let irc_listener = TcpListener::bind(&irc_addr).await?;
+    info!(addr = %irc_addr, "listening for irc clients");
+    spawn(
+        "irc listener",
+        chat::serve_irc(Arc::clone(&state), irc_listener),
+    );
+
+    // tcp clients encrypting with noise rather than tls, framed like the others
+    let noise_addr =
+        std::env::var("CHAT_NOISE_ADDR").unwrap_or_else(|_| "0.0.0.0:8083".to_string());
+    let noise_listener = TcpListener::bind(&noise_addr).await?;
+    let noise_key = state.noise_key()?;
+    info!(addr = %noise_addr, key = %hex::encode(&noise_key.public), "listening for noise clients");
+    spawn(
+        "noise listener",
+        chat::serve_noise(Arc::clone(&state), noise_listener, noise_key),
+    );
+
+    // browsers chat through websockets, in the same rooms as the tcp clients
+    let ws_addr = std::env::var("CHAT_WS_ADDR").unwrap_or_else(|_| "0.0.0.0:8081".to_string());
+    let ws_listener = std::net::TcpListener::bind(&ws_addr)?;
+    info!(addr = %ws_addr, "serving websockets");
+    spawn(
+        "websocket server",
+        chat::serve_web(Arc::clone(&state), ws_listener),
+    );
+
+    chat::serve(state, listener).await
+}
+
+// a listener of its own, failing without taking the others down
+fn spawn(name: &'static str, served: impl Future<Output = Result<()>> + Send + 'static) {
+    tokio::spawn(async move {
+        if let Err(e) = served.await {
+            warn!(error = ?e, "{} failed", name);
+        }
+    });
+}
+
+// every line on stdin is an admin command, until stdin is closed
+fn read_console(admin: mpsc::Sender<AdminCommand>) -> Result<()> {
+    for line in std::io::stdin().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match AdminCommand::parse(&line) {
+            Ok(command) => admin.blocking_send(command)?,
+            Err(e) => println!("{}", e),
+        }
+    }
+    Ok(())
+}
+
+// SIGHUP reloads the config file, like `/reload` at the console
+async fn reload_signal(admin: mpsc::Sender<AdminCommand>) {
+    #[cfg(unix)]
+    {
+        let mut hangup = match signal::unix::signal(signal::unix::SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                warn!(error = ?e, "failed to install SIGHUP handler");
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            if admin.send(AdminCommand::Reload).await.is_err() {
+                return;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = admin;
+}
@@ -0,0 +1,358 @@
+// load test of the chat example: N guests join one room over the json protocol, each says
+// something at a steady rate for a while, then they read what is left and disconnect. every
+// message is timed from its sender to each of the others, and what never arrived is counted, so
+// a change to the broadcast path can be measured before and after
+//
+//     CHAT_MAX_CONNECTIONS_PER_IP=1000 CHAT_RATE=100 cargo run --example chat
+//     cargo run --release --example chatbench -- --clients 200 --rate 2
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use clap::Parser;
+use futures::{SinkExt, StreamExt};
+use serde::Deserialize;
+use tokio::net::TcpStream;
+use tokio::time::{interval_at, sleep, timeout, Instant, MissedTickBehavior};
+use tokio_util::codec::{Framed, LinesCodec};
+
+// how long a client may take to log in and join
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_LINE: usize = 64 * 1024;
+
+/// Load test of the chat server.
+#[derive(Debug, Parser)]
+#[command(name = "chatbench")]
+struct Cli {
+    /// address of the chat server's tcp listener
+    #[arg(long, env = "CHATBENCH_ADDR", default_value = "127.0.0.1:8080")]
+    addr: SocketAddr,
+    /// clients connected at once
+    #[arg(short, long, default_value_t = 50)]
+    clients: usize,
+    /// messages per second each client sends
+    #[arg(short, long, default_value_t = 1.0)]
+    rate: f64,
+    /// seconds the clients send for
+    #[arg(short, long, default_value_t = 10)]
+    duration: u64,
+    /// seconds they keep reading after, for what is still on its way
+    #[arg(long, default_value_t = 2)]
+    drain: u64,
+    /// the room they all join
+    #[arg(long, default_value = "#bench")]
+    room: String,
+}
+
+// what the server sends, as far as the benchmark cares
+#[derive(Debug, Deserialize)]
+struct Incoming {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    content: String,
+}
+
+type Connection = Framed<TcpStream, LinesCodec>;
+
+// shared by all clients of a run
+#[derive(Debug)]
+struct Run {
+    started_at: Instant,
+    // per client, when each of its messages was sent, in µs since `started_at`
+    sent: Vec<Mutex<Vec<u64>>>,
+    // from sending a message until another client got it, in µs
+    latencies: Mutex<Vec<u64>>,
+    received: AtomicU64,
+    // told by the server, with "You missed n messages"
+    missed: AtomicU64,
+    // warned or muted for sending too fast
+    throttled: AtomicU64,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    if cli.clients < 2 || cli.rate <= 0.0 {
+        bail!("at least 2 clients and a rate above 0 are needed");
+    }
+
+    println!("connecting {} clients to {}", cli.clients, cli.addr);
+    let logins = (0..cli.clients).map(|_| connect(cli.addr, &cli.room));
+    let mut connections = Vec::new();
+    let mut refused = Vec::new();
+    for login in futures::future::join_all(logins).await {
+        match login {
+            Ok(connection) => connections.push(connection),
+            Err(e) => refused.push(e.to_string()),
+        }
+    }
+    if let Some(reason) = refused.first() {
+        println!("{} clients refused, e.g.: {}", refused.len(), reason);
+    }
+    if connections.len() < 2 {
+        bail!("fewer than 2 clients got in");
+    }
+
+    let run = Arc::new(Run {
+        started_at: Instant::now(),
+        sent: connections.iter().map(|_| Mutex::default()).collect(),
+        latencies: Mutex::default(),
+        received: AtomicU64::new(0),
+        missed: AtomicU64::new(0),
+        throttled: AtomicU64::new(0),
+    });
+    let period = Duration::from_secs_f64(1.0 / cli.rate);
+    let stop = run.started_at + Duration::from_secs(cli.duration);
+    let end = stop + Duration::from_secs(cli.drain);
+    println!(
+        "{} clients sending {}/s each for {}s",
+        connections.len(),
+        cli.rate,
+        cli.duration
+    );
+    let tasks: Vec<_> = connections
+        .into_iter()
+        .enumerate()
+        .map(|(client, connection)| {
+            let run = Arc::clone(&run);
+            // spread over the period, not all at once on every tick
+            let first = run.started_at + period.mul_f64(client as f64 / run.sent.len() as f64);
+            tokio::spawn(chat(run, client, connection, first, period, stop, end))
+        })
+        .collect();
+    let mut failed = 0;
+    for task in tasks {
+        if !matches!(task.await, Ok(Ok(()))) {
+            failed += 1;
+        }
+    }
+
+    report(&run, failed);
+    Ok(())
+}
+
+// logged in as a guest and in `room`
+async fn connect(addr: SocketAddr, room: &str) -> Result<Connection> {
+    let stream = TcpStream::connect(addr).await?;
+    let mut connection = Framed::new(stream, LinesCodec::new_with_max_length(MAX_LINE));
+    timeout(LOGIN_TIMEOUT, async {
+        connection.send(r#"{"type":"hello","version":1}"#).await?;
+        send_line(&mut connection, "guest").await?;
+        expect_notice(&mut connection, "You joined ").await?;
+        send_line(&mut connection, &format!("/join {}", room)).await?;
+        expect_notice(&mut connection, &format!("You joined {}", room)).await
+    })
+    .await
+    .map_err(|_| anyhow!("timed out logging in"))??;
+    Ok(connection)
+}
+
+// one client: sends every `period` from `first` until `stop`, reads until `end`
+async fn chat(
+    run: Arc<Run>,
+    client: usize,
+    mut connection: Connection,
+    first: Instant,
+    period: Duration,
+    stop: Instant,
+    end: Instant,
+) -> Result<()> {
+    let mut ticks = interval_at(first, period);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let deadline = sleep(end - Instant::now());
+    tokio::pin!(deadline);
+    let mut seq = 0;
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            tick = ticks.tick(), if Instant::now() < stop => {
+                if tick >= stop {
+                    continue;
+                }
+                let sent_at = run.started_at.elapsed().as_micros() as u64;
+                run.sent[client].lock().unwrap().push(sent_at);
+                let content = format!("bench {} {}", encode(client as u64), encode(seq));
+                send_line(&mut connection, &content).await?;
+                seq += 1;
+            }
+            line = connection.next() => match line {
+                Some(line) => receive(&run, &line?),
+                None => bail!("client {} was disconnected", client),
+            },
+        }
+    }
+    Ok(())
+}
+
+// counts and times a message of another client
+fn receive(run: &Run, line: &str) {
+    let Ok(incoming) = serde_json::from_str::<Incoming>(line) else {
+        return;
+    };
+    match incoming.kind.as_str() {
+        "chat" => {
+            let Some((client, seq)) = parse(&incoming.content) else {
+                return;
+            };
+            let sent_at = run
+                .sent
+                .get(client as usize)
+                .and_then(|sent| sent.lock().unwrap().get(seq as usize).copied());
+            if let Some(sent_at) = sent_at {
+                let now = run.started_at.elapsed().as_micros() as u64;
+                run.latencies
+                    .lock()
+                    .unwrap()
+                    .push(now.saturating_sub(sent_at));
+                run.received.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        "notice" => {
+            let content = &incoming.content;
+            if let Some(missed) = content
+                .strip_prefix("You missed ")
+                .and_then(|rest| rest.split(' ').next())
+                .and_then(|n| n.parse::<u64>().ok())
+            {
+                run.missed.fetch_add(missed, Ordering::Relaxed);
+            } else if content.starts_with("You are sending too fast")
+                || content.starts_with("You are muted")
+            {
+                run.throttled.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn report(run: &Run, failed: usize) {
+    let clients = run.sent.len() as u64;
+    let sent: u64 = run
+        .sent
+        .iter()
+        .map(|s| s.lock().unwrap().len() as u64)
+        .sum();
+    // every message is for everyone in the room but its sender
+    let expected = sent * (clients - 1);
+    let received = run.received.load(Ordering::Relaxed);
+    let elapsed = run.started_at.elapsed().as_secs_f64();
+    println!("clients     {} ({} failed during the run)", clients, failed);
+    println!(
+        "sent        {} ({:.1}/s)",
+        sent,
+        sent as f64 / elapsed.max(f64::EPSILON)
+    );
+    println!(
+        "delivered   {} of {} expected, {} missing ({} told missed by the server)",
+        received,
+        expected,
+        expected.saturating_sub(received),
+        run.missed.load(Ordering::Relaxed)
+    );
+    println!("throttled   {}", run.throttled.load(Ordering::Relaxed));
+    let mut latencies = run.latencies.lock().unwrap();
+    latencies.sort_unstable();
+    if latencies.is_empty() {
+        println!("latency     -");
+        return;
+    }
+    let ms = |micros: u64| micros as f64 / 1000.0;
+    println!(
+        "latency     p50 {:.2}ms  p90 {:.2}ms  p99 {:.2}ms  max {:.2}ms",
+        ms(percentile(&latencies, 50.0)),
+        ms(percentile(&latencies, 90.0)),
+        ms(percentile(&latencies, 99.0)),
+        ms(percentile(&latencies, 100.0))
+    );
+}
+
+// of sorted values, nearest rank
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+async fn send_line(connection: &mut Connection, line: &str) -> Result<()> {
+    let request = serde_json::json!({ "type": "line", "line": line });
+    connection.send(request.to_string()).await?;
+    Ok(())
+}
+
+// skips everything up to a notice starting with `prefix`, the last notice is the error if the
+// server closes the connection first, e.g. because it is full
+async fn expect_notice(connection: &mut Connection, prefix: &str) -> Result<()> {
+    let mut last = String::new();
+    while let Some(line) = connection.next().await {
+        let Ok(incoming) = serde_json::from_str::<Incoming>(&line?) else {
+            continue;
+        };
+        if incoming.kind == "notice" {
+            if incoming.content.starts_with(prefix) {
+                return Ok(());
+            }
+            last = incoming.content;
+        }
+    }
+    bail!("closed by the server: {}", last)
+}
+
+// digits, with every other one as a letter, so the spam filter never cuts down a run of them
+fn encode(n: u64) -> String {
+    n.to_string()
+        .bytes()
+        .enumerate()
+        .map(|(i, digit)| match i % 2 {
+            0 => digit as char,
+            _ => (digit - b'0' + b'a') as char,
+        })
+        .collect()
+}
+
+fn decode(text: &str) -> Option<u64> {
+    let digits: String = text
+        .chars()
+        .map(|c| match c {
+            'a'..='j' => (c as u8 - b'a' + b'0') as char,
+            c => c,
+        })
+        .collect();
+    digits.parse().ok()
+}
+
+// the client and sequence number of a benchmark message
+fn parse(content: &str) -> Option<(u64, u64)> {
+    let mut words = content.strip_prefix("bench ")?.split(' ');
+    let client = decode(words.next()?)?;
+    let seq = decode(words.next()?)?;
+    Some((client, seq))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_should_survive_the_spam_filter() {
+        assert_eq!(encode(111111), "1b1b1b");
+        assert_eq!(encode(0), "0");
+        for n in [0, 7, 10000, 111111, 1234567890] {
+            assert_eq!(decode(&encode(n)), Some(n));
+        }
+        let content = format!("bench {} {}", encode(12), encode(3000));
+        assert_eq!(parse(&content), Some((12, 3000)));
+        assert_eq!(parse("hello"), None);
+    }
+
+    #[test]
+    fn percentiles_should_be_nearest_rank() {
+        let sorted: Vec<u64> = (1..=100).collect();
+        assert_eq!(percentile(&sorted, 50.0), 50);
+        assert_eq!(percentile(&sorted, 99.0), 99);
+        assert_eq!(percentile(&sorted, 100.0), 100);
+        assert_eq!(percentile(&[5], 0.0), 5);
+    }
+}
@@ -0,0 +1,47 @@
+use anyhow::Result;
+use ecosystem::shortener::{app, grpc, shutdown_signal, tls::Listeners, AppState, Config};
+use tokio::net::TcpListener;
+use tracing_subscriber::{
+    fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer as _,
+};
+
+// the full shortener, configured by `SHORTENER_*` env vars
+#[tokio::main]
+async fn main() -> Result<()> {
+    let config = Config::from_env()?;
+    // tracing, `RUST_LOG` picks the levels, info by default
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let layer = match config.log_json {
+        true => Layer::new()
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(false)
+            .boxed(),
+        false => Layer::new().pretty().boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(layer.with_filter(filter))
+        .init();
+    let listeners = Listeners::bind(&config).await?;
+    let grpc_listener = match config.grpc_addr.as_str() {
+        "" => None,
+        addr => Some(TcpListener::bind(addr).await?),
+    };
+
+    let app_state = AppState::try_new(config).await?;
+    let jobs = app_state.spawn_jobs();
+    let grpc = grpc_listener
+        .map(|listener| tokio::spawn(grpc::serve(app_state.clone(), listener, shutdown_signal())));
+    listeners
+        .serve(app(app_state.clone()), shutdown_signal())
+        .await?;
+    if let Some(grpc) = grpc {
+        grpc.await??;
+    }
+
+    // all in-flight requests are drained here, release db connections
+    jobs.iter().for_each(|job| job.abort());
+    app_state.close().await;
+    Ok(())
+}
@@ -0,0 +1,229 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use reqwest::{header::LOCATION, redirect::Policy, RequestBuilder, Response, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::config::Settings;
+
+#[derive(Debug, Serialize)]
+pub struct ShortenReq {
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect_status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_clicks: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShortenRes {
+    pub url: String,
+}
+
+/// Where a short link leads, without following it.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Resolved {
+    pub short_url: String,
+    pub status: u16,
+    /// None for links answering with a page, e.g. password protected or flagged ones
+    pub destination: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Link {
+    pub id: String,
+    pub url: String,
+    pub version: i32,
+    pub updated_at: DateTime<Utc>,
+    pub redirect_status: Option<i16>,
+    pub flag_reason: Option<String>,
+    pub clicks: i64,
+    pub max_clicks: Option<i64>,
+    pub protected: bool,
+    #[serde(default)]
+    pub domain: Option<String>,
+    #[serde(default)]
+    pub dead: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LinkStats {
+    pub id: String,
+    pub clicks: i64,
+    pub clicks_24h: i64,
+    pub clicks_7d: i64,
+    pub last_click_at: Option<DateTime<Utc>>,
+    pub breakdown: Breakdown,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StatsSummary {
+    pub total_links: i64,
+    pub total_clicks: i64,
+    pub top_24h: Vec<TopLink>,
+    pub top_7d: Vec<TopLink>,
+    pub breakdown: Breakdown,
+    pub generated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TopLink {
+    pub id: String,
+    pub url: String,
+    pub clicks: i64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Breakdown {
+    pub countries: Vec<GroupCount>,
+    pub browsers: Vec<GroupCount>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GroupCount {
+    pub name: String,
+    pub clicks: i64,
+}
+
+// error body of the shortener, `{message, request_id}` or problem details
+#[derive(Debug, Deserialize)]
+struct ErrorBody {
+    #[serde(alias = "detail")]
+    message: String,
+    request_id: Option<String>,
+}
+
+/// Thin client of the shortener json api.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    settings: Settings,
+}
+
+impl Client {
+    pub fn new(settings: Settings) -> Result<Self> {
+        // resolving must see the redirect itself, not its destination
+        let http = reqwest::Client::builder()
+            .redirect(Policy::none())
+            .user_agent(concat!("shortctl/", env!("CARGO_PKG_VERSION")))
+            .build()?;
+        Ok(Self { http, settings })
+    }
+
+    pub async fn shorten(&self, req: &ShortenReq) -> Result<ShortenRes> {
+        let req = self.http.post(self.url("/api/v1/links")).json(req);
+        json(self.send(req).await?).await
+    }
+
+    pub async fn resolve(&self, id_or_url: &str) -> Result<Resolved> {
+        let short_url = self.short_url(id_or_url);
+        let res = self.http.get(&short_url).send().await?;
+        let status = res.status();
+        if status.is_redirection() {
+            let destination = res
+                .headers()
+                .get(LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .map(String::from);
+            return Ok(Resolved {
+                short_url,
+                status: status.as_u16(),
+                destination,
+            });
+        }
+        // the password form and warning pages are html, not errors
+        if status.is_success() || status == StatusCode::UNAUTHORIZED {
+            return Ok(Resolved {
+                short_url,
+                status: status.as_u16(),
+                destination: None,
+            });
+        }
+        Err(error(res).await)
+    }
+
+    pub async fn list(&self) -> Result<Vec<Link>> {
+        let req = self.http.get(self.url("/api/v1/links"));
+        json(self.send(req).await?).await
+    }
+
+    pub async fn delete(&self, id: &str) -> Result<()> {
+        let req = self.http.delete(self.url(&format!("/api/v1/links/{id}")));
+        self.send(req).await?;
+        Ok(())
+    }
+
+    pub async fn link_stats(&self, id: &str) -> Result<LinkStats> {
+        let req = self
+            .http
+            .get(self.url(&format!("/api/v1/links/{id}/stats")));
+        json(self.send(req).await?).await
+    }
+
+    pub async fn summary(&self) -> Result<StatsSummary> {
+        let req = self.http.get(self.url("/api/v1/stats/summary"));
+        json(self.send(req).await?).await
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.settings.url)
+    }
+
+    // full short urls are used as given, bare ids are on the configured instance
+    fn short_url(&self, id_or_url: &str) -> String {
+        if id_or_url.starts_with("http://") || id_or_url.starts_with("https://") {
+            id_or_url.to_string()
+        } else {
+            self.url(&format!("/{}", id_or_url.trim_start_matches('/')))
+        }
+    }
+
+    async fn send(&self, req: RequestBuilder) -> Result<Response> {
+        let req = match &self.settings.api_key {
+            Some(key) => req.bearer_auth(key),
+            None => req,
+        };
+        let res = req.send().await?;
+        if !res.status().is_success() {
+            return Err(error(res).await);
+        }
+        Ok(res)
+    }
+}
+
+async fn json<T: DeserializeOwned>(res: Response) -> Result<T> {
+    Ok(res.json().await?)
+}
+
+async fn error(res: Response) -> anyhow::Error {
+    let status = res.status();
+    match res.json::<ErrorBody>().await {
+        Ok(ErrorBody {
+            message,
+            request_id: Some(id),
+        }) => anyhow!("{status}: {message} (request id {id})"),
+        Ok(ErrorBody { message, .. }) => anyhow!("{status}: {message}"),
+        Err(_) => anyhow!("{status}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_url_should_accept_ids_and_urls() {
+        let client = Client::new(Settings {
+            url: "http://127.0.0.1:9876".to_string(),
+            api_key: None,
+        })
+        .unwrap();
+        assert_eq!(client.short_url("abc123"), "http://127.0.0.1:9876/abc123");
+        assert_eq!(client.short_url("/abc123"), "http://127.0.0.1:9876/abc123");
+        assert_eq!(
+            client.short_url("https://go.example.com/abc123"),
+            "https://go.example.com/abc123"
+        );
+    }
+}
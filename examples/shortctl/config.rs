@@ -0,0 +1,81 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+pub const DEFAULT_URL: &str = "http://127.0.0.1:9876";
+
+/// Where and as whom to call the shortener.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub url: String,
+    /// bearer token, as returned by `POST /api/v1/login`
+    pub api_key: Option<String>,
+}
+
+// `~/.config/shortctl/config.toml`, every key optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    url: Option<String>,
+    api_key: Option<String>,
+}
+
+impl Settings {
+    /// Flags and env vars (already merged by clap) win over the config file.
+    /// A missing file is fine unless it was given explicitly.
+    pub fn load(
+        url: Option<String>,
+        api_key: Option<String>,
+        path: Option<PathBuf>,
+    ) -> Result<Self> {
+        let file = match path {
+            Some(path) => read(&path)?,
+            None => match default_path() {
+                Some(path) if path.exists() => read(&path)?,
+                _ => FileConfig::default(),
+            },
+        };
+        let url = url.or(file.url).unwrap_or_else(|| DEFAULT_URL.to_string());
+        Ok(Self {
+            url: url.trim_end_matches('/').to_string(),
+            api_key: api_key.or(file.api_key),
+        })
+    }
+}
+
+fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("shortctl").join("config.toml"))
+}
+
+fn read(path: &PathBuf) -> Result<FileConfig> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    toml::from_str(&content).with_context(|| format!("invalid config file {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_should_override_config_file() {
+        let path = std::env::temp_dir().join(format!("shortctl-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "url = \"https://go.example.com/\"\napi_key = \"from-file\"\n",
+        )
+        .unwrap();
+
+        let settings = Settings::load(None, None, Some(path.clone())).unwrap();
+        assert_eq!(settings.url, "https://go.example.com");
+        assert_eq!(settings.api_key.as_deref(), Some("from-file"));
+
+        let settings = Settings::load(None, Some("from-env".into()), Some(path.clone())).unwrap();
+        assert_eq!(settings.api_key.as_deref(), Some("from-env"));
+
+        fs::write(&path, "token = \"typo\"\n").unwrap();
+        assert!(Settings::load(None, None, Some(path.clone())).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
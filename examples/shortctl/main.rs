@@ -0,0 +1,112 @@
+mod client;
+mod config;
+mod output;
+
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+
+use client::{Client, ShortenReq};
+use config::Settings;
+use output::{print, Deleted, Output};
+
+/// Command line client of the myshortener api.
+///
+/// The instance and api key come from flags, `SHORTCTL_*` env vars or
+/// `~/.config/shortctl/config.toml` (keys `url` and `api_key`), in that order.
+#[derive(Debug, Parser)]
+#[command(name = "shortctl", version)]
+struct Cli {
+    /// base url of the shortener
+    #[arg(long, global = true, env = "SHORTCTL_URL")]
+    base_url: Option<String>,
+    /// access token, see `POST /api/v1/login`
+    #[arg(long, global = true, env = "SHORTCTL_API_KEY", hide_env_values = true)]
+    api_key: Option<String>,
+    /// config file to read instead of the default one
+    #[arg(long, global = true, env = "SHORTCTL_CONFIG")]
+    config: Option<PathBuf>,
+    #[arg(short, long, global = true, value_enum, default_value_t)]
+    output: Output,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Shorten a url
+    Shorten {
+        url: String,
+        /// 301, 302, 307 or 308
+        #[arg(long)]
+        redirect_status: Option<u16>,
+        /// stop redirecting after this many clicks
+        #[arg(long)]
+        max_clicks: Option<i64>,
+        /// visitors must enter it first
+        #[arg(long)]
+        password: Option<String>,
+    },
+    /// Show where a short id or url leads, without following it
+    Resolve { id: String },
+    /// List your links
+    List,
+    /// Move a link to the trash
+    Delete { id: String },
+    /// Click stats of a link, or the summary of all links (admin) without id
+    Stats { id: Option<String> },
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let settings = Settings::load(cli.base_url, cli.api_key, cli.config)?;
+    let client = Client::new(settings)?;
+    let output = cli.output;
+
+    match cli.command {
+        Command::Shorten {
+            url,
+            redirect_status,
+            max_clicks,
+            password,
+        } => {
+            let req = ShortenReq {
+                url,
+                redirect_status,
+                max_clicks,
+                password,
+            };
+            print(&client.shorten(&req).await?, output)
+        }
+        Command::Resolve { id } => print(&client.resolve(&id).await?, output),
+        Command::List => print(&client.list().await?, output),
+        Command::Delete { id } => {
+            client.delete(&id).await?;
+            print(&Deleted { deleted: id }, output)
+        }
+        Command::Stats { id: Some(id) } => print(&client.link_stats(&id).await?, output),
+        Command::Stats { id: None } => print(&client.summary().await?, output),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn cli_should_be_valid() {
+        Cli::command().debug_assert();
+        let cli = Cli::parse_from(["shortctl", "stats", "abc123", "-o", "json"]);
+        assert_eq!(cli.output, Output::Json);
+        assert!(matches!(cli.command, Command::Stats { id: Some(id) } if id == "abc123"));
+
+        let cli = Cli::parse_from(["shortctl", "shorten", "https://www.rust-lang.org"]);
+        assert!(
+            matches!(cli.command, Command::Shorten { url, .. } if url == "https://www.rust-lang.org")
+        );
+    }
+}
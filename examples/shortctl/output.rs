@@ -0,0 +1,186 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use comfy_table::{presets::UTF8_FULL_CONDENSED, Table};
+use serde::Serialize;
+
+use crate::client::{Breakdown, Link, LinkStats, Resolved, ShortenRes, StatsSummary, TopLink};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum Output {
+    /// human readable tables
+    #[default]
+    Table,
+    /// the api response, for scripts
+    Json,
+}
+
+/// A command result, printable either way.
+pub trait Render: Serialize {
+    fn render(&self) -> String;
+}
+
+#[derive(Debug, Serialize)]
+pub struct Deleted {
+    pub deleted: String,
+}
+
+pub fn print<T: Render>(value: &T, output: Output) -> Result<()> {
+    match output {
+        Output::Table => println!("{}", value.render()),
+        Output::Json => println!("{}", serde_json::to_string_pretty(value)?),
+    }
+    Ok(())
+}
+
+fn table<const N: usize>(header: [&str; N]) -> Table {
+    let mut table = Table::new();
+    table.load_preset(UTF8_FULL_CONDENSED).set_header(header);
+    table
+}
+
+fn or_dash<T: ToString>(value: Option<T>) -> String {
+    value.map_or_else(|| "-".to_string(), |v| v.to_string())
+}
+
+impl Render for ShortenRes {
+    fn render(&self) -> String {
+        self.url.clone()
+    }
+}
+
+impl Render for Resolved {
+    fn render(&self) -> String {
+        let target = match (&self.destination, self.status) {
+            (Some(url), _) => url.clone(),
+            (None, 401) => "(password protected)".to_string(),
+            (None, _) => "(answers with a page, e.g. a warning)".to_string(),
+        };
+        format!("{} -> {target} [{}]", self.short_url, self.status)
+    }
+}
+
+impl Render for Deleted {
+    fn render(&self) -> String {
+        format!("{} moved to the trash", self.deleted)
+    }
+}
+
+impl Render for Vec<Link> {
+    fn render(&self) -> String {
+        let mut table = table(["id", "url", "clicks", "max", "domain", "flags", "updated"]);
+        for link in self {
+            let mut flags = vec![];
+            if link.protected {
+                flags.push("protected");
+            }
+            if link.flag_reason.is_some() {
+                flags.push("flagged");
+            }
+            if link.dead {
+                flags.push("dead");
+            }
+            table.add_row([
+                link.id.clone(),
+                link.url.clone(),
+                link.clicks.to_string(),
+                or_dash(link.max_clicks),
+                or_dash(link.domain.as_ref()),
+                flags.join(","),
+                link.updated_at.format("%Y-%m-%d %H:%M").to_string(),
+            ]);
+        }
+        table.to_string()
+    }
+}
+
+impl Render for LinkStats {
+    fn render(&self) -> String {
+        let mut table = table(["id", "clicks", "24h", "7d", "last click"]);
+        table.add_row([
+            self.id.clone(),
+            self.clicks.to_string(),
+            self.clicks_24h.to_string(),
+            self.clicks_7d.to_string(),
+            or_dash(self.last_click_at.map(|at| at.format("%Y-%m-%d %H:%M"))),
+        ]);
+        format!("{table}\n{}", render_breakdown(&self.breakdown))
+    }
+}
+
+impl Render for StatsSummary {
+    fn render(&self) -> String {
+        let mut totals = table(["links", "clicks", "generated"]);
+        totals.add_row([
+            self.total_links.to_string(),
+            self.total_clicks.to_string(),
+            self.generated_at.format("%Y-%m-%d %H:%M:%S").to_string(),
+        ]);
+        format!(
+            "{totals}\nTop 24h\n{}\nTop 7d\n{}\n{}",
+            render_top(&self.top_24h),
+            render_top(&self.top_7d),
+            render_breakdown(&self.breakdown)
+        )
+    }
+}
+
+fn render_top(links: &[TopLink]) -> Table {
+    let mut table = table(["id", "url", "clicks"]);
+    for link in links {
+        table.add_row([link.id.clone(), link.url.clone(), link.clicks.to_string()]);
+    }
+    table
+}
+
+fn render_breakdown(breakdown: &Breakdown) -> Table {
+    let mut table = table(["by", "name", "clicks"]);
+    let groups = [
+        ("country", &breakdown.countries),
+        ("browser", &breakdown.browsers),
+    ];
+    for (by, counts) in groups {
+        for count in counts {
+            table.add_row([by.to_string(), count.name.clone(), count.clicks.to_string()]);
+        }
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolved_should_describe_pages() {
+        let resolved = Resolved {
+            short_url: "http://127.0.0.1:9876/abc".to_string(),
+            status: 401,
+            destination: None,
+        };
+        assert_eq!(
+            resolved.render(),
+            "http://127.0.0.1:9876/abc -> (password protected) [401]"
+        );
+    }
+
+    #[test]
+    fn links_should_render_as_table() {
+        let links = vec![Link {
+            id: "abc123".to_string(),
+            url: "https://www.rust-lang.org".to_string(),
+            version: 1,
+            updated_at: "2024-05-01T12:00:00Z".parse().unwrap(),
+            redirect_status: None,
+            flag_reason: None,
+            clicks: 42,
+            max_clicks: None,
+            protected: true,
+            domain: None,
+            dead: false,
+        }];
+        let out = links.render();
+        assert!(out.contains("abc123"));
+        assert!(out.contains("protected"));
+        assert!(out.contains("2024-05-01 12:00"));
+    }
+}
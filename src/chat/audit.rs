@@ -0,0 +1,243 @@
+// what operators and the admin console did to others: kicks, bans and unbans, topics and
+// announcements, with who did it, to whom, what exactly, the length of a ban or the text of a
+// topic or announcement, and why. kept in the chat db, which refuses to change
+// or delete an entry once written. the admin console reads it back with /audit
+
+use std::fmt;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use sqlx::{FromRow, SqlitePool};
+
+// the actor of what was done from the admin console, no user may be named so
+pub const ADMIN: &str = "admin";
+
+#[derive(Debug)]
+pub struct AuditLog {
+    pool: SqlitePool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Kick,
+    Ban,
+    Unban,
+    Topic,
+    Announce,
+}
+
+#[derive(Debug, Clone, PartialEq, FromRow)]
+pub struct Entry {
+    pub id: i64,
+    pub action: String,
+    pub actor: String,
+    // a user, an address or a room, empty for an announcement to everyone
+    pub target: String,
+    // the length of a ban, the text of a topic or an announcement, empty for the others
+    pub detail: String,
+    // why, as the actor said, may be empty
+    pub reason: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl AuditLog {
+    pub async fn open(pool: SqlitePool) -> Result<Self> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS audit_log (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                action TEXT NOT NULL,
+                actor TEXT NOT NULL,
+                target TEXT NOT NULL,
+                detail TEXT NOT NULL DEFAULT '',
+                reason TEXT NOT NULL,
+                created_at TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+        // a log from before details had a column of their own gets it, its entries keep them in
+        // `reason` as they can't be changed
+        let columns: Vec<String> =
+            sqlx::query_scalar("SELECT name FROM pragma_table_info('audit_log')")
+                .fetch_all(&pool)
+                .await?;
+        if !columns.iter().any(|c| c == "detail") {
+            sqlx::query("ALTER TABLE audit_log ADD COLUMN detail TEXT NOT NULL DEFAULT ''")
+                .execute(&pool)
+                .await?;
+        }
+        // appended to only, whatever else is run against the db
+        for (name, event) in [
+            ("audit_log_no_update", "UPDATE"),
+            ("audit_log_no_delete", "DELETE"),
+        ] {
+            sqlx::query(&format!(
+                r#"
+                CREATE TRIGGER IF NOT EXISTS {} BEFORE {} ON audit_log
+                BEGIN
+                    SELECT RAISE(ABORT, 'the audit log is append-only');
+                END
+                "#,
+                name, event
+            ))
+            .execute(&pool)
+            .await?;
+        }
+        Ok(Self { pool })
+    }
+
+    pub async fn record(
+        &self,
+        action: Action,
+        actor: &str,
+        target: &str,
+        detail: &str,
+        reason: &str,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO audit_log (action, actor, target, detail, reason, created_at)
+            VALUES (?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(action.to_string())
+        .bind(actor)
+        .bind(target)
+        .bind(detail)
+        .bind(reason)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // the last `n` entries, oldest first
+    pub async fn recent(&self, n: i64) -> Result<Vec<Entry>> {
+        let mut entries: Vec<Entry> = sqlx::query_as(
+            r#"
+            SELECT id, action, actor, target, detail, reason, created_at FROM audit_log
+            ORDER BY id DESC LIMIT ?
+            "#,
+        )
+        .bind(n)
+        .fetch_all(&self.pool)
+        .await?;
+        entries.reverse();
+        Ok(entries)
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self {
+            Self::Kick => "kick",
+            Self::Ban => "ban",
+            Self::Unban => "unban",
+            Self::Topic => "topic",
+            Self::Announce => "announce",
+        };
+        write!(f, "{}", action)
+    }
+}
+
+// `2024-05-01 12:00:00 kick bob by alice: spamming`, or with a detail
+// `2024-05-01 12:00:00 ban bob by alice [banned for good]: spamming`
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}",
+            self.created_at.format("%Y-%m-%d %H:%M:%S"),
+            self.action
+        )?;
+        if !self.target.is_empty() {
+            write!(f, " {}", self.target)?;
+        }
+        write!(f, " by {}", self.actor)?;
+        if !self.detail.is_empty() {
+            write!(f, " [{}]", self.detail)?;
+        }
+        if !self.reason.is_empty() {
+            write!(f, ": {}", self.reason)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::chat::state::open_db;
+
+    #[tokio::test]
+    async fn entries_should_be_appended_only() {
+        let db = std::env::temp_dir().join(format!("chat-audit-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&db);
+        let pool = open_db(db.to_str().unwrap()).await.unwrap();
+        let log = AuditLog::open(pool.clone()).await.unwrap();
+        // opened again on the next start
+        let log = AuditLog::open(log.pool).await.unwrap();
+
+        log.record(Action::Kick, "alice", "bob", "", "spamming")
+            .await
+            .unwrap();
+        log.record(Action::Announce, ADMIN, "", "back in 5", "")
+            .await
+            .unwrap();
+        log.record(Action::Topic, "alice", "#rust", "all things rust", "")
+            .await
+            .unwrap();
+        let entries = log.recent(2).await.unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].action, "announce");
+        assert_eq!(entries[1].target, "#rust");
+        assert_eq!(entries[1].detail, "all things rust");
+        assert_eq!(entries[1].reason, "");
+        let shown = log.recent(10).await.unwrap()[0].to_string();
+        assert!(shown.ends_with(" kick bob by alice: spamming"), "{}", shown);
+        assert!(entries[0]
+            .to_string()
+            .ends_with(" announce by admin [back in 5]"));
+        assert!(entries[1]
+            .to_string()
+            .ends_with(" topic #rust by alice [all things rust]"));
+
+        assert!(sqlx::query("DELETE FROM audit_log")
+            .execute(&pool)
+            .await
+            .is_err());
+        assert!(sqlx::query("UPDATE audit_log SET actor = 'mallory'")
+            .execute(&pool)
+            .await
+            .is_err());
+        assert_eq!(log.recent(10).await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn logs_from_before_details_should_get_the_column() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        sqlx::query(
+            "CREATE TABLE audit_log (id INTEGER PRIMARY KEY AUTOINCREMENT, action TEXT NOT NULL, actor TEXT NOT NULL, target TEXT NOT NULL, reason TEXT NOT NULL, created_at TEXT NOT NULL)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO audit_log (action, actor, target, reason, created_at) VALUES ('topic', 'alice', '#rust', 'rust', '2024-05-01T12:00:00Z')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let log = AuditLog::open(pool).await.unwrap();
+        log.record(Action::Ban, "alice", "bob", "banned for good", "spamming")
+            .await
+            .unwrap();
+        let entries = log.recent(10).await.unwrap();
+        assert_eq!(entries[0].detail, "");
+        assert_eq!(entries[0].reason, "rust");
+        assert!(entries[1]
+            .to_string()
+            .ends_with(" ban bob by alice [banned for good]: spamming"));
+    }
+}
@@ -0,0 +1,285 @@
+// registered usernames and their passwords, and the failed logins of each address. no name is
+// registered while one just like it is, see `nicknames`
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+use anyhow::Result;
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
+use chrono::Utc;
+use dashmap::DashMap;
+use sqlx::SqlitePool;
+use tokio::time::Instant;
+
+use crate::chat::nicknames::skeleton;
+
+// guests get `guest-1234`, nobody may register such a name
+pub const GUEST_PREFIX: &str = "guest-";
+pub const MIN_PASSWORD: usize = 8;
+
+#[derive(Debug)]
+pub struct Accounts {
+    pool: SqlitePool,
+}
+
+// failed logins of one address, counted from the first one
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    since: Instant,
+}
+
+// too many failed logins from an address lock it out, until `lockout` after the first
+#[derive(Debug)]
+pub struct Logins {
+    failures: DashMap<IpAddr, Failures>,
+    max_failures: u32,
+    lockout: Duration,
+}
+
+impl Accounts {
+    pub async fn open(pool: SqlitePool) -> Result<Self> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS accounts (
+                username TEXT PRIMARY KEY,
+                password_hash TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                skeleton TEXT
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+        // a db from before lookalikes were checked gets the column, filled in for every account
+        let columns: Vec<String> =
+            sqlx::query_scalar("SELECT name FROM pragma_table_info('accounts')")
+                .fetch_all(&pool)
+                .await?;
+        if !columns.iter().any(|c| c == "skeleton") {
+            sqlx::query("ALTER TABLE accounts ADD COLUMN skeleton TEXT")
+                .execute(&pool)
+                .await?;
+        }
+        let unfilled: Vec<String> =
+            sqlx::query_scalar("SELECT username FROM accounts WHERE skeleton IS NULL")
+                .fetch_all(&pool)
+                .await?;
+        for username in unfilled {
+            sqlx::query("UPDATE accounts SET skeleton = ? WHERE username = ?")
+                .bind(skeleton(&username))
+                .bind(&username)
+                .execute(&pool)
+                .await?;
+        }
+        sqlx::query("CREATE INDEX IF NOT EXISTS accounts_skeleton ON accounts (skeleton)")
+            .execute(&pool)
+            .await?;
+        Ok(Self { pool })
+    }
+
+    // None if nobody registered `username`
+    pub async fn password_hash(&self, username: &str) -> Result<Option<String>> {
+        let hash = sqlx::query_scalar("SELECT password_hash FROM accounts WHERE username = ?")
+            .bind(username)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(hash)
+    }
+
+    // a registered name other than `username` which looks just like it, see `nicknames`
+    pub async fn lookalike(&self, username: &str) -> Result<Option<String>> {
+        let other = sqlx::query_scalar(
+            "SELECT username FROM accounts WHERE skeleton = ? AND username != ? LIMIT 1",
+        )
+        .bind(skeleton(username))
+        .bind(username)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(other)
+    }
+
+    // the name registered first, `username` itself or one just like it, None once `username`
+    // is registered. checked and inserted in one statement, two alike names can't both be
+    pub async fn register(&self, username: &str, password: &str) -> Result<Option<String>> {
+        let hash = hash_password(password.to_string()).await?;
+        let skeleton = skeleton(username);
+        let result = sqlx::query(
+            r#"
+            INSERT INTO accounts (username, password_hash, created_at, skeleton)
+            SELECT ?, ?, ?, ?
+            WHERE NOT EXISTS (SELECT 1 FROM accounts WHERE username = ? OR skeleton = ?)
+            "#,
+        )
+        .bind(username)
+        .bind(hash)
+        .bind(Utc::now())
+        .bind(&skeleton)
+        .bind(username)
+        .bind(&skeleton)
+        .execute(&self.pool)
+        .await?;
+        if result.rows_affected() > 0 {
+            return Ok(None);
+        }
+        let other: Option<String> = sqlx::query_scalar(
+            "SELECT username FROM accounts WHERE username = ? OR skeleton = ? LIMIT 1",
+        )
+        .bind(username)
+        .bind(&skeleton)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(Some(other.unwrap_or_else(|| username.to_string())))
+    }
+}
+
+impl Logins {
+    pub fn new(max_failures: u32, lockout: Duration) -> Self {
+        Self {
+            failures: DashMap::new(),
+            max_failures,
+            lockout,
+        }
+    }
+
+    // how long `ip` is still locked out for, None if it may try
+    pub fn locked(&self, ip: IpAddr, now: Instant) -> Option<Duration> {
+        let failures = self.failures.get(&ip)?;
+        let until = failures.since + self.lockout;
+        (failures.count >= self.max_failures && now < until).then(|| until - now)
+    }
+
+    // returns the failures of `ip` so far
+    pub fn failed(&self, ip: IpAddr, now: Instant) -> u32 {
+        let mut failures = self.failures.entry(ip).or_insert(Failures {
+            count: 0,
+            since: now,
+        });
+        if now >= failures.since + self.lockout {
+            *failures = Failures {
+                count: 0,
+                since: now,
+            };
+        }
+        failures.count += 1;
+        failures.count
+    }
+
+    pub fn succeeded(&self, ip: IpAddr) {
+        self.failures.remove(&ip);
+    }
+}
+
+// argon2 is slow on purpose, keep it off the async workers
+async fn hash_password(password: String) -> Result<String> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| anyhow::anyhow!("failed to hash password: {e}"))
+    })
+    .await?
+}
+
+pub async fn verify_password(password: String, hash: String) -> Result<bool> {
+    tokio::task::spawn_blocking(move || {
+        let hash =
+            PasswordHash::new(&hash).map_err(|e| anyhow::anyhow!("invalid password hash: {e}"))?;
+        Ok(Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok())
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn registered_passwords_should_verify() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let accounts = Accounts::open(pool).await.unwrap();
+        assert_eq!(accounts.password_hash("alice").await.unwrap(), None);
+
+        assert_eq!(
+            accounts.register("alice", "correct horse").await.unwrap(),
+            None
+        );
+        let taken = accounts.register("alice", "another one").await.unwrap();
+        assert_eq!(taken.as_deref(), Some("alice"));
+        let hash = accounts.password_hash("alice").await.unwrap().unwrap();
+        assert!(!hash.contains("correct horse"));
+        assert!(verify_password("correct horse".into(), hash.clone())
+            .await
+            .unwrap());
+        assert!(!verify_password("another one".into(), hash).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn lookalikes_of_registered_names_should_not_register() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let accounts = Accounts::open(pool).await.unwrap();
+        assert_eq!(
+            accounts.register("alice", "correct horse").await.unwrap(),
+            None
+        );
+
+        // a cyrillic а
+        let lookalike = accounts.lookalike("\u{430}lice").await.unwrap();
+        assert_eq!(lookalike.as_deref(), Some("alice"));
+        assert_eq!(accounts.lookalike("alice").await.unwrap(), None);
+        assert_eq!(accounts.lookalike("bob").await.unwrap(), None);
+        for name in ["\u{430}lice", "ALICE", "a1ice"] {
+            let taken = accounts.register(name, "another one").await.unwrap();
+            assert_eq!(taken.as_deref(), Some("alice"), "{}", name);
+            assert_eq!(accounts.password_hash(name).await.unwrap(), None);
+        }
+        assert_eq!(accounts.register("bob", "another one").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn accounts_from_before_skeletons_should_get_them() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        sqlx::query(
+            "CREATE TABLE accounts (username TEXT PRIMARY KEY, password_hash TEXT NOT NULL, created_at TEXT NOT NULL)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO accounts VALUES ('alice', 'hash', '2024-01-01T00:00:00Z')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let accounts = Accounts::open(pool).await.unwrap();
+        let lookalike = accounts.lookalike("Alice").await.unwrap();
+        assert_eq!(lookalike.as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn failed_logins_should_lock_out_for_a_while() {
+        let logins = Logins::new(3, Duration::from_secs(60));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        let start = Instant::now();
+
+        assert_eq!(logins.failed(ip, start), 1);
+        assert_eq!(logins.failed(ip, start), 2);
+        assert_eq!(logins.locked(ip, start), None);
+        assert_eq!(logins.failed(ip, start + Duration::from_secs(10)), 3);
+        let later = start + Duration::from_secs(20);
+        assert_eq!(logins.locked(ip, later), Some(Duration::from_secs(40)));
+        assert_eq!(logins.locked(other, later), None);
+
+        // counted afresh once the lockout is over
+        let over = start + Duration::from_secs(60);
+        assert_eq!(logins.locked(ip, over), None);
+        assert_eq!(logins.failed(ip, over), 1);
+        logins.succeeded(ip);
+        assert_eq!(logins.failed(ip, over), 1);
+    }
+}
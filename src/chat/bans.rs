@@ -0,0 +1,132 @@
+// bans by username or ip, until a time or for good, kept in sqlite across restarts
+
+use std::fmt;
+use std::net::IpAddr;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use sqlx::SqlitePool;
+use tracing::warn;
+
+// bans by username or ip, kept across restarts in the same db
+#[derive(Debug)]
+pub(super) struct Bans {
+    pool: SqlitePool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum BanTarget {
+    User(String),
+    Ip(IpAddr),
+}
+
+#[derive(Debug, PartialEq)]
+pub(super) struct Ban {
+    // None for good
+    pub(super) until: Option<DateTime<Utc>>,
+}
+
+impl BanTarget {
+    // an ip if it reads as one, a username otherwise
+    pub(super) fn parse(target: &str) -> Self {
+        match target.parse() {
+            Ok(ip) => Self::Ip(ip),
+            Err(_) => Self::User(target.to_string()),
+        }
+    }
+
+    // the primary key in the bans table
+    fn key(&self) -> String {
+        match self {
+            Self::User(name) => format!("user:{}", name),
+            Self::Ip(ip) => format!("ip:{}", ip),
+        }
+    }
+}
+
+impl fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::User(name) => write!(f, "{}", name),
+            Self::Ip(ip) => write!(f, "{}", ip),
+        }
+    }
+}
+
+impl fmt::Display for Ban {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.until {
+            Some(until) => write!(f, "banned until {}", until.format("%Y-%m-%d %H:%M:%S UTC")),
+            None => write!(f, "banned for good"),
+        }
+    }
+}
+
+impl Bans {
+    pub(super) async fn open(pool: SqlitePool) -> Result<Self> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS bans (
+                target TEXT PRIMARY KEY,
+                until TEXT,
+                banned_by TEXT NOT NULL,
+                created_at TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+        Ok(Self { pool })
+    }
+
+    // a later ban of the same target replaces the earlier one
+    pub(super) async fn add(
+        &self,
+        target: &BanTarget,
+        until: Option<DateTime<Utc>>,
+        by: &str,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO bans (target, until, banned_by, created_at) VALUES (?, ?, ?, ?)
+            ON CONFLICT (target) DO UPDATE SET
+                until = excluded.until,
+                banned_by = excluded.banned_by,
+                created_at = excluded.created_at
+            "#,
+        )
+        .bind(target.key())
+        .bind(until)
+        .bind(by)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // false if there was no ban
+    pub(super) async fn remove(&self, target: &BanTarget) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM bans WHERE target = ?")
+            .bind(target.key())
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    // expired bans don't count, a failing db lets everyone in
+    pub(super) async fn banned(&self, target: &BanTarget) -> Option<Ban> {
+        let until: Option<Option<DateTime<Utc>>> =
+            sqlx::query_scalar("SELECT until FROM bans WHERE target = ?")
+                .bind(target.key())
+                .fetch_optional(&self.pool)
+                .await
+                .unwrap_or_else(|e| {
+                    warn!(%target, error = ?e, "failed to look up ban");
+                    None
+                });
+        match until? {
+            Some(until) if until <= Utc::now() => None,
+            until => Some(Ban { until }),
+        }
+    }
+}
@@ -0,0 +1,247 @@
+// bots living in the server, e.g. `CHAT_BOTS=echo,dice`. they hear what is said in every room
+// and who joins, and answer in the room under their own name. they run in a task of their own,
+// a slow bot never holds up a broadcast
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use rand::Rng;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{info, warn};
+
+use crate::chat::{Message, State, REMOTE};
+
+// events wait here while the bots are busy, later ones are dropped
+const BACKLOG: usize = 1024;
+// the most dice and sides of one roll
+const MAX_DICE: u32 = 20;
+const MAX_SIDES: u32 = 1000;
+
+pub trait Bot: Send + Sync {
+    // what it says things as, nobody may log in as it
+    fn name(&self) -> &str;
+
+    // a chat line of someone else, the reply is said in `room`
+    fn on_message(&self, _room: &str, _sender: &str, _content: &str) -> Option<String> {
+        None
+    }
+
+    fn on_join(&self, _room: &str, _username: &str) -> Option<String> {
+        None
+    }
+}
+
+// the bots attached at startup, and the way to them
+pub struct Bots {
+    bots: Vec<Arc<dyn Bot>>,
+    events: mpsc::Sender<BotEvent>,
+}
+
+#[derive(Debug)]
+pub enum BotEvent {
+    Message {
+        room: String,
+        sender: String,
+        content: String,
+    },
+    Join {
+        room: String,
+        username: String,
+    },
+}
+
+// `!echo text` says text back
+pub struct Echo;
+
+// `!roll` or `!roll 2d6` rolls dice
+pub struct Dice;
+
+impl Bots {
+    // the receiving end is for `run`
+    pub fn new(names: &[String]) -> Result<(Self, mpsc::Receiver<BotEvent>)> {
+        let (events, received) = mpsc::channel(BACKLOG);
+        let mut bots = Self {
+            bots: Vec::new(),
+            events,
+        };
+        for name in names {
+            bots.register(bot(name)?);
+        }
+        Ok((bots, received))
+    }
+
+    pub fn register(&mut self, bot: Arc<dyn Bot>) {
+        self.bots.push(bot);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bots.is_empty()
+    }
+
+    pub fn is_bot(&self, name: &str) -> bool {
+        self.bots.iter().any(|bot| bot.name() == name)
+    }
+
+    // queued without waiting, like everything else here
+    pub fn message(&self, room: &str, sender: &str, content: &str) {
+        self.queue(BotEvent::Message {
+            room: room.to_string(),
+            sender: sender.to_string(),
+            content: content.to_string(),
+        });
+    }
+
+    pub fn joined(&self, room: &str, username: &str) {
+        self.queue(BotEvent::Join {
+            room: room.to_string(),
+            username: username.to_string(),
+        });
+    }
+
+    fn queue(&self, event: BotEvent) {
+        if self.bots.is_empty() {
+            return;
+        }
+        match self.events.try_send(event) {
+            Ok(()) | Err(TrySendError::Closed(_)) => {}
+            Err(TrySendError::Full(_)) => warn!("bots are behind, event dropped"),
+        }
+    }
+}
+
+impl std::fmt::Debug for Bots {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.bots.iter().map(|bot| bot.name()))
+            .finish()
+    }
+}
+
+impl Bot for Echo {
+    fn name(&self) -> &str {
+        "echo"
+    }
+
+    fn on_message(&self, _room: &str, _sender: &str, content: &str) -> Option<String> {
+        let text = content.strip_prefix("!echo ")?.trim();
+        (!text.is_empty()).then(|| text.to_string())
+    }
+}
+
+impl Bot for Dice {
+    fn name(&self) -> &str {
+        "dice"
+    }
+
+    fn on_message(&self, _room: &str, sender: &str, content: &str) -> Option<String> {
+        let args = match content.trim() {
+            "!roll" => "1d6",
+            content => content.strip_prefix("!roll ")?.trim(),
+        };
+        let Some((dice, sides)) = parse_roll(args) else {
+            let usage = format!(
+                "{}, roll with !roll NdM, at most {}d{}",
+                sender, MAX_DICE, MAX_SIDES
+            );
+            return Some(usage);
+        };
+        let mut rng = rand::thread_rng();
+        let rolls: Vec<u32> = (0..dice).map(|_| rng.gen_range(1..=sides)).collect();
+        let total: u32 = rolls.iter().sum();
+        let rolls: Vec<String> = rolls.iter().map(u32::to_string).collect();
+        Some(format!(
+            "{} rolled {}d{}: {} = {}",
+            sender,
+            dice,
+            sides,
+            rolls.join(" + "),
+            total
+        ))
+    }
+}
+
+// hands what the rooms said to every bot, and says their replies, until shutdown
+pub async fn run(state: Arc<State>, mut events: mpsc::Receiver<BotEvent>) {
+    info!(bots = ?state.bots, "bots running");
+    loop {
+        let event = tokio::select! {
+            _ = state.shutdown.cancelled() => return,
+            event = events.recv() => match event {
+                Some(event) => event,
+                None => return,
+            },
+        };
+        for bot in &state.bots.bots {
+            let (room, reply) = match &event {
+                BotEvent::Message {
+                    room,
+                    sender,
+                    content,
+                } => (room, bot.on_message(room, sender, content)),
+                BotEvent::Join { room, username } => (room, bot.on_join(room, username)),
+            };
+            if let Some(reply) = reply {
+                // said in the room like anyone's line, the one it answers hears it too
+                let message = Arc::new(Message::chat(bot.name(), room, &reply));
+                state.keep(&message, None).await;
+                state.broadcast(room, REMOTE, &message);
+            }
+        }
+    }
+}
+
+// `2d6`, or `d20` for one die
+fn parse_roll(text: &str) -> Option<(u32, u32)> {
+    let (dice, sides) = text.split_once('d')?;
+    let dice = match dice {
+        "" => 1,
+        dice => dice.parse().ok()?,
+    };
+    let sides = sides.parse().ok()?;
+    ((1..=MAX_DICE).contains(&dice) && (2..=MAX_SIDES).contains(&sides)).then_some((dice, sides))
+}
+
+fn bot(name: &str) -> Result<Arc<dyn Bot>> {
+    match name {
+        "echo" => Ok(Arc::new(Echo)),
+        "dice" => Ok(Arc::new(Dice)),
+        other => anyhow::bail!("Unknown bot {}, use echo or dice", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rolls_should_parse() {
+        assert_eq!(parse_roll("2d6"), Some((2, 6)));
+        assert_eq!(parse_roll("d20"), Some((1, 20)));
+        assert_eq!(parse_roll("0d6"), None);
+        assert_eq!(parse_roll("21d6"), None);
+        assert_eq!(parse_roll("2d1"), None);
+        assert_eq!(parse_roll("two dice"), None);
+    }
+
+    #[test]
+    fn bots_should_answer_their_commands_only() {
+        assert_eq!(
+            Echo.on_message("#rust", "alice", "!echo  hi there"),
+            Some("hi there".to_string())
+        );
+        assert_eq!(Echo.on_message("#rust", "alice", "hi there"), None);
+        assert_eq!(Echo.on_join("#rust", "alice"), None);
+
+        let rolled = Dice.on_message("#rust", "alice", "!roll 3d1").unwrap();
+        assert_eq!(rolled, "alice, roll with !roll NdM, at most 20d1000");
+        let rolled = Dice.on_message("#rust", "alice", "!roll").unwrap();
+        let total: u32 = rolled.rsplit(' ').next().unwrap().parse().unwrap();
+        assert!(rolled.starts_with("alice rolled 1d6: ") && (1..=6).contains(&total));
+        assert_eq!(Dice.on_message("#rust", "alice", "!rolling"), None);
+
+        let names = ["echo".to_string(), "dice".to_string()];
+        let (bots, _) = Bots::new(&names).unwrap();
+        assert!(bots.is_bot("dice") && !bots.is_bot("alice"));
+        assert!(Bots::new(&["eliza".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,164 @@
+// several servers sharing their rooms: each publishes what is said in a room to redis, and
+// delivers what the others published to its own members. names, sessions and history stay
+// with each server
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::StreamExt;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{info, warn};
+
+use crate::chat::{Message, State};
+
+// a room's channel is this and its name
+const CHANNEL_PREFIX: &str = "chat:room:";
+// published messages wait here while redis is slow, later ones are dropped
+const OUTBOX: usize = 1024;
+// before connecting to redis again
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub struct Cluster {
+    // tells our own messages apart, redis sends them back to us
+    instance: String,
+    outbox: mpsc::Sender<(String, Vec<u8>)>,
+}
+
+// what goes over redis, as json
+#[derive(Debug, Serialize)]
+struct Envelope<'a> {
+    origin: &'a str,
+    room: &'a str,
+    message: &'a Message,
+}
+
+#[derive(Debug, Deserialize)]
+struct Received {
+    origin: String,
+    room: String,
+    message: Message,
+}
+
+impl Cluster {
+    // the receiving end of the outbox is for `run`
+    pub fn new() -> (Self, mpsc::Receiver<(String, Vec<u8>)>) {
+        let (outbox, published) = mpsc::channel(OUTBOX);
+        let cluster = Self {
+            instance: format!("{:016x}", rand::random::<u64>()),
+            outbox,
+        };
+        (cluster, published)
+    }
+
+    // queued without waiting, the broadcast doesn't wait for redis
+    pub fn publish(&self, room: &str, message: &Message) {
+        let envelope = Envelope {
+            origin: &self.instance,
+            room,
+            message,
+        };
+        let payload = match serde_json::to_vec(&envelope) {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!(error = ?e, "failed to encode message for the cluster");
+                return;
+            }
+        };
+        let channel = format!("{}{}", CHANNEL_PREFIX, room);
+        match self.outbox.try_send((channel, payload)) {
+            Ok(()) | Err(TrySendError::Closed(_)) => {}
+            Err(TrySendError::Full(_)) => warn!(room, "cluster outbox is full, message dropped"),
+        }
+    }
+
+    // the room and message published by another instance, None for our own or garbage
+    fn receive(&self, payload: &[u8]) -> Option<(String, Message)> {
+        match serde_json::from_slice::<Received>(payload) {
+            Ok(received) if received.origin == self.instance => None,
+            Ok(received) => Some((received.room, received.message)),
+            Err(e) => {
+                warn!(error = ?e, "invalid message from the cluster");
+                None
+            }
+        }
+    }
+}
+
+// publishes the outbox and delivers what the others publish, reconnecting until shutdown
+pub async fn run(
+    state: Arc<State>,
+    url: String,
+    mut published: mpsc::Receiver<(String, Vec<u8>)>,
+) -> Result<()> {
+    let client = redis::Client::open(url)?;
+    loop {
+        let bridged = tokio::select! {
+            _ = state.shutdown.cancelled() => return Ok(()),
+            bridged = bridge(&state, &client, &mut published) => bridged,
+        };
+        if let Err(e) = bridged {
+            warn!(error = ?e, "lost the cluster, reconnecting");
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn bridge(
+    state: &State,
+    client: &redis::Client,
+    published: &mut mpsc::Receiver<(String, Vec<u8>)>,
+) -> Result<()> {
+    let Some(cluster) = &state.cluster else {
+        return Ok(());
+    };
+    let mut publisher = client.get_multiplexed_tokio_connection().await?;
+    let mut subscriber = client.get_async_pubsub().await?;
+    subscriber
+        .psubscribe(format!("{}*", CHANNEL_PREFIX))
+        .await?;
+    let mut messages = subscriber.on_message();
+    info!(instance = %cluster.instance, "joined the cluster");
+    loop {
+        tokio::select! {
+            outgoing = published.recv() => {
+                let Some((channel, payload)) = outgoing else {
+                    return Ok(());
+                };
+                publisher.publish::<_, _, ()>(channel, payload).await?;
+            }
+            incoming = messages.next() => {
+                let Some(incoming) = incoming else {
+                    anyhow::bail!("subscription closed");
+                };
+                if let Some((room, message)) = cluster.receive(incoming.get_payload_bytes()) {
+                    state.fan_out(&room, crate::chat::REMOTE, &Arc::new(message));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn own_messages_should_not_come_back() {
+        let (ours, mut published) = Cluster::new();
+        let (theirs, _) = Cluster::new();
+        let message = Message::chat("alice", "#rust", "hi");
+        ours.publish("#rust", &message);
+        let (channel, payload) = published.try_recv().unwrap();
+        assert_eq!(channel, "chat:room:#rust");
+
+        assert!(ours.receive(&payload).is_none());
+        let (room, received) = theirs.receive(&payload).unwrap();
+        assert_eq!(room, "#rust");
+        assert_eq!(received, message);
+        assert!(theirs.receive(b"not json").is_none());
+    }
+}
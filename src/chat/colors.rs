@@ -0,0 +1,94 @@
+// a color per user, the same on every connection, server and restart: a hash of the name picks
+// one of the 12 ansi colors which aren't black, white or grey. json clients get it as a hint next
+// to the sender, text clients see names painted after /set color on
+
+// the ansi code and how it usually looks, for clients that draw their own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color {
+    pub ansi: u8,
+    pub hex: &'static str,
+}
+
+const PALETTE: [Color; 12] = [
+    Color {
+        ansi: 31,
+        hex: "#cd3131",
+    },
+    Color {
+        ansi: 32,
+        hex: "#0dbc79",
+    },
+    Color {
+        ansi: 33,
+        hex: "#e5e510",
+    },
+    Color {
+        ansi: 34,
+        hex: "#2472c8",
+    },
+    Color {
+        ansi: 35,
+        hex: "#bc3fbc",
+    },
+    Color {
+        ansi: 36,
+        hex: "#11a8cd",
+    },
+    Color {
+        ansi: 91,
+        hex: "#f14c4c",
+    },
+    Color {
+        ansi: 92,
+        hex: "#23d18b",
+    },
+    Color {
+        ansi: 93,
+        hex: "#f5f543",
+    },
+    Color {
+        ansi: 94,
+        hex: "#3b8eea",
+    },
+    Color {
+        ansi: 95,
+        hex: "#d670d6",
+    },
+    Color {
+        ansi: 96,
+        hex: "#29b8db",
+    },
+];
+
+// fnv-1a, unlike std's hasher it is the same in every build
+pub fn of(name: &str) -> Color {
+    let hash = name.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+    PALETTE[(hash % PALETTE.len() as u64) as usize]
+}
+
+// `name` in its color, for a terminal
+pub fn paint(name: &str) -> String {
+    format!("\x1b[{}m{}\x1b[0m", of(name).ansi, name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn names_should_keep_their_color() {
+        assert_eq!(of("alice"), of("alice"));
+        assert_eq!(
+            paint("alice"),
+            format!("\x1b[{}malice\x1b[0m", of("alice").ansi)
+        );
+        // spread over the palette, not all the same
+        let names = ["alice", "bob", "carol", "dave", "erin", "frank", "grace"];
+        let colors: BTreeSet<u8> = names.iter().map(|name| of(name).ansi).collect();
+        assert!(colors.len() > 3, "{:?}", colors);
+    }
+}
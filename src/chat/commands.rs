@@ -0,0 +1,632 @@
+// slash commands typed by chat clients: what they parse into, how to use them and who may
+
+use std::fmt;
+use std::time::Duration;
+
+use chrono::NaiveDate;
+
+use crate::chat::registry::Mode;
+use crate::chat::{BanTarget, MAX_HISTORY, REPLAY_MESSAGES};
+
+#[derive(Debug, PartialEq)]
+pub enum Command<'a> {
+    Join(String),
+    Leave,
+    Nick(&'a str),
+    History(i64),
+    // any traffic keeps a client alive, a pong is just the cheapest
+    Pong,
+    // the messages numbered up to this one arrived, from json clients
+    Ack(u64),
+    Help,
+    Who,
+    Server,
+    Away(Option<&'a str>),
+    // whose messages not to get, by name
+    Ignore(&'a str),
+    Unignore(&'a str),
+    Ignores,
+    Set(Setting),
+    Me(&'a str),
+    Quit,
+    // and why, told to the user
+    Kick(&'a str, Option<&'a str>),
+    Ban(BanTarget, Option<Duration>, Option<&'a str>),
+    Unban(BanTarget),
+    Replay(NaiveDate),
+    Mentions,
+    // shown without a topic, set by operators with one
+    Topic(Option<&'a str>),
+    // shown without a mode
+    Mode(Option<(Mode, bool)>),
+    Invite(&'a str),
+    // another operator of the room, by name
+    Op(&'a str),
+    Send(&'a str),
+    // whether the user is typing, for clients which show it
+    Typing(bool),
+    // the id of a message and its new text
+    Edit(&'a str, &'a str),
+    Delete(&'a str),
+    // the lines up to /end are one message
+    Paste,
+    Chat(&'a str),
+}
+
+// per connection preferences
+#[derive(Debug, PartialEq)]
+pub enum Setting {
+    Timestamps(bool),
+    // the ids to /edit and /delete messages by
+    Ids(bool),
+    // senders in their color, for terminals
+    Color(bool),
+}
+
+// one command, named without its slash
+pub struct Spec {
+    pub name: &'static str,
+    // the arguments, as shown by /help and in usage errors
+    pub args: &'static str,
+    pub about: &'static str,
+    pub operators_only: bool,
+    // from the trimmed text after the name, None if it doesn't fit `args`
+    pub parse: for<'a> fn(&'a str) -> Option<Command<'a>>,
+}
+
+// the commands clients may type, in the order /help lists them
+pub struct Commands {
+    specs: Vec<Spec>,
+}
+
+impl Default for Commands {
+    fn default() -> Self {
+        let mut commands = Self { specs: Vec::new() };
+        commands.register(Spec {
+            name: "help",
+            args: "",
+            about: "list the commands you may use",
+            operators_only: false,
+            parse: |args| none(args, Command::Help),
+        });
+        commands.register(Spec {
+            name: "join",
+            args: "#room",
+            about: "leave your room for another one",
+            operators_only: false,
+            parse: |args| {
+                let name = one(args)?.trim_start_matches('#');
+                (!name.is_empty()).then(|| Command::Join(format!("#{}", name)))
+            },
+        });
+        commands.register(Spec {
+            name: "leave",
+            args: "",
+            about: "leave your room",
+            operators_only: false,
+            parse: |args| none(args, Command::Leave),
+        });
+        commands.register(Spec {
+            name: "who",
+            args: "",
+            about: "list the users in your room, how long they have been idle and who is away",
+            operators_only: false,
+            parse: |args| none(args, Command::Who),
+        });
+        commands.register(Spec {
+            name: "server",
+            args: "",
+            about: "show the server's version, uptime, users and rooms",
+            operators_only: false,
+            parse: |args| none(args, Command::Server),
+        });
+        commands.register(Spec {
+            name: "topic",
+            args: "[topic]",
+            about: "show the topic of your room, its operators set it",
+            operators_only: false,
+            parse: |args| Some(Command::Topic((!args.is_empty()).then_some(args))),
+        });
+        commands.register(Spec {
+            name: "away",
+            args: "[message]",
+            about: "mark yourself away, or back again without a message",
+            operators_only: false,
+            parse: |args| Some(Command::Away((!args.is_empty()).then_some(args))),
+        });
+        commands.register(Spec {
+            name: "ignore",
+            args: "name",
+            about: "stop getting messages from a user",
+            operators_only: false,
+            parse: |args| one(args).map(Command::Ignore),
+        });
+        commands.register(Spec {
+            name: "unignore",
+            args: "name",
+            about: "get messages from an ignored user again",
+            operators_only: false,
+            parse: |args| one(args).map(Command::Unignore),
+        });
+        commands.register(Spec {
+            name: "ignores",
+            args: "",
+            about: "list the users you ignore",
+            operators_only: false,
+            parse: |args| none(args, Command::Ignores),
+        });
+        commands.register(Spec {
+            name: "nick",
+            args: "name",
+            about: "change your name",
+            operators_only: false,
+            parse: |args| one(args).map(Command::Nick),
+        });
+        commands.register(Spec {
+            name: "me",
+            args: "action",
+            about: "tell your room what you are doing",
+            operators_only: false,
+            parse: |args| (!args.is_empty()).then_some(Command::Me(args)),
+        });
+        commands.register(Spec {
+            name: "paste",
+            args: "",
+            about: "send the lines up to /end as one message",
+            operators_only: false,
+            parse: |args| none(args, Command::Paste),
+        });
+        commands.register(Spec {
+            name: "edit",
+            args: "id text",
+            about: "change what you said, see /set ids",
+            operators_only: false,
+            parse: |args| {
+                let (id, text) = args.split_once(char::is_whitespace)?;
+                Some(Command::Edit(id, text.trim_start()))
+            },
+        });
+        commands.register(Spec {
+            name: "delete",
+            args: "id",
+            about: "take back what you said, operators delete anyone's",
+            operators_only: false,
+            parse: |args| one(args).map(Command::Delete),
+        });
+        commands.register(Spec {
+            name: "send",
+            args: "filename",
+            about: "share a file with your room, you get a link to upload it to",
+            operators_only: false,
+            parse: |args| one(args).map(Command::Send),
+        });
+        commands.register(Spec {
+            name: "set",
+            args: "timestamps|ids|color on|off",
+            about: "show when messages were sent, their ids, or who sent them in color",
+            operators_only: false,
+            parse: |args| {
+                let mut words = args.split_whitespace();
+                let setting = words.next()?;
+                let on = match (words.next()?, words.next()) {
+                    ("on", None) => true,
+                    ("off", None) => false,
+                    _ => return None,
+                };
+                match setting {
+                    "timestamps" => Some(Command::Set(Setting::Timestamps(on))),
+                    "ids" => Some(Command::Set(Setting::Ids(on))),
+                    "color" => Some(Command::Set(Setting::Color(on))),
+                    _ => None,
+                }
+            },
+        });
+        commands.register(Spec {
+            name: "history",
+            args: "[n]",
+            about: "replay the last messages of your room",
+            operators_only: false,
+            parse: |args| match args {
+                "" => Some(Command::History(REPLAY_MESSAGES)),
+                n => match n.parse::<i64>() {
+                    Ok(n) if n > 0 => Some(Command::History(n.min(MAX_HISTORY))),
+                    _ => None,
+                },
+            },
+        });
+        commands.register(Spec {
+            name: "mentions",
+            args: "",
+            about: "read the messages naming you with @name since you last looked",
+            operators_only: false,
+            parse: |args| none(args, Command::Mentions),
+        });
+        commands.register(Spec {
+            name: "typing",
+            args: "on|off",
+            about: "tell your room you started or stopped typing",
+            operators_only: false,
+            parse: |args| match args {
+                "on" => Some(Command::Typing(true)),
+                "off" => Some(Command::Typing(false)),
+                _ => None,
+            },
+        });
+        commands.register(Spec {
+            name: "pong",
+            args: "",
+            about: "answer a ping",
+            operators_only: false,
+            parse: |args| none(args, Command::Pong),
+        });
+        commands.register(Spec {
+            name: "ack",
+            args: "seq",
+            about: "tell the server the messages numbered up to seq arrived",
+            operators_only: false,
+            parse: |args| args.parse().ok().map(Command::Ack),
+        });
+        commands.register(Spec {
+            name: "quit",
+            args: "",
+            about: "disconnect",
+            operators_only: false,
+            parse: |args| none(args, Command::Quit),
+        });
+        commands.register(Spec {
+            name: "kick",
+            args: "name [reason]",
+            about: "disconnect a user, telling them why",
+            operators_only: true,
+            parse: |args| {
+                let (name, reason) = args.split_once(' ').unwrap_or((args, ""));
+                let reason = reason.trim();
+                (!name.is_empty())
+                    .then(|| Command::Kick(name, (!reason.is_empty()).then_some(reason)))
+            },
+        });
+        commands.register(Spec {
+            name: "ban",
+            args: "name|ip [duration] [reason]",
+            about: "kick and keep out a user or address, for good or e.g. 30m, 12h or 7d, telling them why",
+            operators_only: true,
+            parse: |args| {
+                let (target, rest) = args.split_once(' ').unwrap_or((args, ""));
+                if target.is_empty() {
+                    return None;
+                }
+                let rest = rest.trim();
+                // a word starting with a digit is the duration, what follows it the reason
+                let (word, after) = rest.split_once(' ').unwrap_or((rest, ""));
+                let (duration, reason) = match word.starts_with(|c: char| c.is_ascii_digit()) {
+                    true => (Some(parse_duration(word)?), after.trim()),
+                    false => (None, rest),
+                };
+                let reason = (!reason.is_empty()).then_some(reason);
+                Some(Command::Ban(BanTarget::parse(target), duration, reason))
+            },
+        });
+        commands.register(Spec {
+            name: "unban",
+            args: "name|ip",
+            about: "let a user or address back in",
+            operators_only: true,
+            parse: |args| one(args).map(|target| Command::Unban(BanTarget::parse(target))),
+        });
+        commands.register(Spec {
+            name: "replay",
+            args: "yyyy-mm-dd",
+            about: "read back the log of your room on a day",
+            operators_only: true,
+            parse: |args| {
+                let date = NaiveDate::parse_from_str(one(args)?, "%Y-%m-%d").ok()?;
+                Some(Command::Replay(date))
+            },
+        });
+        commands.register(Spec {
+            name: "mode",
+            args: "[read-only|invite-only on|off]",
+            about: "show or change who may post in and join your room, as its operator",
+            operators_only: false,
+            parse: |args| {
+                let mut words = args.split_whitespace();
+                let (mode, on) = match (words.next(), words.next(), words.next()) {
+                    (None, _, _) => return Some(Command::Mode(None)),
+                    (Some(mode), Some(on), None) => (mode, on),
+                    _ => return None,
+                };
+                let mode = mode.parse().ok()?;
+                match on {
+                    "on" => Some(Command::Mode(Some((mode, true)))),
+                    "off" => Some(Command::Mode(Some((mode, false)))),
+                    _ => None,
+                }
+            },
+        });
+        commands.register(Spec {
+            name: "invite",
+            args: "name",
+            about: "let a user join your invite-only room, as its operator",
+            operators_only: false,
+            parse: |args| one(args).map(Command::Invite),
+        });
+        commands.register(Spec {
+            name: "op",
+            args: "name",
+            about: "make a user an operator of your room, as its operator",
+            operators_only: false,
+            parse: |args| one(args).map(Command::Op),
+        });
+        commands
+    }
+}
+
+impl Commands {
+    // replaces a command of the same name
+    pub fn register(&mut self, spec: Spec) {
+        match self.specs.iter_mut().find(|s| s.name == spec.name) {
+            Some(existing) => *existing = spec,
+            None => self.specs.push(spec),
+        }
+    }
+
+    // anything not starting with `/` is a chat message. the error is for the sender only
+    pub fn parse<'a>(&self, line: &'a str, operator: bool) -> Result<Command<'a>, String> {
+        let Some(command) = line.strip_prefix('/') else {
+            return Ok(Command::Chat(line));
+        };
+        let (name, args) = command
+            .split_once(char::is_whitespace)
+            .unwrap_or((command, ""));
+        let Some(spec) = self.specs.iter().find(|s| s.name == name) else {
+            return Err(format!("Unknown command: /{}, try /help", name));
+        };
+        if spec.operators_only && !operator {
+            return Err("Only operators can do that".to_string());
+        }
+        (spec.parse)(args.trim()).ok_or_else(|| format!("Usage: {}", spec.usage()))
+    }
+
+    // one line per command
+    pub fn help(&self, operator: bool) -> Vec<String> {
+        self.specs
+            .iter()
+            .filter(|spec| operator || !spec.operators_only)
+            .map(|spec| format!("{} - {}", spec.usage(), spec.about))
+            .collect()
+    }
+}
+
+impl fmt::Debug for Commands {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.specs.iter().map(|spec| spec.name))
+            .finish()
+    }
+}
+
+impl Spec {
+    fn usage(&self) -> String {
+        match self.args {
+            "" => format!("/{}", self.name),
+            args => format!("/{} {}", self.name, args),
+        }
+    }
+}
+
+// `command` if there are no arguments
+fn none<'a>(args: &str, command: Command<'a>) -> Option<Command<'a>> {
+    args.is_empty().then_some(command)
+}
+
+// the only word of `args`
+fn one(args: &str) -> Option<&str> {
+    let mut words = args.split_whitespace();
+    match (words.next(), words.next()) {
+        (Some(word), None) => Some(word),
+        _ => None,
+    }
+}
+
+// `90s`, `30m`, `12h` or `7d`
+fn parse_duration(text: &str) -> Option<Duration> {
+    let unit = match text.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    let n: u64 = text[..text.len() - 1].parse().ok()?;
+    (n > 0).then(|| Duration::from_secs(n * unit))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::*;
+
+    #[test]
+    fn commands_should_parse() {
+        let commands = Commands::default();
+        let parse = |line| commands.parse(line, false);
+        assert_eq!(parse("hello /all"), Ok(Command::Chat("hello /all")));
+        assert_eq!(parse("/join rust"), Ok(Command::Join("#rust".to_string())));
+        assert_eq!(parse("/join #rust"), Ok(Command::Join("#rust".to_string())));
+        assert_eq!(parse("/leave"), Ok(Command::Leave));
+        assert_eq!(parse("/nick  bob "), Ok(Command::Nick("bob")));
+        assert_eq!(parse("/history"), Ok(Command::History(REPLAY_MESSAGES)));
+        assert_eq!(parse("/history 5000"), Ok(Command::History(MAX_HISTORY)));
+        assert_eq!(parse("/me waves at  you"), Ok(Command::Me("waves at  you")));
+        assert_eq!(parse("/who"), Ok(Command::Who));
+        assert_eq!(parse("/away"), Ok(Command::Away(None)));
+        assert_eq!(
+            parse("/set timestamps on"),
+            Ok(Command::Set(Setting::Timestamps(true)))
+        );
+        assert_eq!(
+            parse("/away  at lunch"),
+            Ok(Command::Away(Some("at lunch")))
+        );
+        assert_eq!(parse("/mentions"), Ok(Command::Mentions));
+        assert_eq!(parse("/topic"), Ok(Command::Topic(None)));
+        assert_eq!(parse("/send notes.txt"), Ok(Command::Send("notes.txt")));
+        assert_eq!(parse("/set ids off"), Ok(Command::Set(Setting::Ids(false))));
+        assert_eq!(
+            parse("/set color on"),
+            Ok(Command::Set(Setting::Color(true)))
+        );
+        assert_eq!(
+            parse("/edit 01HZ3 hi  all"),
+            Ok(Command::Edit("01HZ3", "hi  all"))
+        );
+        assert_eq!(parse("/delete 01HZ3"), Ok(Command::Delete("01HZ3")));
+        assert_eq!(parse("/typing off"), Ok(Command::Typing(false)));
+        assert_eq!(parse("/paste"), Ok(Command::Paste));
+        assert_eq!(parse("/ack 42"), Ok(Command::Ack(42)));
+        assert!(parse("/ack -1").is_err());
+        assert_eq!(parse("/ignore bob"), Ok(Command::Ignore("bob")));
+        assert!(parse("/unignore").is_err());
+        assert_eq!(
+            parse("/topic all about  rust"),
+            Ok(Command::Topic(Some("all about  rust")))
+        );
+        assert_eq!(parse("/help"), Ok(Command::Help));
+        assert_eq!(parse("/quit"), Ok(Command::Quit));
+    }
+
+    #[test]
+    fn bad_commands_should_say_how_to_use_them() {
+        let commands = Commands::default();
+        let parse = |line| commands.parse(line, false);
+        assert_eq!(parse("/join #"), Err("Usage: /join #room".to_string()));
+        assert_eq!(parse("/nick a b"), Err("Usage: /nick name".to_string()));
+        assert_eq!(parse("/history -1"), Err("Usage: /history [n]".to_string()));
+        assert_eq!(parse("/me"), Err("Usage: /me action".to_string()));
+        assert_eq!(parse("/leave now"), Err("Usage: /leave".to_string()));
+        assert_eq!(
+            parse("/set timestamps maybe"),
+            Err("Usage: /set timestamps|ids|color on|off".to_string())
+        );
+        assert_eq!(
+            parse("/edit 01HZ3"),
+            Err("Usage: /edit id text".to_string())
+        );
+        assert_eq!(
+            parse("/dance all night"),
+            Err("Unknown command: /dance, try /help".to_string())
+        );
+    }
+
+    #[test]
+    fn moderation_commands_should_need_an_operator() {
+        let commands = Commands::default();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        assert_eq!(
+            commands.parse("/kick bob", false),
+            Err("Only operators can do that".to_string())
+        );
+        assert_eq!(
+            commands.parse("/kick bob", true),
+            Ok(Command::Kick("bob", None))
+        );
+        assert_eq!(
+            commands.parse("/kick bob  spamming again", true),
+            Ok(Command::Kick("bob", Some("spamming again")))
+        );
+        assert_eq!(
+            commands.parse("/ban bob", true),
+            Ok(Command::Ban(BanTarget::User("bob".to_string()), None, None))
+        );
+        assert_eq!(
+            commands.parse("/ban 10.0.0.1 12h", true),
+            Ok(Command::Ban(
+                BanTarget::Ip(ip),
+                Some(Duration::from_secs(12 * 60 * 60)),
+                None
+            ))
+        );
+        assert_eq!(
+            commands.parse("/ban bob 1d  spamming again", true),
+            Ok(Command::Ban(
+                BanTarget::User("bob".to_string()),
+                Some(Duration::from_secs(24 * 60 * 60)),
+                Some("spamming again")
+            ))
+        );
+        assert_eq!(
+            commands.parse("/ban bob spamming", true),
+            Ok(Command::Ban(
+                BanTarget::User("bob".to_string()),
+                None,
+                Some("spamming")
+            ))
+        );
+        assert_eq!(
+            commands.parse("/unban 10.0.0.1", true),
+            Ok(Command::Unban(BanTarget::Ip(ip)))
+        );
+        assert!(commands.parse("/ban bob 0m", true).is_err());
+        assert!(commands.parse("/ban bob 3 strikes", true).is_err());
+        assert!(commands.parse("/ban", true).is_err());
+        assert!(commands.parse("/kick", true).is_err());
+        assert_eq!(
+            commands.parse("/replay 2024-05-01", true),
+            Ok(Command::Replay(
+                NaiveDate::from_ymd_opt(2024, 5, 1).unwrap()
+            ))
+        );
+        assert!(commands.parse("/replay 2024-05-01", false).is_err());
+        assert!(commands.parse("/replay yesterday", true).is_err());
+        assert_eq!(
+            commands.parse("/mode read-only on", true),
+            Ok(Command::Mode(Some((Mode::ReadOnly, true))))
+        );
+        assert_eq!(commands.parse("/mode", true), Ok(Command::Mode(None)));
+        assert!(commands.parse("/mode secret on", true).is_err());
+        assert!(commands.parse("/mode invite-only", true).is_err());
+        assert_eq!(
+            commands.parse("/invite bob", false),
+            Ok(Command::Invite("bob"))
+        );
+        assert_eq!(commands.parse("/op bob", false), Ok(Command::Op("bob")));
+        assert!(commands.parse("/op", false).is_err());
+    }
+
+    #[test]
+    fn help_should_only_list_what_the_user_may_run() {
+        let commands = Commands::default();
+        let help = commands.help(false);
+        assert_eq!(help[0], "/help - list the commands you may use");
+        assert!(help.iter().all(|line| !line.starts_with("/kick")));
+        assert!(commands
+            .help(true)
+            .contains(&"/unban name|ip - let a user or address back in".to_string()));
+    }
+
+    #[test]
+    fn registered_commands_should_replace_or_extend() {
+        let mut commands = Commands::default();
+        commands.register(Spec {
+            name: "shrug",
+            args: "",
+            about: "shrug",
+            operators_only: false,
+            parse: |_| Some(Command::Chat("¯\\_(ツ)_/¯")),
+        });
+        commands.register(Spec {
+            name: "quit",
+            args: "",
+            about: "not allowed here",
+            operators_only: true,
+            parse: |args| none(args, Command::Quit),
+        });
+        assert_eq!(
+            commands.parse("/shrug", false),
+            Ok(Command::Chat("¯\\_(ツ)_/¯"))
+        );
+        assert!(commands.parse("/quit", false).is_err());
+        assert_eq!(commands.help(true).last().unwrap(), "/shrug - shrug");
+    }
+}
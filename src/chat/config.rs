@@ -0,0 +1,197 @@
+// the server's config, from `CHAT_*` env vars at startup and from the config file over them,
+// see `settings`
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::chat::registry::RoomInfo;
+use crate::chat::settings::Settings;
+use crate::chat::{filters, BanTarget, MAX_HISTORY};
+
+/// Taken from the environment at startup, and from the config file over it, see `settings`.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub(super) codec: Codec,
+    pub(super) rate_limit: RateLimit,
+    // a quiet client is pinged after `ping_interval`, and dropped after `idle_timeout`
+    pub(super) ping_interval: Duration,
+    pub(super) idle_timeout: Duration,
+    // how long writers may flush at shutdown
+    pub(super) grace_period: Duration,
+    // a peer missing this many messages in a row is too slow to keep
+    pub(super) max_drops: u32,
+    // and so is one whose client doesn't take a message within this
+    pub(super) write_timeout: Duration,
+    // bytes in a line a client types, a text client's line is cut off there and a json
+    // one's request counts whole
+    pub(super) max_line: usize,
+    // accounts that may kick and ban, logged in with their password. without any, only the
+    // admin console may
+    pub(super) operators: Vec<String>,
+    // whether `guest` logs in under a temporary name, without a password
+    pub(super) guests: bool,
+    // failed logins from an address before it is locked out, for `login_lockout`
+    pub(super) max_login_failures: u32,
+    pub(super) login_lockout: Duration,
+    // connections beyond these are turned away
+    pub(super) max_connections: usize,
+    pub(super) max_connections_per_ip: usize,
+    // how long a logged in user may reconnect with its session token, and how many messages
+    // of its room are kept meanwhile
+    pub(super) resume_window: Duration,
+    pub(super) resume_buffer: usize,
+    // PEM certificate chain of both listeners, TLS is off if empty
+    pub(super) tls_cert: String,
+    // PEM private key of `tls_cert`
+    pub(super) tls_key: String,
+    // the noise listener's static key, made there if missing, a new one every run if empty
+    pub(super) noise_key: String,
+    // redis to share rooms with other servers through, a server of its own if empty
+    pub(super) redis_url: String,
+    // where every room is logged to a file per day, not logged if empty
+    pub(super) log_dir: String,
+    // whether users are told of mentions in rooms they are not in
+    pub(super) mentions_across_rooms: bool,
+    // the filters chat lines go through, in rooms not in `room_filters`
+    pub(super) filters: Vec<String>,
+    pub(super) room_filters: HashMap<String, Vec<String>>,
+    // the topic and modes of a room created by its first /join
+    pub(super) room_defaults: RoomInfo,
+    // starred out by the `words` filter
+    pub(super) banned_words: Vec<String>,
+    // where shared files are kept, in a dir of the server's own below it. files can't be shared
+    // if empty
+    pub(super) uploads_dir: String,
+    // the upload listener as clients reach it, for the links they get
+    pub(super) upload_url: String,
+    // bytes of one file and of all files kept, and how long a file is kept
+    pub(super) max_upload: usize,
+    pub(super) uploads_cap: u64,
+    pub(super) upload_ttl: Duration,
+    // messages of each room kept in memory, for /history and joiners
+    pub(super) scrollback: usize,
+    // the bots in every room, by name
+    pub(super) bots: Vec<String>,
+    // banned for good while they are configured, apart from those banned with /ban
+    pub(super) bans: Vec<BanTarget>,
+    // the toml file read over the environment, none if empty
+    pub(super) config_file: String,
+    // told to every client once it logged in, one notice per line, none if empty
+    pub(super) motd: String,
+    // the bearer token /debug/peers asks for, as it names users and their addresses. not
+    // served at all if empty
+    pub(super) debug_token: String,
+}
+
+// a token bucket per connection, flooding is warned, then muted, then disconnected
+#[derive(Debug, Clone, Copy)]
+pub(super) struct RateLimit {
+    // messages per second, refilled continuously
+    pub(super) rate: f64,
+    // messages allowed in a burst
+    pub(super) burst: f64,
+    pub(super) mute: Duration,
+}
+
+// how the tcp listener frames its clients, websockets are always text
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Codec {
+    Lines,
+    LengthDelimited,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self> {
+        let codec = match std::env::var("CHAT_CODEC").as_deref() {
+            Ok("lines") | Err(_) => Codec::Lines,
+            Ok("binary") => Codec::LengthDelimited,
+            Ok(other) => anyhow::bail!("Unknown CHAT_CODEC {}, use lines or binary", other),
+        };
+        Ok(Self {
+            codec,
+            rate_limit: RateLimit {
+                rate: env_or("CHAT_RATE", 5.0)?,
+                burst: env_or("CHAT_BURST", 10.0)?,
+                mute: Duration::from_secs(env_or("CHAT_MUTE_SECS", 30)?),
+            },
+            ping_interval: Duration::from_secs(env_or("CHAT_PING_SECS", 30)?),
+            idle_timeout: Duration::from_secs(env_or("CHAT_IDLE_SECS", 300)?),
+            grace_period: Duration::from_secs(env_or("CHAT_GRACE_SECS", 5)?),
+            max_drops: env_or("CHAT_MAX_DROPS", 32)?,
+            write_timeout: Duration::from_secs(env_or("CHAT_WRITE_TIMEOUT_SECS", 10)?),
+            max_line: env_or("CHAT_MAX_LINE", 1024)?,
+            operators: std::env::var("CHAT_OPERATORS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(str::to_string)
+                .collect(),
+            guests: env_or("CHAT_GUESTS", true)?,
+            max_login_failures: env_or("CHAT_LOGIN_ATTEMPTS", 5)?,
+            login_lockout: Duration::from_secs(env_or("CHAT_LOGIN_LOCKOUT_SECS", 300)?),
+            max_connections: env_or("CHAT_MAX_CONNECTIONS", 1024)?,
+            max_connections_per_ip: env_or("CHAT_MAX_CONNECTIONS_PER_IP", 8)?,
+            resume_window: Duration::from_secs(env_or("CHAT_RESUME_SECS", 120)?),
+            resume_buffer: env_or("CHAT_RESUME_BUFFER", 100)?,
+            tls_cert: env_or("CHAT_TLS_CERT", String::new())?,
+            tls_key: env_or("CHAT_TLS_KEY", String::new())?,
+            noise_key: env_or("CHAT_NOISE_KEY", String::new())?,
+            redis_url: env_or("CHAT_REDIS_URL", String::new())?,
+            log_dir: env_or("CHAT_LOG_DIR", String::new())?,
+            mentions_across_rooms: env_or("CHAT_MENTIONS_ACROSS_ROOMS", false)?,
+            filters: filters::parse_names(&env_or("CHAT_FILTERS", "spam".to_string())?),
+            room_filters: filters::parse_rooms(&env_or("CHAT_ROOM_FILTERS", String::new())?)?,
+            room_defaults: RoomInfo::defaults(
+                &env_or("CHAT_ROOM_TOPIC", String::new())?,
+                &env_or("CHAT_ROOM_MODES", String::new())?,
+            )?,
+            banned_words: filters::parse_names(&env_or("CHAT_BANNED_WORDS", String::new())?),
+            uploads_dir: env_or("CHAT_UPLOADS_DIR", String::new())?,
+            upload_url: env_or("CHAT_UPLOAD_URL", "http://localhost:8082".to_string())?,
+            max_upload: env_or("CHAT_MAX_UPLOAD", 10 * 1024 * 1024)?,
+            uploads_cap: env_or("CHAT_UPLOADS_CAP", 1024 * 1024 * 1024)?,
+            upload_ttl: Duration::from_secs(env_or("CHAT_UPLOAD_TTL_SECS", 24 * 60 * 60)?),
+            scrollback: env_or("CHAT_SCROLLBACK", MAX_HISTORY as usize)?,
+            bots: filters::parse_names(&env_or("CHAT_BOTS", String::new())?),
+            bans: filters::parse_names(&env_or("CHAT_BANS", String::new())?)
+                .iter()
+                .map(|target| BanTarget::parse(target))
+                .collect(),
+            config_file: env_or("CHAT_CONFIG", String::new())?,
+            motd: env_or("CHAT_MOTD", String::new())?,
+            debug_token: env_or("CHAT_DEBUG_TOKEN", String::new())?,
+        })
+    }
+
+    // the lines of the motd, without the blank ones around it
+    pub(super) fn motd(&self) -> Vec<&str> {
+        match self.motd.trim_matches('\n') {
+            "" => Vec::new(),
+            motd => motd.lines().map(str::trim_end).collect(),
+        }
+    }
+
+    // with what the config file sets over it
+    pub(super) fn load(&self) -> Result<Self> {
+        match self.config_file.as_str() {
+            "" => Ok(self.clone()),
+            path => Ok(Settings::read(Path::new(path))?.apply(self)),
+        }
+    }
+}
+
+fn env_or<T>(name: &str, default: T) -> Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match std::env::var(name) {
+        Ok(value) => Ok(value.parse()?),
+        Err(_) => Ok(default),
+    }
+}
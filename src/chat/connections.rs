@@ -0,0 +1,141 @@
+// open connections, in total and per address, so no single client can take them all
+
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use dashmap::{mapref::entry::Entry, DashMap};
+
+#[derive(Debug)]
+pub struct Connections {
+    total: AtomicUsize,
+    // an address is gone once its last connection closes
+    by_ip: DashMap<IpAddr, usize>,
+    // changed by a reload, open connections beyond them stay open
+    max_total: AtomicUsize,
+    max_per_ip: AtomicUsize,
+}
+
+// counts as open until dropped
+#[derive(Debug)]
+pub struct Connection {
+    connections: Arc<Connections>,
+    ip: IpAddr,
+}
+
+impl Connections {
+    pub fn new(max_total: usize, max_per_ip: usize) -> Self {
+        Self {
+            total: AtomicUsize::new(0),
+            by_ip: DashMap::new(),
+            max_total: AtomicUsize::new(max_total),
+            max_per_ip: AtomicUsize::new(max_per_ip),
+        }
+    }
+
+    pub fn set_limits(&self, max_total: usize, max_per_ip: usize) {
+        self.max_total.store(max_total, Ordering::Relaxed);
+        self.max_per_ip.store(max_per_ip, Ordering::Relaxed);
+    }
+
+    // the error is for the client turned away
+    pub fn open(self: &Arc<Self>, ip: IpAddr) -> Result<Connection, String> {
+        // the address stays locked while the total is taken, so neither cap is overrun
+        let mut entry = self.by_ip.entry(ip).or_insert(0);
+        if *entry >= self.max_per_ip.load(Ordering::Relaxed) {
+            return Err(
+                "Too many connections from your address, close one and try again".to_string(),
+            );
+        }
+        let max_total = self.max_total.load(Ordering::Relaxed);
+        let taken = self
+            .total
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |total| {
+                (total < max_total).then_some(total + 1)
+            });
+        if taken.is_err() {
+            drop(entry);
+            self.by_ip.remove_if(&ip, |_, n| *n == 0);
+            return Err("The server is full, please try again later".to_string());
+        }
+        *entry += 1;
+        Ok(Connection {
+            connections: Arc::clone(self),
+            ip,
+        })
+    }
+
+    pub fn total(&self) -> usize {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    // addresses with at least one open connection
+    pub fn addresses(&self) -> usize {
+        self.by_ip.len()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let connections = &self.connections;
+        connections.total.fetch_sub(1, Ordering::Relaxed);
+        if let Entry::Occupied(mut entry) = connections.by_ip.entry(self.ip) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connections_should_be_capped_in_total_and_per_address() {
+        let connections = Arc::new(Connections::new(3, 2));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let first = connections.open(ip).unwrap();
+        let _second = connections.open(ip).unwrap();
+        assert!(connections
+            .open(ip)
+            .unwrap_err()
+            .starts_with("Too many connections"));
+        let _third = connections.open(other).unwrap();
+        assert!(connections
+            .open(other)
+            .unwrap_err()
+            .starts_with("The server is full"));
+        assert_eq!(connections.total(), 3);
+        assert_eq!(connections.addresses(), 2);
+
+        // a closed connection makes room again, for any address
+        drop(first);
+        assert_eq!(connections.total(), 2);
+        let _fourth = connections.open(other).unwrap();
+        assert_eq!(connections.total(), 3);
+
+        // lower limits keep what is open, higher ones take more
+        connections.set_limits(2, 2);
+        assert_eq!(connections.total(), 3);
+        assert!(connections.open(ip).is_err());
+        connections.set_limits(4, 2);
+        let _fifth = connections.open(ip).unwrap();
+    }
+
+    #[test]
+    fn addresses_should_be_forgotten_once_closed() {
+        let connections = Arc::new(Connections::new(1, 1));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        let connection = connections.open(ip).unwrap();
+        assert!(connections.open(other).is_err());
+        assert_eq!(connections.addresses(), 1);
+        drop(connection);
+        assert_eq!(connections.total(), 0);
+        assert_eq!(connections.addresses(), 0);
+    }
+}
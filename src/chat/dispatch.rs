@@ -0,0 +1,582 @@
+// what a line from a client does, chatting in its room or running a command, and what the
+// admin console's commands do
+
+use std::net::SocketAddr;
+use std::ops::ControlFlow;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+use crate::chat::audit::Action;
+use crate::chat::commands::{Command, Setting};
+use crate::chat::lines::Paste;
+use crate::chat::{
+    uploads, Event, Message, Outgoing, Peer, Role, State, AUDIT_ENTRIES, MAX_MESSAGES,
+    MAX_PASTE_LINES, PROTOCOL_VERSION,
+};
+
+/// Typed on the server's stdin.
+#[derive(Debug, PartialEq)]
+pub enum AdminCommand {
+    Announce(String),
+    List,
+    Kick(String),
+    Stats,
+    // the config file, as on SIGHUP
+    Reload,
+    // the last entries of the audit log
+    Audit(i64),
+}
+
+/// Commands from any admin source, the console or SIGHUP, are applied to the state one at a time
+/// and the replies printed.
+pub fn admin_channel(state: Arc<State>) -> mpsc::Sender<AdminCommand> {
+    let (tx, mut rx) = mpsc::channel(MAX_MESSAGES);
+    tokio::spawn(async move {
+        while let Some(command) = rx.recv().await {
+            info!(?command, "admin command");
+            println!("{}", state.admin(command).await);
+        }
+    });
+    tx
+}
+
+// `3d 4h 5m 6s`, without the larger units that are 0
+fn format_uptime(uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    let parts = [
+        (secs / 86400, "d"),
+        (secs / 3600 % 24, "h"),
+        (secs / 60 % 60, "m"),
+        (secs % 60, "s"),
+    ];
+    let first = parts.iter().position(|(n, _)| *n > 0).unwrap_or(3);
+    let parts: Vec<String> = parts[first..]
+        .iter()
+        .map(|(n, unit)| format!("{}{}", n, unit))
+        .collect();
+    parts.join(" ")
+}
+
+// runs what a client typed, replies go to that client only. Break ends its session
+pub(super) async fn dispatch(
+    state: &State,
+    addr: SocketAddr,
+    peer: &mut Peer,
+    command: Command<'_>,
+) -> ControlFlow<()> {
+    match &command {
+        Command::Pong | Command::Ack(_) => {}
+        Command::Chat(content) => debug!(bytes = content.len(), "message"),
+        command => debug!(?command, "command"),
+    }
+    if !matches!(command, Command::Pong | Command::Ack(_)) {
+        *peer.presence.active_at.lock().unwrap() = Instant::now();
+    }
+    let reply = match command {
+        Command::Join(room) => match state.may_join(peer, &room).await {
+            Ok(()) => {
+                state.create_room(addr, peer, &room).await;
+                state.join(addr, peer, &room).await;
+                return ControlFlow::Continue(());
+            }
+            Err(e) => e,
+        },
+        Command::Pong => return ControlFlow::Continue(()),
+        Command::Ack(seq) => {
+            if let Some(unacked) = &peer.unacked {
+                unacked.lock().unwrap().ack(seq);
+            }
+            return ControlFlow::Continue(());
+        }
+        Command::Paste => match &peer.room {
+            Some(_) => {
+                peer.paste = Some(Paste::new(MAX_PASTE_LINES));
+                format!("Pasting, end with /end, at most {} lines", MAX_PASTE_LINES)
+            }
+            None => "You are not in a room, /join #room first".to_string(),
+        },
+        Command::Typing(active) => {
+            state.typing(addr, peer, active);
+            return ControlFlow::Continue(());
+        }
+        Command::Set(Setting::Timestamps(on)) => {
+            peer.render.timestamps = on;
+            state.queue(addr, Outgoing::Render(peer.render)).await;
+            format!("Timestamps are {}", if on { "on" } else { "off" })
+        }
+        Command::Set(Setting::Ids(on)) => {
+            peer.render.ids = on;
+            state.queue(addr, Outgoing::Render(peer.render)).await;
+            format!("Message ids are {}", if on { "on" } else { "off" })
+        }
+        Command::Set(Setting::Color(on)) => {
+            peer.render.colors = on;
+            state.queue(addr, Outgoing::Render(peer.render)).await;
+            format!("Colors are {}", if on { "on" } else { "off" })
+        }
+        Command::Edit(id, content) => match state.history.get(id).await {
+            Ok(Some((_, author))) if author.as_ref() != Some(&peer.identity) => {
+                "You may only edit your own messages".to_string()
+            }
+            Ok(Some((entry, _))) => {
+                let room = &entry.room;
+                let allowed = state.may_post(peer, room).await;
+                match allowed.and_then(|()| state.filters.load().apply(room, content)) {
+                    Ok(content) => match state.history.edit(id, &content).await {
+                        Ok(true) => {
+                            info!(room, id, "edited");
+                            state.scrollback.edit(room, id, &content);
+                            let message = Arc::new(Message::new(Event::Edited {
+                                id: id.to_string(),
+                                sender: entry.sender.clone(),
+                                room: room.clone(),
+                                content,
+                            }));
+                            state.broadcast(room, addr, &message);
+                            format!("Edited {}", id)
+                        }
+                        Ok(false) => format!("No message {}", id),
+                        Err(e) => {
+                            warn!(id, error = ?e, "failed to edit message");
+                            "Failed to edit the message, try again later".to_string()
+                        }
+                    },
+                    Err(reason) => reason,
+                }
+            }
+            Ok(None) => format!("No message {}", id),
+            Err(e) => {
+                warn!(id, error = ?e, "failed to look up message");
+                "Failed to look up the message, try again later".to_string()
+            }
+        },
+        Command::Delete(id) => match state.history.get(id).await {
+            Ok(Some((_, author)))
+                if author.as_ref() != Some(&peer.identity) && peer.role != Role::Operator =>
+            {
+                "You may only delete your own messages".to_string()
+            }
+            Ok(Some((entry, _))) => match state.history.delete(id).await {
+                Ok(true) => {
+                    info!(room = entry.room, id, "deleted");
+                    state.scrollback.delete(&entry.room, id);
+                    let message = Arc::new(Message::new(Event::Deleted {
+                        id: id.to_string(),
+                        room: entry.room.clone(),
+                    }));
+                    state.broadcast(&entry.room, addr, &message);
+                    format!("Deleted {}", id)
+                }
+                Ok(false) => format!("No message {}", id),
+                Err(e) => {
+                    warn!(id, error = ?e, "failed to delete message");
+                    "Failed to delete the message, try again later".to_string()
+                }
+            },
+            Ok(None) => format!("No message {}", id),
+            Err(e) => {
+                warn!(id, error = ?e, "failed to look up message");
+                "Failed to look up the message, try again later".to_string()
+            }
+        },
+        Command::Quit => {
+            if let Some(token) = peer.session.take() {
+                state.sessions.close(&token);
+            }
+            state.send(addr, Message::notice("Goodbye")).await;
+            return ControlFlow::Break(());
+        }
+        Command::Help => {
+            for line in state.commands.help(peer.role == Role::Operator) {
+                state.send(addr, Message::notice(line)).await;
+            }
+            return ControlFlow::Continue(());
+        }
+        Command::Leave => match state.leave(addr, peer).await {
+            Some(room) => format!("You left {}", room),
+            None => "You are not in a room".to_string(),
+        },
+        Command::Nick(name) => match state.rename(addr, peer, name).await {
+            Ok(name) => format!("You are now known as {}", name),
+            Err(e) => e,
+        },
+        Command::Who => match &peer.room {
+            Some(room) => {
+                let users = state.who(room);
+                let header = format!("{} users in {}", users.len(), room);
+                for line in std::iter::once(header).chain(users) {
+                    state.send(addr, Message::notice(line)).await;
+                }
+                return ControlFlow::Continue(());
+            }
+            None => "You are not in a room, /join #room first".to_string(),
+        },
+        Command::Ignore(name) if name == peer.username => "You can't ignore yourself".to_string(),
+        Command::Ignore(name) => match peer.ignores.add(name) {
+            Ok(true) => format!(
+                "Ignoring {}, /unignore {} to hear from them again",
+                name, name
+            ),
+            Ok(false) => format!("You ignore {} already", name),
+            Err(e) => e,
+        },
+        Command::Unignore(name) => match peer.ignores.remove(name) {
+            true => format!("No longer ignoring {}", name),
+            false => format!("You don't ignore {}", name),
+        },
+        Command::Ignores => match peer.ignores.list().as_slice() {
+            [] => "You ignore nobody".to_string(),
+            names => format!("You ignore {}", names.join(", ")),
+        },
+        Command::Server => format!(
+            "chat {}, protocol {}, up {}, {} users in {} rooms",
+            env!("CARGO_PKG_VERSION"),
+            PROTOCOL_VERSION,
+            format_uptime(state.started_at.elapsed()),
+            state.names.len(),
+            state.rooms.len()
+        ),
+        Command::Away(message) => {
+            let mut away = peer.presence.away.lock().unwrap();
+            match (away.take(), message) {
+                (Some(_), None) => "You are back".to_string(),
+                (_, message) => {
+                    let message = away.insert(message.unwrap_or_default().to_string());
+                    match message.as_str() {
+                        "" => "You are away".to_string(),
+                        message => format!("You are away: {}", message),
+                    }
+                }
+            }
+        }
+        Command::Kick(name, reason) => {
+            let notice = match reason {
+                Some(reason) => format!("You have been kicked by {}: {}", peer.username, reason),
+                None => format!("You have been kicked by {}", peer.username),
+            };
+            match state.kick(name, &notice) {
+                Some(_) => {
+                    let reason = reason.unwrap_or_default();
+                    state
+                        .audit(Action::Kick, &peer.username, name, "", reason)
+                        .await;
+                    format!("Kicked {}", name)
+                }
+                None => format!("No such user: {}", name),
+            }
+        }
+        Command::Ban(target, duration, reason) => {
+            let reason = reason.unwrap_or_default();
+            state.ban(&target, duration, &peer.username, reason).await
+        }
+        Command::Unban(target) => match state.bans.remove(&target).await {
+            Ok(true) => {
+                let target = target.to_string();
+                state
+                    .audit(Action::Unban, &peer.username, &target, "", "")
+                    .await;
+                format!("Unbanned {}", target)
+            }
+            Ok(false) => format!("{} is not banned", target),
+            Err(e) => {
+                warn!(%target, error = ?e, "failed to unban");
+                format!("Failed to unban {}", target)
+            }
+        },
+        Command::Replay(date) => match (&peer.room, &state.room_log) {
+            (None, _) => "You are not in a room, /join #room first".to_string(),
+            (_, None) => "Rooms are not logged on this server".to_string(),
+            (Some(room), Some(log)) => match log.read(room, date).await {
+                Ok(Some(lines)) => {
+                    for line in lines {
+                        state.send(addr, Message::notice(line)).await;
+                    }
+                    return ControlFlow::Continue(());
+                }
+                Ok(None) => format!("Nothing was said in {} on {}", room, date),
+                Err(e) => {
+                    warn!(room, %date, error = ?e, "failed to read room log");
+                    format!("Failed to read the log of {}", room)
+                }
+            },
+        },
+        Command::Topic(topic) => match (&peer.room, topic) {
+            (None, _) => "You are not in a room, /join #room first".to_string(),
+            (Some(room), None) => {
+                let info = state.room_info(room).await;
+                state.send(addr, Message::topic(room, &info.topic)).await;
+                return ControlFlow::Continue(());
+            }
+            (Some(room), Some(_)) if !peer.operates(&state.room_info(room).await) => {
+                "Only operators can set the topic".to_string()
+            }
+            (Some(room), Some(topic)) => {
+                match state.registry.set_topic(room, topic, &peer.username).await {
+                    Ok(()) => {
+                        info!(room, topic, "topic set");
+                        state
+                            .audit(Action::Topic, &peer.username, room, topic, "")
+                            .await;
+                        let message = Arc::new(Message::topic(room, topic));
+                        state.queue(addr, Outgoing::Message(message.clone())).await;
+                        state.broadcast(room, addr, &message);
+                        return ControlFlow::Continue(());
+                    }
+                    Err(e) => {
+                        warn!(room, error = ?e, "failed to set topic");
+                        format!("Failed to set the topic of {}", room)
+                    }
+                }
+            }
+        },
+        Command::Mode(mode) => match (&peer.room, mode) {
+            (None, _) => "You are not in a room, /join #room first".to_string(),
+            (Some(room), None) => format!("{}: {}", room, state.room_info(room).await),
+            (Some(room), Some(_)) if !peer.operates(&state.room_info(room).await) => {
+                format!("Only operators of {} can change its modes", room)
+            }
+            (Some(room), Some((mode, on))) => {
+                match state
+                    .registry
+                    .set_mode(room, mode, on, &peer.username)
+                    .await
+                {
+                    Ok(()) => {
+                        info!(room, %mode, on, "mode set");
+                        format!("{}: {} {}", room, mode, if on { "on" } else { "off" })
+                    }
+                    Err(e) => {
+                        warn!(room, %mode, error = ?e, "failed to set mode");
+                        format!("Failed to set {} of {}", mode, room)
+                    }
+                }
+            }
+        },
+        Command::Invite(name) => match &peer.room {
+            Some(room) if !peer.operates(&state.room_info(room).await) => {
+                format!("Only operators of {} can invite", room)
+            }
+            Some(room) => match state.identify(name).await {
+                Ok(identity) => {
+                    match state.registry.invite(room, &identity, &peer.username).await {
+                        Ok(()) => {
+                            let invitee = state.names.get(name).map(|a| *a);
+                            let handle =
+                                invitee.and_then(|a| state.peers.get(&a).map(|p| p.clone()));
+                            if let (Some(invitee), Some(handle)) = (invitee, handle) {
+                                let notice = format!("{} invited you to {}", peer.username, room);
+                                state.deliver(invitee, &handle, Arc::new(Message::notice(notice)));
+                            }
+                            format!("Invited {} to {}", name, room)
+                        }
+                        Err(e) => {
+                            warn!(room, name, error = ?e, "failed to invite");
+                            format!("Failed to invite {}", name)
+                        }
+                    }
+                }
+                Err(e) => e,
+            },
+            None => "You are not in a room, /join #room first".to_string(),
+        },
+        Command::Op(name) => match &peer.room {
+            Some(room) if !peer.operates(&state.room_info(room).await) => {
+                format!("Only operators of {} can make others operators", room)
+            }
+            Some(room) => match state.identify(name).await {
+                Ok(identity) => match state
+                    .registry
+                    .add_operator(room, &identity, &peer.username)
+                    .await
+                {
+                    Ok(()) => {
+                        info!(room, name, "room operator added");
+                        let operator = state.names.get(name).map(|a| *a);
+                        let handle = operator.and_then(|a| state.peers.get(&a).map(|p| p.clone()));
+                        if let (Some(operator), Some(handle)) = (operator, handle) {
+                            let notice =
+                                format!("{} made you an operator of {}", peer.username, room);
+                            state.deliver(operator, &handle, Arc::new(Message::notice(notice)));
+                        }
+                        format!("{} is an operator of {}", name, room)
+                    }
+                    Err(e) => {
+                        warn!(room, name, error = ?e, "failed to add room operator");
+                        format!("Failed to make {} an operator", name)
+                    }
+                },
+                Err(e) => e,
+            },
+            None => "You are not in a room, /join #room first".to_string(),
+        },
+        Command::Send(filename) => match (&peer.room, &state.uploads) {
+            (None, _) => "You are not in a room, /join #room first".to_string(),
+            (_, None) => "Files can't be shared on this server".to_string(),
+            (Some(room), Some(uploads)) => match state.may_post(peer, room).await {
+                Ok(()) => {
+                    let url = uploads.reserve(&peer.username, room, filename);
+                    format!(
+                        "Upload {} within {} minutes: curl -T {} {}",
+                        filename,
+                        uploads::SLOT_TTL.as_secs() / 60,
+                        filename,
+                        url
+                    )
+                }
+                Err(e) => e,
+            },
+        },
+        Command::Mentions => {
+            let (count, mentions) = peer.mentions.take();
+            let header = match count {
+                0 => "No new mentions".to_string(),
+                1 => "1 new mention".to_string(),
+                n if n > mentions.len() as u64 => {
+                    format!("{} new mentions, the last {}:", n, mentions.len())
+                }
+                n => format!("{} new mentions", n),
+            };
+            state.send(addr, Message::notice(header)).await;
+            for mention in mentions {
+                state.queue(addr, Outgoing::Message(mention)).await;
+            }
+            return ControlFlow::Continue(());
+        }
+        Command::History(n) => match &peer.room {
+            Some(room) => {
+                state.replay(addr, room, n).await;
+                return ControlFlow::Continue(());
+            }
+            None => "You are not in a room, /join #room first".to_string(),
+        },
+        Command::Me(content) => match &peer.room {
+            Some(room) => {
+                let allowed = state.may_post(peer, room).await;
+                let content = match allowed.and_then(|()| state.filters.load().apply(room, content))
+                {
+                    Ok(content) => content,
+                    Err(reason) => {
+                        state.send(addr, Message::notice(reason)).await;
+                        return ControlFlow::Continue(());
+                    }
+                };
+                // the message says the user stopped typing
+                peer.typing.active = false;
+                let message = Arc::new(Message::new(Event::Action {
+                    sender: peer.username.clone(),
+                    room: room.clone(),
+                    content,
+                }));
+                // the sender sees its own action, unlike its chat lines
+                state.queue(addr, Outgoing::Message(message.clone())).await;
+                state.broadcast(room, addr, &message);
+                return ControlFlow::Continue(());
+            }
+            None => "You are not in a room, /join #room first".to_string(),
+        },
+        Command::Chat(content) => match &peer.room {
+            Some(room) => {
+                let allowed = state.may_post(peer, room).await;
+                let content = match allowed.and_then(|()| state.filters.load().apply(room, content))
+                {
+                    Ok(content) => content,
+                    Err(reason) => {
+                        state.send(addr, Message::notice(reason)).await;
+                        return ControlFlow::Continue(());
+                    }
+                };
+                peer.typing.active = false;
+                let message = Arc::new(Message::chat(&peer.username, room, &content));
+                state.keep(&message, Some(&peer.identity)).await;
+                state.broadcast(room, addr, &message);
+                state.bots.message(room, &peer.username, &content);
+                // the sender doesn't get its message back, nor its id
+                if let (Event::Chat { id, .. }, true) = (&message.event, peer.render.ids) {
+                    state
+                        .send(addr, Message::notice(format!("Sent as {}", id)))
+                        .await;
+                }
+                state.mention(&peer.username, room, &content);
+                return ControlFlow::Continue(());
+            }
+            None => "You are not in a room, /join #room first".to_string(),
+        },
+    };
+    state.send(addr, Message::notice(reply)).await;
+    ControlFlow::Continue(())
+}
+
+impl AdminCommand {
+    // `/list`, `/stats`, `/kick name`, `/reload`, `/audit [n]`, `/announce text`, anything else
+    // not starting with `/` is announced
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let Some(command) = line.strip_prefix('/') else {
+            return Ok(Self::Announce(line.to_string()));
+        };
+        let (name, args) = command.split_once(' ').unwrap_or((command, ""));
+        let args = args.trim();
+        match name {
+            "list" if args.is_empty() => Ok(Self::List),
+            "stats" if args.is_empty() => Ok(Self::Stats),
+            "reload" if args.is_empty() => Ok(Self::Reload),
+            "kick" if !args.is_empty() && !args.contains(' ') => Ok(Self::Kick(args.to_string())),
+            "kick" => Err("Usage: /kick name".to_string()),
+            "audit" if args.is_empty() => Ok(Self::Audit(AUDIT_ENTRIES)),
+            "audit" => match args.parse() {
+                Ok(n) if n > 0 => Ok(Self::Audit(n)),
+                _ => Err("Usage: /audit [n]".to_string()),
+            },
+            "announce" if !args.is_empty() => Ok(Self::Announce(args.to_string())),
+            "announce" => Err("Usage: /announce text".to_string()),
+            _ => Err(format!(
+                "Unknown command: {}, try /list, /stats, /kick, /reload, /audit or /announce",
+                line
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admin_commands_should_parse() {
+        let parse = AdminCommand::parse;
+        assert_eq!(parse("/list"), Ok(AdminCommand::List));
+        assert_eq!(parse("/stats"), Ok(AdminCommand::Stats));
+        assert_eq!(parse("/reload"), Ok(AdminCommand::Reload));
+        assert_eq!(
+            parse("/kick bob"),
+            Ok(AdminCommand::Kick("bob".to_string()))
+        );
+        assert_eq!(
+            parse("/announce back in 5"),
+            Ok(AdminCommand::Announce("back in 5".to_string()))
+        );
+        assert_eq!(
+            parse("hello all"),
+            Ok(AdminCommand::Announce("hello all".to_string()))
+        );
+        assert!(parse("/kick").is_err());
+        assert!(parse("/kick bob alice").is_err());
+        assert!(parse("/list all").is_err());
+        assert!(parse("/ban bob").is_err());
+        assert_eq!(parse("/audit"), Ok(AdminCommand::Audit(AUDIT_ENTRIES)));
+        assert_eq!(parse("/audit 5"), Ok(AdminCommand::Audit(5)));
+        assert!(parse("/audit 0").is_err());
+        assert!(parse("/audit all").is_err());
+    }
+
+    #[test]
+    fn uptimes_should_read_short() {
+        assert_eq!(format_uptime(Duration::from_secs(5)), "5s");
+        assert_eq!(format_uptime(Duration::from_secs(3600)), "1h 0m 0s");
+        assert_eq!(format_uptime(Duration::from_secs(90061)), "1d 1h 1m 1s");
+    }
+}
@@ -0,0 +1,219 @@
+// what a chat line goes through before it is broadcast, a chain of filters per room, e.g.
+// `CHAT_FILTERS=spam` for every room and `CHAT_ROOM_FILTERS="#kids=words,spam;#ops="`
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use anyhow::Result;
+
+// a shouting line has more of its letters in upper case, once it has `MIN_LETTERS`
+const MAX_CAPS: f64 = 0.7;
+const MIN_LETTERS: usize = 8;
+// `soooooo` is cut down to this many of a character in a row
+const MAX_REPEAT: usize = 4;
+
+pub trait MessageFilter: Send + Sync {
+    // the line to pass on, maybe changed, or why it is refused, for the sender
+    fn filter(&self, content: &str) -> Result<String, String>;
+}
+
+// the chain of each room, in the order they were named
+pub struct Filters {
+    all: Vec<Arc<dyn MessageFilter>>,
+    rooms: HashMap<String, Vec<Arc<dyn MessageFilter>>>,
+}
+
+// words of the list are starred out, whatever their case
+pub struct WordList {
+    words: HashSet<String>,
+}
+
+// refuses shouting and cuts down runs of a character
+pub struct Spam {
+    max_caps: f64,
+    min_letters: usize,
+    max_repeat: usize,
+}
+
+impl Filters {
+    // `all` for rooms not in `rooms`, `words` for the word list
+    pub fn new(
+        all: &[String],
+        rooms: &HashMap<String, Vec<String>>,
+        words: &[String],
+    ) -> Result<Self> {
+        let chain = |names: &[String]| -> Result<Vec<Arc<dyn MessageFilter>>> {
+            names.iter().map(|name| filter(name, words)).collect()
+        };
+        Ok(Self {
+            all: chain(all)?,
+            rooms: rooms
+                .iter()
+                .map(|(room, names)| Ok((room.clone(), chain(names)?)))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    // the first filter refusing the line stops the chain
+    pub fn apply(&self, room: &str, content: &str) -> Result<String, String> {
+        let chain = self.rooms.get(room).unwrap_or(&self.all);
+        chain
+            .iter()
+            .try_fold(content.to_string(), |content, filter| {
+                filter.filter(&content)
+            })
+    }
+}
+
+impl std::fmt::Debug for Filters {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Filters")
+            .field("all", &self.all.len())
+            .field("rooms", &self.rooms.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl WordList {
+    pub fn new(words: &[String]) -> Self {
+        Self {
+            words: words.iter().map(|word| word.to_lowercase()).collect(),
+        }
+    }
+}
+
+impl MessageFilter for WordList {
+    fn filter(&self, content: &str) -> Result<String, String> {
+        let mut filtered = String::with_capacity(content.len());
+        let mut word = String::new();
+        let redact = |word: &mut String, filtered: &mut String| {
+            if self.words.contains(&word.to_lowercase()) {
+                filtered.extend(word.chars().map(|_| '*'));
+            } else {
+                filtered.push_str(word);
+            }
+            word.clear();
+        };
+        for c in content.chars() {
+            if c.is_alphanumeric() {
+                word.push(c);
+            } else {
+                redact(&mut word, &mut filtered);
+                filtered.push(c);
+            }
+        }
+        redact(&mut word, &mut filtered);
+        Ok(filtered)
+    }
+}
+
+impl Default for Spam {
+    fn default() -> Self {
+        Self {
+            max_caps: MAX_CAPS,
+            min_letters: MIN_LETTERS,
+            max_repeat: MAX_REPEAT,
+        }
+    }
+}
+
+impl MessageFilter for Spam {
+    fn filter(&self, content: &str) -> Result<String, String> {
+        let letters = content.chars().filter(|c| c.is_alphabetic()).count();
+        let caps = content.chars().filter(|c| c.is_uppercase()).count();
+        if letters >= self.min_letters && caps as f64 > letters as f64 * self.max_caps {
+            return Err("Please don't shout".to_string());
+        }
+        let mut filtered = String::with_capacity(content.len());
+        let mut previous = None;
+        let mut run = 0;
+        for c in content.chars() {
+            run = if previous == Some(c) { run + 1 } else { 1 };
+            previous = Some(c);
+            if run <= self.max_repeat {
+                filtered.push(c);
+            }
+        }
+        Ok(filtered)
+    }
+}
+
+// `#kids=words,spam;#ops=`, a room without filters after its `=` has none
+pub fn parse_rooms(text: &str) -> Result<HashMap<String, Vec<String>>> {
+    text.split(';')
+        .map(str::trim)
+        .filter(|room| !room.is_empty())
+        .map(|room| match room.split_once('=') {
+            Some((room, names)) => Ok((room.trim().to_string(), parse_names(names))),
+            None => anyhow::bail!("Invalid room filters {}, use #room=filter,filter", room),
+        })
+        .collect()
+}
+
+// `words, spam`
+pub fn parse_names(text: &str) -> Vec<String> {
+    text.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn filter(name: &str, words: &[String]) -> Result<Arc<dyn MessageFilter>> {
+    match name {
+        "words" => Ok(Arc::new(WordList::new(words))),
+        "spam" => Ok(Arc::new(Spam::default())),
+        other => anyhow::bail!("Unknown filter {}, use words or spam", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listed_words_should_be_starred_out() {
+        let filter = WordList::new(&["darn".to_string(), "Heck".to_string()]);
+        assert_eq!(
+            filter.filter("Darn it, what the heck? darned!").unwrap(),
+            "**** it, what the ****? darned!"
+        );
+        assert_eq!(filter.filter("").unwrap(), "");
+    }
+
+    #[test]
+    fn shouting_should_be_refused_and_runs_cut_down() {
+        let filter = Spam::default();
+        assert_eq!(
+            filter.filter("WHY IS NOBODY HERE"),
+            Err("Please don't shout".to_string())
+        );
+        assert_eq!(filter.filter("OK").unwrap(), "OK");
+        assert_eq!(
+            filter.filter("I like NASA a lot").unwrap(),
+            "I like NASA a lot"
+        );
+        assert_eq!(
+            filter.filter("sooooooo good!!!!!!").unwrap(),
+            "soooo good!!!!"
+        );
+    }
+
+    #[test]
+    fn rooms_should_have_their_own_chains() {
+        let words = ["darn".to_string()];
+        let rooms = parse_rooms("#kids=words, spam; #ops=").unwrap();
+        let filters = Filters::new(&parse_names("spam"), &rooms, &words).unwrap();
+
+        assert_eq!(filters.apply("#kids", "darn!!!!!!").unwrap(), "****!!!!");
+        assert_eq!(filters.apply("#general", "darn!!!!!!").unwrap(), "darn!!!!");
+        assert_eq!(
+            filters.apply("#ops", "DARN IT ALL!!!!!!").unwrap(),
+            "DARN IT ALL!!!!!!"
+        );
+        assert!(filters.apply("#general", "DARN IT ALL").is_err());
+
+        assert!(parse_rooms("#kids").is_err());
+        assert!(Filters::new(&parse_names("words,profanity"), &rooms, &words).is_err());
+    }
+}
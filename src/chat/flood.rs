@@ -0,0 +1,97 @@
+// a token bucket per connection: a client sending faster than its `RateLimit` is warned, then
+// muted, then disconnected
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::chat::RateLimit;
+
+#[derive(Debug)]
+pub(super) struct Flood {
+    tokens: f64,
+    refilled_at: Instant,
+    // times the bucket ran dry, never forgiven for the connection
+    strikes: u32,
+    muted_until: Option<Instant>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(super) enum Verdict {
+    Allow,
+    Warn,
+    Mute(Duration),
+    // dropped silently while muted
+    Muted,
+    Disconnect,
+}
+
+impl Flood {
+    pub(super) fn new(limit: &RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: limit.burst,
+            refilled_at: now,
+            strikes: 0,
+            muted_until: None,
+        }
+    }
+
+    // one message costs one token, an empty bucket is a strike
+    pub(super) fn check(&mut self, limit: &RateLimit, now: Instant) -> Verdict {
+        if let Some(until) = self.muted_until {
+            if now < until {
+                return Verdict::Muted;
+            }
+            self.muted_until = None;
+        }
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst);
+        self.refilled_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Verdict::Allow;
+        }
+        self.strikes += 1;
+        match self.strikes {
+            1 => Verdict::Warn,
+            2 => {
+                self.muted_until = Some(now + limit.mute);
+                Verdict::Mute(limit.mute)
+            }
+            _ => Verdict::Disconnect,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flood_should_warn_then_mute_then_disconnect() {
+        let limit = RateLimit {
+            rate: 1.0,
+            burst: 2.0,
+            mute: Duration::from_secs(10),
+        };
+        let start = Instant::now();
+        let mut flood = Flood::new(&limit, start);
+        assert_eq!(flood.check(&limit, start), Verdict::Allow);
+        assert_eq!(flood.check(&limit, start), Verdict::Allow);
+        assert_eq!(flood.check(&limit, start), Verdict::Warn);
+
+        // a second later there is a token again
+        let later = start + Duration::from_secs(1);
+        assert_eq!(flood.check(&limit, later), Verdict::Allow);
+        assert_eq!(flood.check(&limit, later), Verdict::Mute(limit.mute));
+        assert_eq!(
+            flood.check(&limit, later + Duration::from_secs(9)),
+            Verdict::Muted
+        );
+
+        let unmuted = later + limit.mute;
+        assert_eq!(flood.check(&limit, unmuted), Verdict::Allow);
+        assert_eq!(flood.check(&limit, unmuted), Verdict::Allow);
+        assert_eq!(flood.check(&limit, unmuted), Verdict::Disconnect);
+    }
+}
@@ -0,0 +1,170 @@
+// every chat message in sqlite, replayed to whoever joins a room and kept across restarts
+
+use anyhow::Result;
+use bincode::{Decode, Encode};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Row, SqlitePool};
+
+use crate::chat::{Event, Message};
+
+// chat messages of all rooms, kept across restarts
+#[derive(Debug)]
+pub(super) struct History {
+    pub(super) pool: SqlitePool,
+}
+
+// the columns of a HistoryEntry, and the author it doesn't show
+const SELECT_ENTRIES: &str = "SELECT COALESCE(message_id, '') AS id, room, sender, content, created_at, edited_at IS NOT NULL AS edited, author FROM messages";
+
+#[derive(Debug, Clone, PartialEq, FromRow, Serialize, Deserialize, Encode, Decode)]
+pub(super) struct HistoryEntry {
+    // empty for messages kept before messages had ids
+    pub(super) id: String,
+    pub(super) room: String,
+    pub(super) sender: String,
+    pub(super) content: String,
+    // when it was said, the message replaying it has its own timestamp
+    #[bincode(with_serde)]
+    pub(super) created_at: DateTime<Utc>,
+    pub(super) edited: bool,
+}
+
+impl HistoryEntry {
+    // what the history keeps of a chat message, None for other messages
+    pub(super) fn of(message: &Message) -> Option<Self> {
+        let Event::Chat {
+            id,
+            sender,
+            room,
+            content,
+        } = &message.event
+        else {
+            return None;
+        };
+        Some(Self {
+            id: id.clone(),
+            room: room.clone(),
+            sender: sender.clone(),
+            content: content.clone(),
+            created_at: message.timestamp,
+            edited: false,
+        })
+    }
+}
+
+impl History {
+    pub(super) async fn open(pool: SqlitePool) -> Result<Self> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                room TEXT NOT NULL,
+                sender TEXT NOT NULL,
+                content TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                message_id TEXT,
+                edited_at TEXT,
+                -- the identity of the sender, see `Peer::identity`. NULL for bots
+                author TEXT
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+        // a db from before messages had ids gets the columns, its messages keep no id
+        let columns: Vec<String> =
+            sqlx::query_scalar("SELECT name FROM pragma_table_info('messages')")
+                .fetch_all(&pool)
+                .await?;
+        for column in ["message_id", "edited_at", "author"] {
+            if !columns.iter().any(|c| c == column) {
+                sqlx::query(&format!("ALTER TABLE messages ADD COLUMN {} TEXT", column))
+                    .execute(&pool)
+                    .await?;
+            }
+        }
+        sqlx::query(
+            "CREATE UNIQUE INDEX IF NOT EXISTS messages_message_id ON messages (message_id)",
+        )
+        .execute(&pool)
+        .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS messages_room ON messages (room, id)")
+            .execute(&pool)
+            .await?;
+        Ok(Self { pool })
+    }
+
+    // only chat messages are kept, under the identity of their sender
+    pub(super) async fn append(&self, message: &Message, author: Option<&str>) -> Result<()> {
+        let Event::Chat {
+            id,
+            sender,
+            room,
+            content,
+        } = &message.event
+        else {
+            anyhow::bail!("not a chat message: {:?}", message.event);
+        };
+        sqlx::query(
+            "INSERT INTO messages (message_id, room, sender, content, created_at, author) VALUES (?, ?, ?, ?, ?, ?)",
+        )
+        .bind(id)
+        .bind(room)
+        .bind(sender)
+        .bind(content)
+        .bind(message.timestamp)
+        .bind(author)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // a message and the identity of who wrote it, None for a bot or if kept before authors were
+    pub(super) async fn get(&self, id: &str) -> Result<Option<(HistoryEntry, Option<String>)>> {
+        let query = format!("{} WHERE message_id = ?", SELECT_ENTRIES);
+        let Some(row) = sqlx::query(&query)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?
+        else {
+            return Ok(None);
+        };
+        Ok(Some((
+            HistoryEntry::from_row(&row)?,
+            row.try_get("author")?,
+        )))
+    }
+
+    // false if there is no such message
+    pub(super) async fn edit(&self, id: &str, content: &str) -> Result<bool> {
+        let edited =
+            sqlx::query("UPDATE messages SET content = ?, edited_at = ? WHERE message_id = ?")
+                .bind(content)
+                .bind(Utc::now())
+                .bind(id)
+                .execute(&self.pool)
+                .await?;
+        Ok(edited.rows_affected() > 0)
+    }
+
+    pub(super) async fn delete(&self, id: &str) -> Result<bool> {
+        let deleted = sqlx::query("DELETE FROM messages WHERE message_id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(deleted.rows_affected() > 0)
+    }
+
+    // the last `n` messages of `room`, oldest first
+    pub(super) async fn recent(&self, room: &str, n: i64) -> Result<Vec<HistoryEntry>> {
+        let query = format!("{} WHERE room = ? ORDER BY id DESC LIMIT ?", SELECT_ENTRIES);
+        let mut entries: Vec<HistoryEntry> = sqlx::query_as(&query)
+            .bind(room)
+            .bind(n)
+            .fetch_all(&self.pool)
+            .await?;
+        entries.reverse();
+        Ok(entries)
+    }
+}
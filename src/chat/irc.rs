@@ -0,0 +1,680 @@
+// a listener for irc clients: NICK, USER and PASS to log in, then JOIN, PART, PRIVMSG, TOPIC
+// and NICK in the same rooms as everyone else. a nick registers with its PASS like a username
+// does with its password. there are no private messages and no sessions to resume
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::chat::lines::{self, InvalidLine, Lines};
+use anyhow::Result;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::time::{timeout, timeout_at, Instant};
+use tokio_util::codec::{Decoder, Encoder, Framed, LinesCodecError};
+use tracing::{info, warn, Instrument, Span};
+
+use crate::chat::commands::Command;
+use crate::chat::connections::Connection;
+use crate::chat::{
+    dispatch, BanTarget, Event, Flood, Login, Message, Outgoing, Peer, Protocol, State, Verdict,
+    DEFAULT_ROOM,
+};
+
+// the prefix of what the server says itself
+pub const SERVER: &str = "chat";
+// as long as a line may be, rfc 1459 says 512 with its CRLF
+const MAX_LINE: usize = 512;
+
+// `@tags :prefix COMMAND param param :trailing param`, tags are ignored
+#[derive(Debug, Clone, PartialEq)]
+pub struct IrcMessage {
+    pub prefix: Option<String>,
+    // in upper case
+    pub command: String,
+    pub params: Vec<String>,
+}
+
+// lines in, lines out, each ended by CRLF. what goes out is encoded already
+#[derive(Debug)]
+pub struct IrcCodec {
+    lines: Lines,
+}
+
+impl IrcMessage {
+    pub fn new(prefix: Option<&str>, command: &str, params: &[&str]) -> Self {
+        Self {
+            prefix: prefix.map(str::to_string),
+            command: command.to_string(),
+            params: params.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    // None for an empty line
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut rest = line.trim_start();
+        if rest.starts_with('@') {
+            rest = rest
+                .split_once(' ')
+                .map_or("", |(_, rest)| rest)
+                .trim_start();
+        }
+        let mut prefix = None;
+        if let Some(stripped) = rest.strip_prefix(':') {
+            let (p, r) = stripped.split_once(' ').unwrap_or((stripped, ""));
+            prefix = Some(p.to_string());
+            rest = r.trim_start();
+        }
+        let (command, mut rest) = rest.split_once(' ').unwrap_or((rest, ""));
+        if command.is_empty() {
+            return None;
+        }
+        let mut params = Vec::new();
+        loop {
+            rest = rest.trim_start_matches(' ');
+            if rest.is_empty() {
+                break;
+            }
+            if let Some(trailing) = rest.strip_prefix(':') {
+                params.push(trailing.to_string());
+                break;
+            }
+            let (param, r) = rest.split_once(' ').unwrap_or((rest, ""));
+            params.push(param.to_string());
+            rest = r;
+        }
+        Some(Self {
+            prefix,
+            command: command.to_ascii_uppercase(),
+            params,
+        })
+    }
+
+    pub fn bytes(&self) -> Bytes {
+        Bytes::from(self.to_string())
+    }
+}
+
+impl fmt::Display for IrcMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(prefix) = &self.prefix {
+            write!(f, ":{} ", prefix)?;
+        }
+        write!(f, "{}", self.command)?;
+        let Some((last, middle)) = self.params.split_last() else {
+            return Ok(());
+        };
+        for param in middle {
+            write!(f, " {}", param)?;
+        }
+        if last.is_empty() || last.contains(' ') || last.starts_with(':') {
+            write!(f, " :{}", last)
+        } else {
+            write!(f, " {}", last)
+        }
+    }
+}
+
+impl IrcCodec {
+    pub fn new() -> Self {
+        Self {
+            lines: Lines::new(MAX_LINE),
+        }
+    }
+}
+
+impl Decoder for IrcCodec {
+    type Item = Result<IrcMessage, InvalidLine>;
+    type Error = LinesCodecError;
+
+    // empty lines are skipped
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        loop {
+            match self.lines.decode(buf)? {
+                Some(Ok(line)) => match IrcMessage::parse(&line) {
+                    Some(message) => return Ok(Some(Ok(message))),
+                    None => continue,
+                },
+                Some(Err(invalid)) => return Ok(Some(Err(invalid))),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+impl Encoder<Bytes> for IrcCodec {
+    type Error = LinesCodecError;
+
+    fn encode(&mut self, line: Bytes, buf: &mut BytesMut) -> Result<(), Self::Error> {
+        buf.reserve(line.len() + 2);
+        buf.put(line);
+        buf.put_slice(b"\r\n");
+        Ok(())
+    }
+}
+
+// what a peer's writer sends an irc client. irc has no multi-line messages, a paste is sent line
+// by line
+pub fn encode(message: &Message) -> Vec<IrcMessage> {
+    let encoded = encode_one(message);
+    let Some((last, params)) = encoded.params.split_last() else {
+        return vec![encoded];
+    };
+    last.split('\n')
+        .map(|line| IrcMessage {
+            prefix: encoded.prefix.clone(),
+            command: encoded.command.clone(),
+            params: params.iter().cloned().chain([line.to_string()]).collect(),
+        })
+        .collect()
+}
+
+fn encode_one(message: &Message) -> IrcMessage {
+    let user = |nick: &str, command, params: &[&str]| {
+        IrcMessage::new(Some(&format!("{0}!{0}@{1}", nick, SERVER)), command, params)
+    };
+    match &message.event {
+        Event::UserJoined { username, room } => user(username, "JOIN", &[room]),
+        Event::UserLeft { username, room } => user(username, "PART", &[room]),
+        Event::Chat {
+            sender,
+            room,
+            content,
+            ..
+        } => user(sender, "PRIVMSG", &[room, content]),
+        Event::Action {
+            sender,
+            room,
+            content,
+        } => user(
+            sender,
+            "PRIVMSG",
+            &[room, &format!("\x01ACTION {}\x01", content)],
+        ),
+        Event::Renamed { old, new } => user(old, "NICK", &[new]),
+        Event::History(entry) => {
+            let content = format!(
+                "[{}] {}",
+                entry.created_at.format("%Y-%m-%d %H:%M:%S"),
+                entry.content
+            );
+            user(&entry.sender, "PRIVMSG", &[&entry.room, &content])
+        }
+        Event::Topic { room, topic } => IrcMessage::new(Some(SERVER), "TOPIC", &[room, topic]),
+        Event::Ping => IrcMessage::new(None, "PING", &[SERVER]),
+        // to `*`, the writer doesn't know the nick of its client
+        _ => {
+            let text = message.render(Default::default());
+            IrcMessage::new(Some(SERVER), "NOTICE", &["*", &text])
+        }
+    }
+}
+
+// accepts irc clients until shutdown
+pub async fn serve(state: Arc<State>, listener: TcpListener) -> Result<()> {
+    loop {
+        let (client, addr) = tokio::select! {
+            _ = state.shutdown.cancelled() => return Ok(()),
+            accepted = listener.accept() => accepted?,
+        };
+        let span = state.connection_span(addr);
+        let connection = span.in_scope(|| {
+            info!("accepted irc connection");
+            let connection = state.connections.open(addr.ip());
+            if let Err(e) = &connection {
+                warn!(reason = %e, "refusing connection");
+            }
+            connection
+        });
+        let cloned_state = Arc::clone(&state);
+        state.tasks.spawn(
+            async move {
+                if let Err(e) = handle_irc(cloned_state, addr, connection, client).await {
+                    warn!(error = ?e, "failed to handle irc client");
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+pub async fn handle_irc<S>(
+    state: Arc<State>,
+    addr: SocketAddr,
+    connection: Result<Connection, String>,
+    stream: S,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (sink, stream) = Framed::new(stream, IrcCodec::new()).split();
+    let mut sink = sink.sink_map_err(anyhow::Error::from);
+    let mut stream = std::pin::pin!(stream.map(|message| Ok(message?)));
+    let _connection = match connection {
+        Ok(connection) => connection,
+        Err(e) => {
+            sink.send(IrcMessage::new(None, "ERROR", &[&e]).bytes())
+                .await?;
+            return Ok(());
+        }
+    };
+    if let Some(ban) = state.banned(&BanTarget::Ip(addr.ip())).await {
+        info!(%ban, "turned away");
+        let error = format!("Your address is {}", ban);
+        sink.send(IrcMessage::new(None, "ERROR", &[&error]).bytes())
+            .await?;
+        return Ok(());
+    }
+    let Some((username, account)) = register(&state, addr, &mut sink, &mut stream).await? else {
+        return Ok(());
+    };
+    Span::current().record("username", username.as_str());
+    let config = state.config();
+    let motd = config.motd();
+    let mut replies = vec![
+        ("001", format!("Welcome to the chat, {}", username)),
+        ("002", format!("Your host is {}", SERVER)),
+    ];
+    if motd.is_empty() {
+        replies.push(("422", "MOTD File is missing".to_string()));
+    } else {
+        replies.push(("375", format!("- {} Message of the day -", SERVER)));
+        replies.extend(motd.iter().map(|line| ("372", format!("- {}", line))));
+        replies.push(("376", "End of /MOTD command".to_string()));
+    }
+    for (code, text) in replies {
+        let reply = IrcMessage::new(Some(SERVER), code, &[&username, &text]);
+        sink.send(reply.bytes()).await?;
+    }
+    let mut peer = state
+        .add(addr, username, account, Protocol::Irc, sink, None)
+        .await;
+    info!(role = ?peer.role, protocol = ?Protocol::Irc, "logged in");
+    join(&state, addr, &mut peer, DEFAULT_ROOM).await;
+
+    let reason = run(&state, addr, &mut peer, &mut stream).await;
+    info!(reason, "disconnected");
+    state.end_session(addr, &mut peer).await;
+    Ok(())
+}
+
+// NICK and USER, and PASS for a registered nick or to register a free one. the nick, and its
+// account unless a guest. None if the client went away or was refused
+async fn register<Tx, Rx>(
+    state: &State,
+    addr: SocketAddr,
+    sink: &mut Tx,
+    stream: &mut Rx,
+) -> Result<Option<(String, Option<String>)>>
+where
+    Tx: Sink<Bytes, Error = anyhow::Error> + Unpin,
+    Rx: Stream<Item = Result<Result<IrcMessage, InvalidLine>>> + Unpin,
+{
+    let reply = |code, params: &[&str]| IrcMessage::new(Some(SERVER), code, params).bytes();
+    let (mut nick, mut user, mut pass) = (None, false, String::new());
+    loop {
+        let next = tokio::select! {
+            _ = state.shutdown.cancelled() => return Ok(None),
+            next = timeout(state.config().idle_timeout, stream.next()) => next,
+        };
+        let message = match next {
+            Ok(Some(Ok(Ok(message)))) => message,
+            Ok(Some(Ok(Err(invalid)))) => {
+                sink.send(reply("NOTICE", &["*", &invalid.to_string()]))
+                    .await?;
+                continue;
+            }
+            Ok(Some(Err(e))) => return Err(e),
+            Ok(None) | Err(_) => return Ok(None),
+        };
+        let param = message.params.first().map(String::as_str);
+        match (message.command.as_str(), param) {
+            ("PASS", Some(password)) => pass = password.to_string(),
+            ("NICK", Some(name)) => nick = Some(name.to_string()),
+            ("USER", Some(_)) => user = true,
+            ("PING", token) => sink.send(reply("PONG", &[token.unwrap_or(SERVER)])).await?,
+            ("CAP", Some("LS")) => sink.send(reply("CAP", &["*", "LS", ""])).await?,
+            ("QUIT", _) => return Ok(None),
+            _ => {}
+        }
+        let (Some(name), true) = (nick.as_deref(), user) else {
+            continue;
+        };
+        let (step, prompt) = match state.login(addr, Login::Username, name).await {
+            (step @ (Login::Password { .. } | Login::NewPassword(_)), _) => {
+                match state.login(addr, step, &pass).await {
+                    (Login::LoggedIn(username), _) => {
+                        return Ok(Some((username.clone(), Some(username))))
+                    }
+                    (Login::Refused, prompt) => (Login::Refused, prompt),
+                    // a wrong or too short password, a client doesn't ask for another one
+                    (_, prompt) => {
+                        sink.send(reply("464", &[name, &prompt])).await?;
+                        return Ok(None);
+                    }
+                }
+            }
+            step => step,
+        };
+        match step {
+            Login::LoggedIn(username) => return Ok(Some((username.clone(), Some(username)))),
+            Login::Guest(username) => return Ok(Some((username, None))),
+            // the client picks another nick
+            Login::Username => {
+                sink.send(reply("433", &["*", name, &prompt])).await?;
+                nick = None;
+            }
+            _ => {
+                sink.send(IrcMessage::new(None, "ERROR", &[&prompt]).bytes())
+                    .await?;
+                return Ok(None);
+            }
+        }
+    }
+}
+
+// until the client quits or goes away, the reason why
+async fn run<Rx>(state: &State, addr: SocketAddr, peer: &mut Peer, stream: &mut Rx) -> &'static str
+where
+    Rx: Stream<Item = Result<Result<IrcMessage, InvalidLine>>> + Unpin,
+{
+    let mut flood = Flood::new(&state.config().rate_limit, Instant::now());
+    let mut seen_at = Instant::now();
+    let mut pinged = false;
+    loop {
+        // a reload applies from the next line on
+        let config = state.config();
+        let deadline = if pinged || config.ping_interval >= config.idle_timeout {
+            seen_at + config.idle_timeout
+        } else {
+            seen_at + config.ping_interval
+        };
+        let next = tokio::select! {
+            _ = peer.disconnect.cancelled() => return "kicked or too slow",
+            _ = state.shutdown.cancelled() => return "shutdown",
+            next = timeout_at(deadline, stream.next()) => next,
+        };
+        let message = match next {
+            Ok(Some(Ok(Ok(message)))) => message,
+            Ok(Some(Ok(Err(invalid)))) => {
+                notice(state, addr, peer, &invalid.to_string()).await;
+                continue;
+            }
+            Ok(Some(Err(e))) => {
+                warn!(error = ?e, "failed to read line");
+                return "read error";
+            }
+            Ok(None) => return "closed",
+            Err(_) if deadline < seen_at + config.idle_timeout => {
+                state.send(addr, Message::new(Event::Ping)).await;
+                pinged = true;
+                continue;
+            }
+            Err(_) => return "idle",
+        };
+        seen_at = Instant::now();
+        pinged = false;
+        let command = message.command.as_str();
+        if !matches!(command, "PING" | "PONG") {
+            match flood.check(&config.rate_limit, seen_at) {
+                Verdict::Allow => {}
+                Verdict::Warn => {
+                    let text = "You are sending too fast, slow down or you will be muted";
+                    notice(state, addr, peer, text).await;
+                    continue;
+                }
+                Verdict::Mute(duration) => {
+                    warn!(mute = ?duration, "muted for flooding");
+                    let text = format!("You are muted for {}s", duration.as_secs());
+                    notice(state, addr, peer, &text).await;
+                    continue;
+                }
+                Verdict::Muted => continue,
+                Verdict::Disconnect => return "flooding",
+            }
+        }
+        let nick = peer.username.clone();
+        let params: Vec<&str> = message.params.iter().map(String::as_str).collect();
+        match (command, params.as_slice()) {
+            ("PING", token) => {
+                let token = token.first().copied().unwrap_or(SERVER);
+                send(
+                    state,
+                    addr,
+                    IrcMessage::new(Some(SERVER), "PONG", &[SERVER, token]),
+                )
+                .await;
+            }
+            ("PONG" | "NOTICE" | "CAP", _) => {}
+            ("QUIT", _) => return "quit",
+            ("PRIVMSG", [target, text]) => {
+                if peer.room.as_deref() != Some(*target) {
+                    let reason = if target.starts_with('#') {
+                        "You are not on that channel"
+                    } else {
+                        "Private messages are not supported"
+                    };
+                    reply(state, addr, "404", &[&nick, target, reason]).await;
+                    continue;
+                }
+                let action = text
+                    .strip_prefix("\x01ACTION ")
+                    .map(|action| action.trim_end_matches('\x01'));
+                let text = match lines::clean(action.unwrap_or(text), config.max_line) {
+                    Ok(text) => text,
+                    Err(e) => {
+                        notice(state, addr, peer, &e.to_string()).await;
+                        continue;
+                    }
+                };
+                let command = match action {
+                    Some(_) => Command::Me(&text),
+                    None => Command::Chat(&text),
+                };
+                run_command(state, addr, peer, command).await;
+            }
+            ("JOIN", ["0", ..]) => part(state, addr, peer).await,
+            ("JOIN", [rooms, ..]) => {
+                // the first of `#a,#b`, a peer is in one room at a time
+                let room = rooms.split(',').next().unwrap_or_default();
+                match room.strip_prefix('#') {
+                    Some(name) if !name.is_empty() => join(state, addr, peer, room).await,
+                    _ => reply(state, addr, "403", &[&nick, room, "No such channel"]).await,
+                }
+            }
+            ("PART", [room, ..]) if peer.room.as_deref() == Some(*room) => {
+                part(state, addr, peer).await;
+            }
+            ("TOPIC", [room, topic @ ..]) if peer.room.as_deref() == Some(*room) => {
+                let command = Command::Topic(topic.first().copied());
+                run_command(state, addr, peer, command).await;
+            }
+            ("PART" | "TOPIC" | "NAMES", [room, ..]) if peer.room.as_deref() != Some(*room) => {
+                reply(
+                    state,
+                    addr,
+                    "442",
+                    &[&nick, room, "You are not on that channel"],
+                )
+                .await;
+            }
+            ("NAMES", [room, ..]) => names(state, addr, peer, room).await,
+            ("NICK", [name, ..]) => {
+                run_command(state, addr, peer, Command::Nick(name)).await;
+                if peer.username == *name {
+                    let user = format!("{0}!{0}@{1}", nick, SERVER);
+                    send(state, addr, IrcMessage::new(Some(&user), "NICK", &[name])).await;
+                }
+            }
+            ("MODE", [target, ..]) if target.starts_with('#') => {
+                reply(state, addr, "324", &[&nick, target, "+"]).await;
+            }
+            ("MODE", [target, ..]) => reply(state, addr, "221", &[target, "+"]).await,
+            ("WHO", [target, ..]) => {
+                reply(state, addr, "315", &[&nick, target, "End of WHO list"]).await;
+            }
+            ("USER" | "PASS", _) => {
+                reply(state, addr, "462", &[&nick, "You may not reregister"]).await;
+            }
+            ("PRIVMSG" | "JOIN" | "PART" | "TOPIC" | "NAMES" | "NICK" | "MODE" | "WHO", _) => {
+                reply(
+                    state,
+                    addr,
+                    "461",
+                    &[&nick, command, "Not enough parameters"],
+                )
+                .await;
+            }
+            _ => reply(state, addr, "421", &[&nick, command, "Unknown command"]).await,
+        }
+    }
+}
+
+// the client is told it joined before the room's notices and history, and leaves its old room
+async fn join(state: &State, addr: SocketAddr, peer: &mut Peer, room: &str) {
+    if peer.room.as_deref() == Some(room) {
+        return;
+    }
+    if let Err(e) = state.may_join(peer, room).await {
+        reply(state, addr, "473", &[&peer.username, room, &e]).await;
+        return;
+    }
+    let user = format!("{0}!{0}@{1}", peer.username, SERVER);
+    if let Some(old) = &peer.room {
+        send(state, addr, IrcMessage::new(Some(&user), "PART", &[old])).await;
+    }
+    send(state, addr, IrcMessage::new(Some(&user), "JOIN", &[room])).await;
+    state.join(addr, peer, room).await;
+    names(state, addr, peer, room).await;
+}
+
+async fn part(state: &State, addr: SocketAddr, peer: &mut Peer) {
+    if let Some(room) = &peer.room {
+        let user = format!("{0}!{0}@{1}", peer.username, SERVER);
+        send(state, addr, IrcMessage::new(Some(&user), "PART", &[room])).await;
+        run_command(state, addr, peer, Command::Leave).await;
+    }
+}
+
+async fn names(state: &State, addr: SocketAddr, peer: &Peer, room: &str) {
+    let mut names: Vec<String> = match state.rooms.get(room) {
+        Some(entry) => state
+            .names
+            .iter()
+            .filter(|n| entry.members.contains(n.value()))
+            .map(|n| n.key().clone())
+            .collect(),
+        None => Vec::new(),
+    };
+    names.sort();
+    let nick = &peer.username;
+    reply(state, addr, "353", &[nick, "=", room, &names.join(" ")]).await;
+    reply(state, addr, "366", &[nick, room, "End of NAMES list"]).await;
+}
+
+// what a text client would have typed, none of what irc clients send becomes a /quit
+async fn run_command(state: &State, addr: SocketAddr, peer: &mut Peer, command: Command<'_>) {
+    let _ = dispatch(state, addr, peer, command).await;
+}
+
+// through the writer, in order with everything else the client gets
+async fn send(state: &State, addr: SocketAddr, message: IrcMessage) {
+    state.queue(addr, Outgoing::Raw(message.bytes())).await;
+}
+
+async fn reply(state: &State, addr: SocketAddr, code: &str, params: &[&str]) {
+    send(state, addr, IrcMessage::new(Some(SERVER), code, params)).await;
+}
+
+async fn notice(state: &State, addr: SocketAddr, peer: &Peer, text: &str) {
+    reply(state, addr, "NOTICE", &[&peer.username, text]).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn irc_lines_should_parse() {
+        let message = IrcMessage::parse(":alice!a@host PRIVMSG #rust :hi there :)").unwrap();
+        assert_eq!(message.prefix.as_deref(), Some("alice!a@host"));
+        assert_eq!(message.command, "PRIVMSG");
+        assert_eq!(message.params, ["#rust", "hi there :)"]);
+
+        let message = IrcMessage::parse("@time=2024-05-01 user bob 0 *  :Bob Smith").unwrap();
+        assert_eq!(message.command, "USER");
+        assert_eq!(message.params, ["bob", "0", "*", "Bob Smith"]);
+
+        let message = IrcMessage::parse("join #rust,#go").unwrap();
+        assert_eq!(
+            (message.command.as_str(), message.params.len()),
+            ("JOIN", 1)
+        );
+        assert_eq!(IrcMessage::parse("PING :").unwrap().params, [""]);
+        assert_eq!(IrcMessage::parse("   "), None);
+        assert_eq!(IrcMessage::parse(":prefix.only"), None);
+    }
+
+    #[test]
+    fn irc_messages_should_print_as_they_parse() {
+        let lines = [
+            ":chat 001 alice :Welcome to the chat, alice",
+            ":bob!bob@chat PRIVMSG #rust ::)",
+            "PING chat",
+            ":chat CAP * LS :",
+        ];
+        for line in lines {
+            assert_eq!(IrcMessage::parse(line).unwrap().to_string(), line);
+        }
+    }
+
+    #[test]
+    fn messages_should_encode_as_irc() {
+        let encode = |message: Message| {
+            let lines: Vec<String> = encode(&message).iter().map(|m| m.to_string()).collect();
+            lines.join("\r\n")
+        };
+        assert_eq!(
+            encode(Message::chat("bob", "#rust", "hi all")),
+            ":bob!bob@chat PRIVMSG #rust :hi all"
+        );
+        assert_eq!(
+            encode(Message::chat("bob", "#rust", "fn main() {\n}")),
+            ":bob!bob@chat PRIVMSG #rust :fn main() {\r\n:bob!bob@chat PRIVMSG #rust }"
+        );
+        assert_eq!(
+            encode(Message::user_joined("bob", "#rust")),
+            ":bob!bob@chat JOIN #rust"
+        );
+        assert_eq!(
+            encode(Message::notice("You joined #rust")),
+            ":chat NOTICE * :* You joined #rust"
+        );
+        let action = Message::new(Event::Action {
+            sender: "bob".to_string(),
+            room: "#rust".to_string(),
+            content: "waves".to_string(),
+        });
+        assert_eq!(
+            encode(action),
+            ":bob!bob@chat PRIVMSG #rust :\x01ACTION waves\x01"
+        );
+    }
+
+    #[test]
+    fn the_codec_should_skip_empty_lines_and_end_with_crlf() {
+        let mut codec = IrcCodec::new();
+        let mut buf = BytesMut::from("\r\nNICK bob\r\nUSER bob 0 * :Bob\r\n");
+        let nick = codec.decode(&mut buf).unwrap().unwrap().unwrap();
+        assert_eq!(nick, IrcMessage::new(None, "NICK", &["bob"]));
+        let user = codec.decode(&mut buf).unwrap().unwrap().unwrap();
+        assert_eq!(user.command, "USER");
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        let mut out = BytesMut::new();
+        codec.encode(Bytes::from("PING chat"), &mut out).unwrap();
+        assert_eq!(&out[..], b"PING chat\r\n");
+    }
+}
@@ -0,0 +1,232 @@
+// what clients may type: lines of a bounded length, in UTF-8, without control characters, and
+// pastes of a bounded number of them
+
+use std::{fmt, io};
+
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder, LinesCodec, LinesCodecError};
+
+// a line the client is told about, its connection goes on
+#[derive(Debug, PartialEq)]
+pub enum InvalidLine {
+    TooLong(usize),
+    NotUtf8,
+    // a paste of more lines than this
+    TooManyLines(usize),
+}
+
+// a block of lines sent as one message, collected from `/paste` to `/end`
+#[derive(Debug)]
+pub struct Paste {
+    lines: Vec<String>,
+    max_lines: usize,
+}
+
+// LinesCodec, which goes on after a line too long or not in UTF-8 instead of ending the stream
+#[derive(Debug)]
+pub struct Lines {
+    codec: LinesCodec,
+}
+
+impl Lines {
+    pub fn new(max_length: usize) -> Self {
+        Self {
+            codec: LinesCodec::new_with_max_length(max_length),
+        }
+    }
+
+    // LinesCodec has discarded the line already, or starts to
+    fn recover(
+        &self,
+        line: Result<Option<String>, LinesCodecError>,
+    ) -> Result<Option<Result<String, InvalidLine>>, LinesCodecError> {
+        match line {
+            Ok(line) => Ok(line.map(Ok)),
+            Err(LinesCodecError::MaxLineLengthExceeded) => {
+                Ok(Some(Err(InvalidLine::TooLong(self.codec.max_length()))))
+            }
+            Err(LinesCodecError::Io(e)) if e.kind() == io::ErrorKind::InvalidData => {
+                Ok(Some(Err(InvalidLine::NotUtf8)))
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Decoder for Lines {
+    type Item = Result<String, InvalidLine>;
+    type Error = LinesCodecError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let line = self.codec.decode(buf);
+        self.recover(line)
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let line = self.codec.decode_eof(buf);
+        self.recover(line)
+    }
+}
+
+impl Encoder<String> for Lines {
+    type Error = LinesCodecError;
+
+    fn encode(&mut self, line: String, buf: &mut BytesMut) -> Result<(), Self::Error> {
+        self.codec.encode(line, buf)
+    }
+}
+
+impl fmt::Display for InvalidLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLong(max) => write!(f, "Message too long, at most {} bytes", max),
+            Self::NotUtf8 => write!(f, "Message is not valid UTF-8"),
+            Self::TooManyLines(max) => write!(f, "Paste too long, at most {} lines", max),
+        }
+    }
+}
+
+impl std::error::Error for InvalidLine {}
+
+// a line as it may be broadcast, tabs become spaces and other control characters are dropped
+pub fn clean(line: &str, max_length: usize) -> Result<String, InvalidLine> {
+    if line.len() > max_length {
+        return Err(InvalidLine::TooLong(max_length));
+    }
+    Ok(line
+        .chars()
+        .filter_map(|c| match c {
+            '\t' => Some(' '),
+            c if c.is_control() => None,
+            c => Some(c),
+        })
+        .collect())
+}
+
+// a paste sent whole, each line as `clean` has it. blank lines around it are dropped
+pub fn clean_block(text: &str, max_length: usize, max_lines: usize) -> Result<String, InvalidLine> {
+    let lines = text
+        .trim_matches(|c| c == '\n' || c == '\r')
+        .split('\n')
+        .map(|line| clean(line, max_length))
+        .collect::<Result<Vec<_>, _>>()?;
+    if lines.len() > max_lines {
+        return Err(InvalidLine::TooManyLines(max_lines));
+    }
+    Ok(lines.join("\n"))
+}
+
+impl Paste {
+    pub fn new(max_lines: usize) -> Self {
+        Self {
+            lines: Vec::new(),
+            max_lines,
+        }
+    }
+
+    // a cleaned line, the whole paste once it is `/end`. an error ends the paste
+    pub fn push(&mut self, line: &str) -> Result<Option<String>, String> {
+        if line.trim() == "/end" {
+            let text = std::mem::take(&mut self.lines).join("\n");
+            let text = text.trim_matches('\n');
+            if text.is_empty() {
+                return Err("Nothing was pasted".to_string());
+            }
+            return Ok(Some(text.to_string()));
+        }
+        if self.lines.len() == self.max_lines {
+            return Err(InvalidLine::TooManyLines(self.max_lines).to_string());
+        }
+        self.lines.push(line.to_string());
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(lines: &mut Lines, input: &[u8]) -> Vec<Result<String, InvalidLine>> {
+        let mut buf = BytesMut::from(input);
+        let mut decoded = Vec::new();
+        while let Some(line) = lines.decode_eof(&mut buf).unwrap() {
+            decoded.push(line);
+        }
+        decoded
+    }
+
+    #[test]
+    fn lines_should_be_cut_at_the_max_length() {
+        let mut lines = Lines::new(4);
+        let decoded = decode_all(&mut lines, b"abcd\nabcde\nok\r\n");
+        assert_eq!(
+            decoded,
+            [
+                Ok("abcd".to_string()),
+                Err(InvalidLine::TooLong(4)),
+                Ok("ok".to_string())
+            ]
+        );
+
+        // the rest of a long line is skipped even if it arrives later
+        let mut buf = BytesMut::from(&b"abcdefgh"[..]);
+        assert_eq!(
+            lines.decode(&mut buf).unwrap(),
+            Some(Err(InvalidLine::TooLong(4)))
+        );
+        buf.extend_from_slice(b"ij\nnext\n");
+        assert_eq!(
+            lines.decode(&mut buf).unwrap(),
+            Some(Ok("next".to_string()))
+        );
+    }
+
+    #[test]
+    fn invalid_utf8_should_not_end_the_stream() {
+        let mut lines = Lines::new(16);
+        let decoded = decode_all(&mut lines, b"caf\xc3\xa9\n\xff\xfe\nstill here");
+        assert_eq!(
+            decoded,
+            [
+                Ok("café".to_string()),
+                Err(InvalidLine::NotUtf8),
+                Ok("still here".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn control_characters_should_be_cleaned() {
+        assert_eq!(clean("hi\tthere", 16), Ok("hi there".to_string()));
+        assert_eq!(clean("\x1b[31mred\x07\u{0}", 16), Ok("[31mred".to_string()));
+        assert_eq!(clean("two\nlines\r", 16), Ok("twolines".to_string()));
+        // counted in bytes, before cleaning
+        assert_eq!(clean("ééé", 6), Ok("ééé".to_string()));
+        assert_eq!(clean("éééé", 6), Err(InvalidLine::TooLong(6)));
+    }
+
+    #[test]
+    fn pastes_should_keep_their_lines() {
+        assert_eq!(
+            clean_block("\nfn main() {\r\n\tok();\n}\n", 16, 3),
+            Ok("fn main() {\n ok();\n}".to_string())
+        );
+        assert_eq!(
+            clean_block("a\nb\nc\nd", 16, 3),
+            Err(InvalidLine::TooManyLines(3))
+        );
+        assert_eq!(clean_block("a\nabcdef", 4, 3), Err(InvalidLine::TooLong(4)));
+
+        let mut paste = Paste::new(2);
+        assert_eq!(paste.push("  indented"), Ok(None));
+        assert_eq!(paste.push(""), Ok(None));
+        assert_eq!(paste.push("/end"), Ok(Some("  indented".to_string())));
+        assert_eq!(paste.push("/end"), Err("Nothing was pasted".to_string()));
+        paste.push("a").unwrap();
+        paste.push("b").unwrap();
+        assert_eq!(
+            paste.push("c"),
+            Err("Paste too long, at most 2 lines".to_string())
+        );
+    }
+}
@@ -0,0 +1,79 @@
+// `@name` in chat messages, and the mentions a user hasn't looked at yet
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::chat::Message;
+
+// unread mentions kept per user, older ones are only counted
+pub const MAX_UNREAD: usize = 20;
+
+#[derive(Debug, Default)]
+pub struct Mentions {
+    unread: Mutex<Unread>,
+}
+
+#[derive(Debug, Default)]
+struct Unread {
+    count: u64,
+    recent: VecDeque<Arc<Message>>,
+}
+
+impl Mentions {
+    pub fn add(&self, mention: Arc<Message>) {
+        let mut unread = self.unread.lock().unwrap();
+        unread.count += 1;
+        if unread.recent.len() == MAX_UNREAD {
+            unread.recent.pop_front();
+        }
+        unread.recent.push_back(mention);
+    }
+
+    // how many there were and the last ones of them, oldest first. they are read from now on
+    pub fn take(&self) -> (u64, Vec<Arc<Message>>) {
+        let unread = std::mem::take(&mut *self.unread.lock().unwrap());
+        (unread.count, unread.recent.into())
+    }
+}
+
+// the names mentioned in `content`, each once, in the order they come
+pub fn parse(content: &str) -> Vec<&str> {
+    let mut names: Vec<&str> = Vec::new();
+    for word in content.split_whitespace() {
+        let Some(name) = word.strip_prefix('@') else {
+            continue;
+        };
+        // `@bob, hi` and `thanks @bob!` mention bob
+        let name = name.trim_end_matches(|c: char| c.is_ascii_punctuation());
+        if !name.is_empty() && !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mentions_should_parse() {
+        assert_eq!(parse("@bob, @carol: hi @bob!"), ["bob", "carol"]);
+        assert_eq!(parse("mail me at bob@example.com"), Vec::<&str>::new());
+        assert_eq!(parse("@ @! @guest-0042?"), ["guest-0042"]);
+    }
+
+    #[test]
+    fn unread_mentions_should_be_counted_beyond_those_kept() {
+        let mentions = Mentions::default();
+        assert_eq!(mentions.take(), (0, Vec::new()));
+        for i in 0..MAX_UNREAD + 2 {
+            mentions.add(Arc::new(Message::chat("alice", "#rust", &i.to_string())));
+        }
+        let (count, recent) = mentions.take();
+        assert_eq!(count, MAX_UNREAD as u64 + 2);
+        assert_eq!(recent.len(), MAX_UNREAD);
+        assert_eq!(recent[0].render(Default::default()), "alice: 2");
+        assert_eq!(mentions.take().0, 0);
+    }
+}
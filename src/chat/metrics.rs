@@ -0,0 +1,244 @@
+// prometheus metrics of the chat server, served on their own listener at /metrics. next to them
+// /debug/peers shows which peers fell behind, for finding the one a drop count is about. it names
+// users and their addresses, so only those with the debug token get it
+
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use anyhow::Result;
+use axum::{
+    extract::State as AxumState,
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use http::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    StatusCode,
+};
+use prometheus::{Encoder, Histogram, HistogramOpts, IntCounter, IntGauge, Registry, TextEncoder};
+use serde::Serialize;
+
+use crate::chat::State;
+
+// the registry is owned by the state, so every test gets its own counters
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    registry: Registry,
+    pub messages_broadcast: IntCounter,
+    // messages not queued for a peer whose queue was full, or skipped by a lagging one
+    pub messages_dropped: IntCounter,
+    // times a peer was found behind, however many messages it missed each time
+    pub queue_overflows: IntCounter,
+    // peers cut off after `max_drops` drops in a row
+    pub slow_disconnects: IntCounter,
+    // peers cut off for a write to their client taking longer than `write_timeout`
+    pub write_timeouts: IntCounter,
+    // from a message being made until a member's writer picked it up
+    pub fanout_latency: Histogram,
+    // sampled on scrape, see `observe`
+    peers: IntGauge,
+    rooms: IntGauge,
+    connections: IntGauge,
+    connection_addresses: IntGauge,
+}
+
+impl Metrics {
+    pub fn try_new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("chat".to_string()), None)?;
+
+        let messages_broadcast = IntCounter::new(
+            "messages_broadcast_total",
+            "number of messages sent to a room",
+        )?;
+        let messages_dropped = IntCounter::new(
+            "messages_dropped_total",
+            "number of messages a peer missed for reading too slowly",
+        )?;
+        let queue_overflows = IntCounter::new(
+            "queue_overflows_total",
+            "number of times a peer's queue was full or it lagged behind its room",
+        )?;
+        let slow_disconnects = IntCounter::new(
+            "slow_disconnects_total",
+            "number of peers disconnected for missing too many messages in a row",
+        )?;
+        let write_timeouts = IntCounter::new(
+            "write_timeouts_total",
+            "number of peers disconnected for their client not taking a message in time",
+        )?;
+        let fanout_latency = Histogram::with_opts(
+            HistogramOpts::new(
+                "fanout_latency_seconds",
+                "time from a room message being made until a member's writer picked it up",
+            )
+            .buckets(vec![0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0]),
+        )?;
+        let peers = IntGauge::new("peers", "number of logged in peers")?;
+        let rooms = IntGauge::new("rooms", "number of open rooms")?;
+        let connections = IntGauge::new("connections", "number of open connections")?;
+        let connection_addresses = IntGauge::new(
+            "connection_addresses",
+            "number of addresses with an open connection",
+        )?;
+
+        registry.register(Box::new(messages_broadcast.clone()))?;
+        registry.register(Box::new(messages_dropped.clone()))?;
+        registry.register(Box::new(queue_overflows.clone()))?;
+        registry.register(Box::new(slow_disconnects.clone()))?;
+        registry.register(Box::new(write_timeouts.clone()))?;
+        registry.register(Box::new(fanout_latency.clone()))?;
+        registry.register(Box::new(peers.clone()))?;
+        registry.register(Box::new(rooms.clone()))?;
+        registry.register(Box::new(connections.clone()))?;
+        registry.register(Box::new(connection_addresses.clone()))?;
+
+        Ok(Self {
+            registry,
+            messages_broadcast,
+            messages_dropped,
+            queue_overflows,
+            slow_disconnects,
+            write_timeouts,
+            fanout_latency,
+            peers,
+            rooms,
+            connections,
+            connection_addresses,
+        })
+    }
+
+    // the wall clock, a message only carries when it was made
+    pub fn observe_fanout(&self, made_at: DateTime<Utc>) {
+        if let Ok(latency) = (Utc::now() - made_at).to_std() {
+            self.fanout_latency.observe(latency.as_secs_f64());
+        }
+    }
+
+    pub fn observe(&self, state: &State) {
+        self.peers.set(state.peers.len() as i64);
+        self.rooms.set(state.rooms.len() as i64);
+        self.connections.set(state.connections.total() as i64);
+        self.connection_addresses
+            .set(state.connections.addresses() as i64);
+    }
+
+    // render all metrics in the prometheus text format
+    pub fn render(&self) -> Result<String> {
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(String::from_utf8(buf)?)
+    }
+}
+
+pub async fn metrics_handler(AxumState(state): AxumState<Arc<State>>) -> Response {
+    state.metrics.observe(&state);
+    match state.metrics.render() {
+        Ok(body) => ([(CONTENT_TYPE, TextEncoder::new().format_type())], body).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+// a logged in peer's queue, as /debug/peers shows it
+#[derive(Debug, Serialize)]
+pub struct PeerQueue {
+    pub username: String,
+    pub addr: SocketAddr,
+    // messages waiting for its writer, of at most `capacity`
+    pub queued: usize,
+    pub capacity: usize,
+    pub dropped: u64,
+    pub in_a_row: u64,
+    pub overflows: u64,
+    pub last_overflow_at: Option<DateTime<Utc>>,
+}
+
+// the peers most often behind first, then by name
+pub fn peer_queues(state: &State) -> Vec<PeerQueue> {
+    let mut queues: Vec<PeerQueue> = state
+        .names
+        .iter()
+        .filter_map(|name| {
+            let addr = *name.value();
+            let peer = state.peers.get(&addr)?;
+            let drops = &peer.drops;
+            let capacity = peer.sender.max_capacity();
+            let last_overflow_at = *drops.last_overflow_at.lock().unwrap();
+            Some(PeerQueue {
+                username: name.key().clone(),
+                addr,
+                queued: capacity - peer.sender.capacity(),
+                capacity,
+                dropped: drops.total.load(Ordering::Relaxed),
+                in_a_row: drops.in_a_row.load(Ordering::Relaxed),
+                overflows: drops.overflows.load(Ordering::Relaxed),
+                last_overflow_at,
+            })
+        })
+        .collect();
+    queues.sort_by(|a, b| {
+        b.overflows
+            .cmp(&a.overflows)
+            .then_with(|| a.username.cmp(&b.username))
+    });
+    queues
+}
+
+pub async fn peers_handler(
+    AxumState(state): AxumState<Arc<State>>,
+    headers: HeaderMap,
+) -> Response {
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match authorized(&state.config().debug_token, token) {
+        Ok(()) => Json(peer_queues(&state)).into_response(),
+        Err(status) => status.into_response(),
+    }
+}
+
+// not found without a token configured, as if there were no such page
+fn authorized(expected: &str, token: Option<&str>) -> Result<(), StatusCode> {
+    if expected.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    // compared in constant time, blake3 hashes are
+    match token {
+        Some(token) if blake3::hash(token.as_bytes()) == blake3::hash(expected.as_bytes()) => {
+            Ok(())
+        }
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_should_include_counters_and_latency() {
+        let metrics = Metrics::try_new().unwrap();
+        metrics.messages_broadcast.inc();
+        metrics.messages_dropped.inc_by(3);
+        metrics.observe_fanout(Utc::now());
+        let text = metrics.render().unwrap();
+        assert!(text.contains("chat_messages_broadcast_total 1"));
+        assert!(text.contains("chat_messages_dropped_total 3"));
+        assert!(text.contains("chat_fanout_latency_seconds_count 1"));
+    }
+
+    #[test]
+    fn debug_peers_should_need_the_token() {
+        assert_eq!(authorized("", None), Err(StatusCode::NOT_FOUND));
+        assert_eq!(authorized("", Some("")), Err(StatusCode::NOT_FOUND));
+        assert_eq!(authorized("secret", None), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(
+            authorized("secret", Some("guess")),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(authorized("secret", Some("secret")), Ok(()));
+    }
+}
@@ -0,0 +1,1662 @@
+//! The chat server behind the `chat` and `mychat` examples: rooms, accounts, history and the
+//! sessions of every protocol. The binaries only add config, listeners and the admin console.
+
+mod audit;
+mod auth;
+mod bans;
+mod bots;
+mod cluster;
+mod colors;
+mod commands;
+mod config;
+mod connections;
+mod dispatch;
+mod filters;
+mod flood;
+mod history;
+mod irc;
+pub mod lines;
+mod mentions;
+mod metrics;
+mod nicknames;
+mod noise;
+mod protocol;
+mod registry;
+mod room_log;
+mod scrollback;
+mod server;
+mod sessions;
+mod settings;
+mod state;
+mod uploads;
+
+use std::time::Duration;
+
+use bans::{Ban, BanTarget, Bans};
+pub use config::Config;
+use config::{Codec, RateLimit};
+use connections::Connection;
+use dispatch::dispatch;
+pub use dispatch::{admin_channel, AdminCommand};
+use flood::{Flood, Verdict};
+use history::{History, HistoryEntry};
+pub use irc::serve as serve_irc;
+use protocol::{encode_numbered, Event, Message, Protocol, Render};
+use server::{handle_client, Outgoing, Writer};
+pub use server::{serve, serve_metrics, serve_noise, serve_uploads, serve_web, shutdown_signal};
+pub use state::State;
+use state::{Drops, Ignores, Login, Peer, Role, RoomMessage, REMOTE};
+
+const MAX_MESSAGES: usize = 128;
+// everyone starts here, until they /join another room
+const DEFAULT_ROOM: &str = "#general";
+// audit entries /audit shows without a number
+const AUDIT_ENTRIES: i64 = 20;
+// messages replayed on join, and at most for /history
+const REPLAY_MESSAGES: i64 = 20;
+const MAX_HISTORY: i64 = 200;
+// the newest json protocol this server speaks, clients may ask for an older one
+const PROTOCOL_VERSION: u32 = 2;
+// from this version on json messages are numbered, and resent after a resume until acked
+const ACKS_VERSION: u32 = 2;
+
+// the most users one peer may /ignore
+const MAX_IGNORES: usize = 100;
+// a client typing on is told to its room again at most this often
+const TYPING_INTERVAL: Duration = Duration::from_secs(3);
+// the most lines of one /paste
+const MAX_PASTE_LINES: usize = 50;
+// what a paste is sent on as, the lines after it are one message
+const PASTE_PREFIX: &str = "/paste\n";
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::sync::atomic::Ordering;
+    use std::sync::{Arc, Mutex};
+
+    use anyhow::Result;
+    use bytes::Bytes;
+    use chrono::Utc;
+    use futures::{future, SinkExt, Stream, StreamExt};
+    use tokio::io::DuplexStream;
+    use tokio::sync::{broadcast, mpsc};
+    use tokio::task::JoinHandle;
+    use tokio::time::timeout;
+    use tokio_util::codec::{Framed, LinesCodec};
+    use tokio_util::sync::CancellationToken;
+    use tracing::Instrument;
+
+    use super::audit::{AuditLog, ADMIN};
+    use super::auth::Accounts;
+    use super::bots::Bots;
+    use super::registry::{RoomInfo, RoomRegistry};
+    use super::server::handle_frames;
+    use super::state::{open_db, PeerHandle, Presence};
+    use super::*;
+
+    #[tokio::test]
+    async fn binary_frames_longer_than_a_line_should_end_the_session() {
+        use tokio::io::AsyncWriteExt;
+
+        let state = test_state("binary-limit", test_config()).await;
+        let (mut client, server) = tokio::io::duplex(4096);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 4020));
+        let connection = state.connections.open(addr.ip());
+        let session = tokio::spawn(handle_client(
+            Arc::clone(&state),
+            addr,
+            connection,
+            server,
+            Codec::LengthDelimited,
+        ));
+        // the length of a frame far past a line, its bytes are not waited for
+        client.write_all(&1_000_000u32.to_be_bytes()).await.unwrap();
+        let ended = timeout(Duration::from_secs(5), session)
+            .await
+            .expect("the frame was waited for")
+            .unwrap();
+        assert!(ended.is_err());
+    }
+
+    fn test_config() -> Config {
+        Config {
+            codec: Codec::Lines,
+            rate_limit: RateLimit {
+                rate: 5.0,
+                burst: 10.0,
+                mute: Duration::from_secs(30),
+            },
+            ping_interval: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(300),
+            grace_period: Duration::from_secs(5),
+            max_drops: 32,
+            write_timeout: Duration::from_secs(5),
+            max_line: 64,
+            operators: Vec::new(),
+            guests: true,
+            max_login_failures: 3,
+            login_lockout: Duration::from_secs(60),
+            max_connections: 16,
+            max_connections_per_ip: 4,
+            resume_window: Duration::from_secs(60),
+            resume_buffer: 10,
+            tls_cert: String::new(),
+            tls_key: String::new(),
+            noise_key: String::new(),
+            redis_url: String::new(),
+            log_dir: String::new(),
+            mentions_across_rooms: false,
+            filters: Vec::new(),
+            room_filters: HashMap::new(),
+            room_defaults: RoomInfo::default(),
+            banned_words: Vec::new(),
+            uploads_dir: String::new(),
+            upload_url: "http://localhost:8082".to_string(),
+            max_upload: 1024,
+            uploads_cap: 4096,
+            upload_ttl: Duration::from_secs(60),
+            scrollback: MAX_HISTORY as usize,
+            bots: Vec::new(),
+            bans: Vec::new(),
+            config_file: String::new(),
+            motd: String::new(),
+            debug_token: String::new(),
+        }
+    }
+
+    // a state on a fresh db, named after the test
+    async fn test_state(name: &str, config: Config) -> Arc<State> {
+        let db = std::env::temp_dir().join(format!("chat-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&db);
+        let pool = open_db(db.to_str().unwrap()).await.unwrap();
+        let history = History::open(pool.clone()).await.unwrap();
+        let bans = Bans::open(pool.clone()).await.unwrap();
+        let audit = AuditLog::open(pool.clone()).await.unwrap();
+        let accounts = Accounts::open(pool.clone()).await.unwrap();
+        let registry = RoomRegistry::open(pool).await.unwrap();
+        let (bots, bot_events) = Bots::new(&config.bots).unwrap();
+        let state =
+            State::new(history, bans, audit, accounts, registry, None, bots, config).unwrap();
+        let state = Arc::new(state);
+        if !state.bots.is_empty() {
+            tokio::spawn(bots::run(Arc::clone(&state), bot_events));
+        }
+        state
+    }
+
+    // a text client sending `frames`, returns all it received once it is gone
+    async fn run_client<S>(state: &Arc<State>, port: u16, frames: S) -> Vec<String>
+    where
+        S: Stream<Item = Result<Bytes>>,
+    {
+        let (sink, received) = futures::channel::mpsc::unbounded::<Bytes>();
+        let sink = sink.sink_map_err(anyhow::Error::from);
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let connection = state.connections.open(addr.ip());
+        handle_frames(
+            Arc::clone(state),
+            addr,
+            connection,
+            Protocol::Text,
+            sink,
+            frames,
+        )
+        .await
+        .unwrap();
+        received
+            .map(|frame| String::from_utf8(frame.to_vec()).unwrap())
+            .collect()
+            .await
+    }
+
+    // a text client on one end of an in-memory stream, its session on the other
+    struct DuplexClient {
+        lines: Framed<DuplexStream, LinesCodec>,
+        session: JoinHandle<Result<()>>,
+    }
+
+    impl DuplexClient {
+        // `buffer` bytes may be in flight each way before the writer waits for the client
+        fn connect(state: &Arc<State>, port: u16, buffer: usize) -> Self {
+            let (client, server) = tokio::io::duplex(buffer);
+            let addr = SocketAddr::from(([127, 0, 0, 1], port));
+            let connection = state.connections.open(addr.ip());
+            let session = tokio::spawn(handle_client(
+                Arc::clone(state),
+                addr,
+                connection,
+                server,
+                Codec::Lines,
+            ));
+            Self {
+                lines: Framed::new(client, LinesCodec::new()),
+                session,
+            }
+        }
+
+        // registered and in the default room
+        async fn login(state: &Arc<State>, port: u16, username: &str) -> Self {
+            let mut client = Self::connect(state, port, 4096);
+            client.send(username).await;
+            client.send("password").await;
+            client.expect("* You joined #general").await;
+            client
+        }
+
+        // a guest in the default room, gone by `nick`
+        async fn guest(state: &Arc<State>, port: u16, nick: &str) -> Self {
+            let mut client = Self::connect(state, port, 4096);
+            client.send("guest").await;
+            client.expect("* You joined #general").await;
+            client.send(&format!("/nick {}", nick)).await;
+            client
+                .expect(&format!("* You are now known as {}", nick))
+                .await;
+            client
+        }
+
+        async fn send(&mut self, line: &str) {
+            self.lines.send(line).await.unwrap();
+        }
+
+        // the first line it received starting with `prefix`
+        async fn expect_prefix(&mut self, prefix: &str) -> String {
+            loop {
+                let next = timeout(Duration::from_secs(5), self.lines.next())
+                    .await
+                    .unwrap_or_else(|_| panic!("no line starting with {:?}", prefix))
+                    .expect("closed")
+                    .unwrap();
+                if next.starts_with(prefix) {
+                    return next;
+                }
+            }
+        }
+
+        // what it received up to and with `line`
+        async fn expect(&mut self, line: &str) -> Vec<String> {
+            let mut received = Vec::new();
+            loop {
+                let next = timeout(Duration::from_secs(5), self.lines.next())
+                    .await
+                    .unwrap_or_else(|_| panic!("no {:?} after {:?}", line, received))
+                    .expect("closed")
+                    .unwrap();
+                let found = next == line;
+                received.push(next);
+                if found {
+                    return received;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn duplex_clients_should_hear_their_room_only() {
+        let state = test_state("duplex-rooms", test_config()).await;
+        let mut alice = DuplexClient::login(&state, 4900, "alice").await;
+        let mut bob = DuplexClient::login(&state, 4901, "bob").await;
+        alice.expect("[bob has joined #general]").await;
+
+        bob.send("hi all").await;
+        alice.expect("bob: hi all").await;
+        bob.send("/who").await;
+        let received = bob.expect("* 2 users in #general").await;
+        assert!(!received.contains(&"bob: hi all".into()));
+
+        alice.send("/join #rust").await;
+        alice.expect("* You joined #rust").await;
+        bob.expect("[alice has left #general :(]").await;
+        bob.send("anyone?").await;
+        alice.send("/who").await;
+        let received = alice.expect("* 1 users in #rust").await;
+        assert!(!received.contains(&"bob: anyone?".into()));
+    }
+
+    #[tokio::test]
+    async fn pastes_should_be_sent_as_one_message() {
+        let state = test_state("paste", test_config()).await;
+        let mut alice = DuplexClient::login(&state, 5500, "alice").await;
+        let mut bob = DuplexClient::login(&state, 5501, "bob").await;
+        alice.expect("[bob has joined #general]").await;
+
+        bob.send("/paste").await;
+        bob.expect("* Pasting, end with /end, at most 50 lines")
+            .await;
+        for line in ["fn main() {", "\t/who", "}", "/end"] {
+            bob.send(line).await;
+        }
+        let received = alice.expect("}").await;
+        assert_eq!(received, ["bob: fn main() {", " /who", "}"]);
+        let entries = state.history.recent(DEFAULT_ROOM, 1).await.unwrap();
+        assert_eq!(entries[0].content, "fn main() {\n /who\n}");
+
+        bob.send("/paste").await;
+        bob.send("/end").await;
+        bob.expect("* Nothing was pasted").await;
+    }
+
+    #[tokio::test]
+    async fn closed_duplex_clients_should_be_cleaned_up() {
+        let state = test_state("duplex-cleanup", test_config()).await;
+        let mut alice = DuplexClient::login(&state, 4902, "alice").await;
+        let bob = DuplexClient::login(&state, 4903, "bob").await;
+        alice.expect("[bob has joined #general]").await;
+
+        drop(bob.lines);
+        timeout(Duration::from_secs(5), bob.session)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        alice.expect("[bob has left #general :(]").await;
+        assert!(!state.names.contains_key("bob"));
+        assert_eq!(state.peers.len(), 1);
+        assert_eq!(state.connections.total(), 1);
+        assert_eq!(state.rooms.get(DEFAULT_ROOM).unwrap().members.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn duplex_clients_not_reading_should_be_disconnected() {
+        let mut config = test_config();
+        config.max_drops = 3;
+        let state = test_state("duplex-slow", config).await;
+        let carol = DuplexClient::login(&state, 4904, "carol").await;
+
+        // carol reads nothing more, her stream and then her queue fill up
+        for _ in 0..10_000 {
+            if carol.session.is_finished() {
+                break;
+            }
+            state.admin(AdminCommand::Announce("x".repeat(100))).await;
+            tokio::task::yield_now().await;
+        }
+        timeout(Duration::from_secs(5), carol.session)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(state.metrics.slow_disconnects.get(), 1);
+        assert!(state.peers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn duplex_clients_not_taking_a_write_should_be_disconnected() {
+        let mut config = test_config();
+        config.write_timeout = Duration::from_millis(200);
+        let state = test_state("duplex-stuck", config).await;
+        let dave = DuplexClient::login(&state, 4905, "dave").await;
+
+        // fewer than its queue takes, so nothing is dropped, but more than its stream does
+        for _ in 0..MAX_MESSAGES / 2 {
+            state.admin(AdminCommand::Announce("x".repeat(200))).await;
+        }
+        timeout(Duration::from_secs(5), dave.session)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(state.metrics.write_timeouts.get(), 1);
+        assert_eq!(state.metrics.slow_disconnects.get(), 0);
+        assert!(state.peers.is_empty() && state.names.is_empty());
+    }
+
+    #[tokio::test]
+    async fn ignored_users_should_not_be_heard() {
+        let state = test_state("ignores", test_config()).await;
+        let mut alice = DuplexClient::login(&state, 5800, "alice").await;
+        let mut bob = DuplexClient::login(&state, 5801, "bob").await;
+        let mut carol = DuplexClient::login(&state, 5802, "carol").await;
+        alice.expect("[carol has joined #general]").await;
+
+        alice.send("/ignore alice").await;
+        alice.expect("* You can't ignore yourself").await;
+        alice.send("/ignore bob").await;
+        alice
+            .expect("* Ignoring bob, /unignore bob to hear from them again")
+            .await;
+        bob.send("spam").await;
+        bob.send("@alice look").await;
+        carol.expect("bob: @alice look").await;
+        carol.send("hi").await;
+        let received = alice.expect("carol: hi").await;
+        assert_eq!(received, ["carol: hi"]);
+
+        // still ignored under a new name
+        bob.send("/nick bobby").await;
+        alice.expect("[bob is now known as bobby]").await;
+        alice.send("/ignores").await;
+        alice.expect("* You ignore bobby").await;
+        alice.send("/unignore bobby").await;
+        alice.expect("* No longer ignoring bobby").await;
+        bob.send("back").await;
+        alice.expect("bobby: back").await;
+        alice.send("/ignores").await;
+        alice.expect("* You ignore nobody").await;
+    }
+
+    #[tokio::test]
+    async fn noise_clients_should_chat_with_everyone() {
+        let state = test_state("noise", test_config()).await;
+        let mut alice = DuplexClient::login(&state, 5900, "alice").await;
+
+        let key = noise::load_key("").unwrap();
+        let public = key.public.clone();
+        let (client, server) = tokio::io::duplex(4096);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 5901));
+        let connection = state.connections.open(addr.ip());
+        let cloned_state = Arc::clone(&state);
+        let session = tokio::spawn(async move {
+            noise::handle_noise(cloned_state, addr, connection, server, &key, Codec::Lines).await
+        });
+        let connected = noise::connect(client).await.unwrap();
+        assert_eq!(connected.remote_static(), Some(public.as_slice()));
+        let (plain, relay) = connected.into_plain();
+        tokio::spawn(relay);
+        let mut bob = DuplexClient {
+            lines: Framed::new(plain, LinesCodec::new()),
+            session,
+        };
+        bob.send("bob").await;
+        bob.send("password").await;
+        bob.expect("* You joined #general").await;
+
+        alice.expect("[bob has joined #general]").await;
+        bob.send("hi over noise").await;
+        alice.expect("bob: hi over noise").await;
+        alice.send("hi back").await;
+        bob.expect("alice: hi back").await;
+    }
+
+    #[tokio::test]
+    async fn bots_should_answer_in_the_room() {
+        let mut config = test_config();
+        config.bots = vec!["echo".to_string(), "dice".to_string()];
+        let state = test_state("bots", config).await;
+        let mut alice = DuplexClient::login(&state, 5400, "alice").await;
+        let mut bob = DuplexClient::login(&state, 5401, "bob").await;
+        alice.expect("[bob has joined #general]").await;
+
+        alice.send("!echo hello bots").await;
+        alice.expect("echo: hello bots").await;
+        bob.expect("alice: !echo hello bots").await;
+        bob.expect("echo: hello bots").await;
+        bob.send("!roll 2d6").await;
+        let rolled = alice.expect_prefix("dice: bob rolled 2d6: ").await;
+        let total: u32 = rolled.rsplit(' ').next().unwrap().parse().unwrap();
+        assert!((2..=12).contains(&total));
+
+        bob.send("/nick dice").await;
+        bob.expect("* Username dice is a bot's").await;
+    }
+
+    #[tokio::test]
+    async fn usernames_should_be_checked_as_unicode() {
+        let state = test_state("nicknames", test_config()).await;
+        let mut alice = DuplexClient::login(&state, 6200, "alice").await;
+
+        // a cyrillic а, then a zero width space
+        let mut mallory = DuplexClient::connect(&state, 6201, 4096);
+        mallory.send("\u{430}lice").await;
+        mallory
+            .expect("Username \u{430}lice looks too much like alice, enter another one:")
+            .await;
+        mallory.send("mal\u{200B}lory").await;
+        mallory
+            .expect("A username can't have control or invisible characters, enter another one:")
+            .await;
+        // kept in nfc, however it was typed
+        mallory.send("jose\u{301}").await;
+        mallory.send("password").await;
+        mallory.expect("* You joined #general").await;
+        assert!(state.names.contains_key("jos\u{e9}"));
+        alice.expect("[jos\u{e9} has joined #general]").await;
+
+        mallory.send("/nick ALICE").await;
+        mallory
+            .expect("* Username ALICE looks too much like alice")
+            .await;
+        mallory.send(&format!("/nick {}", "m".repeat(40))).await;
+        mallory
+            .expect(&format!(
+                "* A username is at most {} characters",
+                nicknames::MAX_GRAPHEMES
+            ))
+            .await;
+        mallory.send("/nick mallory").await;
+        mallory.expect("* You are now known as mallory").await;
+        // alike to herself only
+        alice.send("/nick Alice").await;
+        alice.expect("* You are now known as Alice").await;
+    }
+
+    #[tokio::test]
+    async fn lookalikes_should_not_keep_owners_out() {
+        let state = test_state("lookalikes", test_config()).await;
+        // a guest took a name before one like it was registered
+        let mut mallory = DuplexClient::connect(&state, 6202, 4096);
+        mallory.send("guest").await;
+        mallory.expect("* You joined #general").await;
+        mallory.send("/nick b0b").await;
+        mallory.expect("* You are now known as b0b").await;
+        state.accounts.register("bob", "password").await.unwrap();
+
+        // the owner logs in all the same
+        let mut bob = DuplexClient::connect(&state, 6203, 4096);
+        bob.send("bob").await;
+        bob.expect("Password:").await;
+        bob.send("password").await;
+        bob.expect("* You joined #general").await;
+        drop(bob);
+
+        // nobody registers a name like a registered one, online or not
+        let mut eve = DuplexClient::connect(&state, 6204, 4096);
+        eve.send("B0B").await;
+        eve.expect("Username B0B looks too much like bob, enter another one:")
+            .await;
+        eve.send("eve").await;
+        eve.send("password").await;
+        eve.expect("* You joined #general").await;
+        eve.send("/nick BOB").await;
+        eve.expect("* Username BOB looks too much like bob").await;
+    }
+
+    #[test]
+    fn alike_names_should_be_claimed_one_at_a_time() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let state = runtime.block_on(test_state("claims", test_config()));
+        let names = [
+            "alice",
+            "\u{430}lice",
+            "ALICE",
+            "a1ice",
+            "aIice",
+            "\u{e0}lice",
+        ];
+        std::thread::scope(|scope| {
+            for (port, name) in names.into_iter().enumerate() {
+                let state = &state;
+                let addr = SocketAddr::from(([127, 0, 0, 1], port as u16 + 6210));
+                scope.spawn(move || {
+                    let _ = state.claim(addr, name, false);
+                });
+            }
+        });
+        assert_eq!(state.names.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn history_should_be_read_from_the_scrollback() {
+        let mut config = test_config();
+        config.scrollback = 2;
+        let state = test_state("scrollback", config).await;
+        let mut alice = DuplexClient::login(&state, 5300, "alice").await;
+        for line in ["one", "two", "three"] {
+            alice.send(line).await;
+        }
+        alice.send("/who").await;
+        alice.expect("* 1 users in #general").await;
+
+        // gone from the db, still in memory
+        sqlx::query("DELETE FROM messages")
+            .execute(&state.history.pool)
+            .await
+            .unwrap();
+        alice.send("/history 2").await;
+        let replayed = alice.expect_prefix("20").await;
+        assert!(replayed.ends_with(" alice: two"), "{}", replayed);
+        let replayed = alice.expect_prefix("20").await;
+        assert!(replayed.ends_with(" alice: three"), "{}", replayed);
+        // more than it keeps come from the db
+        alice.send("/history 3").await;
+        alice.send("/who").await;
+        let received = alice.expect("* 1 users in #general").await;
+        assert!(received.iter().all(|line| !line.contains("alice: ")));
+    }
+
+    #[tokio::test]
+    async fn messages_should_be_edited_and_deleted_by_id() {
+        let state = test_state("edits", test_config()).await;
+        let mut alice = DuplexClient::login(&state, 5100, "alice").await;
+        let mut bob = DuplexClient::login(&state, 5101, "bob").await;
+        alice.expect("[bob has joined #general]").await;
+        alice.send("/set ids on").await;
+        alice.expect("* Message ids are on").await;
+        bob.send("/set ids on").await;
+        bob.expect("* Message ids are on").await;
+
+        alice.send("helo").await;
+        let sent = alice.expect_prefix("* Sent as ").await;
+        let id = sent.trim_start_matches("* Sent as ").to_string();
+        bob.expect(&format!("{} alice: helo", id)).await;
+
+        bob.send(&format!("/edit {} hijacked", id)).await;
+        bob.expect("* You may only edit your own messages").await;
+        alice.send(&format!("/edit {} hello", id)).await;
+        alice.expect(&format!("* Edited {}", id)).await;
+        bob.expect(&format!("{} alice edited: hello", id)).await;
+        bob.send("/history").await;
+        let replayed = bob.expect_prefix(&id).await;
+        assert!(replayed.ends_with(" alice: hello (edited)"), "{}", replayed);
+
+        bob.send(&format!("/delete {}", id)).await;
+        bob.expect("* You may only delete your own messages").await;
+        alice.send(&format!("/delete {}", id)).await;
+        alice.expect(&format!("* Deleted {}", id)).await;
+        bob.expect(&format!("[{} was deleted]", id)).await;
+        alice.send(&format!("/delete {}", id)).await;
+        alice.expect(&format!("* No message {}", id)).await;
+        assert!(state
+            .history
+            .recent(DEFAULT_ROOM, 10)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn messages_should_be_edited_by_who_wrote_them_not_by_their_name() {
+        let state = test_state("edits-identity", test_config()).await;
+        let mut carol = DuplexClient::guest(&state, 5110, "carol").await;
+        carol.send("/set ids on").await;
+        carol.expect("* Message ids are on").await;
+        carol.send("hi").await;
+        let id = carol.expect_prefix("* Sent as ").await;
+        let id = id.trim_start_matches("* Sent as ").to_string();
+        carol.send("/quit").await;
+        carol.expect("* Goodbye").await;
+
+        // another guest taking the name doesn't take the message with it
+        let mut other = DuplexClient::guest(&state, 5111, "carol").await;
+        other.send(&format!("/edit {} hijacked", id)).await;
+        other.expect("* You may only edit your own messages").await;
+        other.send(&format!("/delete {}", id)).await;
+        other
+            .expect("* You may only delete your own messages")
+            .await;
+
+        // while its author keeps it under another name
+        let mut alice = DuplexClient::login(&state, 5112, "alice").await;
+        alice.send("/set ids on").await;
+        alice.expect("* Message ids are on").await;
+        alice.send("helo").await;
+        let id = alice.expect_prefix("* Sent as ").await;
+        let id = id.trim_start_matches("* Sent as ").to_string();
+        alice.send("/nick ali").await;
+        alice.expect("* You are now known as ali").await;
+        alice.send(&format!("/edit {} hello", id)).await;
+        alice.expect(&format!("* Edited {}", id)).await;
+    }
+
+    #[tokio::test]
+    async fn typing_should_reach_json_clients_only_and_not_too_often() {
+        async fn json_login(state: &Arc<State>, port: u16, username: &str) -> DuplexClient {
+            let mut client = DuplexClient::connect(state, port, 4096);
+            client.send(r#"{"type":"hello","version":1}"#).await;
+            for line in [username, "password"] {
+                client
+                    .send(&format!(r#"{{"type":"line","line":"{}"}}"#, line))
+                    .await;
+            }
+            client
+                .expect_prefix(r##"{"type":"notice","content":"You joined #general""##)
+                .await;
+            client
+        }
+        let state = test_state("typing", test_config()).await;
+        let mut alice = json_login(&state, 5200, "alice").await;
+        let mut bob = json_login(&state, 5201, "bob").await;
+        let mut carol = DuplexClient::login(&state, 5202, "carol").await;
+        bob.expect_prefix(r#"{"type":"user_joined","username":"carol""#)
+            .await;
+
+        let typing = r#"{"type":"typing","active":true}"#;
+        alice.send(typing).await;
+        alice.send(typing).await;
+        alice.send(r#"{"type":"typing","active":false}"#).await;
+        alice.send(r#"{"type":"line","line":"hi"}"#).await;
+        let mut told = Vec::new();
+        loop {
+            let line = timeout(Duration::from_secs(5), bob.lines.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            if line.starts_with(r#"{"type":"chat""#) {
+                break;
+            }
+            if line.starts_with(r#"{"type":"typing""#) {
+                told.push(line);
+            }
+        }
+        assert_eq!(told.len(), 2, "{:?}", told);
+        assert!(told[0].contains(r##""username":"alice","room":"#general","active":true"##));
+        assert!(told[1].contains(r#""active":false"#));
+
+        let received = carol.expect("alice: hi").await;
+        assert!(received.iter().all(|line| !line.contains("typing")));
+        assert_eq!(
+            state.history.recent(DEFAULT_ROOM, 10).await.unwrap().len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn irc_clients_should_chat_with_text_clients() {
+        let state = test_state("irc", test_config()).await;
+        let mut alice = DuplexClient::login(&state, 5000, "alice").await;
+        let (client, server) = tokio::io::duplex(4096);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 5001));
+        let connection = state.connections.open(addr.ip());
+        let session = tokio::spawn(irc::handle_irc(
+            Arc::clone(&state),
+            addr,
+            connection,
+            server,
+        ));
+        // LinesCodec drops the \r of each line
+        let mut dave = DuplexClient {
+            lines: Framed::new(client, LinesCodec::new()),
+            session,
+        };
+
+        dave.send("CAP LS 302").await;
+        dave.send("PASS password").await;
+        dave.send("NICK dave").await;
+        dave.send("USER dave 0 * :Dave").await;
+        dave.expect(":chat 001 dave :Welcome to the chat, dave")
+            .await;
+        dave.expect(":dave!dave@chat JOIN #general").await;
+        dave.expect(":chat 353 dave = #general :alice dave").await;
+        alice.expect("[dave has joined #general]").await;
+
+        dave.send("PRIVMSG #general :hi alice").await;
+        alice.expect("dave: hi alice").await;
+        alice.send("hi dave").await;
+        dave.expect(":alice!alice@chat PRIVMSG #general :hi dave")
+            .await;
+        dave.send("PRIVMSG #general :\x01ACTION waves\x01").await;
+        alice.expect("* dave waves").await;
+
+        dave.send("PING :12345").await;
+        dave.expect(":chat PONG chat 12345").await;
+        dave.send("PRIVMSG #rust :anyone?").await;
+        dave.expect(":chat 404 dave #rust :You are not on that channel")
+            .await;
+        dave.send("JOIN #rust").await;
+        dave.expect(":dave!dave@chat PART #general").await;
+        dave.expect(":dave!dave@chat JOIN #rust").await;
+        alice.expect("[dave has left #general :(]").await;
+
+        dave.send("QUIT :bye").await;
+        timeout(Duration::from_secs(5), dave.session)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(!state.names.contains_key("dave"));
+        assert_eq!(state.peers.len(), 1);
+
+        // the nick is registered now, a wrong PASS is refused
+        let (client, server) = tokio::io::duplex(4096);
+        let connection = state.connections.open(addr.ip());
+        let session = tokio::spawn(irc::handle_irc(
+            Arc::clone(&state),
+            addr,
+            connection,
+            server,
+        ));
+        let mut dave = DuplexClient {
+            lines: Framed::new(client, LinesCodec::new()),
+            session,
+        };
+        dave.send("NICK alice").await;
+        dave.send("USER dave 0 * :Dave").await;
+        dave.expect(":chat 433 * alice :Username alice is taken, enter another one:")
+            .await;
+        dave.send("PASS wrong-password").await;
+        dave.send("NICK dave").await;
+        dave.expect(":chat 464 dave :Wrong password, enter your username:")
+            .await;
+    }
+
+    #[tokio::test]
+    async fn commands_should_reply_to_the_sender_only() {
+        let state = test_state("commands", test_config()).await;
+        let frames = [
+            "alice",
+            "password",
+            "/away lunch",
+            "/who",
+            "/me waves",
+            "/dance",
+            "/quit",
+            "too late",
+        ]
+        .map(|frame| Ok(Bytes::from(frame)));
+        let received = run_client(&state, 4100, futures::stream::iter(frames)).await;
+
+        assert!(received.contains(&"* 1 users in #general".into()));
+        assert!(received
+            .iter()
+            .any(|line| line.starts_with("* alice joined ")));
+        assert!(received.contains(&"* alice waves".into()));
+        assert!(received.contains(&"* Unknown command: /dance, try /help".into()));
+        assert_eq!(received.last().map(String::as_str), Some("* Goodbye"));
+        // neither the action nor what came after /quit was stored
+        let stored = state.history.recent(DEFAULT_ROOM, 10).await.unwrap();
+        assert!(stored.is_empty());
+    }
+
+    #[tokio::test]
+    async fn wrong_passwords_should_lock_the_address_out() {
+        let state = test_state("logins", test_config()).await;
+        state.accounts.register("alice", "password").await.unwrap();
+
+        // guests need no password, but can't take a registered name
+        let frames = ["guest", "/nick alice"].map(|frame| Ok(Bytes::from(frame)));
+        let received = run_client(&state, 4200, futures::stream::iter(frames)).await;
+        assert!(received.contains(&"* You joined #general".into()));
+        assert!(received.contains(&"* Username alice is registered".into()));
+
+        let frames = [
+            "alice", "guess1", "alice", "guess2", "alice", "guess3", "alice",
+        ]
+        .map(|frame| Ok(Bytes::from(frame)));
+        let received = run_client(&state, 4201, futures::stream::iter(frames)).await;
+        let wrong = received
+            .iter()
+            .filter(|line| *line == "Wrong password, enter your username:")
+            .count();
+        assert_eq!(wrong, 3);
+        assert!(received
+            .last()
+            .unwrap()
+            .starts_with("Too many failed logins, try again in"));
+        assert!(state.names.is_empty());
+    }
+
+    #[tokio::test]
+    async fn lost_clients_should_resume_with_what_they_missed() {
+        let state = test_state("resume", test_config()).await;
+        let frames = ["alice", "password"].map(|frame| Ok(Bytes::from(frame)));
+        let received = run_client(&state, 4300, futures::stream::iter(frames)).await;
+        let token = received
+            .iter()
+            .find_map(|line| line.strip_prefix("* Your session is "))
+            .and_then(|rest| rest.split(',').next())
+            .unwrap()
+            .to_string();
+
+        // #general is kept for alice although it was empty
+        let frames = ["bob", "password", "anyone here?"].map(|frame| Ok(Bytes::from(frame)));
+        run_client(&state, 4301, futures::stream::iter(frames)).await;
+
+        let frames = [format!("/resume {}", token), "/quit".to_string()];
+        let frames = frames.map(|frame| Ok(Bytes::from(frame)));
+        let received = run_client(&state, 4302, futures::stream::iter(frames)).await;
+        assert!(received.contains(&"* Welcome back, 3 new messages".into()));
+        assert!(received.contains(&"bob: anyone here?".into()));
+        assert!(received.contains(&"[bob has left #general :(]".into()));
+
+        // /quit ended the session
+        let frames = [Ok(Bytes::from(format!("/resume {}", token)))];
+        let received = run_client(&state, 4303, futures::stream::iter(frames)).await;
+        assert!(received.contains(&"Unknown or expired session, enter your username:".into()));
+    }
+
+    #[tokio::test]
+    async fn banned_users_should_not_resume() {
+        let state = test_state("resume-banned", test_config()).await;
+        let session = |received: Vec<String>| {
+            received
+                .iter()
+                .find_map(|line| line.strip_prefix("* Your session is "))
+                .and_then(|rest| rest.split(',').next())
+                .unwrap()
+                .to_string()
+        };
+        let resume = |port, token: String| {
+            let frames = [Ok(Bytes::from(format!("/resume {}", token)))];
+            run_client(&state, port, futures::stream::iter(frames))
+        };
+
+        // a ban closes the detached session
+        let frames = ["alice", "password"].map(|frame| Ok(Bytes::from(frame)));
+        let token = session(run_client(&state, 4310, futures::stream::iter(frames)).await);
+        let alice = BanTarget::User("alice".to_string());
+        state.ban(&alice, None, ADMIN, "").await;
+        let received = resume(4311, token).await;
+        assert!(received.contains(&"Unknown or expired session, enter your username:".into()));
+
+        // and one it didn't close is checked on resuming
+        let frames = ["bob", "password", "/nick robert"].map(|frame| Ok(Bytes::from(frame)));
+        let token = session(run_client(&state, 4312, futures::stream::iter(frames)).await);
+        let bob = BanTarget::User("bob".to_string());
+        state.bans.add(&bob, None, ADMIN).await.unwrap();
+        let received = resume(4313, token).await;
+        assert!(received.contains(&"Username bob is banned for good, enter another one:".into()));
+        assert!(!state.names.contains_key("robert"));
+    }
+
+    #[tokio::test]
+    async fn unacked_messages_should_be_resent_after_a_resume() {
+        // what a json client received up to and with the message saying `content`
+        async fn json_until(client: &mut DuplexClient, content: &str) -> Vec<serde_json::Value> {
+            let mut received = Vec::new();
+            loop {
+                let line = timeout(Duration::from_secs(5), client.lines.next())
+                    .await
+                    .unwrap_or_else(|_| panic!("no {:?} after {:?}", content, received))
+                    .expect("closed")
+                    .unwrap();
+                // the greeting before the hello is plain text
+                let Ok(message) = serde_json::from_str::<serde_json::Value>(&line) else {
+                    continue;
+                };
+                let found = message["content"]
+                    .as_str()
+                    .is_some_and(|c| c.starts_with(content));
+                received.push(message);
+                if found {
+                    return received;
+                }
+            }
+        }
+        async fn json_connect(state: &Arc<State>, port: u16, lines: &[&str]) -> DuplexClient {
+            let mut client = DuplexClient::connect(state, port, 4096);
+            client.send(r#"{"type":"hello","version":2}"#).await;
+            for line in lines {
+                let request = serde_json::json!({ "type": "line", "line": line });
+                client.send(&request.to_string()).await;
+            }
+            client
+        }
+        let mut config = test_config();
+        // a /resume in json is longer than the usual test line
+        config.max_line = 128;
+        let state = test_state("acks", config).await;
+        let mut bob = DuplexClient::login(&state, 6000, "bob").await;
+        let mut alice = json_connect(&state, 6001, &["alice", "password"]).await;
+        let received = json_until(&mut alice, "Your session is ").await;
+        let session = received.last().unwrap();
+        assert!(session["seq"].is_u64());
+        let token = session["content"].as_str().unwrap()["Your session is ".len()..]
+            .split(',')
+            .next()
+            .unwrap()
+            .to_string();
+        bob.expect("[alice has joined #general]").await;
+
+        bob.send("one").await;
+        bob.send("two").await;
+        let one = json_until(&mut alice, "one").await.pop().unwrap();
+        let two = json_until(&mut alice, "two").await.pop().unwrap();
+        let seq = two["seq"].as_u64().unwrap();
+        assert_eq!(one["seq"].as_u64(), Some(seq - 1));
+        alice
+            .send(&format!(r#"{{"type":"ack","seq":{}}}"#, seq - 1))
+            .await;
+        drop(alice);
+        bob.expect("[alice has left #general :(]").await;
+        bob.send("three").await;
+
+        let resume = format!("/resume {}", token);
+        let mut alice = json_connect(&state, 6002, &[&resume]).await;
+        let received = json_until(&mut alice, "three").await;
+        let chats: Vec<(u64, &str)> = received
+            .iter()
+            .filter(|message| message["type"] == "chat")
+            .map(|message| {
+                let seq = message["seq"].as_u64().unwrap();
+                (seq, message["content"].as_str().unwrap())
+            })
+            .collect();
+        // "one" was acked, "two" keeps its number and the session numbers on
+        assert_eq!(chats[0], (seq, "two"));
+        assert_eq!(chats.len(), 2);
+        assert_eq!(chats[1].1, "three");
+        assert!(chats[1].0 > seq);
+
+        // acks are no traffic to mute a client for
+        for _ in 0..20 {
+            alice
+                .send(&format!(r#"{{"type":"ack","seq":{}}}"#, chats[1].0))
+                .await;
+        }
+        alice.send(r#"{"type":"line","line":"still here"}"#).await;
+        bob.expect("alice: still here").await;
+    }
+
+    #[tokio::test]
+    async fn mentioned_users_should_be_told_and_may_read_them_later() {
+        let state = test_state("mentions", test_config()).await;
+        let (mentioned, wait) = tokio::sync::oneshot::channel();
+        let frames = futures::stream::iter(["bob", "password"].map(|frame| Ok(Bytes::from(frame))))
+            .chain(futures::stream::once(async {
+                wait.await.unwrap();
+                Ok(Bytes::from("/mentions"))
+            }))
+            .chain(futures::stream::iter(
+                ["/mentions", "/quit"].map(|frame| Ok(Bytes::from(frame))),
+            ));
+        let bob = tokio::spawn({
+            let state = Arc::clone(&state);
+            async move { run_client(&state, 4700, frames).await }
+        });
+        while state
+            .rooms
+            .get(DEFAULT_ROOM)
+            .is_none_or(|room| room.members.is_empty())
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // nobody named carol is here, and alice mentioning herself is no news
+        let frames = ["alice", "password", "hi @bob!", "@carol @alice", "/quit"];
+        run_client(
+            &state,
+            4701,
+            futures::stream::iter(frames.map(|f| Ok(Bytes::from(f)))),
+        )
+        .await;
+        mentioned.send(()).unwrap();
+
+        let received = bob.await.unwrap();
+        let mention = "[alice mentioned you in #general] hi @bob!".to_string();
+        assert!(received.contains(&"alice: hi @bob!".into()));
+        assert_eq!(received.iter().filter(|line| **line == mention).count(), 2);
+        let mentions = received.iter().position(|line| line == "* 1 new mention");
+        assert_eq!(received.get(mentions.unwrap() + 1), Some(&mention));
+        assert!(received.contains(&"* No new mentions".into()));
+    }
+
+    #[tokio::test]
+    async fn room_modes_should_keep_users_out_and_quiet() {
+        let mut config = test_config();
+        config.operators = vec!["alice".to_string()];
+        let state = test_state("modes", config).await;
+        let run = |port, frames: Vec<&'static str>| {
+            let frames = frames.into_iter().map(|frame| Ok(Bytes::from(frame)));
+            run_client(&state, port, futures::stream::iter(frames))
+        };
+        let received = run(
+            4800,
+            vec![
+                "alice",
+                "password",
+                "/join #news",
+                "/topic what's new",
+                "/mode read-only on",
+                "/mode invite-only on",
+                "/mode",
+            ],
+        )
+        .await;
+        assert!(received.contains(&"[topic of #news: what's new]".into()));
+        assert!(received.contains(&"* #news: read-only on, invite-only on".into()));
+
+        let received = run(4801, vec!["bob", "password", "/join #news", "/topic hi"]).await;
+        assert!(received.contains(&"* #news is invite only".into()));
+        assert!(received.contains(&"* Only operators can set the topic".into()));
+
+        run(
+            4802,
+            vec!["alice", "password", "/join #news", "/invite bob"],
+        )
+        .await;
+        let received = run(4803, vec!["bob", "password", "/join #news", "hi all"]).await;
+        assert!(received.contains(&"* You joined #news".into()));
+        assert!(received.contains(&"[topic of #news: what's new]".into()));
+        assert!(received.contains(&"* #news is read only".into()));
+        assert!(state.history.recent("#news", 10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn admin_actions_should_be_audited() {
+        let mut config = test_config();
+        config.operators = vec!["alice".to_string()];
+        let state = test_state("audit", config).await;
+        let mut alice = DuplexClient::login(&state, 6300, "alice").await;
+        let mut bob = DuplexClient::login(&state, 6301, "bob").await;
+        alice.expect("[bob has joined #general]").await;
+
+        alice.send("/kick bob too many links").await;
+        bob.expect("* You have been kicked by alice: too many links")
+            .await;
+        alice.expect("* Kicked bob").await;
+        alice.send("/topic rules apply").await;
+        alice.expect("[topic of #general: rules apply]").await;
+        alice.send("/ban 10.0.0.1 spam bot").await;
+        alice
+            .expect("* 10.0.0.1 is banned for good, 0 users kicked")
+            .await;
+        alice.send("/unban 10.0.0.1").await;
+        alice.expect("* Unbanned 10.0.0.1").await;
+        // what failed isn't audited
+        alice.send("/kick nobody").await;
+        alice.expect("* No such user: nobody").await;
+        state
+            .admin(AdminCommand::Announce("back in 5".into()))
+            .await;
+        alice.expect("[server] back in 5").await;
+
+        let reply = state.admin(AdminCommand::Audit(AUDIT_ENTRIES)).await;
+        let lines: Vec<&str> = reply.lines().collect();
+        assert_eq!(lines[0], "5 audit entries");
+        let expected = [
+            " kick bob by alice: too many links",
+            " topic #general by alice [rules apply]",
+            " ban 10.0.0.1 by alice [banned for good]: spam bot",
+            " unban 10.0.0.1 by alice",
+            " announce by admin [back in 5]",
+        ];
+        for (line, expected) in lines[1..].iter().zip(expected) {
+            assert!(line.ends_with(expected), "{:?}", line);
+        }
+        let reply = state.admin(AdminCommand::Audit(1)).await;
+        assert_eq!(reply.lines().count(), 2);
+        assert!(reply.ends_with(" announce by admin [back in 5]"));
+
+        // nobody passes for the admin console in the log
+        let mut mallory = DuplexClient::connect(&state, 6302, 4096);
+        mallory.send("admin").await;
+        mallory
+            .expect("Username admin is reserved, enter another one:")
+            .await;
+        mallory.send("mallory").await;
+        mallory.send("password").await;
+        mallory.expect("* You joined #general").await;
+        mallory.send("/nick Adm1n").await;
+        mallory.expect("* Username Adm1n is reserved").await;
+    }
+
+    #[tokio::test]
+    async fn rooms_should_be_run_by_whoever_created_them() {
+        let mut config = test_config();
+        config.room_defaults = RoomInfo::defaults("new room", "").unwrap();
+        let state = test_state("created", config).await;
+        let mut alice = DuplexClient::login(&state, 6100, "alice").await;
+        let mut bob = DuplexClient::login(&state, 6101, "bob").await;
+        alice.expect("[bob has joined #general]").await;
+
+        alice.send("/join #new").await;
+        let received = alice.expect("[topic of #new: new room]").await;
+        assert_eq!(received[0], "* You created #new, you are its operator");
+        bob.send("/join #new").await;
+        let received = bob.expect("[topic of #new: new room]").await;
+        assert!(!received.iter().any(|line| line.contains("You created")));
+        bob.send("/topic mine now").await;
+        bob.expect("* Only operators can set the topic").await;
+        bob.send("/mode read-only on").await;
+        bob.expect("* Only operators of #new can change its modes")
+            .await;
+
+        alice.send("/op bob").await;
+        alice.expect("* bob is an operator of #new").await;
+        bob.expect("* alice made you an operator of #new").await;
+        bob.send("/mode read-only on").await;
+        bob.expect("* #new: read-only on").await;
+        bob.send("still heard").await;
+        alice.expect("bob: still heard").await;
+
+        // the default room is nobody's
+        bob.send("/join #general").await;
+        let received = bob.expect("* You joined #general").await;
+        assert!(!received.iter().any(|line| line.contains("You created")));
+        assert!(state.room_info(DEFAULT_ROOM).await.operators.is_empty());
+    }
+
+    #[tokio::test]
+    async fn room_operators_should_be_who_they_were_made_not_their_names() {
+        let state = test_state("operators-identity", test_config()).await;
+        let mut alice = DuplexClient::login(&state, 6110, "alice").await;
+        alice.send("/join #mine").await;
+        alice
+            .expect("* You created #mine, you are its operator")
+            .await;
+        // a new name keeps the room
+        alice.send("/nick ali").await;
+        alice.expect("* You are now known as ali").await;
+        alice.send("/topic still mine").await;
+        alice.expect("[topic of #mine: still mine]").await;
+
+        let mut carol = DuplexClient::guest(&state, 6111, "carol").await;
+        alice.send("/op carol").await;
+        alice.expect("* carol is an operator of #mine").await;
+        carol.expect("* ali made you an operator of #mine").await;
+        carol.send("/quit").await;
+        carol.expect("* Goodbye").await;
+
+        // the next guest calling itself carol is not made one by its name
+        let mut other = DuplexClient::guest(&state, 6112, "carol").await;
+        other.send("/join #mine").await;
+        other.expect("* You joined #mine").await;
+        other.send("/topic hijacked").await;
+        other.expect("* Only operators can set the topic").await;
+        alice.send("/op dave").await;
+        alice
+            .expect("* dave is neither online nor registered")
+            .await;
+    }
+
+    #[tokio::test]
+    async fn invites_should_be_for_who_was_invited_not_their_name() {
+        let mut config = test_config();
+        config.room_defaults = RoomInfo::defaults("", "invite-only").unwrap();
+        let state = test_state("invites-identity", config).await;
+        state.accounts.register("bob", "password").await.unwrap();
+        let mut alice = DuplexClient::login(&state, 6113, "alice").await;
+        alice.send("/join #club").await;
+        alice
+            .expect("* You created #club, you are its operator")
+            .await;
+
+        // an account is invited while offline, and keeps it under another name
+        alice.send("/invite bob").await;
+        alice.expect("* Invited bob to #club").await;
+        let mut bob = DuplexClient::login(&state, 6114, "bob").await;
+        bob.send("/nick robert").await;
+        bob.expect("* You are now known as robert").await;
+        bob.send("/join #club").await;
+        bob.expect("* You joined #club").await;
+
+        let mut carol = DuplexClient::guest(&state, 6115, "carol").await;
+        alice.send("/invite carol").await;
+        alice.expect("* Invited carol to #club").await;
+        carol.expect("* alice invited you to #club").await;
+        carol.send("/quit").await;
+        carol.expect("* Goodbye").await;
+        let mut other = DuplexClient::guest(&state, 6116, "carol").await;
+        other.send("/join #club").await;
+        other.expect("* #club is invite only").await;
+    }
+
+    #[tokio::test]
+    async fn clients_over_the_limit_should_be_turned_away() {
+        let mut config = test_config();
+        config.max_connections_per_ip = 1;
+        let state = test_state("limits", config).await;
+        let held = state.connections.open([127, 0, 0, 1].into()).unwrap();
+
+        let frames = [Ok(Bytes::from("alice"))];
+        let received = run_client(&state, 4400, futures::stream::iter(frames)).await;
+        assert_eq!(
+            received,
+            ["Too many connections from your address, close one and try again"]
+        );
+        assert!(state.names.is_empty());
+
+        // the refused connection was never counted
+        drop(held);
+        assert_eq!(state.connections.total(), 0);
+        let frames = ["alice", "password", "/quit"].map(|frame| Ok(Bytes::from(frame)));
+        let received = run_client(&state, 4401, futures::stream::iter(frames)).await;
+        assert!(received.contains(&"* You joined #general".into()));
+        assert_eq!(state.connections.total(), 0);
+    }
+
+    #[tokio::test]
+    async fn invalid_lines_should_be_refused_without_disconnecting() {
+        let state = test_state("lines", test_config()).await;
+        let frames = [
+            Bytes::from("alice"),
+            Bytes::from("password"),
+            Bytes::from("x".repeat(65)),
+            Bytes::from_static(b"\xff\xfe"),
+            Bytes::from("x".repeat(64)),
+            Bytes::from("\x1b[2Jhi\tall\x07"),
+        ]
+        .map(Ok);
+        let received = run_client(&state, 4500, futures::stream::iter(frames)).await;
+        assert!(received.contains(&"* Message too long, at most 64 bytes".into()));
+        assert!(received.contains(&"* Message is not valid UTF-8".into()));
+
+        // the longest line there may be, and one cleaned of control characters
+        let stored = state.history.recent(DEFAULT_ROOM, 10).await.unwrap();
+        let contents: Vec<&str> = stored.iter().map(|e| e.content.as_str()).collect();
+        assert_eq!(contents, ["x".repeat(64).as_str(), "[2Jhi all"]);
+    }
+
+    #[tokio::test]
+    async fn connection_logs_should_carry_the_connection() {
+        #[derive(Clone, Default)]
+        struct Logs(Arc<Mutex<Vec<u8>>>);
+        impl std::io::Write for Logs {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let logs = Logs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .with_ansi(false)
+            .finish();
+        let _default = tracing::subscriber::set_default(subscriber);
+        let state = test_state("spans", test_config()).await;
+        let span = state.connection_span(SocketAddr::from(([127, 0, 0, 1], 4600)));
+        let frames = ["alice", "password", "hi", "/quit"].map(|frame| Ok(Bytes::from(frame)));
+        run_client(&state, 4600, futures::stream::iter(frames))
+            .instrument(span)
+            .await;
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let connection = "connection{id=1 addr=127.0.0.1:4600 username=\"alice\"}";
+        assert!(logs
+            .lines()
+            .any(|line| line.contains(connection) && line.contains("joined room=\"#general\"")));
+        assert!(
+            logs.lines()
+                .any(|line| line.contains(connection)
+                    && line.contains("disconnected reason=\"quit\""))
+        );
+    }
+
+    #[tokio::test]
+    async fn bursty_clients_should_be_disconnected() {
+        let mut config = test_config();
+        config.rate_limit = RateLimit {
+            rate: 0.001,
+            burst: 3.0,
+            mute: Duration::ZERO,
+        };
+        let state = test_state("flood", config).await;
+
+        // a username and then a burst of messages, all at once
+        let mut frames = vec![Ok(Bytes::from("alice")), Ok(Bytes::from("password"))];
+        frames.extend((0..10).map(|i| Ok(Bytes::from(format!("spam {}", i)))));
+        let received = run_client(&state, 4000, futures::stream::iter(frames)).await;
+
+        assert!(
+            received.contains(&"* You are sending too fast, slow down or you will be muted".into())
+        );
+        assert!(received.contains(&"* You are muted for 0s".into()));
+        assert_eq!(
+            received.last().map(String::as_str),
+            Some("* You have been disconnected for flooding")
+        );
+        // only the burst got through, and the flooder is gone
+        let stored = state.history.recent(DEFAULT_ROOM, 10).await.unwrap();
+        assert_eq!(stored.len(), 3);
+        assert!(state.peers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn silent_clients_should_be_pinged_then_dropped() {
+        let mut config = test_config();
+        config.ping_interval = Duration::from_millis(50);
+        config.idle_timeout = Duration::from_millis(150);
+        let state = test_state("idle", config).await;
+
+        // a username and then nothing, as from a dead connection
+        let frames = futures::stream::iter(["bob", "password"].map(|frame| Ok(Bytes::from(frame))))
+            .chain(futures::stream::pending());
+        let received = run_client(&state, 4001, frames).await;
+
+        let ping = Message::new(Event::Ping).render(Render::default());
+        assert_eq!(received.iter().filter(|line| **line == ping).count(), 1);
+        assert_eq!(
+            received.last().map(String::as_str),
+            Some("* You have been disconnected for being idle")
+        );
+        assert!(state.names.is_empty());
+    }
+
+    #[tokio::test]
+    async fn clients_should_get_a_goodbye_at_shutdown() {
+        let state = test_state("shutdown", test_config()).await;
+        // the client is in its read loop once it waits for the frame after its username
+        let reading = Arc::new(tokio::sync::Notify::new());
+        let frames = futures::stream::iter(
+            ["carol", "password"].map(|frame| Ok(Bytes::from(frame))),
+        )
+        .chain(futures::stream::once({
+            let reading = Arc::clone(&reading);
+            async move {
+                reading.notify_one();
+                future::pending().await
+            }
+        }));
+        let client = tokio::spawn({
+            let state = Arc::clone(&state);
+            async move { run_client(&state, 4002, frames).await }
+        });
+        reading.notified().await;
+
+        state.shut_down().await;
+        let received = client.await.unwrap();
+        assert_eq!(
+            received.last().map(String::as_str),
+            Some("[server] Server is shutting down, goodbye")
+        );
+        assert!(state.peers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn slow_peers_should_be_disconnected_after_drops_in_a_row() {
+        let mut config = test_config();
+        config.max_drops = 3;
+        let state = test_state("drops", config).await;
+        let (sender, mut receiver) = mpsc::channel(1);
+        let peer = PeerHandle {
+            identity: "alice".to_string(),
+            sender,
+            disconnect: CancellationToken::new(),
+            drops: Arc::default(),
+            presence: Arc::new(Presence::new()),
+            mentions: Arc::default(),
+            ignores: Arc::default(),
+        };
+        let addr = SocketAddr::from(([127, 0, 0, 1], 4003));
+        let message = Arc::new(Message::notice("hi"));
+
+        // a drop is forgiven once a message gets through again
+        state.deliver(addr, &peer, message.clone());
+        state.deliver(addr, &peer, message.clone());
+        state.deliver(addr, &peer, message.clone());
+        receiver.recv().await.unwrap();
+        state.deliver(addr, &peer, message.clone());
+        assert!(!peer.disconnect.is_cancelled());
+
+        for _ in 0..3 {
+            state.deliver(addr, &peer, message.clone());
+        }
+        assert!(peer.disconnect.is_cancelled());
+        assert_eq!(peer.drops.total.load(Ordering::Relaxed), 5);
+        let metrics = &state.metrics;
+        assert_eq!(metrics.messages_dropped.get(), 5);
+        assert_eq!(metrics.queue_overflows.get(), 5);
+        assert_eq!(metrics.slow_disconnects.get(), 1);
+
+        // /debug/peers tells which peer it was, ahead of one never behind
+        let bob = SocketAddr::from(([127, 0, 0, 1], 4005));
+        state.names.insert("alice".to_string(), bob);
+        state.peers.insert(
+            bob,
+            PeerHandle {
+                drops: Arc::default(),
+                ..peer.clone()
+            },
+        );
+        state.names.insert("carol".to_string(), addr);
+        state.peers.insert(addr, peer);
+        let queues = metrics::peer_queues(&state);
+        let names: Vec<&str> = queues.iter().map(|q| q.username.as_str()).collect();
+        assert_eq!(names, ["carol", "alice"]);
+        let carol = &queues[0];
+        assert_eq!((carol.queued, carol.capacity), (1, 1));
+        assert_eq!((carol.dropped, carol.in_a_row, carol.overflows), (5, 3, 5));
+        assert!(carol.last_overflow_at.is_some());
+        assert_eq!(queues[1].overflows, 0);
+        assert!(queues[1].last_overflow_at.is_none());
+    }
+
+    #[tokio::test]
+    async fn lagging_readers_should_be_told_what_they_missed() {
+        let state = test_state("lag", test_config()).await;
+        let (queue, receiver) = mpsc::channel(MAX_MESSAGES);
+        let (sink, mut received) = futures::channel::mpsc::unbounded::<Bytes>();
+        let writer = Writer {
+            state: Arc::clone(&state),
+            addr: SocketAddr::from(([127, 0, 0, 1], 4004)),
+            protocol: Protocol::Text,
+            drops: Arc::default(),
+            ignores: Arc::default(),
+            unacked: None,
+            disconnect: CancellationToken::new(),
+        };
+        let drops = Arc::clone(&writer.drops);
+        let task = tokio::spawn(writer.run(receiver, sink.sink_map_err(anyhow::Error::from)));
+
+        // five messages into a room that holds two, before the writer reads any
+        let (room, room_receiver) = broadcast::channel(2);
+        let other = SocketAddr::from(([127, 0, 0, 1], 4005));
+        for i in 0..5 {
+            let message = Message::chat("bob", "#general", &i.to_string());
+            room.send((other, Arc::new(message))).unwrap();
+        }
+        queue
+            .send(Outgoing::Room(Some(room_receiver)))
+            .await
+            .unwrap();
+
+        let mut lines = Vec::new();
+        for _ in 0..3 {
+            let frame = received.next().await.unwrap();
+            lines.push(String::from_utf8(frame.to_vec()).unwrap());
+        }
+        assert_eq!(
+            lines,
+            [
+                "* You missed 3 messages, you are reading too slowly",
+                "bob: 3",
+                "bob: 4"
+            ]
+        );
+        assert_eq!(drops.total.load(Ordering::Relaxed), 3);
+
+        drop(queue);
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn bans_should_expire_and_be_removable() {
+        let state = test_state("bans", test_config()).await;
+        let bob = BanTarget::User("bob".to_string());
+        let ip = BanTarget::Ip("10.0.0.1".parse().unwrap());
+        assert_eq!(state.bans.banned(&bob).await, None);
+
+        state.bans.add(&bob, None, "op").await.unwrap();
+        assert_eq!(state.bans.banned(&bob).await, Some(Ban { until: None }));
+        assert_eq!(state.bans.banned(&ip).await, None);
+
+        let past = Utc::now() - chrono::Duration::minutes(1);
+        state.bans.add(&ip, Some(past), "op").await.unwrap();
+        assert_eq!(state.bans.banned(&ip).await, None);
+
+        assert!(state.bans.remove(&bob).await.unwrap());
+        assert!(!state.bans.remove(&bob).await.unwrap());
+        assert_eq!(state.bans.banned(&bob).await, None);
+    }
+
+    #[tokio::test]
+    async fn reloads_should_reach_running_sessions() {
+        let path = std::env::temp_dir().join(format!("chat-reload-{}.toml", std::process::id()));
+        std::fs::write(&path, "max_line = 64\n").unwrap();
+        let mut config = test_config();
+        config.config_file = path.to_str().unwrap().to_string();
+        let state = test_state("reload", config).await;
+        let mut alice = DuplexClient::login(&state, 5600, "alice").await;
+        let mut bob = DuplexClient::login(&state, 5601, "bob").await;
+        let mut carol = DuplexClient::login(&state, 5602, "carol").await;
+        alice.expect("[carol has joined #general]").await;
+        alice.send("darn it").await;
+        carol.expect("alice: darn it").await;
+
+        let file =
+            "max_line = 8\nfilters = [\"words\"]\nbanned_words = [\"darn\"]\nbans = [\"bob\"]\nmotd = \"Be nice\"\n";
+        std::fs::write(&path, file).unwrap();
+        assert_eq!(
+            state.admin(AdminCommand::Reload).await,
+            "Config reloaded, 1 users kicked"
+        );
+        bob.expect("* You have been banned for good").await;
+        carol.expect("[bob has left #general :(]").await;
+        alice.send("darn it").await;
+        carol.expect("alice: **** it").await;
+        alice.send("too long for it").await;
+        alice.expect("* Message too long, at most 8 bytes").await;
+        let bob = BanTarget::User("bob".to_string());
+        assert_eq!(state.banned(&bob).await, Some(Ban { until: None }));
+        // the motd is for those logging in from now on
+        let mut dave = DuplexClient::connect(&state, 5603, 4096);
+        dave.send("dave").await;
+        dave.send("password").await;
+        dave.expect("* Be nice").await;
+
+        // a broken file keeps what was loaded
+        std::fs::write(&path, "max_line = \"long\"\n").unwrap();
+        let reply = state.admin(AdminCommand::Reload).await;
+        assert!(reply.starts_with("Failed to reload config"), "{}", reply);
+        assert_eq!(state.config().max_line, 8);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn motd_and_server_info_should_be_shown() {
+        let mut config = test_config();
+        config.motd = "\nWelcome to the test server\nBe nice\n".to_string();
+        let state = test_state("motd", config).await;
+        let mut alice = DuplexClient::connect(&state, 5700, 4096);
+        alice.send("alice").await;
+        alice.send("password").await;
+        let received = alice.expect("* You joined #general").await;
+        let motd = received
+            .iter()
+            .position(|line| line == "* Welcome to the test server")
+            .unwrap();
+        assert_eq!(received[motd + 1], "* Be nice");
+
+        alice.send("/server").await;
+        let server = alice.expect_prefix("* chat ").await;
+        assert!(
+            server.contains(&format!("protocol {}, up ", PROTOCOL_VERSION)),
+            "{}",
+            server
+        );
+        assert!(server.ends_with(", 1 users in 1 rooms"), "{}", server);
+    }
+
+    #[tokio::test]
+    async fn only_configured_accounts_should_be_operators() {
+        // nobody without a list, however early they log in
+        let state = test_state("roles", test_config()).await;
+        assert_eq!(state.role(Some("alice")), Role::User);
+        assert_eq!(state.role(None), Role::User);
+
+        let mut config = test_config();
+        config.operators = vec!["bob".to_string()];
+        let state = test_state("roles-list", config).await;
+        assert_eq!(state.role(Some("alice")), Role::User);
+        assert_eq!(state.role(Some("bob")), Role::Operator);
+
+        // a guest taking the name of an operator is none
+        let frames = ["guest", "/nick bob", "/kick alice"].map(|frame| Ok(Bytes::from(frame)));
+        let received = run_client(&state, 4210, futures::stream::iter(frames)).await;
+        assert!(received.contains(&"* You are now known as bob".into()));
+        assert!(!received
+            .iter()
+            .any(|line| line.contains("You are an operator")));
+        assert!(!received.contains(&"* Kicked alice".into()));
+    }
+}
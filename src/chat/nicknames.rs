@@ -0,0 +1,259 @@
+// what a username may be: one word of at most `MAX_GRAPHEMES` characters as a reader counts them,
+// kept in nfc so the same name typed two ways is one name, and nothing invisible. a name that
+// only looks like another, `аlice` with a cyrillic а, is caught by comparing skeletons
+
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+use unicode_segmentation::UnicodeSegmentation;
+
+pub const MAX_GRAPHEMES: usize = 32;
+
+// capitals of other scripts which pass for latin ones. they are mapped before lowercasing: a
+// cyrillic В is a B, but its в is no b
+const CAPITALS: &[(char, char)] = &[
+    ('А', 'a'),
+    ('В', 'b'),
+    ('Е', 'e'),
+    ('К', 'k'),
+    ('М', 'm'),
+    ('Н', 'h'),
+    ('О', 'o'),
+    ('Р', 'p'),
+    ('С', 'c'),
+    ('Т', 't'),
+    ('Х', 'x'),
+    ('Α', 'a'),
+    ('Β', 'b'),
+    ('Ε', 'e'),
+    ('Ζ', 'z'),
+    ('Η', 'h'),
+    ('Ι', 'i'),
+    ('Κ', 'k'),
+    ('Μ', 'm'),
+    ('Ν', 'n'),
+    ('Ο', 'o'),
+    ('Ρ', 'p'),
+    ('Τ', 't'),
+    ('Υ', 'y'),
+    ('Χ', 'x'),
+];
+
+// and the same for what is left once lowercased, digits included. an l, a 1 and an I are
+// hard to tell apart in many fonts, they all become the i the I is lowercased to
+const LETTERS: &[(char, char)] = &[
+    ('0', 'o'),
+    ('1', 'i'),
+    ('l', 'i'),
+    ('|', 'i'),
+    ('а', 'a'),
+    ('е', 'e'),
+    ('о', 'o'),
+    ('р', 'p'),
+    ('с', 'c'),
+    ('у', 'y'),
+    ('х', 'x'),
+    ('і', 'i'),
+    ('ј', 'j'),
+    ('ѕ', 's'),
+    ('һ', 'h'),
+    ('ԁ', 'd'),
+    ('ɡ', 'g'),
+    ('α', 'a'),
+    ('ι', 'i'),
+    ('κ', 'k'),
+    ('ν', 'v'),
+    ('ο', 'o'),
+    ('ρ', 'p'),
+    ('υ', 'u'),
+    ('χ', 'x'),
+];
+
+// letters that together pass for another
+const PAIRS: &[(&str, &str)] = &[("rn", "m"), ("vv", "w")];
+
+// `name` as it is kept, or why it can't be a username
+pub fn normalize(name: &str) -> Result<String, String> {
+    let name: String = name.nfc().collect();
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err("A username is one word".to_string());
+    }
+    if name.chars().any(|c| c.is_control() || is_invisible(c)) {
+        return Err("A username can't have control or invisible characters".to_string());
+    }
+    if name.graphemes(true).count() > MAX_GRAPHEMES {
+        return Err(format!(
+            "A username is at most {} characters",
+            MAX_GRAPHEMES
+        ));
+    }
+    Ok(name)
+}
+
+// what is left of a name to tell it from others by: compatibility forms unfolded, accents
+// dropped, lookalikes of other scripts mapped to latin and all of it lowercased. two names
+// with the same skeleton are too alike to both be in use
+pub fn skeleton(name: &str) -> String {
+    let mut skeleton = String::with_capacity(name.len());
+    for c in name.nfkd().filter(|&c| !is_combining_mark(c)) {
+        match lookup(CAPITALS, c) {
+            Some(latin) => skeleton.push(latin),
+            None => skeleton.extend(c.to_lowercase().map(|c| lookup(LETTERS, c).unwrap_or(c))),
+        }
+    }
+    for (pair, latin) in PAIRS {
+        skeleton = skeleton.replace(pair, latin);
+    }
+    skeleton
+}
+
+// whether `name` could be taken for `other`, which isn't the same name
+pub fn confusable(name: &str, other: &str) -> bool {
+    name != other && skeleton(name) == skeleton(other)
+}
+
+fn lookup(table: &[(char, char)], c: char) -> Option<char> {
+    table
+        .iter()
+        .find_map(|&(from, to)| (from == c).then_some(to))
+}
+
+// format characters and fillers which take up no room, or turn the text around it
+fn is_invisible(c: char) -> bool {
+    matches!(
+        c,
+        '\u{00AD}'
+            | '\u{034F}'
+            | '\u{061C}'
+            | '\u{115F}'
+            | '\u{1160}'
+            | '\u{17B4}'
+            | '\u{17B5}'
+            | '\u{180B}'..='\u{180F}'
+            | '\u{200B}'..='\u{200F}'
+            | '\u{202A}'..='\u{202E}'
+            | '\u{2060}'..='\u{206F}'
+            | '\u{3164}'
+            | '\u{FE00}'..='\u{FE0F}'
+            | '\u{FEFF}'
+            | '\u{FFA0}'
+            | '\u{FFF0}'..='\u{FFF8}'
+            | '\u{1D173}'..='\u{1D17A}'
+            | '\u{E0000}'..='\u{E0FFF}'
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_should_be_kept_in_nfc() {
+        // e and a combining acute, or the precomposed é
+        let decomposed = "jose\u{301}";
+        assert_eq!(normalize(decomposed), Ok("jos\u{e9}".to_string()));
+        assert_eq!(normalize("jos\u{e9}"), normalize(decomposed));
+        assert_eq!(normalize("alice"), Ok("alice".to_string()));
+        // other scripts are welcome
+        assert_eq!(normalize("Ἀθηνᾶ"), Ok("Ἀθηνᾶ".to_string()));
+        assert_eq!(normalize("李小龙"), Ok("李小龙".to_string()));
+    }
+
+    #[test]
+    fn names_should_be_one_word() {
+        let one_word = Err("A username is one word".to_string());
+        assert_eq!(normalize(""), one_word);
+        assert_eq!(normalize("alice smith"), one_word);
+        assert_eq!(normalize("alice\tsmith"), one_word);
+        // spaces of other widths too
+        assert_eq!(normalize("alice\u{3000}smith"), one_word);
+        assert_eq!(normalize("alice\u{a0}"), one_word);
+    }
+
+    #[test]
+    fn invisible_characters_should_be_refused() {
+        let invisible = Err("A username can't have control or invisible characters".to_string());
+        for name in [
+            "al\u{200B}ice",
+            "alice\u{200D}",
+            "\u{FEFF}alice",
+            "ali\u{AD}ce",
+            "alice\u{202E}",
+            "al\u{2066}ice",
+            "alice\u{FE0F}",
+            "\u{3164}",
+            "alice\u{E0041}",
+            "alice\u{7}",
+            "\u{1b}[31malice",
+        ] {
+            assert_eq!(normalize(name), invisible, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn length_should_be_counted_in_graphemes() {
+        let long = Err(format!(
+            "A username is at most {} characters",
+            MAX_GRAPHEMES
+        ));
+        assert!(normalize(&"a".repeat(MAX_GRAPHEMES)).is_ok());
+        assert_eq!(normalize(&"a".repeat(MAX_GRAPHEMES + 1)), long);
+        // 2 bytes each, and two characters each but one grapheme
+        assert!(normalize(&"é".repeat(MAX_GRAPHEMES)).is_ok());
+        assert!(normalize(&"y\u{308}".repeat(MAX_GRAPHEMES)).is_ok());
+        // a flag is two characters too
+        assert!(normalize(&"🇫🇷".repeat(MAX_GRAPHEMES)).is_ok());
+        assert_eq!(normalize(&"🇫🇷".repeat(MAX_GRAPHEMES + 1)), long);
+    }
+
+    #[test]
+    fn lookalikes_should_share_a_skeleton() {
+        assert_eq!(skeleton("alice"), "aiice");
+        for lookalike in [
+            // cyrillic а, е and о
+            "\u{430}lice",
+            "alic\u{435}",
+            // greek ο and cyrillic ο in bob
+            "b\u{3bf}b",
+            // case
+            "Alice",
+            "ALICE",
+            // accents
+            "\u{e0}lice",
+            "a\u{301}lice",
+            // fullwidth
+            "ａｌｉｃｅ",
+            // a capital I for the l, and a 1
+            "aIice",
+            "a1ice",
+        ] {
+            let name = if lookalike.contains('b') {
+                "bob"
+            } else {
+                "alice"
+            };
+            assert!(
+                confusable(lookalike, name),
+                "{:?} for {:?}",
+                lookalike,
+                name
+            );
+        }
+        // capitals of other scripts
+        assert!(confusable("\u{412}\u{41e}\u{412}", "bob"));
+        assert!(confusable("\u{39a}\u{399}\u{39c}", "klm"));
+        // letters passing for one
+        assert!(confusable("rnallory", "mallory"));
+        assert!(confusable("vvalter", "walter"));
+        assert!(confusable("b0b", "bob"));
+    }
+
+    #[test]
+    fn different_names_should_not_be_confusable() {
+        assert!(!confusable("alice", "alice"));
+        assert!(!confusable("alice", "alicia"));
+        assert!(!confusable("bob", "rob"));
+        assert!(!confusable("李小龙", "李小虎"));
+        // a lowercase cyrillic в is no b
+        assert!(!confusable("\u{432}ob", "bob"));
+        assert!(!confusable("dave", "dove"));
+    }
+}
@@ -0,0 +1,314 @@
+// tcp clients that would rather not set up tls and its certificates talk noise instead, on a
+// listener of their own: `Noise_NX_25519_ChaChaPoly_BLAKE2s`, the server sends its static key in
+// the handshake for the client to pin, it is logged at startup. every noise message goes with
+// its length in 2 bytes before it. past the handshake what is decrypted is a plain stream again,
+// framed like any tcp client's
+
+use std::fs::{self, OpenOptions};
+use std::future::Future;
+use std::io::{ErrorKind, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use snow::{Builder, HandshakeState, Keypair, TransportState};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::TcpListener;
+use tokio::time::timeout;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tracing::{info, warn, Instrument};
+
+use crate::chat::{handle_client, Codec, Connection, State};
+
+pub const PATTERN: &str = "Noise_NX_25519_ChaChaPoly_BLAKE2s";
+// the most a noise message may be, its tag included
+const MAX_MESSAGE: usize = 65535;
+const TAG: usize = 16;
+// plaintext between the session and the relay, like a socket's buffer
+const BUFFER: usize = 64 * 1024;
+
+// past the handshake, nothing read or written yet
+pub struct NoiseStream<S> {
+    framed: Framed<S, LengthDelimitedCodec>,
+    transport: TransportState,
+}
+
+impl<S> NoiseStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // the server's static key, for the client to check against the one it pinned
+    #[cfg(test)]
+    pub fn remote_static(&self) -> Option<&[u8]> {
+        self.transport.get_remote_static()
+    }
+
+    // the plain end to frame lines on, and the relay encrypting what is written to it and
+    // decrypting what the other side sent. run both until either closes
+    pub fn into_plain(self) -> (DuplexStream, impl Future<Output = Result<()>>) {
+        let (plain, relayed) = io::duplex(BUFFER);
+        (plain, relay(self.framed, self.transport, relayed))
+    }
+}
+
+// the server's static key from `path`, made and saved there the first time. without a path it is
+// one of this run, which no client can pin
+pub fn load_key(path: &str) -> Result<Keypair> {
+    let builder = Builder::new(PATTERN.parse()?);
+    if path.is_empty() {
+        return Ok(builder.generate_keypair()?);
+    }
+    match fs::read_to_string(path) {
+        Ok(content) => parse_key(&content).with_context(|| format!("invalid noise key {}", path)),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let key = builder.generate_keypair()?;
+            write_key(path, &key).with_context(|| format!("failed to write noise key {}", path))?;
+            info!(path, "made a new noise key");
+            Ok(key)
+        }
+        Err(e) => Err(anyhow::Error::from(e).context(format!("failed to read noise key {}", path))),
+    }
+}
+
+// the private key, then the public one, in hex on a line each
+fn parse_key(content: &str) -> Result<Keypair> {
+    let mut lines = content.lines();
+    let mut next = || -> Result<Vec<u8>> {
+        let key = hex::decode(lines.next().unwrap_or_default().trim())?;
+        if key.len() != 32 {
+            bail!("expected a key of 32 bytes, not {}", key.len());
+        }
+        Ok(key)
+    };
+    Ok(Keypair {
+        private: next()?,
+        public: next()?,
+    })
+}
+
+fn write_key(path: &str, key: &Keypair) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    // readable by the server only
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    writeln!(file, "{}", hex::encode(&key.private))?;
+    writeln!(file, "{}", hex::encode(&key.public))?;
+    Ok(())
+}
+
+// the server's end of the handshake
+pub async fn accept<S>(stream: S, key: &Keypair) -> Result<NoiseStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let state = Builder::new(PATTERN.parse()?)
+        .local_private_key(&key.private)
+        .build_responder()?;
+    handshake(stream, state).await
+}
+
+// the client's end, it has no static key of its own. the server only needs it in tests
+#[cfg(test)]
+pub async fn connect<S>(stream: S) -> Result<NoiseStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let state = Builder::new(PATTERN.parse()?).build_initiator()?;
+    handshake(stream, state).await
+}
+
+async fn handshake<S>(stream: S, mut state: HandshakeState) -> Result<NoiseStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let codec = LengthDelimitedCodec::builder()
+        .length_field_length(2)
+        .max_frame_length(MAX_MESSAGE)
+        .new_codec();
+    let mut framed = Framed::new(stream, codec);
+    let mut buf = vec![0; MAX_MESSAGE];
+    while !state.is_handshake_finished() {
+        if state.is_my_turn() {
+            let len = state.write_message(&[], &mut buf)?;
+            framed.send(Bytes::copy_from_slice(&buf[..len])).await?;
+        } else {
+            let message = framed
+                .next()
+                .await
+                .ok_or_else(|| anyhow!("closed during the handshake"))??;
+            state.read_message(&message, &mut buf)?;
+        }
+    }
+    Ok(NoiseStream {
+        framed,
+        transport: state.into_transport_mode()?,
+    })
+}
+
+// both ways at once, a peer not reading never holds up what it sends
+async fn relay<S>(
+    framed: Framed<S, LengthDelimitedCodec>,
+    transport: TransportState,
+    plain: DuplexStream,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let transport = Mutex::new(transport);
+    let (mut sink, mut stream) = framed.split();
+    let (mut reader, mut writer) = io::split(plain);
+    let inbound = async {
+        let mut buf = vec![0; MAX_MESSAGE];
+        while let Some(message) = stream.next().await {
+            let len = transport
+                .lock()
+                .unwrap()
+                .read_message(&message?, &mut buf)?;
+            writer.write_all(&buf[..len]).await?;
+        }
+        Ok(())
+    };
+    let outbound = async {
+        let mut chunk = vec![0; MAX_MESSAGE - TAG];
+        let mut buf = vec![0; MAX_MESSAGE];
+        loop {
+            let read = reader.read(&mut chunk).await?;
+            if read == 0 {
+                return Ok(());
+            }
+            let len = transport
+                .lock()
+                .unwrap()
+                .write_message(&chunk[..read], &mut buf)?;
+            sink.send(Bytes::copy_from_slice(&buf[..len])).await?;
+        }
+    };
+    tokio::select! {
+        relayed = inbound => relayed,
+        relayed = outbound => relayed,
+    }
+}
+
+// like the tcp listener, with a noise handshake first
+pub async fn serve(
+    state: Arc<State>,
+    listener: TcpListener,
+    key: Keypair,
+    codec: Codec,
+) -> Result<()> {
+    let key = Arc::new(key);
+    loop {
+        let (client, addr) = tokio::select! {
+            _ = state.shutdown.cancelled() => return Ok(()),
+            accepted = listener.accept() => accepted?,
+        };
+        let span = state.connection_span(addr);
+        let connection = span.in_scope(|| {
+            info!("accepted noise connection");
+            let connection = state.connections.open(addr.ip());
+            if let Err(e) = &connection {
+                warn!(reason = %e, "refusing connection");
+            }
+            connection
+        });
+        let cloned_state = Arc::clone(&state);
+        let key = Arc::clone(&key);
+        state.tasks.spawn(
+            async move {
+                if let Err(e) =
+                    handle_noise(cloned_state, addr, connection, client, &key, codec).await
+                {
+                    warn!(error = ?e, "failed to handle noise client");
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+pub async fn handle_noise<S>(
+    state: Arc<State>,
+    addr: SocketAddr,
+    connection: Result<Connection, String>,
+    stream: S,
+    key: &Keypair,
+    codec: Codec,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // the handshake is bounded like any other wait for the client
+    let handshake = state.config().idle_timeout;
+    let stream = match timeout(handshake, accept(stream, key)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => return Err(e.context("noise handshake failed")),
+        Err(_) => bail!("noise handshake timed out"),
+    };
+    let (plain, relay) = stream.into_plain();
+    let (handled, relayed) =
+        tokio::join!(handle_client(state, addr, connection, plain, codec), relay);
+    handled?;
+    relayed.context("noise relay failed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio_util::codec::LinesCodec;
+
+    #[tokio::test]
+    async fn lines_should_cross_encrypted() {
+        let key = load_key("").unwrap();
+        let (client, server) = io::duplex(4096);
+        let (accepted, connected) = tokio::join!(accept(server, &key), connect(client));
+        let (accepted, connected) = (accepted.unwrap(), connected.unwrap());
+        // pinned by the client
+        assert_eq!(connected.remote_static(), Some(key.public.as_slice()));
+        assert_eq!(accepted.remote_static(), None);
+
+        let (server, server_relay) = accepted.into_plain();
+        let (client, client_relay) = connected.into_plain();
+        tokio::spawn(server_relay);
+        tokio::spawn(client_relay);
+        let mut server = Framed::new(server, LinesCodec::new());
+        let mut client = Framed::new(client, LinesCodec::new());
+        client.send("alice").await.unwrap();
+        assert_eq!(server.next().await.unwrap().unwrap(), "alice");
+        // more than fits in one noise message
+        let long = "x".repeat(3 * MAX_MESSAGE);
+        server.send(&long).await.unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap(), long);
+
+        // closing one end closes the other
+        drop(server);
+        assert!(client.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn a_client_not_speaking_noise_should_fail_the_handshake() {
+        let key = load_key("").unwrap();
+        let (client, server) = io::duplex(4096);
+        let mut client = Framed::new(client, LinesCodec::new());
+        client.send("alice").await.unwrap();
+        drop(client);
+        assert!(accept(server, &key).await.is_err());
+    }
+
+    #[test]
+    fn keys_should_be_kept_across_runs() {
+        let path = std::env::temp_dir().join(format!("chat-noise-{}.key", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+        let made = load_key(path).unwrap();
+        let loaded = load_key(path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert!(made == loaded);
+        assert!(load_key("").unwrap() != made);
+        assert!(parse_key("abcd\n").is_err());
+    }
+}
@@ -0,0 +1,604 @@
+// what goes over the wire: messages as text lines, json objects or bincode frames, and the
+// requests clients send back
+
+use anyhow::Result;
+use bincode::{Decode, Encode};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use ulid::Ulid;
+
+use crate::chat::lines::InvalidLine;
+use crate::chat::{colors, irc, lines, HistoryEntry, MAX_PASTE_LINES, PASTE_PREFIX};
+
+// one json object per line in the json protocol, e.g.
+// `{"type":"notice","content":"...","timestamp":"..."}`, and one bincode frame in the binary one
+#[derive(Debug, PartialEq, Serialize, Deserialize, Encode, Decode)]
+pub(super) struct Message {
+    #[serde(flatten)]
+    pub(super) event: Event,
+    // when the server made it
+    #[bincode(with_serde)]
+    pub(super) timestamp: DateTime<Utc>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Encode, Decode)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(super) enum Event {
+    UserJoined {
+        username: String,
+        room: String,
+    },
+    UserLeft {
+        username: String,
+        room: String,
+    },
+    // `id` is a ULID, sortable by when it was said
+    Chat {
+        id: String,
+        sender: String,
+        room: String,
+        content: String,
+    },
+    // a chat message with new content, in the history too
+    Edited {
+        id: String,
+        sender: String,
+        room: String,
+        content: String,
+    },
+    // a chat message taken back, gone from the history
+    Deleted {
+        id: String,
+        room: String,
+    },
+    Renamed {
+        old: String,
+        new: String,
+    },
+    History(HistoryEntry),
+    // only for the user it is sent to, e.g. replies to commands
+    Notice {
+        content: String,
+    },
+    // answers a hello with the version both sides speak from now on
+    Hello {
+        version: u32,
+    },
+    // to a quiet client, which answers with a pong or any other traffic
+    Ping,
+    // from the admin console, to everyone in every room
+    ServerAnnouncement {
+        content: String,
+    },
+    // `/me waves`, not kept in the history
+    Action {
+        sender: String,
+        room: String,
+        content: String,
+    },
+    // a link to a file uploaded after /send, not kept in the history
+    File {
+        sender: String,
+        room: String,
+        filename: String,
+        size: u64,
+        url: String,
+    },
+    // told to joiners, and to the room when it changes
+    Topic {
+        room: String,
+        topic: String,
+    },
+    // ephemeral, to the room's members of the moment only, see `State::notify`
+    Typing {
+        username: String,
+        room: String,
+        active: bool,
+    },
+    // a chat message naming its receiver with `@name`, besides the message itself
+    Mention {
+        sender: String,
+        room: String,
+        content: String,
+    },
+}
+
+// how a text client wants its messages, changed with /set
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(super) struct Render {
+    pub(super) timestamps: bool,
+    // of chat messages, to /edit and /delete them by
+    pub(super) ids: bool,
+    // senders in their color, see `colors`
+    pub(super) colors: bool,
+}
+
+// what a json client sends, a hello first and then lines as a text client would type them
+#[derive(Debug, PartialEq, Deserialize, Encode, Decode)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Request {
+    Hello { version: u32 },
+    Line { line: String },
+    Pong,
+    // the client's user started or stopped typing
+    Typing { active: bool },
+    // lines sent as one message, instead of a /paste ... /end
+    Paste { text: String },
+    // the messages numbered up to `seq` arrived, see `Unacked`
+    Ack { seq: u64 },
+}
+
+// how a client talks to us, json is picked by the first line of a text client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Protocol {
+    Text,
+    // the negotiated version only tells whether the client acks, which its peer keeps
+    Json,
+    // bincode requests and messages in length delimited frames
+    Binary,
+    // from the irc listener, see `irc`
+    Irc,
+}
+
+// `{"seq":1,"type":"chat",...}`, to a json client that acks
+pub(super) fn encode_numbered(seq: u64, message: &Message) -> Bytes {
+    match serde_json::to_vec(&JsonMessage::new(message, Some(seq))) {
+        Ok(encoded) => Bytes::from(encoded),
+        Err(e) => {
+            warn!(?message, error = ?e, "failed to encode message");
+            Bytes::new()
+        }
+    }
+}
+
+// a message as json clients get it, with hints on how to show it
+#[derive(Debug, Serialize)]
+struct JsonMessage<'a> {
+    // for a client that acks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
+    #[serde(flatten)]
+    message: &'a Message,
+    // of the sender, see `colors`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color: Option<&'static str>,
+}
+
+impl<'a> JsonMessage<'a> {
+    fn new(message: &'a Message, seq: Option<u64>) -> Self {
+        Self {
+            seq,
+            message,
+            color: message.sender().map(|sender| colors::of(sender).hex),
+        }
+    }
+}
+
+impl Message {
+    pub(super) fn new(event: Event) -> Self {
+        Self {
+            event,
+            timestamp: Utc::now(),
+        }
+    }
+
+    pub(super) fn user_joined(username: &str, room: &str) -> Self {
+        Self::new(Event::UserJoined {
+            username: username.to_string(),
+            room: room.to_string(),
+        })
+    }
+
+    pub(super) fn user_left(username: &str, room: &str) -> Self {
+        Self::new(Event::UserLeft {
+            username: username.to_string(),
+            room: room.to_string(),
+        })
+    }
+
+    // with a new id
+    pub(super) fn chat(sender: &str, room: &str, content: &str) -> Self {
+        Self::new(Event::Chat {
+            id: Ulid::new().to_string(),
+            sender: sender.to_string(),
+            room: room.to_string(),
+            content: content.to_string(),
+        })
+    }
+
+    pub(super) fn topic(room: &str, topic: &str) -> Self {
+        Self::new(Event::Topic {
+            room: room.to_string(),
+            topic: topic.to_string(),
+        })
+    }
+
+    // the user it is from, None for what the server says
+    pub(super) fn sender(&self) -> Option<&str> {
+        match &self.event {
+            Event::Chat { sender, .. }
+            | Event::Edited { sender, .. }
+            | Event::Action { sender, .. }
+            | Event::File { sender, .. }
+            | Event::Mention { sender, .. } => Some(sender),
+            Event::Typing { username, .. } => Some(username),
+            Event::History(entry) => Some(&entry.sender),
+            _ => None,
+        }
+    }
+
+    // whether a client that acks gets it numbered, what is only of the moment isn't resent
+    pub(super) fn acked(&self) -> bool {
+        !matches!(self.event, Event::Ping | Event::Typing { .. })
+    }
+
+    pub(super) fn notice(content: impl Into<String>) -> Self {
+        Self::new(Event::Notice {
+            content: content.into(),
+        })
+    }
+
+    // the line a text client sees
+    pub(super) fn render(&self, render: Render) -> String {
+        let name = |name: &str| match render.colors {
+            true => colors::paint(name),
+            false => name.to_string(),
+        };
+        let text = match &self.event {
+            Event::UserJoined { username, room } => format!("[{} has joined {}]", username, room),
+            Event::UserLeft { username, room } => format!("[{} has left {} :(]", username, room),
+            Event::Chat {
+                id,
+                sender,
+                content,
+                ..
+            } if render.ids => format!("{} {}: {}", id, name(sender), content),
+            Event::Chat {
+                sender, content, ..
+            } => format!("{}: {}", name(sender), content),
+            Event::Edited {
+                id,
+                sender,
+                content,
+                ..
+            } if render.ids => format!("{} {} edited: {}", id, name(sender), content),
+            Event::Edited {
+                sender, content, ..
+            } => format!("{} edited: {}", name(sender), content),
+            Event::Deleted { id, .. } if render.ids => format!("[{} was deleted]", id),
+            Event::Deleted { room, .. } => format!("[a message of {} was deleted]", room),
+            Event::Renamed { old, new } => format!("[{} is now known as {}]", old, new),
+            // always dated, it may be from long ago
+            Event::History(entry) => {
+                let mut text = format!(
+                    "{} {}: {}",
+                    entry.created_at.format("%Y-%m-%d %H:%M:%S"),
+                    name(&entry.sender),
+                    entry.content
+                );
+                if entry.edited {
+                    text.push_str(" (edited)");
+                }
+                if render.ids && !entry.id.is_empty() {
+                    text = format!("{} {}", entry.id, text);
+                }
+                return text;
+            }
+            Event::Notice { content } => format!("* {}", content),
+            Event::Hello { version } => format!("* protocol version {}", version),
+            Event::Ping => "PING, reply /pong to stay connected".to_string(),
+            Event::ServerAnnouncement { content } => format!("[server] {}", content),
+            Event::Action {
+                sender, content, ..
+            } => format!("* {} {}", name(sender), content),
+            Event::File {
+                sender,
+                filename,
+                size,
+                url,
+                ..
+            } => format!(
+                "[{} shared {}, {} bytes: {}]",
+                name(sender),
+                filename,
+                size,
+                url
+            ),
+            Event::Topic { room, topic } if topic.is_empty() => format!("[{} has no topic]", room),
+            Event::Topic { room, topic } => format!("[topic of {}: {}]", room, topic),
+            Event::Mention {
+                sender,
+                room,
+                content,
+            } => format!("[{} mentioned you in {}] {}", name(sender), room, content),
+            Event::Typing {
+                username, active, ..
+            } if *active => format!("[{} is typing]", name(username)),
+            Event::Typing { username, .. } => format!("[{} stopped typing]", name(username)),
+        };
+        if render.timestamps {
+            format!("[{}] {}", self.timestamp.format("%H:%M:%S"), text)
+        } else {
+            text
+        }
+    }
+}
+
+impl Protocol {
+    // `render` is for text clients, the others get the timestamp anyway
+    pub(super) fn encode(&self, message: &Message, render: Render) -> Bytes {
+        let encoded = match self {
+            Self::Text => Ok(message.render(render).into_bytes()),
+            Self::Irc => {
+                let lines: Vec<String> =
+                    irc::encode(message).iter().map(|m| m.to_string()).collect();
+                Ok(lines.join("\r\n").into_bytes())
+            }
+            Self::Json => {
+                serde_json::to_vec(&JsonMessage::new(message, None)).map_err(anyhow::Error::from)
+            }
+            Self::Binary => bincode::encode_to_vec(message, bincode::config::standard())
+                .map_err(anyhow::Error::from),
+        };
+        match encoded {
+            Ok(encoded) => Bytes::from(encoded),
+            Err(e) => {
+                warn!(?message, error = ?e, "failed to encode message");
+                Bytes::new()
+            }
+        }
+    }
+
+    // the line as a text client would have typed it
+    fn decode(&self, frame: &[u8]) -> Result<String, String> {
+        let request = match self {
+            Self::Text | Self::Irc => {
+                return String::from_utf8(frame.to_vec())
+                    .map_err(|_| InvalidLine::NotUtf8.to_string());
+            }
+            Self::Json => serde_json::from_slice(frame).map_err(|e| e.to_string()),
+            Self::Binary => bincode::decode_from_slice(frame, bincode::config::standard())
+                .map(|(request, _)| request)
+                .map_err(|e| e.to_string()),
+        };
+        match request {
+            Ok(Request::Line { line }) => Ok(line),
+            Ok(Request::Pong) => Ok("/pong".to_string()),
+            Ok(Request::Ack { seq }) => Ok(format!("/ack {}", seq)),
+            Ok(Request::Typing { active }) => {
+                Ok(format!("/typing {}", if active { "on" } else { "off" }))
+            }
+            Ok(Request::Paste { text }) => Ok(format!("{}{}", PASTE_PREFIX, text)),
+            Ok(Request::Hello { .. }) => Err("Protocol already negotiated".to_string()),
+            Err(e) => Err(format!("Invalid request: {}", e)),
+        }
+    }
+
+    // what the client typed in a frame, within `max_length` and without control characters. a
+    // paste keeps its lines
+    pub(super) fn line(&self, frame: &[u8], max_length: usize) -> Result<String, String> {
+        let line = self.decode(frame)?;
+        let cleaned = match line.strip_prefix(PASTE_PREFIX) {
+            Some(text) => lines::clean_block(text, max_length, MAX_PASTE_LINES)
+                .map(|text| format!("{}{}", PASTE_PREFIX, text)),
+            None => lines::clean(&line, max_length),
+        };
+        cleaned.map_err(|e| e.to_string())
+    }
+
+    // the protocol and version asked for, if the first frame of a client is a hello
+    pub(super) fn hello(&self, frame: &[u8]) -> Option<(Protocol, u32)> {
+        match self {
+            Self::Text => match serde_json::from_slice(frame) {
+                Ok(Request::Hello { version }) => Some((Self::Json, version)),
+                _ => None,
+            },
+            Self::Json | Self::Irc => None,
+            Self::Binary => match bincode::decode_from_slice(frame, bincode::config::standard()) {
+                Ok((Request::Hello { version }, _)) => Some((Self::Binary, version)),
+                _ => None,
+            },
+        }
+    }
+
+    // line clients can't take back an indicator once it is printed, they go without
+    pub(super) fn wants(&self, message: &Message) -> bool {
+        match self {
+            Self::Text | Self::Irc => !matches!(message.event, Event::Typing { .. }),
+            Self::Json | Self::Binary => true,
+        }
+    }
+
+    // text clients get prompts as they are, the others as a notice
+    pub(super) fn prompt(&self, text: &str) -> Bytes {
+        match self {
+            Self::Text => Bytes::from(text.to_string()),
+            Self::Json | Self::Binary | Self::Irc => {
+                self.encode(&Message::notice(text), Render::default())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
+
+    use super::*;
+
+    // a message through the binary protocol and the framing, as a client reads it
+    fn round_trip(message: &Message) -> Message {
+        let mut codec = LengthDelimitedCodec::new();
+        let mut buf = BytesMut::new();
+        codec
+            .encode(
+                Protocol::Binary.encode(message, Render::default()),
+                &mut buf,
+            )
+            .unwrap();
+        let frame = codec.decode(&mut buf).unwrap().unwrap();
+        assert!(buf.is_empty());
+        let (decoded, _) = bincode::decode_from_slice(&frame, bincode::config::standard()).unwrap();
+        decoded
+    }
+
+    #[test]
+    fn binary_messages_should_survive_newlines() {
+        let message = Message::chat("alice", "#general", "two\nlines\n");
+        assert_eq!(round_trip(&message), message);
+
+        let message = Message::new(Event::History(HistoryEntry {
+            id: Ulid::new().to_string(),
+            room: "#general".to_string(),
+            sender: "bob".to_string(),
+            content: "hi\r\n".to_string(),
+            created_at: Utc::now(),
+            edited: true,
+        }));
+        assert_eq!(round_trip(&message), message);
+        let message = Message::notice("");
+        assert_eq!(round_trip(&message), message);
+    }
+
+    #[test]
+    fn binary_requests_should_decode_to_lines() {
+        let encode = |request: &Request| {
+            bincode::encode_to_vec(request, bincode::config::standard()).unwrap()
+        };
+        let line = Request::Line {
+            line: "/join #rust\n".to_string(),
+        };
+        assert_eq!(
+            Protocol::Binary.decode(&encode(&line)),
+            Ok("/join #rust\n".to_string())
+        );
+
+        let hello = encode(&Request::Hello { version: 7 });
+        assert_eq!(Protocol::Binary.hello(&hello), Some((Protocol::Binary, 7)));
+        assert!(Protocol::Binary.decode(&hello).is_err());
+        assert!(Protocol::Binary.decode(b"\xff\xff").is_err());
+    }
+
+    #[test]
+    fn pasted_requests_should_keep_their_lines() {
+        let paste = br#"{"type":"paste","text":"fn main() {\n\tok();\r\n}\n"}"#;
+        assert_eq!(
+            Protocol::Json.line(paste, 16),
+            Ok("/paste\nfn main() {\n ok();\n}".to_string())
+        );
+        let long = br#"{"type":"paste","text":"a\nb\nc"}"#;
+        assert_eq!(
+            Protocol::Json.line(long, 1),
+            Ok("/paste\na\nb\nc".to_string())
+        );
+        assert!(Protocol::Json
+            .line(br#"{"type":"paste","text":"abc"}"#, 2)
+            .is_err());
+        // a text client types /paste on a line of its own
+        assert_eq!(
+            Protocol::Text.line(b"/paste\x07", 16),
+            Ok("/paste".to_string())
+        );
+    }
+
+    #[test]
+    fn text_clients_should_switch_to_json_on_hello() {
+        let hello = br#"{"type":"hello","version":1}"#;
+        assert_eq!(Protocol::Text.hello(hello), Some((Protocol::Json, 1)));
+        assert_eq!(Protocol::Text.hello(b"alice"), None);
+        assert_eq!(Protocol::Text.decode(b"alice"), Ok("alice".to_string()));
+
+        let mut notice = Message::notice("hi");
+        notice.timestamp = "2024-05-01T12:00:00Z".parse().unwrap();
+        let encoded = Protocol::Json.encode(&notice, Render::default());
+        assert_eq!(
+            &encoded[..],
+            br#"{"type":"notice","content":"hi","timestamp":"2024-05-01T12:00:00Z"}"#
+        );
+    }
+
+    #[test]
+    fn text_clients_should_see_timestamps_once_set() {
+        let timestamps = Render {
+            timestamps: true,
+            ..Default::default()
+        };
+        let mut message = Message::chat("alice", "#general", "hi");
+        message.timestamp = "2024-05-01T12:00:00Z".parse().unwrap();
+        assert_eq!(message.render(Render::default()), "alice: hi");
+        assert_eq!(message.render(timestamps), "[12:00:00] alice: hi");
+
+        // a replayed message keeps the date it was said on
+        let message = Message::new(Event::History(HistoryEntry {
+            id: "01HWA1".to_string(),
+            room: "#general".to_string(),
+            sender: "bob".to_string(),
+            content: "old news".to_string(),
+            created_at: "2024-04-01T08:30:00Z".parse().unwrap(),
+            edited: false,
+        }));
+        assert_eq!(
+            message.render(timestamps),
+            "2024-04-01 08:30:00 bob: old news"
+        );
+        let ids = Render {
+            ids: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            message.render(ids),
+            "01HWA1 2024-04-01 08:30:00 bob: old news"
+        );
+    }
+
+    #[test]
+    fn senders_should_be_shown_in_their_color() {
+        let colors = Render {
+            colors: true,
+            ..Default::default()
+        };
+        let message = Message::chat("alice", "#general", "hi");
+        assert_eq!(
+            message.render(colors),
+            format!("{}: hi", colors::paint("alice"))
+        );
+        assert_eq!(message.render(Render::default()), "alice: hi");
+        // what isn't from a user stays as it is
+        let joined = Message::user_joined("alice", "#general");
+        assert_eq!(joined.render(colors), "[alice has joined #general]");
+
+        // json clients get the color to draw it themselves, with or without /set color
+        let encoded = Protocol::Json.encode(&message, Render::default());
+        let encoded: serde_json::Value = serde_json::from_slice(&encoded).unwrap();
+        assert_eq!(encoded["color"], colors::of("alice").hex);
+        let encoded = Protocol::Json.encode(&joined, Render::default());
+        assert!(!String::from_utf8_lossy(&encoded).contains("color"));
+    }
+
+    #[test]
+    fn edits_should_reach_json_clients_as_events() {
+        let mut edited = Message::new(Event::Edited {
+            id: "01HWA1".to_string(),
+            sender: "alice".to_string(),
+            room: "#general".to_string(),
+            content: "hello".to_string(),
+        });
+        edited.timestamp = "2024-05-01T12:00:00Z".parse().unwrap();
+        assert_eq!(
+            &Protocol::Json.encode(&edited, Render::default())[..],
+            br##"{"type":"edited","id":"01HWA1","sender":"alice","room":"#general","content":"hello","timestamp":"2024-05-01T12:00:00Z","color":"#29b8db"}"##
+        );
+        let mut deleted = Message::new(Event::Deleted {
+            id: "01HWA1".to_string(),
+            room: "#general".to_string(),
+        });
+        deleted.timestamp = edited.timestamp;
+        assert_eq!(
+            &Protocol::Json.encode(&deleted, Render::default())[..],
+            br##"{"type":"deleted","id":"01HWA1","room":"#general","timestamp":"2024-05-01T12:00:00Z"}"##
+        );
+    }
+}
@@ -0,0 +1,380 @@
+// the topic, modes and operators of every room and who is invited to it, kept across restarts
+// in the chat db. a room is created there on its first /join, with the defaults of the config.
+// a room's settings are cached once read
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::Result;
+use chrono::Utc;
+use dashmap::DashMap;
+use sqlx::{FromRow, SqlitePool};
+
+#[derive(Debug)]
+pub struct RoomRegistry {
+    pool: SqlitePool,
+    cache: DashMap<String, RoomInfo>,
+}
+
+// a room nobody created or configured has no topic, no modes and no operators
+#[derive(Debug, Clone, Default, PartialEq, FromRow)]
+pub struct RoomInfo {
+    pub topic: String,
+    // only operators may post
+    pub read_only: bool,
+    // only operators and invited users may join
+    pub invite_only: bool,
+    // besides the server's operators, who may change the topic and modes and invite. by
+    // identity, an account or a guest's connection, not by the name it goes by
+    #[sqlx(skip)]
+    pub operators: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    ReadOnly,
+    InviteOnly,
+}
+
+impl RoomRegistry {
+    pub async fn open(pool: SqlitePool) -> Result<Self> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS rooms (
+                name TEXT PRIMARY KEY,
+                topic TEXT NOT NULL DEFAULT '',
+                read_only BOOLEAN NOT NULL DEFAULT FALSE,
+                invite_only BOOLEAN NOT NULL DEFAULT FALSE,
+                updated_by TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+        // `username` holds identities here too, as for operators
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS room_invites (
+                room TEXT NOT NULL,
+                username TEXT NOT NULL,
+                invited_by TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                PRIMARY KEY (room, username)
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+        // `username` holds identities, see `RoomInfo::operators`
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS room_operators (
+                room TEXT NOT NULL,
+                username TEXT NOT NULL,
+                added_by TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                PRIMARY KEY (room, username)
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+        Ok(Self {
+            pool,
+            cache: DashMap::new(),
+        })
+    }
+
+    pub async fn get(&self, room: &str) -> Result<RoomInfo> {
+        if let Some(info) = self.cache.get(room) {
+            return Ok(info.clone());
+        }
+        let info: Option<RoomInfo> =
+            sqlx::query_as("SELECT topic, read_only, invite_only FROM rooms WHERE name = ?")
+                .bind(room)
+                .fetch_optional(&self.pool)
+                .await?;
+        let mut info = info.unwrap_or_default();
+        info.operators = sqlx::query_scalar(
+            "SELECT username FROM room_operators WHERE room = ? ORDER BY username",
+        )
+        .bind(room)
+        .fetch_all(&self.pool)
+        .await?;
+        self.cache.insert(room.to_string(), info.clone());
+        Ok(info)
+    }
+
+    // with the topic and modes of `defaults`, false if it was there already
+    pub async fn create(&self, room: &str, defaults: &RoomInfo, by: &str) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            INSERT OR IGNORE INTO rooms (name, topic, read_only, invite_only, updated_by, updated_at)
+            VALUES (?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(room)
+        .bind(&defaults.topic)
+        .bind(defaults.read_only)
+        .bind(defaults.invite_only)
+        .bind(by)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+        self.cache.remove(room);
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn add_operator(&self, room: &str, identity: &str, by: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT OR IGNORE INTO room_operators (room, username, added_by, created_at)
+            VALUES (?, ?, ?, ?)
+            "#,
+        )
+        .bind(room)
+        .bind(identity)
+        .bind(by)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+        self.cache.remove(room);
+        Ok(())
+    }
+
+    // an empty topic clears it
+    pub async fn set_topic(&self, room: &str, topic: &str, by: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO rooms (name, topic, updated_by, updated_at) VALUES (?, ?, ?, ?)
+            ON CONFLICT (name) DO UPDATE SET
+                topic = excluded.topic,
+                updated_by = excluded.updated_by,
+                updated_at = excluded.updated_at
+            "#,
+        )
+        .bind(room)
+        .bind(topic)
+        .bind(by)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+        self.cache.remove(room);
+        Ok(())
+    }
+
+    pub async fn set_mode(&self, room: &str, mode: Mode, on: bool, by: &str) -> Result<()> {
+        let column = match mode {
+            Mode::ReadOnly => "read_only",
+            Mode::InviteOnly => "invite_only",
+        };
+        sqlx::query(&format!(
+            r#"
+            INSERT INTO rooms (name, {column}, updated_by, updated_at) VALUES (?, ?, ?, ?)
+            ON CONFLICT (name) DO UPDATE SET
+                {column} = excluded.{column},
+                updated_by = excluded.updated_by,
+                updated_at = excluded.updated_at
+            "#
+        ))
+        .bind(room)
+        .bind(on)
+        .bind(by)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+        self.cache.remove(room);
+        Ok(())
+    }
+
+    // kept when the room stops being invite only, for when it is again. by identity, see
+    // `RoomInfo::operators`
+    pub async fn invite(&self, room: &str, identity: &str, by: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT OR IGNORE INTO room_invites (room, username, invited_by, created_at)
+            VALUES (?, ?, ?, ?)
+            "#,
+        )
+        .bind(room)
+        .bind(identity)
+        .bind(by)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn invited(&self, room: &str, identity: &str) -> Result<bool> {
+        let invited: Option<String> =
+            sqlx::query_scalar("SELECT username FROM room_invites WHERE room = ? AND username = ?")
+                .bind(room)
+                .bind(identity)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(invited.is_some())
+    }
+}
+
+// `read-only on, invite-only off`
+impl fmt::Display for RoomInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let on = |on| if on { "on" } else { "off" };
+        write!(
+            f,
+            "{} {}, {} {}",
+            Mode::ReadOnly,
+            on(self.read_only),
+            Mode::InviteOnly,
+            on(self.invite_only)
+        )
+    }
+}
+
+impl RoomInfo {
+    // what a room starts with, `modes` as in `read-only,invite-only`
+    pub fn defaults(topic: &str, modes: &str) -> Result<Self> {
+        let mut info = Self {
+            topic: topic.to_string(),
+            ..Self::default()
+        };
+        for mode in modes
+            .split(',')
+            .map(str::trim)
+            .filter(|mode| !mode.is_empty())
+        {
+            match mode.parse().map_err(anyhow::Error::msg)? {
+                Mode::ReadOnly => info.read_only = true,
+                Mode::InviteOnly => info.invite_only = true,
+            }
+        }
+        Ok(info)
+    }
+
+    pub fn is_operator(&self, identity: &str) -> bool {
+        self.operators.iter().any(|operator| operator == identity)
+    }
+}
+
+// as it is shown, `read-only` or `invite-only`
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read-only" => Ok(Self::ReadOnly),
+            "invite-only" => Ok(Self::InviteOnly),
+            other => Err(format!(
+                "Unknown mode {}, use read-only or invite-only",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ReadOnly => write!(f, "read-only"),
+            Self::InviteOnly => write!(f, "invite-only"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a fresh db, named after the test
+    async fn test_pool(name: &str) -> SqlitePool {
+        let db = std::env::temp_dir().join(format!("chat-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&db);
+        crate::chat::state::open_db(db.to_str().unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn settings_should_be_kept_and_cached() {
+        let pool = test_pool("registry").await;
+        let registry = RoomRegistry::open(pool.clone()).await.unwrap();
+        assert_eq!(registry.get("#news").await.unwrap(), RoomInfo::default());
+
+        registry
+            .set_topic("#news", "what's new", "alice")
+            .await
+            .unwrap();
+        registry
+            .set_mode("#news", Mode::ReadOnly, true, "alice")
+            .await
+            .unwrap();
+        let info = registry.get("#news").await.unwrap();
+        assert_eq!(info.topic, "what's new");
+        assert!(info.read_only && !info.invite_only);
+        assert_eq!(info.to_string(), "read-only on, invite-only off");
+
+        // a registry opened again reads what the first one wrote
+        let reopened = RoomRegistry::open(pool).await.unwrap();
+        assert_eq!(reopened.get("#news").await.unwrap(), info);
+        assert_eq!(reopened.get("#rust").await.unwrap(), RoomInfo::default());
+    }
+
+    #[tokio::test]
+    async fn invites_should_be_per_room() {
+        let pool = test_pool("invites").await;
+        let registry = RoomRegistry::open(pool).await.unwrap();
+        registry.invite("#ops", "bob", "alice").await.unwrap();
+        registry.invite("#ops", "bob", "alice").await.unwrap();
+        assert!(registry.invited("#ops", "bob").await.unwrap());
+        assert!(!registry.invited("#ops", "carol").await.unwrap());
+        assert!(!registry.invited("#rust", "bob").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn rooms_should_be_created_once_with_the_defaults() {
+        let pool = test_pool("created").await;
+        let registry = RoomRegistry::open(pool.clone()).await.unwrap();
+        let defaults = RoomInfo {
+            topic: "new here".to_string(),
+            invite_only: true,
+            ..RoomInfo::default()
+        };
+        // looked up, and cached, before it was created
+        assert_eq!(registry.get("#new").await.unwrap(), RoomInfo::default());
+        assert!(registry.create("#new", &defaults, "alice").await.unwrap());
+        registry
+            .add_operator("#new", "alice", "alice")
+            .await
+            .unwrap();
+        assert!(!registry
+            .create("#new", &RoomInfo::default(), "bob")
+            .await
+            .unwrap());
+
+        let reopened = RoomRegistry::open(pool).await.unwrap();
+        let info = reopened.get("#new").await.unwrap();
+        assert_eq!(info.topic, "new here");
+        assert!(info.invite_only && !info.read_only);
+        assert!(info.is_operator("alice") && !info.is_operator("bob"));
+        reopened.add_operator("#new", "bob", "alice").await.unwrap();
+        assert_eq!(
+            reopened.get("#new").await.unwrap().operators,
+            ["alice", "bob"]
+        );
+        assert!(reopened.get("#rust").await.unwrap().operators.is_empty());
+    }
+
+    #[test]
+    fn modes_should_parse_as_shown() {
+        for mode in [Mode::ReadOnly, Mode::InviteOnly] {
+            assert_eq!(mode.to_string().parse(), Ok(mode));
+        }
+        assert!("secret".parse::<Mode>().is_err());
+
+        let defaults = RoomInfo::defaults("hi", " invite-only, ").unwrap();
+        assert!(defaults.invite_only && !defaults.read_only);
+        assert_eq!(defaults.topic, "hi");
+        assert!(RoomInfo::defaults("", "secret").is_err());
+    }
+}
@@ -0,0 +1,181 @@
+// what is said in every room, appended to a file per room and day by a thread of its own,
+// e.g. `logs/general/2024-05-01.log`
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Result;
+use chrono::NaiveDate;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::warn;
+
+use crate::chat::{Message, Render};
+
+// messages waiting for the writer, later ones are dropped
+const BACKLOG: usize = 1024;
+
+#[derive(Debug)]
+pub struct RoomLog {
+    dir: PathBuf,
+    sender: mpsc::Sender<(String, Arc<Message>)>,
+}
+
+// the file each room is appended to, for the day of its last message
+struct Files {
+    dir: PathBuf,
+    open: HashMap<String, (NaiveDate, File)>,
+}
+
+impl RoomLog {
+    // the writer stops once the log is dropped
+    pub fn start(dir: impl Into<PathBuf>) -> Self {
+        let dir = dir.into();
+        let (sender, mut receiver) = mpsc::channel::<(String, Arc<Message>)>(BACKLOG);
+        let mut files = Files {
+            dir: dir.clone(),
+            open: HashMap::new(),
+        };
+        std::thread::spawn(move || {
+            while let Some((room, message)) = receiver.blocking_recv() {
+                if let Err(e) = files.append(&room, &message) {
+                    warn!(room, error = ?e, "failed to write room log");
+                }
+            }
+        });
+        Self { dir, sender }
+    }
+
+    // never waits, the broadcast goes on while the file is written
+    pub fn append(&self, room: &str, message: &Arc<Message>) {
+        match self
+            .sender
+            .try_send((room.to_string(), Arc::clone(message)))
+        {
+            Ok(()) | Err(TrySendError::Closed(_)) => {}
+            Err(TrySendError::Full(_)) => warn!(room, "room log is behind, message dropped"),
+        }
+    }
+
+    // the lines of `room` on `date`, None if nothing was said
+    pub async fn read(&self, room: &str, date: NaiveDate) -> Result<Option<Vec<String>>> {
+        match tokio::fs::read_to_string(path(&self.dir, room, date)).await {
+            Ok(log) => Ok(Some(log.lines().map(str::to_string).collect())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Files {
+    // a new day is a new file
+    fn append(&mut self, room: &str, message: &Message) -> io::Result<()> {
+        let date = message.timestamp.date_naive();
+        if !matches!(self.open.get(room), Some((opened, _)) if *opened == date) {
+            let path = path(&self.dir, room, date);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            self.open.insert(room.to_string(), (date, file));
+        }
+        let (_, file) = self.open.get_mut(room).expect("opened above");
+        writeln!(
+            file,
+            "{}",
+            message.render(Render {
+                timestamps: true,
+                ..Default::default()
+            })
+        )
+    }
+}
+
+// a room name may be anything. letters, digits, `-` and `_` make it into the path as they
+// are, anything else percent encoded: no two rooms share a dir, and none leaves the log dir
+fn path(dir: &Path, room: &str, date: NaiveDate) -> PathBuf {
+    let mut name = String::new();
+    for c in room.strip_prefix('#').unwrap_or(room).chars() {
+        if c.is_alphanumeric() || c == '-' || c == '_' {
+            name.push(c);
+        } else {
+            let mut bytes = [0; 4];
+            for byte in c.encode_utf8(&mut bytes).bytes() {
+                name.push_str(&format!("%{:02X}", byte));
+            }
+        }
+    }
+    // no encoding is a lone `%`
+    if name.is_empty() {
+        name.push('%');
+    }
+    dir.join(name)
+        .join(format!("{}.log", date.format("%Y-%m-%d")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn room_names_should_stay_in_the_log_dir() {
+        let date = NaiveDate::from_ymd_opt(2024, 5, 1).unwrap();
+        let dir = Path::new("logs");
+        assert_eq!(
+            path(dir, "#general", date),
+            Path::new("logs/general/2024-05-01.log")
+        );
+        assert_eq!(
+            path(dir, "#../../etc", date),
+            Path::new("logs/%2E%2E%2F%2E%2E%2Fetc/2024-05-01.log")
+        );
+        assert_eq!(path(dir, "#", date), Path::new("logs/%/2024-05-01.log"));
+        // rooms told apart by what isn't kept as is get dirs of their own
+        let names = ["#a.b", "#a_b", "#a%2Eb", "##a", "#a", "#a b", "#日本"];
+        let dirs: std::collections::HashSet<PathBuf> =
+            names.iter().map(|room| path(dir, room, date)).collect();
+        assert_eq!(dirs.len(), names.len());
+        assert_eq!(
+            path(dir, "#a.b", date),
+            Path::new("logs/a%2Eb/2024-05-01.log")
+        );
+    }
+
+    #[tokio::test]
+    async fn messages_should_be_read_back_by_day() {
+        let dir = std::env::temp_dir().join(format!("chat-room-log-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut files = Files {
+            dir: dir.clone(),
+            open: HashMap::new(),
+        };
+        let mut first = Message::chat("alice", "#general", "hi");
+        first.timestamp = "2024-05-01T23:59:59Z".parse().unwrap();
+        let mut second = Message::chat("bob", "#general", "morning");
+        second.timestamp = "2024-05-02T00:00:01Z".parse().unwrap();
+        let mut third = Message::user_left("bob", "#general");
+        third.timestamp = "2024-05-02T00:10:00Z".parse().unwrap();
+        for message in [&first, &second, &third] {
+            files.append("#general", message).unwrap();
+        }
+
+        let log = RoomLog::start(&dir);
+        let may = |day| NaiveDate::from_ymd_opt(2024, 5, day).unwrap();
+        assert_eq!(
+            log.read("#general", may(1)).await.unwrap().unwrap(),
+            ["[23:59:59] alice: hi"]
+        );
+        assert_eq!(
+            log.read("#general", may(2)).await.unwrap().unwrap(),
+            [
+                "[00:00:01] bob: morning",
+                "[00:10:00] [bob has left #general :(]"
+            ]
+        );
+        assert_eq!(log.read("#general", may(3)).await.unwrap(), None);
+        assert_eq!(log.read("#rust", may(1)).await.unwrap(), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,166 @@
+// the last messages of every room, in memory so /history and joining need no db. a room's
+// buffer is filled from the db the first time it is read, and kept up to date from then on
+
+use std::collections::VecDeque;
+
+use dashmap::DashMap;
+
+use crate::chat::HistoryEntry;
+
+#[derive(Debug)]
+pub struct Scrollback {
+    // messages kept per room, none if 0
+    size: usize,
+    rooms: DashMap<String, Buffer>,
+}
+
+#[derive(Debug, Default)]
+struct Buffer {
+    // whether it has what the db had before, not only what was said since
+    loaded: bool,
+    // oldest first
+    entries: VecDeque<HistoryEntry>,
+}
+
+impl Scrollback {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            rooms: DashMap::new(),
+        }
+    }
+
+    // the oldest message is evicted once the buffer is full
+    pub fn push(&self, entry: HistoryEntry) {
+        if self.size == 0 {
+            return;
+        }
+        let mut buffer = self.rooms.entry(entry.room.clone()).or_default();
+        if buffer.entries.len() == self.size {
+            buffer.entries.pop_front();
+        }
+        buffer.entries.push_back(entry);
+    }
+
+    // the last `n` messages of `room`, oldest first. None if they have to come from the db
+    pub fn recent(&self, room: &str, n: usize) -> Option<Vec<HistoryEntry>> {
+        if n > self.size {
+            return None;
+        }
+        let buffer = self.rooms.get(room)?;
+        if !buffer.loaded {
+            return None;
+        }
+        let skip = buffer.entries.len().saturating_sub(n);
+        Some(buffer.entries.iter().skip(skip).cloned().collect())
+    }
+
+    // `entries` are the last of the db, oldest first. what was pushed while they were read is
+    // kept after them
+    pub fn fill(&self, room: &str, entries: Vec<HistoryEntry>) {
+        if self.size == 0 {
+            return;
+        }
+        let mut buffer = self.rooms.entry(room.to_string()).or_default();
+        if buffer.loaded {
+            return;
+        }
+        let pushed = std::mem::take(&mut buffer.entries);
+        buffer.entries = entries.into();
+        for entry in pushed {
+            if !buffer.entries.iter().any(|e| e.id == entry.id) {
+                buffer.entries.push_back(entry);
+            }
+        }
+        while buffer.entries.len() > self.size {
+            buffer.entries.pop_front();
+        }
+        buffer.loaded = true;
+    }
+
+    pub fn edit(&self, room: &str, id: &str, content: &str) {
+        if let Some(mut buffer) = self.rooms.get_mut(room) {
+            if let Some(entry) = buffer.entries.iter_mut().find(|e| e.id == id) {
+                entry.content = content.to_string();
+                entry.edited = true;
+            }
+        }
+    }
+
+    pub fn delete(&self, room: &str, id: &str) {
+        if let Some(mut buffer) = self.rooms.get_mut(room) {
+            buffer.entries.retain(|e| e.id != id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+
+    fn entry(room: &str, id: &str) -> HistoryEntry {
+        HistoryEntry {
+            id: id.to_string(),
+            room: room.to_string(),
+            sender: "alice".to_string(),
+            content: format!("message {}", id),
+            created_at: Utc::now(),
+            edited: false,
+        }
+    }
+
+    fn ids(entries: Option<Vec<HistoryEntry>>) -> Vec<String> {
+        entries.unwrap().into_iter().map(|e| e.id).collect()
+    }
+
+    #[test]
+    fn old_messages_should_be_evicted() {
+        let scrollback = Scrollback::new(3);
+        scrollback.fill("#rust", vec![entry("#rust", "1"), entry("#rust", "2")]);
+        for id in ["3", "4", "5"] {
+            scrollback.push(entry("#rust", id));
+        }
+        scrollback.push(entry("#go", "6"));
+        assert_eq!(ids(scrollback.recent("#rust", 3)), ["3", "4", "5"]);
+        assert_eq!(ids(scrollback.recent("#rust", 2)), ["4", "5"]);
+        // more than kept, or a room never read from the db
+        assert!(scrollback.recent("#rust", 4).is_none());
+        assert!(scrollback.recent("#go", 1).is_none());
+        assert!(Scrollback::new(0).recent("#rust", 0).is_none());
+    }
+
+    #[test]
+    fn messages_pushed_before_the_fill_should_be_kept() {
+        let scrollback = Scrollback::new(3);
+        scrollback.push(entry("#rust", "3"));
+        scrollback.push(entry("#rust", "4"));
+        scrollback.fill(
+            "#rust",
+            vec![
+                entry("#rust", "1"),
+                entry("#rust", "2"),
+                entry("#rust", "3"),
+            ],
+        );
+        assert_eq!(ids(scrollback.recent("#rust", 3)), ["2", "3", "4"]);
+        // filled once only
+        scrollback.fill("#rust", Vec::new());
+        assert_eq!(ids(scrollback.recent("#rust", 3)), ["2", "3", "4"]);
+    }
+
+    #[test]
+    fn edits_and_deletions_should_show() {
+        let scrollback = Scrollback::new(3);
+        scrollback.fill("#rust", vec![entry("#rust", "1"), entry("#rust", "2")]);
+        scrollback.edit("#rust", "1", "hello");
+        scrollback.delete("#rust", "2");
+        let entries = scrollback.recent("#rust", 3).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(
+            (entries[0].content.as_str(), entries[0].edited),
+            ("hello", true)
+        );
+    }
+}
@@ -0,0 +1,710 @@
+// the listeners: tcp, tls, noise and websocket clients are accepted here, and each session reads
+// its frames into `dispatch` and writes what reaches its peer
+
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{
+        ws::{Message as WsMessage, WebSocket, WebSocketUpgrade},
+        ConnectInfo, DefaultBodyLimit, State as AxumState,
+    },
+    response::{Html, IntoResponse},
+    routing::{get, put},
+    Router,
+};
+use axum_server::Handle;
+use bytes::Bytes;
+use futures::{future, Sink, SinkExt, Stream, StreamExt};
+use snow::Keypair;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::signal;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+use tokio::time::{timeout, timeout_at, Instant};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::codec::{Framed, FramedRead, FramedWrite, LengthDelimitedCodec};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn, Instrument, Span};
+
+use crate::chat::commands::Command;
+use crate::chat::connections::Connection;
+use crate::chat::lines::{InvalidLine, Lines};
+use crate::chat::metrics::{metrics_handler, peers_handler};
+use crate::chat::sessions::Unacked;
+use crate::chat::uploads::{download_handler, upload_handler};
+use crate::chat::{
+    dispatch, encode_numbered, noise, uploads, BanTarget, Codec, Drops, Event, Flood, Ignores,
+    Login, Message, Protocol, Render, Role, RoomMessage, State, Verdict, ACKS_VERSION,
+    DEFAULT_ROOM, PASTE_PREFIX, PROTOCOL_VERSION,
+};
+
+// a page to chat from a browser, over the websocket at /ws
+const INDEX_HTML: &str = r#"<!DOCTYPE html>
+<html>
+<body>
+<pre id="log"></pre>
+<form id="form"><input id="line" autofocus autocomplete="off"></form>
+<script>
+const log = document.getElementById("log");
+const line = document.getElementById("line");
+const scheme = location.protocol === "https:" ? "wss" : "ws";
+const ws = new WebSocket(`${scheme}://${location.host}/ws`);
+ws.onmessage = (e) => { log.textContent += e.data + "
+"; };
+ws.onclose = () => { log.textContent += "* disconnected
+"; };
+document.getElementById("form").onsubmit = (e) => {
+  e.preventDefault();
+  ws.send(line.value);
+  line.value = "";
+};
+</script>
+</body>
+</html>
+"#;
+
+// sends to the client what reaches its peer
+pub(super) struct Writer {
+    pub(super) state: Arc<State>,
+    pub(super) addr: SocketAddr,
+    pub(super) protocol: Protocol,
+    pub(super) drops: Arc<Drops>,
+    pub(super) ignores: Arc<Ignores>,
+    // numbers what it sends, for a client that acks
+    pub(super) unacked: Option<Arc<Mutex<Unacked>>>,
+    pub(super) disconnect: CancellationToken,
+}
+
+// what a peer's writer gets on its own queue, besides the messages of its room
+#[derive(Debug)]
+pub(super) enum Outgoing {
+    Message(Arc<Message>),
+    // the room to follow from now on, None after a leave
+    Room(Option<broadcast::Receiver<RoomMessage>>),
+    Render(Render),
+    // sent to the client as it is, e.g. an irc reply
+    Raw(Bytes),
+}
+
+/// Accepts tcp clients until the server shuts down, then gives their sessions the grace period to
+/// end. Over tls if the config names a certificate, framed as the config says.
+pub async fn serve(state: Arc<State>, listener: TcpListener) -> Result<()> {
+    let codec = state.config().codec;
+    info!(?codec, "framing tcp clients");
+    let acceptor = state
+        .tls
+        .as_ref()
+        .map(|tls| TlsAcceptor::from(tls.get_inner()));
+    loop {
+        let (client, addr) = tokio::select! {
+            _ = state.shutdown.cancelled() => break,
+            accepted = listener.accept() => accepted?,
+        };
+        let span = state.connection_span(addr);
+        // counted from here, a refused client is still told why
+        let connection = span.in_scope(|| {
+            info!("accepted tcp connection");
+            let connection = state.connections.open(addr.ip());
+            if let Err(e) = &connection {
+                warn!(reason = %e, "refusing connection");
+            }
+            connection
+        });
+        let cloned_state = Arc::clone(&state);
+        let acceptor = acceptor.clone();
+        state.tasks.spawn(
+            async move {
+                let handled = match acceptor {
+                    // the handshake is bounded like any other wait for the client
+                    Some(acceptor) => {
+                        let handshake = cloned_state.config().idle_timeout;
+                        match timeout(handshake, acceptor.accept(client)).await {
+                            Ok(Ok(client)) => {
+                                handle_client(cloned_state, addr, connection, client, codec).await
+                            }
+                            Ok(Err(e)) => {
+                                Err(anyhow::Error::from(e).context("tls handshake failed"))
+                            }
+                            Err(_) => Err(anyhow!("tls handshake timed out")),
+                        }
+                    }
+                    None => handle_client(cloned_state, addr, connection, client, codec).await,
+                };
+                if let Err(e) = handled {
+                    warn!(error = ?e, "failed to handle client");
+                }
+                Ok::<(), anyhow::Error>(())
+            }
+            .instrument(span),
+        );
+    }
+
+    // no new clients from here, the sessions end and their writers flush
+    drop(listener);
+    state.tasks.close();
+    if timeout(state.config().grace_period, state.tasks.wait())
+        .await
+        .is_err()
+    {
+        warn!(
+            tasks = state.tasks.len(),
+            "tasks still running after the grace period, closing them"
+        );
+    }
+    info!("chat server stopped");
+    Ok(())
+}
+
+/// Browsers chat through websockets at `/ws`, in the same rooms as the tcp clients, from the page
+/// at `/`. Over tls like the tcp clients.
+pub async fn serve_web(state: Arc<State>, listener: std::net::TcpListener) -> Result<()> {
+    listener.set_nonblocking(true)?;
+    let tls = state.tls.clone();
+    let shutdown = state.shutdown.clone();
+    let app = Router::new()
+        .route("/", get(|| async { Html(INDEX_HTML) }))
+        .route("/ws", get(ws_handler))
+        .with_state(state);
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
+    match tls {
+        Some(tls) => {
+            let handle = Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                async move {
+                    shutdown.cancelled().await;
+                    handle.graceful_shutdown(None);
+                }
+            });
+            axum_server::from_tcp_rustls(listener, tls)
+                .handle(handle)
+                .serve(service)
+                .await?
+        }
+        None => {
+            axum::serve(TcpListener::from_std(listener)?, service)
+                .with_graceful_shutdown(shutdown.cancelled_owned())
+                .await?
+        }
+    }
+    Ok(())
+}
+
+/// Scraped by prometheus at `/metrics`, apart from the clients, with what is behind on
+/// `/debug/peers` for those holding the debug token.
+pub async fn serve_metrics(state: Arc<State>, listener: TcpListener) -> Result<()> {
+    let shutdown = state.shutdown.clone();
+    let app = Router::new()
+        .route("/metrics", get(metrics_handler))
+        .route("/debug/peers", get(peers_handler))
+        .with_state(state);
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await?;
+    Ok(())
+}
+
+/// Files shared with /send are uploaded and downloaded here, and removed once they expire.
+/// Only needed if the server shares files, see [`State::shares_files`].
+pub async fn serve_uploads(state: Arc<State>, listener: TcpListener) -> Result<()> {
+    let shutdown = state.shutdown.clone();
+    tokio::spawn(uploads::run_cleanup(Arc::clone(&state)));
+    let app = Router::new()
+        .route("/upload/:token", put(upload_handler))
+        .route("/files/:id/:filename", get(download_handler))
+        .layer(DefaultBodyLimit::max(state.config().max_upload))
+        .with_state(state);
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await?;
+    Ok(())
+}
+
+/// Tcp clients encrypting with noise rather than tls under `key`, see [`State::noise_key`],
+/// framed like the others.
+pub async fn serve_noise(state: Arc<State>, listener: TcpListener, key: Keypair) -> Result<()> {
+    let codec = state.config().codec;
+    noise::serve(state, listener, key, codec).await
+}
+
+/// Resolves when SIGINT (ctrl-c) or SIGTERM is received, see [`State::shut_down`].
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("failed to install ctrl-c handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("shutdown signal received, saying goodbye to the clients");
+}
+// a plain or tls stream, framed as configured
+pub(super) async fn handle_client<S>(
+    state: Arc<State>,
+    addr: SocketAddr,
+    connection: Result<Connection, String>,
+    stream: S,
+    codec: Codec,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    match codec {
+        Codec::Lines => {
+            let lines = Lines::new(state.config().max_line);
+            let (sink, stream) = Framed::new(stream, lines).split();
+            let sink = sink.sink_map_err(anyhow::Error::from).with(|frame: Bytes| {
+                future::ready(String::from_utf8(frame.to_vec()).map_err(Into::into))
+            });
+            // an invalid line is told about, see `read_error`
+            let stream = stream.map(|line| Ok(Bytes::from(line??)));
+            handle_frames(state, addr, connection, Protocol::Text, sink, stream).await
+        }
+        Codec::LengthDelimited => {
+            // a frame counts whole like a json request, a longer one isn't even buffered. what
+            // the server sends may be longer, e.g. a paste with its sender
+            let (reader, writer) = tokio::io::split(stream);
+            let codec = LengthDelimitedCodec::builder()
+                .max_frame_length(state.config().max_line)
+                .new_codec();
+            let stream = FramedRead::new(reader, codec);
+            let sink = FramedWrite::new(writer, LengthDelimitedCodec::new());
+            let sink = sink.sink_map_err(anyhow::Error::from);
+            let stream = stream.map(|frame| Ok(frame?.freeze()));
+            handle_frames(state, addr, connection, Protocol::Binary, sink, stream).await
+        }
+    }
+}
+
+async fn ws_handler(
+    AxumState(state): AxumState<Arc<State>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    let span = state.connection_span(addr);
+    let connection = span.in_scope(|| {
+        info!("accepted websocket");
+        let connection = state.connections.open(addr.ip());
+        if let Err(e) = &connection {
+            warn!(reason = %e, "refusing websocket");
+        }
+        connection
+    });
+    ws.on_upgrade(move |socket| {
+        async move {
+            if let Err(e) = handle_socket(state, addr, connection, socket).await {
+                warn!(error = ?e, "failed to handle websocket");
+            }
+        }
+        .instrument(span)
+    })
+}
+
+// every text frame is a line, the other frames are skipped
+async fn handle_socket(
+    state: Arc<State>,
+    addr: SocketAddr,
+    connection: Result<Connection, String>,
+    socket: WebSocket,
+) -> Result<()> {
+    let (sink, stream) = socket.split();
+    let sink = sink.sink_map_err(anyhow::Error::from).with(|frame: Bytes| {
+        let line = String::from_utf8(frame.to_vec()).map_err(Into::into);
+        future::ready(line.map(WsMessage::Text))
+    });
+    let stream = stream
+        .take_while(|frame| future::ready(!matches!(frame, Ok(WsMessage::Close(_)))))
+        .filter_map(|frame| {
+            future::ready(match frame {
+                Ok(WsMessage::Text(line)) => Some(Ok(Bytes::from(line))),
+                Ok(_) => None,
+                Err(e) => Some(Err(e.into())),
+            })
+        });
+    handle_frames(state, addr, connection, Protocol::Text, sink, stream).await
+}
+
+// a chat session over any client, tcp or websocket, a text line or a binary message per frame.
+// the connection is given back once the session ends
+pub(super) async fn handle_frames<Tx, Rx>(
+    state: Arc<State>,
+    addr: SocketAddr,
+    connection: Result<Connection, String>,
+    mut protocol: Protocol,
+    mut sink: Tx,
+    stream: Rx,
+) -> Result<()>
+where
+    Tx: Sink<Bytes, Error = anyhow::Error> + Unpin + Send + 'static,
+    Rx: Stream<Item = Result<Bytes>>,
+{
+    let mut stream = std::pin::pin!(stream);
+    let _connection = match connection {
+        Ok(connection) => connection,
+        Err(e) => {
+            sink.send(protocol.prompt(&e)).await?;
+            return Ok(());
+        }
+    };
+    if let Some(ban) = state.banned(&BanTarget::Ip(addr.ip())).await {
+        info!(%ban, "turned away");
+        let notice = format!("Your address is {}", ban);
+        sink.send(protocol.prompt(&notice)).await?;
+        return Ok(());
+    }
+    let greeting = if state.config().guests {
+        "Enter your username, /resume token, or guest to look around:"
+    } else {
+        "Enter your username or /resume token:"
+    };
+    sink.send(protocol.prompt(greeting)).await?; // send to client
+
+    // a json or binary client may say hello before its username
+    let mut first = true;
+    let mut acks = false;
+    let mut step = Login::Username;
+    // read from client, until it logs in
+    let (username, account, mut resumed) = loop {
+        let next = tokio::select! {
+            _ = state.shutdown.cancelled() => return Ok(()),
+            next = timeout(state.config().idle_timeout, stream.next()) => next,
+        };
+        let frame = match next {
+            Ok(Some(Ok(frame))) => frame,
+            Ok(Some(Err(e))) => {
+                sink.send(protocol.prompt(&read_error(e)?)).await?;
+                continue;
+            }
+            Ok(None) => return Ok(()),
+            Err(_) => {
+                info!(reason = "never logged in", "disconnected");
+                return Ok(());
+            }
+        };
+        if first {
+            first = false;
+            if let Some((negotiated, version)) = protocol.hello(&frame) {
+                if version == 0 {
+                    let reply = Message::notice(format!(
+                        "Unsupported protocol version {}, this server speaks 1 to {}",
+                        version, PROTOCOL_VERSION
+                    ));
+                    sink.send(negotiated.encode(&reply, Render::default()))
+                        .await?;
+                    return Ok(());
+                }
+                let version = version.min(PROTOCOL_VERSION);
+                protocol = negotiated;
+                acks = protocol == Protocol::Json && version >= ACKS_VERSION;
+                let hello = Message::new(Event::Hello { version });
+                sink.send(protocol.encode(&hello, Render::default()))
+                    .await?;
+                sink.send(protocol.prompt(greeting)).await?;
+                continue;
+            }
+        }
+        let line = match protocol.line(&frame, state.config().max_line) {
+            Ok(line) => line.trim().to_string(),
+            Err(e) => {
+                sink.send(protocol.prompt(&e)).await?;
+                continue;
+            }
+        };
+        let prompt;
+        (step, prompt) = state.login(addr, step, &line).await;
+        match step {
+            Login::LoggedIn(username) => break (username.clone(), Some(username), None),
+            Login::Guest(username) => break (username, None, None),
+            Login::Resumed(resumed) => {
+                break (
+                    resumed.username.clone(),
+                    resumed.account.clone(),
+                    Some(resumed),
+                )
+            }
+            Login::Refused => {
+                sink.send(protocol.prompt(&prompt)).await?;
+                return Ok(());
+            }
+            _ => sink.send(protocol.prompt(&prompt)).await?,
+        }
+    };
+    Span::current().record("username", username.as_str());
+    // an acking client picks up the numbering of its session, and what was not acked in it
+    let unacked = acks.then(|| match &mut resumed {
+        Some(resumed) => std::mem::take(&mut resumed.unacked),
+        None => Unacked::default(),
+    });
+    let mut peer = state
+        .add(addr, username, account, protocol, sink, unacked)
+        .await;
+    info!(role = ?peer.role, ?protocol, "logged in");
+    for line in state.config().motd() {
+        state.send(addr, Message::notice(line)).await;
+    }
+    if peer.role == Role::Operator {
+        let notice = "You are an operator, you may /kick, /ban and /unban";
+        state.send(addr, Message::notice(notice)).await;
+    }
+    match resumed {
+        Some(resumed) => state.resume(addr, &mut peer, resumed).await,
+        None => {
+            if peer.account.is_some() {
+                let token = state.open_session();
+                let notice = format!(
+                    "Your session is {}, reconnect within {}s with /resume {} to pick up where you left",
+                    token,
+                    state.config().resume_window.as_secs(),
+                    token
+                );
+                state.send(addr, Message::notice(notice)).await;
+                peer.session = Some(token);
+            }
+            state.join(addr, &mut peer, DEFAULT_ROOM).await;
+        }
+    }
+    let mut flood = Flood::new(&state.config().rate_limit, Instant::now());
+    let mut seen_at = Instant::now();
+    let mut pinged = false;
+
+    // broadcast messages from the client to the others in its room
+    let reason = loop {
+        // a reload applies from the next line on
+        let config = state.config();
+        // a ping is due first, after that only the idle timeout is left
+        let deadline = if pinged || config.ping_interval >= config.idle_timeout {
+            seen_at + config.idle_timeout
+        } else {
+            seen_at + config.ping_interval
+        };
+        let next = tokio::select! {
+            // told why by whoever cancelled it
+            _ = peer.disconnect.cancelled() => break "kicked or too slow",
+            _ = state.shutdown.cancelled() => break "shutdown",
+            next = timeout_at(deadline, stream.next()) => next,
+        };
+        let frame = match next {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break "closed",
+            Err(_) if deadline < seen_at + config.idle_timeout => {
+                state.send(addr, Message::new(Event::Ping)).await;
+                pinged = true;
+                continue;
+            }
+            Err(_) => {
+                let notice = "You have been disconnected for being idle";
+                state.send(addr, Message::notice(notice)).await;
+                break "idle";
+            }
+        };
+        seen_at = Instant::now();
+        pinged = false;
+        let mut line = match frame.map(|frame| peer.protocol.line(&frame, config.max_line)) {
+            Ok(Ok(line)) => line,
+            Ok(Err(e)) => {
+                state.send(addr, Message::notice(e)).await;
+                continue;
+            }
+            Err(e) => match read_error(e) {
+                Ok(notice) => {
+                    state.send(addr, Message::notice(notice)).await;
+                    continue;
+                }
+                Err(_) => break "read error",
+            },
+        };
+        // the lines of a paste count as one, once it ends
+        if let Some(paste) = &mut peer.paste {
+            match paste.push(&line) {
+                Ok(None) => continue,
+                Ok(Some(text)) => {
+                    peer.paste = None;
+                    line = format!("{}{}", PASTE_PREFIX, text);
+                }
+                Err(e) => {
+                    peer.paste = None;
+                    state.send(addr, Message::notice(e)).await;
+                    continue;
+                }
+            }
+        }
+        let operator = peer.role == Role::Operator;
+        let command = match line.strip_prefix(PASTE_PREFIX) {
+            Some(text) => Ok(Command::Chat(text)),
+            None => state.commands.parse(&line, operator),
+        };
+        // a client may ack every message it gets, that isn't flooding
+        let verdict = match command {
+            Ok(Command::Ack(_)) => Verdict::Allow,
+            _ => flood.check(&config.rate_limit, seen_at),
+        };
+        match verdict {
+            Verdict::Allow => {}
+            Verdict::Warn => {
+                let notice = "You are sending too fast, slow down or you will be muted";
+                state.send(addr, Message::notice(notice)).await;
+                continue;
+            }
+            Verdict::Mute(duration) => {
+                warn!(mute = ?duration, "muted for flooding");
+                let notice = format!("You are muted for {}s", duration.as_secs());
+                state.send(addr, Message::notice(notice)).await;
+                continue;
+            }
+            Verdict::Muted => continue,
+            Verdict::Disconnect => {
+                let notice = "You have been disconnected for flooding";
+                state.send(addr, Message::notice(notice)).await;
+                break "flooding";
+            }
+        }
+        match command {
+            Ok(command) => {
+                if dispatch(&state, addr, &mut peer, command).await.is_break() {
+                    break "quit";
+                }
+            }
+            Err(e) => state.send(addr, Message::notice(e)).await,
+        }
+    };
+    info!(reason, "disconnected");
+    state.end_session(addr, &mut peer).await;
+    Ok(())
+}
+
+// what to tell a client whose line was invalid, an error if its connection failed
+fn read_error(e: anyhow::Error) -> Result<String> {
+    match e.downcast::<InvalidLine>() {
+        Ok(invalid) => {
+            info!(%invalid, "invalid line");
+            Ok(invalid.to_string())
+        }
+        Err(e) => {
+            warn!(error = ?e, "failed to read line");
+            Err(e)
+        }
+    }
+}
+
+impl Writer {
+    // state -> peer -> client, from the peer's own queue and from its room, until the queue
+    // closes once `peers` lets go of the peer
+    pub(super) async fn run<Tx>(self, mut queue: mpsc::Receiver<Outgoing>, mut sink: Tx)
+    where
+        Tx: Sink<Bytes, Error = anyhow::Error> + Unpin,
+    {
+        let addr = self.addr;
+        let mut room: Option<broadcast::Receiver<RoomMessage>> = None;
+        let mut render = Render::default();
+        // what the session's last client didn't ack, before anything new
+        if let Some(unacked) = &self.unacked {
+            let resent: Vec<_> = unacked.lock().unwrap().iter().cloned().collect();
+            for (seq, message) in resent {
+                if !self.write(&mut sink, encode_numbered(seq, &message)).await {
+                    return;
+                }
+            }
+        }
+        loop {
+            let message = tokio::select! {
+                outgoing = queue.recv() => match outgoing {
+                    Some(Outgoing::Message(message)) => message,
+                    Some(Outgoing::Room(receiver)) => {
+                        room = receiver;
+                        continue;
+                    }
+                    Some(Outgoing::Render(changed)) => {
+                        render = changed;
+                        continue;
+                    }
+                    Some(Outgoing::Raw(frame)) => {
+                        if !self.write(&mut sink, frame).await {
+                            break;
+                        }
+                        continue;
+                    }
+                    None => break,
+                },
+                received = recv_room(&mut room) => match received {
+                    Ok((from, _)) if from == addr => continue,
+                    Ok((_, message)) => {
+                        self.drops.in_a_row.store(0, Ordering::Relaxed);
+                        self.state.metrics.observe_fanout(message.timestamp);
+                        message
+                    }
+                    Err(RecvError::Lagged(n)) => {
+                        self.state.dropped(addr, &self.drops, &self.disconnect, n);
+                        let notice = format!("You missed {} messages, you are reading too slowly", n);
+                        Arc::new(Message::notice(notice))
+                    }
+                    Err(RecvError::Closed) => {
+                        room = None;
+                        continue;
+                    }
+                },
+            };
+            if !self.protocol.wants(&message) || self.ignores.ignores(&message) {
+                continue;
+            }
+            let frame = match &self.unacked {
+                Some(unacked) if message.acked() => {
+                    let limit = self.state.config().resume_buffer;
+                    let seq = unacked.lock().unwrap().push(Arc::clone(&message), limit);
+                    encode_numbered(seq, &message)
+                }
+                _ => self.protocol.encode(&message, render),
+            };
+            if !self.write(&mut sink, frame).await {
+                break;
+            }
+        }
+    }
+
+    // false once the client is gone or stopped reading, its session is told to end then. the
+    // messages still queued for it go with the writer
+    async fn write<Tx>(&self, sink: &mut Tx, frame: Bytes) -> bool
+    where
+        Tx: Sink<Bytes, Error = anyhow::Error> + Unpin,
+    {
+        let write_timeout = self.state.config().write_timeout;
+        match timeout(write_timeout, sink.send(frame)).await {
+            Ok(Ok(())) => return true,
+            Ok(Err(e)) => warn!(error = ?e, "failed to send message"),
+            // unless it is being disconnected already, e.g. for missing messages
+            Err(_) if self.disconnect.is_cancelled() => {}
+            Err(_) => {
+                warn!(timeout = ?write_timeout, "disconnecting a client which stopped reading");
+                self.state.metrics.write_timeouts.inc();
+            }
+        }
+        self.disconnect.cancel();
+        false
+    }
+}
+
+async fn recv_room(
+    room: &mut Option<broadcast::Receiver<RoomMessage>>,
+) -> Result<RoomMessage, RecvError> {
+    match room {
+        Some(receiver) => receiver.recv().await,
+        None => future::pending().await,
+    }
+}
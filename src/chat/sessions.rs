@@ -0,0 +1,300 @@
+// sessions of logged in users, which outlive their connection for a while so a client can
+// reconnect with its token and get what it missed. a json client acking its messages gets those
+// it didn't ack again, with the numbers it was sent them with
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use dashmap::DashMap;
+use tokio::sync::broadcast::{self, error::TryRecvError};
+use tokio::time::Instant;
+
+use crate::chat::{Message, RoomMessage};
+
+#[derive(Debug)]
+pub struct Sessions {
+    // by token
+    sessions: DashMap<String, Session>,
+    // how long a detached session may be resumed
+    window: Duration,
+    // messages kept for a detached session, older ones are dropped first
+    buffer: usize,
+}
+
+#[derive(Debug, Default)]
+struct Session {
+    // None while a client is connected with it
+    detached: Option<Detached>,
+}
+
+// a session while its client is gone
+#[derive(Debug)]
+pub struct Detached {
+    // what the client itself sent is not kept for it
+    pub addr: SocketAddr,
+    pub username: String,
+    // of the password login the session was opened by
+    pub account: Option<String>,
+    pub room: Option<String>,
+    // the room goes on without the client, what it says waits here
+    pub receiver: Option<broadcast::Receiver<RoomMessage>>,
+    pub unacked: Unacked,
+    pub at: Instant,
+}
+
+// what a client acking its messages was sent and didn't ack yet. numbered for the whole session,
+// so one resuming it can tell what it got already
+#[derive(Debug, Default)]
+pub struct Unacked {
+    // of the last message numbered, the first is 1
+    seq: u64,
+    messages: VecDeque<(u64, Arc<Message>)>,
+}
+
+// what a reconnecting client picks up
+#[derive(Debug)]
+pub struct Resumed {
+    pub token: String,
+    pub username: String,
+    pub account: Option<String>,
+    pub room: Option<String>,
+    pub pending: Vec<Arc<Message>>,
+    // more than the buffer holds, or than the room kept
+    pub missed: u64,
+    // sent before the client was lost, not acked
+    pub unacked: Unacked,
+}
+
+impl Sessions {
+    pub fn new(window: Duration, buffer: usize) -> Self {
+        Self {
+            sessions: DashMap::new(),
+            window,
+            buffer,
+        }
+    }
+
+    // returns the token of a new session, attached to the client it is given to
+    pub fn open(&self) -> String {
+        let token = format!("{:032x}", rand::random::<u128>());
+        self.sessions.insert(token.clone(), Session::default());
+        token
+    }
+
+    pub fn close(&self, token: &str) {
+        self.sessions.remove(token);
+    }
+
+    // the client is gone, its receiver follows the room meanwhile
+    pub fn detach(&self, token: &str, detached: Detached) {
+        if let Some(mut session) = self.sessions.get_mut(token) {
+            session.detached = Some(detached);
+        }
+    }
+
+    // whether a detached session still follows `room`, which should stay open for it
+    pub fn following(&self, room: &str, now: Instant) -> bool {
+        self.sessions.iter().any(|session| match &session.detached {
+            Some(detached) => {
+                detached.room.as_deref() == Some(room) && !self.expired(detached, now)
+            }
+            None => false,
+        })
+    }
+
+    // forgets the sessions which can't be resumed anymore, returns the rooms they followed
+    pub fn expire(&self, now: Instant) -> Vec<String> {
+        let mut rooms = Vec::new();
+        self.sessions.retain(|_, session| match &session.detached {
+            Some(detached) if self.expired(detached, now) => {
+                rooms.extend(detached.room.clone());
+                false
+            }
+            _ => true,
+        });
+        rooms
+    }
+
+    // forgets the detached sessions `gone` picks, e.g. of a banned user, returns the rooms they
+    // followed
+    pub fn close_detached(&self, gone: impl Fn(&Detached) -> bool) -> Vec<String> {
+        let mut rooms = Vec::new();
+        self.sessions.retain(|_, session| match &session.detached {
+            Some(detached) if gone(detached) => {
+                rooms.extend(detached.room.clone());
+                false
+            }
+            _ => true,
+        });
+        rooms
+    }
+
+    // attaches the session to a new client, None if it is unknown, expired or in use
+    pub fn resume(&self, token: &str, now: Instant) -> Option<Resumed> {
+        let detached = {
+            let mut session = self.sessions.get_mut(token)?;
+            if self.expired(session.detached.as_ref()?, now) {
+                return None;
+            }
+            session.detached.take()?
+        };
+        let mut pending = VecDeque::new();
+        let mut missed = 0;
+        if let Some(mut receiver) = detached.receiver {
+            loop {
+                match receiver.try_recv() {
+                    Ok((from, _)) if from == detached.addr => {}
+                    Ok((_, message)) => {
+                        if pending.len() >= self.buffer {
+                            pending.pop_front();
+                            missed += 1;
+                        }
+                        pending.push_back(message);
+                    }
+                    Err(TryRecvError::Lagged(n)) => missed += n,
+                    Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+                }
+            }
+        }
+        Some(Resumed {
+            token: token.to_string(),
+            username: detached.username,
+            account: detached.account,
+            room: detached.room,
+            pending: pending.into(),
+            missed,
+            unacked: detached.unacked,
+        })
+    }
+
+    fn expired(&self, detached: &Detached, now: Instant) -> bool {
+        now >= detached.at + self.window
+    }
+}
+
+impl Unacked {
+    // the number `message` is sent with, the oldest is forgotten beyond `limit`
+    pub fn push(&mut self, message: Arc<Message>, limit: usize) -> u64 {
+        self.seq += 1;
+        if self.messages.len() >= limit {
+            self.messages.pop_front();
+        }
+        self.messages.push_back((self.seq, message));
+        self.seq
+    }
+
+    // forgets what is numbered up to `seq`
+    pub fn ack(&mut self, seq: u64) {
+        while self
+            .messages
+            .front()
+            .is_some_and(|(first, _)| *first <= seq)
+        {
+            self.messages.pop_front();
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(u64, Arc<Message>)> {
+        self.messages.iter()
+    }
+
+    // without their numbers, for a client that doesn't ack
+    pub fn into_messages(self) -> impl Iterator<Item = Arc<Message>> {
+        self.messages.into_iter().map(|(_, message)| message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detached(
+        addr: SocketAddr,
+        room: Option<String>,
+        receiver: Option<broadcast::Receiver<RoomMessage>>,
+        at: Instant,
+    ) -> Detached {
+        Detached {
+            addr,
+            username: "alice".to_string(),
+            account: Some("alice".to_string()),
+            room,
+            receiver,
+            unacked: Unacked::default(),
+            at,
+        }
+    }
+
+    #[test]
+    fn detached_sessions_should_keep_the_last_messages() {
+        let sessions = Sessions::new(Duration::from_secs(60), 2);
+        let start = Instant::now();
+        let token = sessions.open();
+        assert!(sessions.resume(&token, start).is_none());
+
+        let (sender, receiver) = broadcast::channel(4);
+        let alice = SocketAddr::from(([127, 0, 0, 1], 5000));
+        let room = Some("#rust".to_string());
+        sessions.detach(&token, detached(alice, room, Some(receiver), start));
+        let from = SocketAddr::from(([127, 0, 0, 1], 5001));
+        for content in ["one", "two", "three", "four", "five"] {
+            sender
+                .send((from, Arc::new(Message::notice(content))))
+                .unwrap();
+        }
+        let left = Arc::new(Message::user_left("alice", "#rust"));
+        sender.send((alice, left)).unwrap();
+        let resumed = sessions.resume(&token, start).unwrap();
+        assert_eq!(resumed.username, "alice");
+        assert_eq!(resumed.room.as_deref(), Some("#rust"));
+        let contents: Vec<String> = resumed
+            .pending
+            .iter()
+            .map(|message| message.render(Default::default()))
+            .collect();
+        assert_eq!(contents, ["* four", "* five"]);
+        // one lagged behind the room, two more than the buffer held
+        assert_eq!(resumed.missed, 3);
+
+        // attached again, so it can't be resumed twice
+        assert!(sessions.resume(&token, start).is_none());
+    }
+
+    #[test]
+    fn sessions_should_expire_after_the_window() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 5000));
+        let sessions = Sessions::new(Duration::from_secs(60), 2);
+        let start = Instant::now();
+        let token = sessions.open();
+        let room = Some("#rust".to_string());
+        sessions.detach(&token, detached(addr, room, None, start));
+        assert!(sessions.following("#rust", start));
+
+        let over = start + Duration::from_secs(60);
+        assert!(sessions.resume(&token, over).is_none());
+        assert!(!sessions.following("#rust", over));
+        assert_eq!(sessions.expire(over), ["#rust"]);
+        // gone for good, even for a clock that says otherwise
+        assert!(sessions.resume(&token, start).is_none());
+    }
+
+    #[test]
+    fn acked_messages_should_be_forgotten() {
+        let mut unacked = Unacked::default();
+        for content in ["one", "two", "three", "four"] {
+            unacked.push(Arc::new(Message::notice(content)), 3);
+        }
+        // numbered on, "one" was forgotten past the limit
+        let seqs: Vec<u64> = unacked.iter().map(|(seq, _)| *seq).collect();
+        assert_eq!(seqs, [2, 3, 4]);
+        unacked.ack(3);
+        assert_eq!(unacked.push(Arc::new(Message::notice("five")), 3), 5);
+        let contents: Vec<String> = unacked
+            .into_messages()
+            .map(|message| message.render(Default::default()))
+            .collect();
+        assert_eq!(contents, ["* four", "* five"]);
+    }
+}
@@ -0,0 +1,118 @@
+// what the toml file at `CHAT_CONFIG` sets over the environment, every key optional, e.g.
+//
+//     rate = 2.0
+//     filters = ["spam", "words"]
+//     banned_words = ["darn"]
+//     bans = ["mallory", "10.0.0.7"]
+//     motd = """
+//     Welcome! Be nice.
+//     """
+//
+// read at startup, and again on SIGHUP or `/reload` at the console. sessions already running see
+// the new limits, filters and bans, and those logging in the new motd. the rest of the config waits for a restart
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::chat::{BanTarget, Config, RateLimit};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    rate: Option<f64>,
+    burst: Option<f64>,
+    mute_secs: Option<u64>,
+    ping_secs: Option<u64>,
+    idle_secs: Option<u64>,
+    max_drops: Option<u32>,
+    write_timeout_secs: Option<u64>,
+    max_line: Option<usize>,
+    operators: Option<Vec<String>>,
+    guests: Option<bool>,
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    mentions_across_rooms: Option<bool>,
+    filters: Option<Vec<String>>,
+    room_filters: Option<HashMap<String, Vec<String>>>,
+    banned_words: Option<Vec<String>>,
+    // usernames and addresses, banned for good while they are listed
+    bans: Option<Vec<String>>,
+    motd: Option<String>,
+}
+
+impl Settings {
+    pub fn read(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    // `config` with what the file sets over it
+    pub fn apply(self, config: &Config) -> Config {
+        let secs = |secs: Option<u64>, default: Duration| secs.map_or(default, Duration::from_secs);
+        let limit = &config.rate_limit;
+        Config {
+            rate_limit: RateLimit {
+                rate: self.rate.unwrap_or(limit.rate),
+                burst: self.burst.unwrap_or(limit.burst),
+                mute: secs(self.mute_secs, limit.mute),
+            },
+            ping_interval: secs(self.ping_secs, config.ping_interval),
+            idle_timeout: secs(self.idle_secs, config.idle_timeout),
+            max_drops: self.max_drops.unwrap_or(config.max_drops),
+            write_timeout: secs(self.write_timeout_secs, config.write_timeout),
+            max_line: self.max_line.unwrap_or(config.max_line),
+            operators: self.operators.unwrap_or_else(|| config.operators.clone()),
+            guests: self.guests.unwrap_or(config.guests),
+            max_connections: self.max_connections.unwrap_or(config.max_connections),
+            max_connections_per_ip: self
+                .max_connections_per_ip
+                .unwrap_or(config.max_connections_per_ip),
+            mentions_across_rooms: self
+                .mentions_across_rooms
+                .unwrap_or(config.mentions_across_rooms),
+            filters: self.filters.unwrap_or_else(|| config.filters.clone()),
+            room_filters: self
+                .room_filters
+                .unwrap_or_else(|| config.room_filters.clone()),
+            banned_words: self
+                .banned_words
+                .unwrap_or_else(|| config.banned_words.clone()),
+            bans: match self.bans {
+                Some(bans) => bans.iter().map(|ban| BanTarget::parse(ban)).collect(),
+                None => config.bans.clone(),
+            },
+            motd: self.motd.unwrap_or_else(|| config.motd.clone()),
+            ..config.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_what_may_change_should_be_read() {
+        let settings: Settings = toml::from_str(
+            r##"
+            rate = 2.0
+            room_filters = { "#kids" = ["words"] }
+            bans = ["mallory", "10.0.0.7"]
+            "##,
+        )
+        .unwrap();
+        assert_eq!(settings.rate, Some(2.0));
+        assert_eq!(settings.room_filters.unwrap()["#kids"], ["words"]);
+        assert!(settings.idle_secs.is_none());
+
+        // what needs a restart isn't read from the file
+        assert!(toml::from_str::<Settings>("redis_url = \"redis://x\"").is_err());
+        assert!(toml::from_str::<Settings>("rate = \"fast\"").is_err());
+    }
+}
@@ -0,0 +1,1419 @@
+// what the server keeps of everyone connected: peers by address and by name, the rooms they
+// are in, and the stores behind them. the sessions of every protocol go through `State`
+
+use std::collections::BTreeSet;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{fmt, net::SocketAddr};
+
+use anyhow::{Context, Result};
+use arc_swap::ArcSwap;
+use axum_server::tls_rustls::RustlsConfig;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use dashmap::{mapref::entry::Entry, DashMap, DashSet};
+use futures::Sink;
+use snow::Keypair;
+use sqlx::{sqlite::SqliteConnectOptions, SqlitePool};
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::time::Instant;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, field, info, info_span, warn, Instrument, Span};
+
+use crate::chat::audit::{Action, AuditLog, ADMIN};
+use crate::chat::auth::{verify_password, Accounts, Logins, GUEST_PREFIX, MIN_PASSWORD};
+use crate::chat::bots::Bots;
+use crate::chat::cluster::Cluster;
+use crate::chat::commands::Commands;
+use crate::chat::connections::Connections;
+use crate::chat::filters::Filters;
+use crate::chat::lines::Paste;
+use crate::chat::mentions::Mentions;
+use crate::chat::metrics::Metrics;
+use crate::chat::registry::{RoomInfo, RoomRegistry};
+use crate::chat::room_log::RoomLog;
+use crate::chat::scrollback::Scrollback;
+use crate::chat::sessions::{Detached, Resumed, Sessions, Unacked};
+use crate::chat::uploads::Uploads;
+use crate::chat::{
+    bots, cluster, mentions, nicknames, noise, AdminCommand, Ban, BanTarget, Bans, Config, Event,
+    History, HistoryEntry, Message, Outgoing, Protocol, Render, Writer, DEFAULT_ROOM, MAX_IGNORES,
+    MAX_MESSAGES, REPLAY_MESSAGES, TYPING_INTERVAL,
+};
+
+/// Everyone connected over any protocol, their rooms, and what is kept of them.
+#[derive(Debug)]
+pub struct State {
+    pub(super) peers: DashMap<SocketAddr, PeerHandle>,
+    // a room is gone once its last member leaves
+    pub(super) rooms: DashMap<String, Room>,
+    // username -> peer, a name is free again once its peer leaves
+    pub(super) names: DashMap<String, SocketAddr>,
+    // held while a name is claimed, see `claim`
+    claiming: Mutex<()>,
+    pub(super) history: History,
+    // the last messages of each room, read instead of `history`
+    pub(super) scrollback: Scrollback,
+    pub(super) bans: Bans,
+    // kicks, bans, topics and announcements, for the admin to look back at
+    audit: AuditLog,
+    pub(super) accounts: Accounts,
+    // topics and modes, the rooms themselves are only in `rooms`
+    pub(super) registry: RoomRegistry,
+    logins: Logins,
+    pub(super) sessions: Sessions,
+    pub(super) connections: Arc<Connections>,
+    // None unless the server shares its rooms with others
+    pub(super) cluster: Option<Cluster>,
+    // empty unless bots were attached
+    pub(super) bots: Bots,
+    // None unless rooms are logged to files
+    pub(super) room_log: Option<RoomLog>,
+    // None unless files may be shared
+    pub(super) uploads: Option<Uploads>,
+    // of the tcp and the websocket listener, None unless the config names a certificate
+    pub(super) tls: Option<RustlsConfig>,
+    // swapped whole by a reload, like `config`
+    pub(super) filters: ArcSwap<Filters>,
+    pub(super) commands: Commands,
+    // the environment's, what the config file is read over on every reload
+    base: Config,
+    // read anew wherever it is used, so a reload reaches sessions already running
+    config: ArcSwap<Config>,
+    // for the uptime of /server
+    pub(super) started_at: Instant,
+    // the id of the last connection, see `connection_span`
+    connection_id: AtomicU64,
+    // cancelled on SIGINT/SIGTERM, ends every session
+    pub(super) shutdown: CancellationToken,
+    // sessions and their writers, waited on for the grace period at shutdown
+    pub(super) tasks: TaskTracker,
+    pub(super) metrics: Metrics,
+}
+
+// a message of a room, with the peer it came from, who doesn't get it back
+pub(super) type RoomMessage = (SocketAddr, Arc<Message>);
+
+// where messages from the other servers of a cluster come from, no peer has it
+pub(super) const REMOTE: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+// one send reaches all members, each reads it from its own receiver
+#[derive(Debug)]
+pub(super) struct Room {
+    pub(super) members: DashSet<SocketAddr>,
+    sender: broadcast::Sender<RoomMessage>,
+}
+
+#[derive(Debug)]
+pub(super) struct Peer {
+    pub(super) username: String,
+    // the registered account it logged in to with its password, whatever its name is now.
+    // None for guests
+    pub(super) account: Option<String>,
+    // who it is to what it wrote: its account, or for a guest one of its connection's own
+    pub(super) identity: String,
+    // None after /leave, until the next /join
+    pub(super) room: Option<String>,
+    pub(super) protocol: Protocol,
+    pub(super) role: Role,
+    // the token of its session, None for guests
+    pub(super) session: Option<String>,
+    pub(super) render: Render,
+    pub(super) presence: Arc<Presence>,
+    pub(super) mentions: Arc<Mentions>,
+    pub(super) ignores: Arc<Ignores>,
+    // what it was sent and didn't ack, None for clients that don't
+    pub(super) unacked: Option<Arc<Mutex<Unacked>>>,
+    pub(super) typing: Typing,
+    // the lines of a /paste so far, until its /end
+    pub(super) paste: Option<Paste>,
+    // cancelled when an admin kicks the user, or when it can't keep up
+    pub(super) disconnect: CancellationToken,
+}
+
+// what the room was told of a peer typing, see `State::typing`
+#[derive(Debug, Default)]
+pub(super) struct Typing {
+    pub(super) active: bool,
+    // when it was last told the peer started
+    started_at: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Role {
+    User,
+    // may kick, ban and unban
+    Operator,
+}
+
+// where a client is in logging in
+#[derive(Debug)]
+pub(super) enum Login {
+    Username,
+    // the name is registered, its password is next
+    Password { username: String, hash: String },
+    // the name is free, the password to register it with is next
+    NewPassword(String),
+    // to a registered account
+    LoggedIn(String),
+    // under a temporary name, without an account
+    Guest(String),
+    // back within the window of a session
+    Resumed(Resumed),
+    // locked out, the connection is closed
+    Refused,
+}
+
+// how the others reach a connected peer
+#[derive(Debug, Clone)]
+pub(super) struct PeerHandle {
+    // see `Peer::identity`
+    pub(super) identity: String,
+    pub(super) sender: mpsc::Sender<Outgoing>,
+    pub(super) disconnect: CancellationToken,
+    pub(super) drops: Arc<Drops>,
+    pub(super) presence: Arc<Presence>,
+    pub(super) mentions: Arc<Mentions>,
+    pub(super) ignores: Arc<Ignores>,
+}
+
+// what /who shows of a peer, besides its name
+#[derive(Debug)]
+pub(super) struct Presence {
+    // when it joined its current room
+    joined_at: Mutex<DateTime<Utc>>,
+    // when it last typed anything but a pong
+    pub(super) active_at: Mutex<Instant>,
+    // the message while away, may be empty
+    pub(super) away: Mutex<Option<String>>,
+}
+
+// what a peer missed because its queue was full
+#[derive(Debug, Default)]
+pub(super) struct Drops {
+    pub(super) total: AtomicU64,
+    // since the last message that got through
+    pub(super) in_a_row: AtomicU64,
+    // times it was found behind, its queue full or its room gone on without it
+    pub(super) overflows: AtomicU64,
+    pub(super) last_overflow_at: Mutex<Option<DateTime<Utc>>>,
+}
+
+// the users a peer doesn't want to hear from, by name, until it disconnects
+#[derive(Debug, Default)]
+pub(super) struct Ignores {
+    names: Mutex<BTreeSet<String>>,
+}
+
+impl Presence {
+    pub(super) fn new() -> Self {
+        Self {
+            joined_at: Mutex::new(Utc::now()),
+            active_at: Mutex::new(Instant::now()),
+            away: Mutex::new(None),
+        }
+    }
+}
+
+impl Ignores {
+    // false if `name` was ignored already
+    pub(super) fn add(&self, name: &str) -> Result<bool, String> {
+        let mut names = self.names.lock().unwrap();
+        if names.len() >= MAX_IGNORES && !names.contains(name) {
+            return Err(format!("You ignore {} users already", MAX_IGNORES));
+        }
+        Ok(names.insert(name.to_string()))
+    }
+
+    pub(super) fn remove(&self, name: &str) -> bool {
+        self.names.lock().unwrap().remove(name)
+    }
+
+    // sorted by name
+    pub(super) fn list(&self) -> Vec<String> {
+        self.names.lock().unwrap().iter().cloned().collect()
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        self.names.lock().unwrap().contains(name)
+    }
+
+    // whether `message` is from someone ignored, anything not from a user isn't
+    pub(super) fn ignores(&self, message: &Message) -> bool {
+        message.sender().is_some_and(|sender| self.contains(sender))
+    }
+
+    // an ignored user stays ignored under a new name
+    fn rename(&self, old: &str, new: &str) {
+        let mut names = self.names.lock().unwrap();
+        if names.remove(old) {
+            names.insert(new.to_string());
+        }
+    }
+}
+
+impl Peer {
+    // whether it may change the room's topic and modes, and invite to it
+    pub(super) fn operates(&self, room: &RoomInfo) -> bool {
+        self.role == Role::Operator || room.is_operator(&self.identity)
+    }
+}
+
+// `joined 14:02:11, idle 3m, away: lunch`
+impl fmt::Display for Presence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let joined_at = *self.joined_at.lock().unwrap();
+        let idle = self.active_at.lock().unwrap().elapsed().as_secs();
+        write!(f, "joined {}, idle ", joined_at.format("%H:%M:%S"))?;
+        match idle {
+            0..60 => write!(f, "{}s", idle)?,
+            60..3600 => write!(f, "{}m", idle / 60)?,
+            _ => write!(f, "{}h", idle / 3600)?,
+        }
+        match self.away.lock().unwrap().as_deref() {
+            None => Ok(()),
+            Some("") => write!(f, ", away"),
+            Some(message) => write!(f, ", away: {}", message),
+        }
+    }
+}
+
+pub(super) async fn open_db(path: &str) -> Result<SqlitePool> {
+    let options = SqliteConnectOptions::new()
+        .filename(path)
+        .create_if_missing(true);
+    Ok(SqlitePool::connect_with(options).await?)
+}
+
+impl State {
+    /// The server over `config`, keeping its users, rooms and history in the sqlite database at
+    /// `db`. Its bots and its link to the other servers of a cluster run from here on.
+    pub async fn open(config: Config, db: &str) -> Result<Arc<Self>> {
+        let tls = match config.tls_cert.as_str() {
+            "" => None,
+            cert => {
+                let tls = RustlsConfig::from_pem_file(cert, &config.tls_key)
+                    .await
+                    .with_context(|| format!("failed to load tls cert {}", cert))?;
+                info!(cert, "clients connect over tls");
+                Some(tls)
+            }
+        };
+        let pool = open_db(db).await?;
+        let history = History::open(pool.clone()).await?;
+        let bans = Bans::open(pool.clone()).await?;
+        let audit = AuditLog::open(pool.clone()).await?;
+        let accounts = Accounts::open(pool.clone()).await?;
+        let registry = RoomRegistry::open(pool).await?;
+        let (cluster, published) = match config.redis_url.as_str() {
+            "" => (None, None),
+            _ => {
+                let (cluster, published) = Cluster::new();
+                (Some(cluster), Some(published))
+            }
+        };
+        let (bots, bot_events) = Bots::new(&config.bots)?;
+        let redis_url = config.redis_url.clone();
+        let mut state = State::new(
+            history, bans, audit, accounts, registry, cluster, bots, config,
+        )?;
+        state.tls = tls;
+        let state = Arc::new(state);
+        if !state.bots.is_empty() {
+            tokio::spawn(bots::run(Arc::clone(&state), bot_events));
+        }
+        if let Some(published) = published {
+            info!(url = %redis_url, "sharing rooms through redis");
+            let cloned_state = Arc::clone(&state);
+            tokio::spawn(async move {
+                if let Err(e) = cluster::run(cloned_state, redis_url, published).await {
+                    warn!(error = ?e, "cluster failed");
+                }
+            });
+        }
+        Ok(state)
+    }
+
+    /// The static key of [`serve_noise`], made and saved if its file is missing, a new one every
+    /// run if the config names none.
+    pub fn noise_key(&self) -> Result<Keypair> {
+        noise::load_key(&self.config().noise_key)
+    }
+
+    /// Whether files may be shared with /send, through [`serve_uploads`].
+    pub fn shares_files(&self) -> bool {
+        self.uploads.is_some()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn new(
+        history: History,
+        bans: Bans,
+        audit: AuditLog,
+        accounts: Accounts,
+        registry: RoomRegistry,
+        cluster: Option<Cluster>,
+        bots: Bots,
+        base: Config,
+    ) -> Result<Self> {
+        let metrics = Metrics::try_new()?;
+        let config = base.load()?;
+        Ok(Self {
+            peers: DashMap::new(),
+            rooms: DashMap::new(),
+            names: DashMap::new(),
+            claiming: Mutex::new(()),
+            history,
+            scrollback: Scrollback::new(config.scrollback),
+            bans,
+            audit,
+            accounts,
+            registry,
+            logins: Logins::new(config.max_login_failures, config.login_lockout),
+            sessions: Sessions::new(config.resume_window, config.resume_buffer),
+            cluster,
+            bots,
+            room_log: (!config.log_dir.is_empty()).then(|| RoomLog::start(&config.log_dir)),
+            uploads: match config.uploads_dir.as_str() {
+                "" => None,
+                dir => Some(Uploads::start(
+                    dir,
+                    &config.upload_url,
+                    config.max_upload,
+                    config.uploads_cap,
+                    config.upload_ttl,
+                )?),
+            },
+            tls: None,
+            filters: ArcSwap::from_pointee(Filters::new(
+                &config.filters,
+                &config.room_filters,
+                &config.banned_words,
+            )?),
+            connections: Arc::new(Connections::new(
+                config.max_connections,
+                config.max_connections_per_ip,
+            )),
+            commands: Commands::default(),
+            base,
+            config: ArcSwap::from_pointee(config),
+            started_at: Instant::now(),
+            connection_id: AtomicU64::new(0),
+            shutdown: CancellationToken::new(),
+            tasks: TaskTracker::new(),
+            metrics,
+        })
+    }
+
+    // to the members of `room` but the sender, here and on the other servers of a cluster
+    pub(super) fn broadcast(&self, room: &str, addr: SocketAddr, message: &Arc<Message>) {
+        if let Some(cluster) = &self.cluster {
+            cluster.publish(room, message);
+        }
+        if let Some(log) = &self.room_log {
+            log.append(room, message);
+        }
+        self.fan_out(room, addr, message);
+    }
+
+    // to the members of `room` on this server, one send however many members there are.
+    // a member reading too slowly lags behind, and is told what it missed
+    pub(super) fn fan_out(&self, room: &str, addr: SocketAddr, message: &Arc<Message>) {
+        if let Some(room) = self.rooms.get(room) {
+            self.metrics.messages_broadcast.inc();
+            // an error only means nobody is listening
+            let _ = room.sender.send((addr, message.clone()));
+        }
+    }
+
+    // ephemeral events, to the members of `room` on this server and nobody else: not to the
+    // cluster, the room log or detached sessions. a full queue drops it without counting
+    fn notify(&self, room: &str, addr: SocketAddr, message: &Arc<Message>) {
+        let Some(entry) = self.rooms.get(room) else {
+            return;
+        };
+        for member in entry.members.iter() {
+            if *member == addr {
+                continue;
+            }
+            if let Some(peer) = self.peers.get(&member) {
+                let _ = peer.sender.try_send(Outgoing::Message(Arc::clone(message)));
+            }
+        }
+    }
+
+    // tells the room a peer started or stopped typing. a start is told at most once per
+    // `TYPING_INTERVAL` and a stop only after a start, whatever the client sends
+    pub(super) fn typing(&self, addr: SocketAddr, peer: &mut Peer, active: bool) {
+        let Some(room) = &peer.room else {
+            return;
+        };
+        let now = Instant::now();
+        if active {
+            if peer
+                .typing
+                .started_at
+                .is_some_and(|at| now < at + TYPING_INTERVAL)
+            {
+                return;
+            }
+            peer.typing.started_at = Some(now);
+        } else if !peer.typing.active {
+            return;
+        }
+        peer.typing.active = active;
+        let message = Arc::new(Message::new(Event::Typing {
+            username: peer.username.clone(),
+            room: room.clone(),
+            active,
+        }));
+        self.notify(room, addr, &message);
+    }
+
+    // queue without waiting, a full queue drops the message. it leaves `peers` itself when
+    // its session ends
+    pub(super) fn deliver(&self, addr: SocketAddr, peer: &PeerHandle, message: Arc<Message>) {
+        if peer.ignores.ignores(&message) {
+            return;
+        }
+        match peer.sender.try_send(Outgoing::Message(message)) {
+            Ok(()) => peer.drops.in_a_row.store(0, Ordering::Relaxed),
+            // the session is ending already
+            Err(TrySendError::Closed(_)) => {}
+            Err(TrySendError::Full(_)) => self.dropped(addr, &peer.drops, &peer.disconnect, 1),
+        }
+    }
+
+    // highlights a chat message to the users it names, who may read it again with /mentions.
+    // only members of `room` are told, unless mentions go across rooms
+    pub(super) fn mention(&self, sender: &str, room: &str, content: &str) {
+        let mut mention = None;
+        for name in mentions::parse(content) {
+            let Some(addr) = self.names.get(name).map(|a| *a) else {
+                continue;
+            };
+            let member = self
+                .rooms
+                .get(room)
+                .is_some_and(|r| r.members.contains(&addr));
+            if name == sender || !(member || self.config().mentions_across_rooms) {
+                continue;
+            }
+            let Some(peer) = self.peers.get(&addr).map(|p| p.clone()) else {
+                continue;
+            };
+            if peer.ignores.contains(sender) {
+                continue;
+            }
+            let mention = mention.get_or_insert_with(|| {
+                Arc::new(Message::new(Event::Mention {
+                    sender: sender.to_string(),
+                    room: room.to_string(),
+                    content: content.to_string(),
+                }))
+            });
+            peer.mentions.add(Arc::clone(mention));
+            self.deliver(addr, &peer, Arc::clone(mention));
+        }
+    }
+
+    // too many drops in a row disconnect the peer
+    pub(super) fn dropped(
+        &self,
+        addr: SocketAddr,
+        drops: &Drops,
+        disconnect: &CancellationToken,
+        n: u64,
+    ) {
+        let total = drops.total.fetch_add(n, Ordering::Relaxed) + n;
+        let overflows = drops.overflows.fetch_add(1, Ordering::Relaxed) + 1;
+        *drops.last_overflow_at.lock().unwrap() = Some(Utc::now());
+        self.metrics.messages_dropped.inc_by(n);
+        self.metrics.queue_overflows.inc();
+        let in_a_row = drops.in_a_row.fetch_add(n, Ordering::Relaxed) + n;
+        debug!(%addr, missed = n, in_a_row, total, overflows, "peer fell behind");
+        if in_a_row >= u64::from(self.config().max_drops) && !disconnect.is_cancelled() {
+            warn!(
+                %addr,
+                in_a_row,
+                total,
+                overflows,
+                "disconnecting a peer which missed too many messages"
+            );
+            self.metrics.slow_disconnects.inc();
+            disconnect.cancel();
+        }
+    }
+
+    // replies to a session's own peer wait for room in its queue, only that session slows down
+    pub(super) async fn send(&self, addr: SocketAddr, message: Message) {
+        self.queue(addr, Outgoing::Message(Arc::new(message))).await;
+    }
+
+    pub(super) async fn queue(&self, addr: SocketAddr, outgoing: Outgoing) {
+        let Some(sender) = self.peers.get(&addr).map(|p| p.sender.clone()) else {
+            return;
+        };
+        if let Err(e) = sender.send(outgoing).await {
+            warn!(%addr, error = ?e, "failed to queue message");
+        }
+    }
+
+    // tells everyone, then ends every session, the writers keep flushing what is queued
+    pub async fn shut_down(&self) {
+        let goodbye = AdminCommand::Announce("Server is shutting down, goodbye".to_string());
+        let reply = self.admin(goodbye).await;
+        info!(%reply, "said goodbye");
+        self.shutdown.cancel();
+    }
+
+    // the reply is for the admin console
+    pub(super) async fn admin(&self, command: AdminCommand) -> String {
+        match command {
+            AdminCommand::Announce(content) => {
+                self.audit(Action::Announce, ADMIN, "", &content, "").await;
+                let message = Arc::new(Message::new(Event::ServerAnnouncement { content }));
+                // collected first, no lock is held across the sends
+                let peers: Vec<(SocketAddr, PeerHandle)> = self
+                    .peers
+                    .iter()
+                    .map(|p| (*p.key(), p.value().clone()))
+                    .collect();
+                for (addr, peer) in &peers {
+                    self.deliver(*addr, peer, message.clone());
+                }
+                format!("Announced to {} peers", peers.len())
+            }
+            AdminCommand::List => {
+                let mut users: Vec<(String, SocketAddr)> = self
+                    .names
+                    .iter()
+                    .map(|n| (n.key().clone(), *n.value()))
+                    .collect();
+                users.sort();
+                let mut reply = format!("{} users connected", users.len());
+                for (name, addr) in users {
+                    let room = self
+                        .rooms
+                        .iter()
+                        .find(|r| r.value().members.contains(&addr))
+                        .map(|r| r.key().clone())
+                        .unwrap_or_else(|| "-".to_string());
+                    let dropped = self
+                        .peers
+                        .get(&addr)
+                        .map(|p| p.drops.total.load(Ordering::Relaxed))
+                        .unwrap_or_default();
+                    reply.push_str(&format!("\n{} {} {} dropped={}", name, addr, room, dropped));
+                }
+                reply
+            }
+            AdminCommand::Kick(name) => {
+                match self.kick(&name, "You have been kicked by an admin") {
+                    Some(addr) => {
+                        self.audit(Action::Kick, ADMIN, &name, "", "").await;
+                        format!("Kicked {} ({})", name, addr)
+                    }
+                    None => format!("No such user: {}", name),
+                }
+            }
+            AdminCommand::Stats => format!(
+                "{} connections from {} addresses, {} peers, {} messages dropped, {} slow peers disconnected",
+                self.connections.total(),
+                self.connections.addresses(),
+                self.peers.len(),
+                self.metrics.messages_dropped.get(),
+                self.metrics.slow_disconnects.get()
+            ),
+            AdminCommand::Audit(n) => match self.audit.recent(n).await {
+                Ok(entries) => {
+                    let mut reply = format!("{} audit entries", entries.len());
+                    for entry in entries {
+                        reply.push_str(&format!("\n{}", entry));
+                    }
+                    reply
+                }
+                Err(e) => {
+                    warn!(error = ?e, "failed to read the audit log");
+                    "Failed to read the audit log".to_string()
+                }
+            },
+            AdminCommand::Reload => match self.reload() {
+                Ok(reply) => reply,
+                Err(e) => {
+                    warn!(error = ?e, "failed to reload config");
+                    format!("Failed to reload config: {:#}", e)
+                }
+            },
+        }
+    }
+
+    // one line per member of `room`, sorted by name
+    pub(super) fn who(&self, room: &str) -> Vec<String> {
+        let Some(room) = self.rooms.get(room) else {
+            return Vec::new();
+        };
+        let mut members: Vec<(String, SocketAddr)> = self
+            .names
+            .iter()
+            .filter(|n| room.members.contains(n.value()))
+            .map(|n| (n.key().clone(), *n.value()))
+            .collect();
+        members.sort();
+        members
+            .into_iter()
+            .filter_map(|(name, addr)| {
+                let presence = Arc::clone(&self.peers.get(&addr)?.presence);
+                Some(format!("{} {}", name, presence))
+            })
+            .collect()
+    }
+
+    // tells the user why, and ends its session. None if nobody has the name
+    pub(super) fn kick(&self, name: &str, reason: &str) -> Option<SocketAddr> {
+        let addr = self.names.get(name).map(|a| *a)?;
+        if let Some(peer) = self.peers.get(&addr).map(|p| p.clone()) {
+            self.deliver(addr, &peer, Arc::new(Message::notice(reason)));
+            peer.disconnect.cancel();
+        }
+        Some(addr)
+    }
+
+    // stored first, then whoever it applies to is kicked and told why. the reply is for the
+    // operator
+    pub(super) async fn ban(
+        &self,
+        target: &BanTarget,
+        duration: Option<Duration>,
+        by: &str,
+        reason: &str,
+    ) -> String {
+        let until =
+            duration.and_then(|d| chrono::Duration::from_std(d).ok().map(|d| Utc::now() + d));
+        if let Err(e) = self.bans.add(target, until, by).await {
+            warn!(%target, error = ?e, "failed to ban");
+            return format!("Failed to ban {}", target);
+        }
+        let ban = Ban { until };
+        info!(%target, %ban, by, "banned");
+        self.audit(
+            Action::Ban,
+            by,
+            &target.to_string(),
+            &ban.to_string(),
+            reason,
+        )
+        .await;
+        let notice = match reason {
+            "" => format!("You have been {} by {}", ban, by),
+            reason => format!("You have been {} by {}: {}", ban, by, reason),
+        };
+        let kicked = self.kick_banned(target, &notice);
+        format!("{} is {}, {} users kicked", target, ban, kicked)
+    }
+
+    // what was done stays done if it can't be written down, the failure is logged
+    pub(super) async fn audit(
+        &self,
+        action: Action,
+        actor: &str,
+        target: &str,
+        detail: &str,
+        reason: &str,
+    ) {
+        if let Err(e) = self
+            .audit
+            .record(action, actor, target, detail, reason)
+            .await
+        {
+            warn!(%action, actor, target, error = ?e, "failed to write the audit log");
+        }
+    }
+
+    // the users `target` applies to, how many were connected. their sessions are closed, a
+    // detached one can't be resumed anymore
+    fn kick_banned(&self, target: &BanTarget, reason: &str) -> usize {
+        let rooms = self.sessions.close_detached(|detached| match target {
+            BanTarget::User(name) => {
+                detached.username == *name || detached.account.as_ref() == Some(name)
+            }
+            BanTarget::Ip(ip) => detached.addr.ip() == *ip,
+        });
+        for room in rooms {
+            self.close_room(&room);
+        }
+        let names: Vec<String> = match target {
+            BanTarget::User(name) => vec![name.clone()],
+            BanTarget::Ip(ip) => self
+                .names
+                .iter()
+                .filter(|n| n.value().ip() == *ip)
+                .map(|n| n.key().clone())
+                .collect(),
+        };
+        names
+            .iter()
+            .filter(|name| self.kick(name, reason).is_some())
+            .count()
+    }
+
+    // configured bans first, then those of /ban
+    pub(super) async fn banned(&self, target: &BanTarget) -> Option<Ban> {
+        if self.config().bans.contains(target) {
+            return Some(Ban { until: None });
+        }
+        self.bans.banned(target).await
+    }
+
+    pub(super) fn config(&self) -> Arc<Config> {
+        self.config.load_full()
+    }
+
+    // the config file read again. the new limits apply to sessions as they read them, whoever
+    // the new bans apply to is kicked. the reply is for the admin
+    fn reload(&self) -> Result<String> {
+        let config = self.base.load()?;
+        let filters = Filters::new(&config.filters, &config.room_filters, &config.banned_words)?;
+        self.connections
+            .set_limits(config.max_connections, config.max_connections_per_ip);
+        self.filters.store(Arc::new(filters));
+        let config = Arc::new(config);
+        self.config.store(Arc::clone(&config));
+        let reason = format!("You have been {}", Ban { until: None });
+        let kicked: usize = config
+            .bans
+            .iter()
+            .map(|target| self.kick_banned(target, &reason))
+            .sum();
+        Ok(format!("Config reloaded, {} users kicked", kicked))
+    }
+
+    // all that is logged of one connection, its username once it logged in
+    pub(super) fn connection_span(&self, addr: SocketAddr) -> Span {
+        let id = self.connection_id.fetch_add(1, Ordering::Relaxed) + 1;
+        info_span!("connection", id, %addr, username = field::Empty)
+    }
+
+    // configured operators, by the account logged in to. never a guest, whatever its name
+    pub(super) fn role(&self, account: Option<&str>) -> Role {
+        match account {
+            Some(account) if self.config().operators.iter().any(|name| name == account) => {
+                Role::Operator
+            }
+            _ => Role::User,
+        }
+    }
+
+    // one line from a client logging in, and what to prompt it with next
+    pub(super) async fn login(&self, addr: SocketAddr, step: Login, line: &str) -> (Login, String) {
+        let ip = addr.ip();
+        if let Some(wait) = self.logins.locked(ip, Instant::now()) {
+            let prompt = format!(
+                "Too many failed logins, try again in {}s",
+                wait.as_secs() + 1
+            );
+            return (Login::Refused, prompt);
+        }
+        match step {
+            Login::Username if line.starts_with("/resume") => {
+                let token = line.trim_start_matches("/resume").trim();
+                let Some(resumed) = self.sessions.resume(token, Instant::now()) else {
+                    let failures = self.logins.failed(ip, Instant::now());
+                    warn!(failures, "failed resume");
+                    let prompt = "Unknown or expired session, enter your username:".to_string();
+                    return (Login::Username, prompt);
+                };
+                // banned since it dropped, under its name or its account, it may not come back
+                let account = resumed.account.clone();
+                let banned = match self.available(&resumed.username).await {
+                    Ok(()) => match account {
+                        Some(account) => self
+                            .banned(&BanTarget::User(account.clone()))
+                            .await
+                            .map(|ban| format!("Username {} is {}", account, ban)),
+                        None => None,
+                    },
+                    Err(e) => Some(e),
+                };
+                if let Some(e) = banned {
+                    self.sessions.close(&resumed.token);
+                    return (Login::Username, format!("{}, enter another one:", e));
+                }
+                // the name was the user's until it dropped, like a registered one
+                match self.claim(addr, &resumed.username, true) {
+                    Ok(()) => (Login::Resumed(resumed), String::new()),
+                    Err(e) => {
+                        self.sessions.close(&resumed.token);
+                        (Login::Username, format!("{}, enter another one:", e))
+                    }
+                }
+            }
+            Login::Username if line == "guest" && self.config().guests => {
+                // a few tries, the names are random
+                for _ in 0..10 {
+                    let name = format!("{}{:04}", GUEST_PREFIX, rand::random::<u16>() % 10000);
+                    if self.claim(addr, &name, false).is_ok() {
+                        return (Login::Guest(name), String::new());
+                    }
+                }
+                let prompt = "No guest names left, enter your username:".to_string();
+                (Login::Username, prompt)
+            }
+            Login::Username => {
+                let line = match nicknames::normalize(line) {
+                    Ok(name) => name,
+                    Err(e) => return (Login::Username, format!("{}, enter another one:", e)),
+                };
+                let line = line.as_str();
+                if let Err(e) = self.available(line).await {
+                    return (Login::Username, format!("{}, enter another one:", e));
+                }
+                if self.names.contains_key(line) {
+                    let prompt = format!("Username {} is taken, enter another one:", line);
+                    return (Login::Username, prompt);
+                }
+                match self.accounts.password_hash(line).await {
+                    // its owner logs in, whoever online looks like them
+                    Ok(Some(hash)) => {
+                        let username = line.to_string();
+                        (Login::Password { username, hash }, "Password:".to_string())
+                    }
+                    Ok(None) => {
+                        let unlike = match self.unlike_accounts(line, None).await {
+                            Ok(()) => self.unlike_others(addr, line),
+                            Err(e) => Err(e),
+                        };
+                        if let Err(e) = unlike {
+                            return (Login::Username, format!("{}, enter another one:", e));
+                        }
+                        let prompt = format!("{} is not registered yet, choose a password:", line);
+                        (Login::NewPassword(line.to_string()), prompt)
+                    }
+                    Err(e) => {
+                        warn!(username = line, error = ?e, "failed to look up account");
+                        let prompt = "Failed to look up username, enter it again:".to_string();
+                        (Login::Username, prompt)
+                    }
+                }
+            }
+            Login::Password { username, hash } => {
+                match verify_password(line.to_string(), hash).await {
+                    Ok(true) => {
+                        self.logins.succeeded(ip);
+                        self.logged_in(addr, username)
+                    }
+                    verified => {
+                        if let Err(e) = verified {
+                            warn!(%username, error = ?e, "failed to verify password");
+                        }
+                        let failures = self.logins.failed(ip, Instant::now());
+                        warn!(%username, failures, "failed login");
+                        let prompt = "Wrong password, enter your username:".to_string();
+                        (Login::Username, prompt)
+                    }
+                }
+            }
+            Login::NewPassword(username) => {
+                if line.chars().count() < MIN_PASSWORD {
+                    let prompt = format!(
+                        "A password has at least {} characters, choose another one:",
+                        MIN_PASSWORD
+                    );
+                    return (Login::NewPassword(username), prompt);
+                }
+                // held while registering, so nobody online takes it or one like it meanwhile
+                if let Err(e) = self.claim(addr, &username, false) {
+                    return (Login::Username, format!("{}, enter another one:", e));
+                }
+                let registered = self.accounts.register(&username, line).await;
+                if !matches!(registered, Ok(None)) {
+                    self.names.remove_if(&username, |_, a| *a == addr);
+                }
+                match registered {
+                    Ok(None) => {
+                        info!(%username, "registered");
+                        (Login::LoggedIn(username), String::new())
+                    }
+                    Ok(Some(other)) if other == username => {
+                        let prompt =
+                            format!("{} was just registered, enter another one:", username);
+                        (Login::Username, prompt)
+                    }
+                    Ok(Some(other)) => {
+                        let prompt = format!(
+                            "Username {} looks too much like {}, enter another one:",
+                            username, other
+                        );
+                        (Login::Username, prompt)
+                    }
+                    Err(e) => {
+                        warn!(%username, error = ?e, "failed to register");
+                        let prompt = "Failed to register, enter your username:".to_string();
+                        (Login::Username, prompt)
+                    }
+                }
+            }
+            step @ (Login::LoggedIn(_) | Login::Guest(_) | Login::Resumed(_) | Login::Refused) => {
+                (step, String::new())
+            }
+        }
+    }
+
+    // the name is claimed last, someone may have taken it during the password
+    fn logged_in(&self, addr: SocketAddr, username: String) -> (Login, String) {
+        match self.claim(addr, &username, true) {
+            Ok(()) => (Login::LoggedIn(username), String::new()),
+            Err(e) => (Login::Username, format!("{}, enter another one:", e)),
+        }
+    }
+
+    // who `name` is, to give a room or an invite to: whoever is online under it, or else the
+    // account of that name. a guest offline is nobody anymore
+    pub(super) async fn identify(&self, name: &str) -> Result<String, String> {
+        let online = self.names.get(name).map(|a| *a);
+        if let Some(handle) = online.and_then(|a| self.peers.get(&a).map(|p| p.clone())) {
+            return Ok(handle.identity);
+        }
+        match self.accounts.password_hash(name).await {
+            Ok(Some(_)) => Ok(name.to_string()),
+            Ok(None) => Err(format!("{} is neither online nor registered", name)),
+            Err(e) => {
+                warn!(username = name, error = ?e, "failed to look up account");
+                Err(format!("Failed to look up username {}", name))
+            }
+        }
+    }
+
+    // whether `name` may be logged in as or taken with /nick, if nobody has it
+    async fn available(&self, name: &str) -> Result<(), String> {
+        // the actor of the admin console in the audit log, nobody may pass for it
+        if nicknames::skeleton(name) == nicknames::skeleton(ADMIN) {
+            return Err(format!("Username {} is reserved", name));
+        }
+        if name.starts_with(GUEST_PREFIX) {
+            return Err(format!(
+                "Usernames starting with {} are for guests",
+                GUEST_PREFIX
+            ));
+        }
+        if self.bots.is_bot(name) {
+            return Err(format!("Username {} is a bot's", name));
+        }
+        match self.banned(&BanTarget::User(name.to_string())).await {
+            Some(ban) => Err(format!("Username {} is {}", name, ban)),
+            None => Ok(()),
+        }
+    }
+
+    // a name too like one in use by someone else would let its user pass for them
+    fn unlike_others(&self, addr: SocketAddr, name: &str) -> Result<(), String> {
+        let lookalike = self
+            .names
+            .iter()
+            .find(|n| *n.value() != addr && nicknames::confusable(name, n.key()))
+            .map(|n| n.key().clone());
+        match lookalike {
+            Some(other) => Err(format!("Username {} looks too much like {}", name, other)),
+            None => Ok(()),
+        }
+    }
+
+    // a new name too like a registered one would let its user pass for the owner. `own` is the
+    // user's name so far, alike to itself only
+    async fn unlike_accounts(&self, name: &str, own: Option<&str>) -> Result<(), String> {
+        match self.accounts.lookalike(name).await {
+            Ok(Some(other)) if Some(other.as_str()) != own => {
+                Err(format!("Username {} looks too much like {}", name, other))
+            }
+            Ok(_) => Ok(()),
+            Err(e) => {
+                warn!(username = name, error = ?e, "failed to look up account");
+                Err(format!("Failed to look up username {}", name))
+            }
+        }
+    }
+
+    // reserve `name` for the peer at `addr`, unless someone else has it. or one like it, unless
+    // the name is `registered` to the user: an account is its owner's, whoever passes for them.
+    // one claim at a time, or two alike names could both get past the check
+    pub(super) fn claim(
+        &self,
+        addr: SocketAddr,
+        name: &str,
+        registered: bool,
+    ) -> Result<(), String> {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err("A username is one word".to_string());
+        }
+        let _claiming = self.claiming.lock().unwrap();
+        if !registered {
+            // checked first, no shard may be locked while all of them are looked through
+            self.unlike_others(addr, name)?;
+        }
+        match self.names.entry(name.to_string()) {
+            Entry::Occupied(_) => Err(format!("Username {} is taken", name)),
+            Entry::Vacant(entry) => {
+                entry.insert(addr);
+                Ok(())
+            }
+        }
+    }
+
+    // the new name is claimed before the old one is released, so neither is ever free
+    // for someone else in between
+    pub(super) async fn rename(
+        &self,
+        addr: SocketAddr,
+        peer: &mut Peer,
+        name: &str,
+    ) -> Result<String, String> {
+        let name = nicknames::normalize(name)?;
+        let name = name.as_str();
+        if name == peer.username {
+            return Ok(name.to_string());
+        }
+        self.available(name).await?;
+        // a registered name is only for logging in with its password
+        match self.accounts.password_hash(name).await {
+            Ok(None) => {}
+            Ok(Some(_)) => return Err(format!("Username {} is registered", name)),
+            Err(e) => {
+                warn!(username = name, error = ?e, "failed to look up account");
+                return Err(format!("Failed to look up username {}", name));
+            }
+        }
+        self.unlike_accounts(name, Some(&peer.username)).await?;
+        self.claim(addr, name, false)?;
+        self.names.remove_if(&peer.username, |_, a| *a == addr);
+        let old = std::mem::replace(&mut peer.username, name.to_string());
+        info!(%old, new = name, "renamed");
+        Span::current().record("username", name);
+
+        for peer in self.peers.iter() {
+            peer.ignores.rename(&old, name);
+        }
+        let message = Arc::new(Message::new(Event::Renamed {
+            old,
+            new: name.to_string(),
+        }));
+        if let Some(room) = &peer.room {
+            self.broadcast(room, addr, &message);
+        }
+        Ok(name.to_string())
+    }
+
+    // a user is in one room at a time, joining leaves the current one
+    pub(super) async fn join(&self, addr: SocketAddr, peer: &mut Peer, room: &str) {
+        if peer.room.as_deref() == Some(room) {
+            return;
+        }
+        self.leave(addr, peer).await;
+        self.enter(addr, peer, room).await;
+        self.send(addr, Message::notice(format!("You joined {}", room)))
+            .await;
+        let info = self.room_info(room).await;
+        if !info.topic.is_empty() {
+            self.send(addr, Message::topic(room, &info.topic)).await;
+        }
+        self.replay(addr, room, REPLAY_MESSAGES).await;
+        self.bots.joined(room, &peer.username);
+    }
+
+    // operators join any room, the others invite-only ones only when invited
+    pub(super) async fn may_join(&self, peer: &Peer, room: &str) -> Result<(), String> {
+        let info = self.room_info(room).await;
+        if !info.invite_only || peer.operates(&info) {
+            return Ok(());
+        }
+        match self.registry.invited(room, &peer.identity).await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(format!("{} is invite only", room)),
+            // as with bans, a failing db lets everyone in
+            Err(e) => {
+                warn!(room, error = ?e, "failed to look up invite");
+                Ok(())
+            }
+        }
+    }
+
+    // only operators post in read-only rooms
+    pub(super) async fn may_post(&self, peer: &Peer, room: &str) -> Result<(), String> {
+        let info = self.room_info(room).await;
+        if info.read_only && !peer.operates(&info) {
+            return Err(format!("{} is read only", room));
+        }
+        Ok(())
+    }
+
+    // kept from its first /join on, with the configured topic and modes. whoever joined first
+    // runs it, unless a guest. the default room is the server operators' own
+    pub(super) async fn create_room(&self, addr: SocketAddr, peer: &Peer, room: &str) {
+        if room == DEFAULT_ROOM {
+            return;
+        }
+        let defaults = &self.config().room_defaults;
+        match self.registry.create(room, defaults, &peer.username).await {
+            Ok(false) => {}
+            Ok(true) if peer.account.is_none() => info!(room, "room created"),
+            Ok(true) => {
+                info!(room, "room created");
+                let added = self
+                    .registry
+                    .add_operator(room, &peer.identity, &peer.username)
+                    .await;
+                match added {
+                    Ok(()) => {
+                        let notice = format!("You created {}, you are its operator", room);
+                        self.send(addr, Message::notice(notice)).await;
+                    }
+                    Err(e) => warn!(room, error = ?e, "failed to add room operator"),
+                }
+            }
+            Err(e) => warn!(room, error = ?e, "failed to create room"),
+        }
+    }
+
+    // a failing db leaves a room without a topic, modes or operators
+    pub(super) async fn room_info(&self, room: &str) -> RoomInfo {
+        self.registry.get(room).await.unwrap_or_else(|e| {
+            warn!(room, error = ?e, "failed to look up room");
+            RoomInfo::default()
+        })
+    }
+
+    // into `room`, from no room
+    async fn enter(&self, addr: SocketAddr, peer: &mut Peer, room: &str) {
+        let receiver = {
+            let entry = self.rooms.entry(room.to_string()).or_insert_with(Room::new);
+            entry.members.insert(addr);
+            entry.sender.subscribe()
+        };
+        // followed before anyone is told, so no message of the room is missed
+        self.queue(addr, Outgoing::Room(Some(receiver))).await;
+        peer.room = Some(room.to_string());
+        *peer.presence.joined_at.lock().unwrap() = Utc::now();
+
+        info!(room, "joined");
+        let message = Arc::new(Message::user_joined(&peer.username, room));
+        self.broadcast(room, addr, &message);
+    }
+
+    // gone once nobody is in it, nor coming back to it
+    fn close_room(&self, room: &str) {
+        let now = Instant::now();
+        self.rooms.remove_if(room, |_, entry| {
+            entry.members.is_empty() && !self.sessions.following(room, now)
+        });
+    }
+
+    // expired sessions are only looked for when a new one opens
+    pub(super) fn open_session(&self) -> String {
+        for room in self.sessions.expire(Instant::now()) {
+            self.close_room(&room);
+        }
+        self.sessions.open()
+    }
+
+    // keeps the session of a lost peer, following its room until it is resumed
+    fn detach(&self, token: &str, addr: SocketAddr, peer: &Peer) {
+        info!("detached, the session is kept");
+        // subscribed before the peer leaves, so nothing said after is missed
+        let receiver = peer
+            .room
+            .as_ref()
+            .and_then(|room| self.rooms.get(room).map(|r| r.sender.subscribe()));
+        let unacked = peer
+            .unacked
+            .as_ref()
+            .map(|unacked| std::mem::take(&mut *unacked.lock().unwrap()))
+            .unwrap_or_default();
+        let detached = Detached {
+            addr,
+            username: peer.username.clone(),
+            account: peer.account.clone(),
+            room: peer.room.clone(),
+            receiver,
+            unacked,
+            at: Instant::now(),
+        };
+        self.sessions.detach(token, detached);
+    }
+
+    // back in the room of the session, with what was said there meanwhile
+    pub(super) async fn resume(&self, addr: SocketAddr, peer: &mut Peer, resumed: Resumed) {
+        info!(pending = resumed.pending.len(), "resumed the session");
+        let mut notice = format!("Welcome back, {} new messages", resumed.pending.len());
+        if resumed.missed > 0 {
+            notice.push_str(&format!(", {} more were dropped", resumed.missed));
+        }
+        self.send(addr, Message::notice(notice)).await;
+        // what wasn't acked is resent by the writer to a client that acks, numbered as before
+        for message in resumed.unacked.into_messages().chain(resumed.pending) {
+            self.queue(addr, Outgoing::Message(message)).await;
+        }
+        peer.session = Some(resumed.token);
+        match resumed.room {
+            Some(room) => self.enter(addr, peer, &room).await,
+            None => {
+                let notice = "You are not in a room, /join #room first";
+                self.send(addr, Message::notice(notice)).await;
+            }
+        }
+    }
+
+    // send the last `n` messages of `room` to the peer at `addr`
+    pub(super) async fn replay(&self, addr: SocketAddr, room: &str, n: i64) {
+        match self.recent(room, n).await {
+            Ok(entries) => {
+                for entry in entries {
+                    self.send(addr, Message::new(Event::History(entry))).await;
+                }
+            }
+            Err(e) => warn!(room, error = ?e, "failed to load history"),
+        }
+    }
+
+    // from the scrollback, which is filled from the db the first time. more than it keeps
+    // come from the db
+    async fn recent(&self, room: &str, n: i64) -> Result<Vec<HistoryEntry>> {
+        let n = usize::try_from(n)?;
+        if let Some(entries) = self.scrollback.recent(room, n) {
+            return Ok(entries);
+        }
+        if n > self.config().scrollback {
+            return self.history.recent(room, n as i64).await;
+        }
+        let entries = self
+            .history
+            .recent(room, self.config().scrollback as i64)
+            .await?;
+        self.scrollback.fill(room, entries);
+        Ok(self.scrollback.recent(room, n).unwrap_or_default())
+    }
+
+    // a chat message in the db and the scrollback, `author` is the identity of its sender
+    pub(super) async fn keep(&self, message: &Message, author: Option<&str>) {
+        if let Err(e) = self.history.append(message, author).await {
+            warn!(error = ?e, "failed to store message");
+        }
+        if let Some(entry) = HistoryEntry::of(message) {
+            self.scrollback.push(entry);
+        }
+    }
+
+    // returns the room left, None if not in one
+    pub(super) async fn leave(&self, addr: SocketAddr, peer: &mut Peer) -> Option<String> {
+        let room = peer.room.take()?;
+        peer.typing.active = false;
+        if let Some(entry) = self.rooms.get(&room) {
+            entry.members.remove(&addr);
+        }
+        self.close_room(&room);
+        self.queue(addr, Outgoing::Room(None)).await;
+
+        info!(room, "left");
+        let message = Arc::new(Message::user_left(&peer.username, &room));
+        self.broadcast(&room, addr, &message);
+        Some(room)
+    }
+
+    // after the client went away, whichever listener it came from
+    pub(super) async fn end_session(&self, addr: SocketAddr, peer: &mut Peer) {
+        // a lost connection may come back, a kicked or slow one may not
+        if let Some(token) = peer.session.take() {
+            if peer.disconnect.is_cancelled() || self.shutdown.is_cancelled() {
+                self.sessions.close(&token);
+            } else {
+                self.detach(&token, addr, peer);
+            }
+        }
+        // gone from `peers` first, leaving waits for nothing from a writer that may be stuck
+        self.peers.remove(&addr);
+        self.leave(addr, peer).await;
+        self.names.remove_if(&peer.username, |_, a| *a == addr);
+    }
+
+    // an operator if its account is one
+    pub(super) async fn add<Tx>(
+        self: &Arc<Self>,
+        addr: SocketAddr,
+        username: String,
+        account: Option<String>,
+        protocol: Protocol,
+        stream_sender: Tx,
+        unacked: Option<Unacked>,
+    ) -> Peer
+    where
+        Tx: Sink<Bytes, Error = anyhow::Error> + Unpin + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(MAX_MESSAGES);
+        let disconnect = CancellationToken::new();
+        let drops = Arc::<Drops>::default();
+        let presence = Arc::new(Presence::new());
+        let mentions = Arc::<Mentions>::default();
+        let ignores = Arc::<Ignores>::default();
+        let unacked = unacked.map(|unacked| Arc::new(Mutex::new(unacked)));
+        // nobody else starts with the prefix, see `available`
+        let identity = match &account {
+            Some(account) => account.clone(),
+            None => format!("{}#{:016x}", GUEST_PREFIX, rand::random::<u64>()),
+        };
+        self.peers.insert(
+            addr,
+            PeerHandle {
+                identity: identity.clone(),
+                sender: tx,
+                disconnect: disconnect.clone(),
+                drops: Arc::clone(&drops),
+                presence: Arc::clone(&presence),
+                mentions: Arc::clone(&mentions),
+                ignores: Arc::clone(&ignores),
+            },
+        );
+
+        // receive messages from the others, and send them to the client
+        let writer = Writer {
+            state: Arc::clone(self),
+            addr,
+            protocol,
+            drops,
+            ignores: Arc::clone(&ignores),
+            unacked: unacked.clone(),
+            disconnect: disconnect.clone(),
+        };
+        // logs in the span of its connection
+        self.tasks
+            .spawn(writer.run(rx, stream_sender).in_current_span());
+        // return a peer, not in any room yet
+        Peer {
+            role: self.role(account.as_deref()),
+            username,
+            account,
+            identity,
+            room: None,
+            protocol,
+            session: None,
+            render: Render::default(),
+            presence,
+            mentions,
+            ignores,
+            unacked,
+            typing: Typing::default(),
+            paste: None,
+            disconnect,
+        }
+    }
+}
+
+impl Room {
+    fn new() -> Self {
+        let (sender, _) = broadcast::channel(MAX_MESSAGES);
+        Self {
+            members: DashSet::new(),
+            sender,
+        }
+    }
+}
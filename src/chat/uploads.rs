@@ -0,0 +1,398 @@
+// files shared in a room: `/send report.pdf` reserves a slot, the file is PUT to the slot's url
+// on the upload listener, and the room gets a link to download it from. files are kept on
+// disk within a cap, until they expire or the server restarts
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use axum::{
+    body::Bytes,
+    extract::{Path, State as AxumState},
+    response::{IntoResponse, Response},
+};
+use dashmap::DashMap;
+use http::{
+    header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+    StatusCode,
+};
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::chat::{Event, Message, State, REMOTE};
+
+// how long a reserved slot waits for its file
+pub const SLOT_TTL: Duration = Duration::from_secs(10 * 60);
+// how often expired files are removed
+pub const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+// the dir of the server's own within the configured one, nothing else there is touched
+const OWN_DIR: &str = "chat-uploads";
+
+#[derive(Debug)]
+pub struct Uploads {
+    dir: PathBuf,
+    // where the upload listener is reached from outside, e.g. `https://chat.example.com:8082`
+    url: String,
+    max_size: usize,
+    // bytes of all files kept
+    cap: u64,
+    ttl: Duration,
+    // token -> what is to be uploaded with it
+    slots: DashMap<String, Slot>,
+    // id -> file on disk, counted against `cap` from before it is written
+    files: Mutex<HashMap<String, File>>,
+}
+
+#[derive(Debug)]
+struct Slot {
+    sender: String,
+    room: String,
+    filename: String,
+    expires_at: Instant,
+}
+
+#[derive(Debug)]
+struct File {
+    filename: String,
+    size: u64,
+    expires_at: Instant,
+}
+
+// an uploaded file, to be announced to its room
+#[derive(Debug, PartialEq)]
+pub struct Stored {
+    pub sender: String,
+    pub room: String,
+    pub filename: String,
+    pub size: u64,
+    pub url: String,
+}
+
+#[derive(Debug)]
+pub enum UploadError {
+    // never reserved, expired or used already
+    UnknownSlot,
+    TooLarge(usize),
+    Full,
+    Io(std::io::Error),
+}
+
+impl Uploads {
+    // files of an earlier run are gone, nobody could link to them anymore. only those, kept in
+    // a dir of their own: the configured one may well hold files of others
+    pub fn start(
+        dir: impl Into<PathBuf>,
+        url: &str,
+        max_size: usize,
+        cap: u64,
+        ttl: Duration,
+    ) -> Result<Self> {
+        let dir = dir.into().join(OWN_DIR);
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)?;
+        }
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            url: url.trim_end_matches('/').to_string(),
+            max_size,
+            cap,
+            ttl,
+            slots: DashMap::new(),
+            files: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // the url to PUT `filename` to
+    pub fn reserve(&self, sender: &str, room: &str, filename: &str) -> String {
+        let token = format!("{:032x}", rand::random::<u128>());
+        let slot = Slot {
+            sender: sender.to_string(),
+            room: room.to_string(),
+            filename: sanitize(filename),
+            expires_at: Instant::now() + SLOT_TTL,
+        };
+        self.slots.insert(token.clone(), slot);
+        format!("{}/upload/{}", self.url, token)
+    }
+
+    // the slot is used up, whether the file is stored or not
+    pub async fn store(&self, token: &str, body: &[u8]) -> Result<Stored, UploadError> {
+        let Some((_, slot)) = self
+            .slots
+            .remove_if(token, |_, slot| slot.expires_at > Instant::now())
+        else {
+            return Err(UploadError::UnknownSlot);
+        };
+        if body.len() > self.max_size {
+            return Err(UploadError::TooLarge(self.max_size));
+        }
+        let size = body.len() as u64;
+        let id = format!("{:016x}", rand::random::<u64>());
+        {
+            let mut files = self.files.lock().unwrap();
+            let used: u64 = files.values().map(|file| file.size).sum();
+            if used + size > self.cap {
+                return Err(UploadError::Full);
+            }
+            let file = File {
+                filename: slot.filename.clone(),
+                size,
+                expires_at: Instant::now() + self.ttl,
+            };
+            files.insert(id.clone(), file);
+        }
+        if let Err(e) = tokio::fs::write(self.dir.join(&id), body).await {
+            self.files.lock().unwrap().remove(&id);
+            return Err(UploadError::Io(e));
+        }
+        Ok(Stored {
+            url: format!("{}/files/{}/{}", self.url, id, slot.filename),
+            sender: slot.sender,
+            room: slot.room,
+            filename: slot.filename,
+            size,
+        })
+    }
+
+    // None for a file that expired, or was never uploaded under that name
+    pub async fn read(&self, id: &str, filename: &str) -> Option<Vec<u8>> {
+        {
+            let files = self.files.lock().unwrap();
+            let file = files.get(id)?;
+            if file.filename != filename || file.expires_at <= Instant::now() {
+                return None;
+            }
+        }
+        tokio::fs::read(self.dir.join(id)).await.ok()
+    }
+
+    // removes expired slots and files, returns how many files
+    pub async fn expire(&self, now: Instant) -> usize {
+        self.slots.retain(|_, slot| slot.expires_at > now);
+        let expired: Vec<String> = {
+            let mut files = self.files.lock().unwrap();
+            let expired: Vec<String> = files
+                .iter()
+                .filter(|(_, file)| file.expires_at <= now)
+                .map(|(id, _)| id.clone())
+                .collect();
+            for id in &expired {
+                files.remove(id);
+            }
+            expired
+        };
+        for id in &expired {
+            if let Err(e) = tokio::fs::remove_file(self.dir.join(id)).await {
+                warn!(id, error = ?e, "failed to remove expired upload");
+            }
+        }
+        expired.len()
+    }
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownSlot => write!(f, "unknown or expired upload slot"),
+            Self::TooLarge(max) => write!(f, "file too large, at most {} bytes", max),
+            Self::Full => write!(f, "no room for more files, try again later"),
+            Self::Io(e) => write!(f, "failed to store file: {}", e),
+        }
+    }
+}
+
+// PUT /upload/:token
+pub async fn upload_handler(
+    AxumState(state): AxumState<Arc<State>>,
+    Path(token): Path<String>,
+    body: Bytes,
+) -> Response {
+    let Some(uploads) = &state.uploads else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    match uploads.store(&token, &body).await {
+        Ok(stored) => {
+            info!(
+                room = stored.room,
+                filename = stored.filename,
+                stored.size,
+                "file uploaded"
+            );
+            let message = Arc::new(Message::new(Event::File {
+                sender: stored.sender,
+                room: stored.room.clone(),
+                filename: stored.filename,
+                size: stored.size,
+                url: stored.url.clone(),
+            }));
+            // from nobody in the room, so its sender is told too
+            state.broadcast(&stored.room, REMOTE, &message);
+            (StatusCode::CREATED, format!("{}\n", stored.url)).into_response()
+        }
+        Err(e) => {
+            let status = match e {
+                UploadError::UnknownSlot => StatusCode::NOT_FOUND,
+                UploadError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+                UploadError::Full => StatusCode::INSUFFICIENT_STORAGE,
+                UploadError::Io(_) => {
+                    warn!(error = %e, "failed to store upload");
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            };
+            (status, format!("{}\n", e)).into_response()
+        }
+    }
+}
+
+// GET /files/:id/:filename
+pub async fn download_handler(
+    AxumState(state): AxumState<Arc<State>>,
+    Path((id, filename)): Path<(String, String)>,
+) -> Response {
+    let Some(uploads) = &state.uploads else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    match uploads.read(&id, &filename).await {
+        Some(body) => (
+            [
+                (CONTENT_TYPE, "application/octet-stream".to_string()),
+                (
+                    CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"{}\"", filename),
+                ),
+            ],
+            body,
+        )
+            .into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+// until shutdown
+pub async fn run_cleanup(state: Arc<State>) {
+    let Some(uploads) = &state.uploads else {
+        return;
+    };
+    let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = state.shutdown.cancelled() => return,
+            _ = interval.tick() => {}
+        }
+        let expired = uploads.expire(Instant::now()).await;
+        if expired > 0 {
+            info!(expired, "removed expired uploads");
+        }
+    }
+}
+
+// the last part of a path, only letters, digits, `.`, `-` and `_`
+fn sanitize(filename: &str) -> String {
+    let name: String = filename
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    match name.trim_start_matches('.') {
+        "" => "file".to_string(),
+        name => name.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // uploads in a fresh dir, named after the test
+    fn test_uploads(name: &str, max_size: usize, cap: u64) -> Uploads {
+        let dir = std::env::temp_dir().join(format!("chat-{}-{}", name, std::process::id()));
+        let ttl = Duration::from_secs(60);
+        Uploads::start(dir, "http://chat.test/", max_size, cap, ttl).unwrap()
+    }
+
+    #[test]
+    fn start_should_only_clear_its_own_files() {
+        let dir = std::env::temp_dir().join(format!("chat-shared-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("notes.txt"), "keep me").unwrap();
+        let ttl = Duration::from_secs(60);
+        let uploads = Uploads::start(&dir, "http://chat.test/", 10, 100, ttl).unwrap();
+        std::fs::write(uploads.dir.join("left-over"), "from an earlier run").unwrap();
+
+        let uploads = Uploads::start(&dir, "http://chat.test/", 10, 100, ttl).unwrap();
+        assert!(!uploads.dir.join("left-over").exists());
+        assert_eq!(std::fs::read(dir.join("notes.txt")).unwrap(), b"keep me");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn token(url: &str) -> &str {
+        url.rsplit('/').next().unwrap()
+    }
+
+    #[test]
+    fn filenames_should_not_leave_the_dir() {
+        assert_eq!(sanitize("report.pdf"), "report.pdf");
+        assert_eq!(sanitize("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize("C:\\my files\\a b.txt"), "a_b.txt");
+        assert_eq!(sanitize(".."), "file");
+    }
+
+    #[tokio::test]
+    async fn uploads_should_stay_within_the_cap() {
+        let uploads = test_uploads("uploads", 8, 12);
+        let url = uploads.reserve("alice", "#rust", "notes.txt");
+        assert!(url.starts_with("http://chat.test/upload/"));
+        let stored = uploads.store(token(&url), b"12345678").await.unwrap();
+        assert_eq!((stored.room.as_str(), stored.size), ("#rust", 8));
+        let id = stored.url.split('/').nth_back(1).unwrap();
+        assert_eq!(
+            uploads.read(id, "notes.txt").await.unwrap(),
+            b"12345678".to_vec()
+        );
+        assert!(uploads.read(id, "other.txt").await.is_none());
+
+        // a slot is used once
+        assert!(matches!(
+            uploads.store(token(&url), b"1").await,
+            Err(UploadError::UnknownSlot)
+        ));
+        let url = uploads.reserve("alice", "#rust", "big.txt");
+        assert!(matches!(
+            uploads.store(token(&url), b"123456789").await,
+            Err(UploadError::TooLarge(8))
+        ));
+        let url = uploads.reserve("alice", "#rust", "more.txt");
+        assert!(matches!(
+            uploads.store(token(&url), b"12345").await,
+            Err(UploadError::Full)
+        ));
+    }
+
+    #[tokio::test]
+    async fn expired_files_should_be_removed() {
+        let uploads = test_uploads("expired-uploads", 8, 64);
+        let url = uploads.reserve("alice", "#rust", "a.txt");
+        let stored = uploads.store(token(&url), b"a").await.unwrap();
+        let id = stored.url.split('/').nth_back(1).unwrap();
+        uploads.reserve("alice", "#rust", "b.txt");
+
+        assert_eq!(uploads.expire(Instant::now()).await, 0);
+        let later = Instant::now() + SLOT_TTL + Duration::from_secs(60);
+        assert_eq!(uploads.expire(later).await, 1);
+        assert!(uploads.slots.is_empty());
+        assert!(uploads.read(id, "a.txt").await.is_none());
+        assert!(!uploads.dir.join(id).exists());
+    }
+}
@@ -0,0 +1,111 @@
+use std::{convert::Infallible, fmt, net::IpAddr, path::Path};
+
+use anyhow::Result;
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+use http::header::USER_AGENT;
+use maxminddb::geoip2;
+use woothee::parser::Parser;
+
+use crate::shortener::{client_ip::ClientIp, AppState};
+
+/// Country of a client address, as an ISO 3166 code.
+pub trait GeoLookup: fmt::Debug + Send + Sync {
+    fn country(&self, ip: IpAddr) -> Option<String>;
+}
+
+/// Used when no GeoIP database is configured.
+#[derive(Debug)]
+pub struct NoGeoLookup;
+
+/// Lookup in a MaxMind GeoLite2/GeoIP2 Country (or City) database.
+pub struct MaxMindLookup {
+    reader: maxminddb::Reader<Vec<u8>>,
+}
+
+/// What a click tells about its visitor, fields are None when unknown.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Visitor {
+    pub country: Option<String>,
+    pub browser: Option<String>,
+    pub os: Option<String>,
+    /// destination served by a split link, see `split`
+    pub arm: Option<i16>,
+}
+
+impl GeoLookup for NoGeoLookup {
+    fn country(&self, _ip: IpAddr) -> Option<String> {
+        None
+    }
+}
+
+impl MaxMindLookup {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let reader = maxminddb::Reader::open_readfile(path)?;
+        Ok(Self { reader })
+    }
+}
+
+impl fmt::Debug for MaxMindLookup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MaxMindLookup")
+            .field("database_type", &self.reader.metadata.database_type)
+            .finish()
+    }
+}
+
+impl GeoLookup for MaxMindLookup {
+    fn country(&self, ip: IpAddr) -> Option<String> {
+        let record: geoip2::Country = self.reader.lookup(ip).ok()?;
+        record.country?.iso_code.map(String::from)
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Visitor {
+    // analytics are best effort, never fail the redirect
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Infallible> {
+        let Ok(ClientIp(ip)) = ClientIp::from_request_parts(parts, state).await;
+        let (browser, os) = parts
+            .headers
+            .get(USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(parse_user_agent)
+            .unwrap_or_default();
+        Ok(Self {
+            country: ip.and_then(|ip| state.geo.country(ip)),
+            browser,
+            os,
+            arm: None,
+        })
+    }
+}
+
+// browser and os family, e.g. ("Chrome", "Windows 10")
+fn parse_user_agent(ua: &str) -> (Option<String>, Option<String>) {
+    let known =
+        |s: &str| (!s.is_empty() && s != woothee::woothee::VALUE_UNKNOWN).then(|| s.to_string());
+    match Parser::new().parse(ua) {
+        Some(ret) => (known(ret.name), known(ret.os)),
+        None => (None, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_agent_should_be_parsed() {
+        let chrome = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 \
+            (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36";
+        assert_eq!(
+            parse_user_agent(chrome),
+            (Some("Chrome".to_string()), Some("Windows 10".to_string()))
+        );
+        let curl = parse_user_agent("curl/8.5.0");
+        assert_eq!(curl.1, None);
+        assert_eq!(parse_user_agent("-"), (None, None));
+    }
+}
@@ -0,0 +1,108 @@
+use axum::{
+    extract::DefaultBodyLimit,
+    routing::{delete, get, post, put},
+    Router,
+};
+
+use crate::shortener::{
+    auth::{login_handler, register_handler},
+    batch_shorten_handler,
+    config::Config,
+    delete_link_handler,
+    domains::{add_domain_handler, delete_domain_handler, list_domains_handler},
+    hosts::{add_short_domain_handler, delete_short_domain_handler, list_short_domains_handler},
+    list_links_handler,
+    reports::{list_reports_handler, moderate_handler},
+    shorten_handler,
+    split::{get_split_handler, set_split_handler},
+    stats::{clicks_stream_handler, link_stats_handler, summary_handler},
+    tenants::{add_tenant_handler, delete_tenant_handler, list_tenants_handler},
+    transfer::{export_handler, import_handler},
+    trash::{list_trash_handler, restore_link_handler},
+    update_link_handler,
+    webhooks::{create_webhook_handler, delete_webhook_handler, list_webhooks_handler},
+    window::set_window_handler,
+    AppState,
+};
+
+/// Version 1 of the json api, nested under `/api/v1`.
+/// Breaking changes go to a new version served next to this one.
+pub fn v1(config: &Config) -> Router<AppState> {
+    Router::new()
+        .merge(links())
+        .merge(stats())
+        .merge(accounts())
+        .merge(admin())
+        .merge(webhooks())
+        .merge(transfer(config))
+}
+
+fn links() -> Router<AppState> {
+    Router::new()
+        .route("/links", get(list_links_handler).post(shorten_handler))
+        .route("/batch", post(batch_shorten_handler))
+        .route("/links/trash", get(list_trash_handler))
+        .route(
+            "/links/:id",
+            put(update_link_handler).delete(delete_link_handler),
+        )
+        .route("/links/:id/restore", post(restore_link_handler))
+        .route(
+            "/links/:id/destinations",
+            get(get_split_handler).put(set_split_handler),
+        )
+}
+
+fn stats() -> Router<AppState> {
+    Router::new()
+        .route("/links/:id/stats", get(link_stats_handler))
+        .route("/links/:id/clicks/stream", get(clicks_stream_handler))
+        .route("/stats/summary", get(summary_handler))
+}
+
+fn accounts() -> Router<AppState> {
+    Router::new()
+        .route("/users", post(register_handler))
+        .route("/login", post(login_handler))
+}
+
+fn admin() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/admin/domains",
+            get(list_domains_handler).post(add_domain_handler),
+        )
+        .route("/admin/domains/:domain", delete(delete_domain_handler))
+        .route(
+            "/admin/short-domains",
+            get(list_short_domains_handler).post(add_short_domain_handler),
+        )
+        .route(
+            "/admin/short-domains/:domain",
+            delete(delete_short_domain_handler),
+        )
+        .route(
+            "/admin/tenants",
+            get(list_tenants_handler).post(add_tenant_handler),
+        )
+        .route("/admin/tenants/:slug", delete(delete_tenant_handler))
+        .route("/admin/links/:id/window", put(set_window_handler))
+        .route("/admin/reports", get(list_reports_handler))
+        .route("/admin/reports/:id", post(moderate_handler))
+}
+
+fn webhooks() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/webhooks",
+            get(list_webhooks_handler).post(create_webhook_handler),
+        )
+        .route("/webhooks/:id", delete(delete_webhook_handler))
+}
+
+fn transfer(config: &Config) -> Router<AppState> {
+    Router::new().route("/export", get(export_handler)).route(
+        "/import",
+        post(import_handler).layer(DefaultBodyLimit::max(config.import_max_bytes)),
+    )
+}
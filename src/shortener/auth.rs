@@ -0,0 +1,315 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, State},
+    http::request::Parts,
+    response::IntoResponse,
+    Json,
+};
+use http::{header::AUTHORIZATION, StatusCode};
+use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::shortener::{AppError, AppState, ErrorResponse};
+
+/// HMAC keys used to sign and verify access tokens.
+pub struct Keys {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+}
+
+impl std::fmt::Debug for Keys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Keys").finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    // user id
+    pub sub: i64,
+    pub username: String,
+    pub exp: u64,
+}
+
+/// The authenticated caller, extracted from `Authorization: Bearer <token>`.
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub id: i64,
+    pub username: String,
+}
+
+/// Like `AuthUser`, but a request without `Authorization` header is let through as anonymous.
+/// A present but invalid token is still rejected.
+#[derive(Debug, Clone)]
+pub struct MaybeAuthUser(pub Option<AuthUser>);
+
+/// An authenticated caller whose user id is listed in `Config::admins`.
+#[derive(Debug, Clone)]
+pub struct AdminUser(pub AuthUser);
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TokenRes {
+    pub token: String,
+}
+
+#[derive(Debug)]
+struct UserRecord {
+    id: i64,
+    username: String,
+    password_hash: String,
+}
+
+impl Keys {
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+        }
+    }
+
+    pub fn sign(&self, user_id: i64, username: &str, ttl_secs: u64) -> Result<String, AppError> {
+        let claims = Claims {
+            sub: user_id,
+            username: username.to_string(),
+            exp: now_secs() + ttl_secs,
+        };
+        jsonwebtoken::encode(&Header::default(), &claims, &self.encoding)
+            .map_err(|e| AppError::Anyhow(e.into()))
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, AppError> {
+        let data = jsonwebtoken::decode::<Claims>(token, &self.decoding, &Validation::default())
+            .map_err(|e| AppError::Unauthorized(e.to_string()))?;
+        Ok(data.claims)
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for MaybeAuthUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, AppError> {
+        let Some(value) = parts.headers.get(AUTHORIZATION) else {
+            return Ok(Self(None));
+        };
+        let token = value
+            .to_str()
+            .ok()
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or_else(|| AppError::Unauthorized("expect a bearer token".to_string()))?;
+        let claims = state.keys.verify(token)?;
+        Ok(Self(Some(AuthUser {
+            id: claims.sub,
+            username: claims.username,
+        })))
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for AuthUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, AppError> {
+        MaybeAuthUser::from_request_parts(parts, state)
+            .await?
+            .0
+            .ok_or_else(|| AppError::Unauthorized("missing authorization header".to_string()))
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for AdminUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, AppError> {
+        let user = AuthUser::from_request_parts(parts, state).await?;
+        // by id: names are picked by whoever registers first
+        if !state.config.admins.contains(&user.id) {
+            return Err(AppError::Forbidden(format!(
+                "{} is not an admin",
+                user.username
+            )));
+        }
+        Ok(Self(user))
+    }
+}
+
+impl AppState {
+    pub async fn create_user(&self, username: &str, password: &str) -> Result<i64, AppError> {
+        let password_hash = hash_password(password.to_string()).await?;
+        let id = sqlx::query_scalar!(
+            "INSERT INTO users (username, password_hash) VALUES ($1, $2) RETURNING id",
+            username,
+            password_hash,
+        )
+        .fetch_one(self.db.primary())
+        .await?;
+        Ok(id)
+    }
+
+    // returns the user only if the password matches
+    pub async fn verify_user(&self, username: &str, password: &str) -> Result<AuthUser, AppError> {
+        let user = self
+            .db
+            .retry("verify_user", || {
+                sqlx::query_as!(
+                    UserRecord,
+                    "SELECT id, username, password_hash FROM users WHERE username = $1",
+                    username,
+                )
+                .fetch_optional(self.db.primary())
+            })
+            .await?;
+        let invalid = || AppError::Unauthorized("invalid username or password".to_string());
+        let user = user.ok_or_else(invalid)?;
+        if !verify_password(password.to_string(), user.password_hash).await? {
+            return Err(invalid());
+        }
+        Ok(AuthUser {
+            id: user.id,
+            username: user.username,
+        })
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/users",
+    tag = "users",
+    request_body = Credentials,
+    responses(
+        (status = 201, description = "user created, with an access token", body = TokenRes),
+        (status = 400, description = "invalid request", body = ErrorResponse),
+        (status = 409, description = "username taken", body = ErrorResponse),
+    )
+)]
+pub async fn register_handler(
+    State(state): State<AppState>,
+    Json(req): Json<Credentials>,
+) -> Result<impl IntoResponse, AppError> {
+    if req.username.is_empty() || req.password.len() < 8 {
+        return Err(AppError::BadRequest(
+            "username must not be empty and password needs at least 8 characters".to_string(),
+        ));
+    }
+    let id = state.create_user(&req.username, &req.password).await?;
+    let token = state
+        .keys
+        .sign(id, &req.username, state.config.token_ttl_secs)?;
+    Ok((StatusCode::CREATED, Json(TokenRes { token })))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/login",
+    tag = "users",
+    request_body = Credentials,
+    responses(
+        (status = 200, description = "access token", body = TokenRes),
+        (status = 401, description = "missing or invalid token", body = ErrorResponse),
+    )
+)]
+pub async fn login_handler(
+    State(state): State<AppState>,
+    Json(req): Json<Credentials>,
+) -> Result<Json<TokenRes>, AppError> {
+    let user = state.verify_user(&req.username, &req.password).await?;
+    let token = state
+        .keys
+        .sign(user.id, &user.username, state.config.token_ttl_secs)?;
+    Ok(Json(TokenRes { token }))
+}
+
+// argon2 is slow on purpose, keep it off the async workers
+pub async fn hash_password(password: String) -> Result<String, AppError> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| anyhow::anyhow!("failed to hash password: {e}"))
+    })
+    .await
+    .map_err(anyhow::Error::from)?
+    .map_err(AppError::from)
+}
+
+pub async fn verify_password(password: String, hash: String) -> Result<bool, AppError> {
+    tokio::task::spawn_blocking(move || {
+        let hash =
+            PasswordHash::new(&hash).map_err(|e| anyhow::anyhow!("invalid password hash: {e}"))?;
+        Ok::<_, anyhow::Error>(
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok(),
+        )
+    })
+    .await
+    .map_err(anyhow::Error::from)?
+    .map_err(AppError::from)
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time before unix epoch")
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_should_round_trip() {
+        let keys = Keys::new(b"secret");
+        let token = keys.sign(42, "alice", 60).unwrap();
+        let claims = keys.verify(&token).unwrap();
+        assert_eq!(claims.sub, 42);
+        assert_eq!(claims.username, "alice");
+
+        // signed with another secret
+        let ret = Keys::new(b"another").verify(&token);
+        assert!(matches!(ret.unwrap_err(), AppError::Unauthorized(_)));
+    }
+
+    #[test]
+    fn expired_token_should_be_rejected() {
+        let keys = Keys::new(b"secret");
+        let claims = Claims {
+            sub: 1,
+            username: "bob".to_string(),
+            // well past the default 60s leeway
+            exp: now_secs() - 3600,
+        };
+        let token = jsonwebtoken::encode(&Header::default(), &claims, &keys.encoding).unwrap();
+        assert!(matches!(
+            keys.verify(&token).unwrap_err(),
+            AppError::Unauthorized(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn password_should_verify() {
+        let hash = hash_password("correct horse".to_string()).await.unwrap();
+        assert!(verify_password("correct horse".to_string(), hash.clone())
+            .await
+            .unwrap());
+        assert!(!verify_password("battery staple".to_string(), hash)
+            .await
+            .unwrap());
+    }
+}
@@ -0,0 +1,283 @@
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Context, Result};
+use axum::{async_trait, extract::ConnectInfo, extract::FromRequestParts, http::request::Parts};
+use http::HeaderName;
+
+use crate::shortener::AppState;
+
+const FORWARDED: HeaderName = HeaderName::from_static("forwarded");
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+/// Address of the client, None if the server was started without connect info.
+///
+/// The socket peer, unless it is a trusted proxy: then the `Forwarded` (or, without
+/// it, `X-Forwarded-For`) chain is walked from the right, skipping trusted proxies,
+/// and the first other hop is the client. Entries left of it may be made up by
+/// the client and are never used.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+/// Networks of the proxies in front of the server, e.g. `10.0.0.0/8` or `::1`.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(Vec<Cidr>);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl TrustedProxies {
+    pub fn parse(cidrs: &[String]) -> Result<Self> {
+        let cidrs = cidrs
+            .iter()
+            .map(|s| {
+                s.parse()
+                    .with_context(|| format!("invalid trusted proxy: {s}"))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self(cidrs))
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    // the client of a request received from `peer`
+    fn resolve(&self, peer: IpAddr, parts: &Parts) -> IpAddr {
+        if !self.contains(peer) {
+            return peer;
+        }
+        let chain = if parts.headers.contains_key(FORWARDED) {
+            forwarded_for(parts)
+        } else {
+            x_forwarded_for(parts)
+        };
+        let mut client = peer;
+        for hop in chain.iter().rev() {
+            // a garbled entry can't be told apart from a spoofed one, stop at the proxy
+            let Some(ip) = hop else {
+                break;
+            };
+            client = *ip;
+            if !self.contains(client) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>()?, Some(prefix.parse::<u8>()?)),
+            None => (s.parse::<IpAddr>()?, None),
+        };
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        if prefix > max {
+            return Err(anyhow!("prefix /{prefix} is too long"));
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl Cidr {
+    fn contains(&self, ip: IpAddr) -> bool {
+        // ipv4 clients of a dual stack listener show up as mapped ipv6 addresses
+        let ip = ip.to_canonical();
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_eq(u32::from(net).into(), u32::from(ip).into(), 32, self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(u128::from(net), u128::from(ip), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+// whether the first `prefix` of `bits` bits of both addresses match
+fn prefix_eq(a: u128, b: u128, bits: u8, prefix: u8) -> bool {
+    if prefix == 0 {
+        return true;
+    }
+    let shift = bits - prefix;
+    a >> shift == b >> shift
+}
+
+// `for` of every element of all `Forwarded` headers (RFC 7239), in order.
+// None for entries without a usable address, e.g. `unknown` or obfuscated ones
+fn forwarded_for(parts: &Parts) -> Vec<Option<IpAddr>> {
+    parts
+        .headers
+        .get_all(FORWARDED)
+        .iter()
+        .flat_map(|v| v.to_str().unwrap_or_default().split(','))
+        .map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                .and_then(|(_, value)| parse_node(value.trim().trim_matches('"')))
+        })
+        .collect()
+}
+
+fn x_forwarded_for(parts: &Parts) -> Vec<Option<IpAddr>> {
+    parts
+        .headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .flat_map(|v| v.to_str().unwrap_or_default().split(','))
+        .map(|hop| parse_node(hop.trim()))
+        .collect()
+}
+
+// `192.0.2.1`, `192.0.2.1:80`, `2001:db8::1` or `[2001:db8::1]:80`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    node.parse::<IpAddr>()
+        .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| node.trim_start_matches('[').trim_end_matches(']').parse())
+        .ok()
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Infallible> {
+        Ok(client_ip(parts, &state.trusted_proxies))
+    }
+}
+
+pub fn client_ip(parts: &Parts, trusted: &TrustedProxies) -> ClientIp {
+    let peer = parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    ClientIp(peer.map(|peer| trusted.resolve(peer, parts)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROXY: [u8; 4] = [10, 0, 0, 1];
+
+    fn proxies(cidrs: &[&str]) -> TrustedProxies {
+        TrustedProxies::parse(&cidrs.iter().map(|s| s.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    // a request from `peer` with the given headers
+    fn parts(peer: [u8; 4], headers: &[(&str, &str)]) -> Parts {
+        let mut req = http::Request::get("/abc");
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        let req = req
+            .extension(ConnectInfo(SocketAddr::from((peer, 4321))))
+            .body(())
+            .unwrap();
+        req.into_parts().0
+    }
+
+    fn ip(s: &str) -> ClientIp {
+        ClientIp(Some(s.parse().unwrap()))
+    }
+
+    #[test]
+    fn forwarded_headers_should_be_ignored_from_untrusted_peers() {
+        let trusted = proxies(&["10.0.0.0/8"]);
+        let spoofed = parts(
+            [198, 51, 100, 9],
+            &[
+                ("x-forwarded-for", "203.0.113.7"),
+                ("forwarded", "for=203.0.113.7"),
+            ],
+        );
+        assert_eq!(client_ip(&spoofed, &trusted), ip("198.51.100.9"));
+        // nobody is trusted by default
+        let req = parts(PROXY, &[("x-forwarded-for", "203.0.113.7")]);
+        assert_eq!(client_ip(&req, &proxies(&[])), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn client_should_be_first_untrusted_hop_from_the_right() {
+        let trusted = proxies(&["10.0.0.0/8", "192.168.1.1"]);
+        // the client prepends a fake address, the proxies append the real ones
+        let req = parts(
+            PROXY,
+            &[("x-forwarded-for", "1.2.3.4, 203.0.113.7, 192.168.1.1")],
+        );
+        assert_eq!(client_ip(&req, &trusted), ip("203.0.113.7"));
+        // the same chain over several header lines
+        let req = parts(
+            PROXY,
+            &[
+                ("x-forwarded-for", "1.2.3.4"),
+                ("x-forwarded-for", "203.0.113.7"),
+            ],
+        );
+        assert_eq!(client_ip(&req, &trusted), ip("203.0.113.7"));
+        // only proxies in the chain: the farthest one
+        let req = parts(PROXY, &[("x-forwarded-for", "10.1.1.1, 192.168.1.1")]);
+        assert_eq!(client_ip(&req, &trusted), ip("10.1.1.1"));
+        // no header at all
+        assert_eq!(client_ip(&parts(PROXY, &[]), &trusted), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn garbled_hops_should_stop_the_walk() {
+        let trusted = proxies(&["10.0.0.0/8"]);
+        let req = parts(
+            PROXY,
+            &[("x-forwarded-for", "203.0.113.7, bogus, 10.2.2.2")],
+        );
+        assert_eq!(client_ip(&req, &trusted), ip("10.2.2.2"));
+        let req = parts(PROXY, &[("forwarded", "for=unknown")]);
+        assert_eq!(client_ip(&req, &trusted), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn forwarded_should_take_precedence() {
+        let trusted = proxies(&["10.0.0.0/8"]);
+        let req = parts(
+            PROXY,
+            &[
+                ("x-forwarded-for", "198.51.100.9"),
+                (
+                    "forwarded",
+                    r#"for=1.2.3.4, for="[2001:db8::1]:4711";proto=https, For=10.3.3.3:80"#,
+                ),
+            ],
+        );
+        assert_eq!(client_ip(&req, &trusted), ip("2001:db8::1"));
+    }
+
+    #[test]
+    fn cidrs_should_match_by_prefix() {
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.contains("10.255.0.1".parse().unwrap()));
+        assert!(cidr.contains("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(!cidr.contains("11.0.0.1".parse().unwrap()));
+        let cidr: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(cidr.contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!cidr.contains("2001:db9::1".parse().unwrap()));
+        assert!("0.0.0.0/0"
+            .parse::<Cidr>()
+            .unwrap()
+            .contains("8.8.8.8".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!(TrustedProxies::parse(&["nope".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use http::{HeaderName, HeaderValue, Method};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::shortener::{config::Config, request_id::X_REQUEST_ID};
+
+/// Cross-origin access for browser frontends, None when no origin is configured.
+/// `*` allows any origin. Tokens are sent as a header, so credentials are never allowed.
+pub fn layer(config: &Config) -> Result<Option<CorsLayer>> {
+    if config.cors_origins.is_empty() {
+        return Ok(None);
+    }
+    let origins = if config.cors_origins.iter().any(|o| o == "*") {
+        AllowOrigin::any()
+    } else {
+        let origins = config
+            .cors_origins
+            .iter()
+            .map(|o| {
+                o.parse::<HeaderValue>()
+                    .with_context(|| format!("invalid cors origin: {o}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        AllowOrigin::list(origins)
+    };
+    let methods = config
+        .cors_methods
+        .iter()
+        .map(|m| {
+            m.parse::<Method>()
+                .with_context(|| format!("invalid cors method: {m}"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let headers = config
+        .cors_headers
+        .iter()
+        .map(|h| {
+            h.parse::<HeaderName>()
+                .with_context(|| format!("invalid cors header: {h}"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Some(
+        CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(methods)
+            .allow_headers(headers)
+            .expose_headers([X_REQUEST_ID])
+            .max_age(Duration::from_secs(config.cors_max_age_secs)),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, routing::post, Router};
+    use http::{
+        header::{
+            ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+            ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_REQUEST_HEADERS,
+            ACCESS_CONTROL_REQUEST_METHOD, ORIGIN,
+        },
+        Request, StatusCode,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn config(origins: &[&str]) -> Config {
+        Config {
+            cors_origins: origins.iter().map(|o| o.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    async fn preflight(layer: CorsLayer, origin: &str) -> http::Response<Body> {
+        // post only, like most api routes
+        let app = Router::new()
+            .route("/api/links", post(|| async {}))
+            .layer(layer);
+        let req = Request::options("/api/links")
+            .header(ORIGIN, origin)
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .header(ACCESS_CONTROL_REQUEST_HEADERS, "authorization,content-type")
+            .body(Body::empty())
+            .unwrap();
+        app.oneshot(req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn preflight_should_allow_configured_origins() {
+        let cors = layer(&config(&["https://app.example.com"]))
+            .unwrap()
+            .unwrap();
+        let res = preflight(cors.clone(), "https://app.example.com").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let headers = res.headers();
+        assert_eq!(
+            headers[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert!(headers[ACCESS_CONTROL_ALLOW_METHODS]
+            .to_str()
+            .unwrap()
+            .contains("POST"));
+        assert!(headers[ACCESS_CONTROL_ALLOW_HEADERS]
+            .to_str()
+            .unwrap()
+            .contains("authorization"));
+
+        let res = preflight(cors, "https://evil.example.com").await;
+        assert!(res.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+
+        let any = layer(&config(&["*"])).unwrap().unwrap();
+        let res = preflight(any, "https://evil.example.com").await;
+        assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+    }
+
+    #[test]
+    fn layer_should_follow_config() {
+        assert!(layer(&config(&[])).unwrap().is_none());
+        assert!(layer(&config(&["bad\norigin"])).is_err());
+        let config = Config {
+            cors_methods: vec!["NOT A METHOD".to_string()],
+            ..config(&["*"])
+        };
+        assert!(layer(&config).is_err());
+    }
+}
@@ -0,0 +1,238 @@
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use sqlx::PgPool;
+use tracing::warn;
+
+// written ids are pruned once there are this many
+const PRUNE_AT: usize = 1024;
+
+/// Primary pool for writes and an optional read replica.
+///
+/// Redirects and stats may read slightly stale data, so they go to the
+/// replica, except for links written within the read-your-writes window:
+/// the replica may not have them yet, so these are read from the primary.
+#[derive(Debug, Clone)]
+pub struct Db {
+    primary: PgPool,
+    replica: Option<PgPool>,
+    recent: Arc<RecentWrites>,
+    retry: Retry,
+}
+
+/// How statements failing with a transient error are retried.
+#[derive(Debug, Clone, Copy)]
+pub struct Retry {
+    /// attempts in total, 1 disables retrying
+    pub attempts: u32,
+    /// longest wait before the first retry, doubled on every further one
+    pub backoff: Duration,
+}
+
+/// Ids written recently, with the time of their last write.
+#[derive(Debug)]
+struct RecentWrites {
+    window: Duration,
+    written: DashMap<String, Instant>,
+}
+
+impl Db {
+    pub fn new(primary: PgPool, replica: Option<PgPool>, window: Duration, retry: Retry) -> Self {
+        Self {
+            primary,
+            replica,
+            recent: Arc::new(RecentWrites {
+                window,
+                written: DashMap::new(),
+            }),
+            retry,
+        }
+    }
+
+    /// Run the statement built by `f` until it succeeds or fails for good.
+    /// Only for idempotent statements: a connection lost right after the commit
+    /// looks the same as one lost before it.
+    pub async fn retry<T, F, Fut>(&self, op: &str, f: F) -> Result<T, sqlx::Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, sqlx::Error>>,
+    {
+        self.retry.run(op, f).await
+    }
+
+    /// For writes and reads that must see them.
+    pub fn primary(&self) -> &PgPool {
+        &self.primary
+    }
+
+    /// For reads over all links, where replica lag doesn't matter.
+    pub fn replica(&self) -> &PgPool {
+        self.replica.as_ref().unwrap_or(&self.primary)
+    }
+
+    /// For reads of a single link, the primary if it was written recently.
+    pub fn reader(&self, id: &str) -> &PgPool {
+        match &self.replica {
+            Some(replica) if !self.recent.contains(id) => replica,
+            _ => &self.primary,
+        }
+    }
+
+    /// Remember a write to `id`, so it is read from the primary for a while.
+    pub fn wrote(&self, id: &str) {
+        if self.replica.is_some() {
+            self.recent.insert(id);
+        }
+    }
+
+    pub async fn close(&self) {
+        self.primary.close().await;
+        if let Some(replica) = &self.replica {
+            replica.close().await;
+        }
+    }
+}
+
+impl Retry {
+    async fn run<T, F, Fut>(&self, op: &str, mut f: F) -> Result<T, sqlx::Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, sqlx::Error>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f().await {
+                Err(e) if attempt < self.attempts && is_transient(&e) => {
+                    let delay = self.delay(attempt);
+                    warn!(op, attempt, ?delay, "transient db error, retrying: {e}");
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                ret => return ret,
+            }
+        }
+    }
+
+    // full jitter, so clients failing together don't retry together
+    fn delay(&self, attempt: u32) -> Duration {
+        let max = self.backoff * 2u32.saturating_pow(attempt - 1);
+        max.mul_f64(rand::random())
+    }
+}
+
+/// Whether the connection failed rather than the statement, e.g. a reset
+/// connection or no free one in time. Constraint violations and statement
+/// timeouts are not transient.
+pub fn is_transient(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::PoolTimedOut | sqlx::Error::Io(_) => true,
+        // connection exceptions, serialization failures, deadlocks and server shutdowns
+        sqlx::Error::Database(e) => e.code().is_some_and(|code| {
+            code.starts_with("08")
+                || ["40001", "40P01", "57P01", "57P02", "57P03"].contains(&&*code)
+        }),
+        _ => false,
+    }
+}
+
+impl RecentWrites {
+    fn contains(&self, id: &str) -> bool {
+        self.written
+            .get(id)
+            .is_some_and(|at| at.elapsed() < self.window)
+    }
+
+    fn insert(&self, id: &str) {
+        if self.written.len() >= PRUNE_AT {
+            self.written.retain(|_, at| at.elapsed() < self.window);
+        }
+        self.written.insert(id.to_string(), Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::atomic::{AtomicU32, Ordering},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn only_transient_errors_should_be_retried() {
+        let retry = Retry {
+            attempts: 3,
+            backoff: Duration::from_millis(1),
+        };
+        let calls = AtomicU32::new(0);
+        let ret = retry
+            .run("test", || async {
+                match calls.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(sqlx::Error::PoolTimedOut),
+                    1 => Err(sqlx::Error::Io(io::ErrorKind::ConnectionReset.into())),
+                    n => Ok(n),
+                }
+            })
+            .await;
+        assert_eq!(ret.unwrap(), 2);
+
+        // gives up after all attempts
+        calls.store(0, Ordering::SeqCst);
+        let ret: Result<(), _> = retry
+            .run("test", || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(sqlx::Error::PoolTimedOut)
+            })
+            .await;
+        assert!(matches!(ret, Err(sqlx::Error::PoolTimedOut)));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // permanent errors fail right away
+        calls.store(0, Ordering::SeqCst);
+        let ret: Result<(), _> = retry
+            .run("test", || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(sqlx::Error::RowNotFound)
+            })
+            .await;
+        assert!(ret.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn backoff_should_grow_with_attempts() {
+        let retry = Retry {
+            attempts: 5,
+            backoff: Duration::from_millis(100),
+        };
+        for _ in 0..32 {
+            assert!(retry.delay(1) <= Duration::from_millis(100));
+            assert!(retry.delay(3) <= Duration::from_millis(400));
+        }
+    }
+
+    #[test]
+    fn recent_writes_should_expire() {
+        let recent = RecentWrites {
+            window: Duration::from_millis(50),
+            written: DashMap::new(),
+        };
+        recent.insert("abc123");
+        assert!(recent.contains("abc123"));
+        assert!(!recent.contains("xyz789"));
+        for i in 1..PRUNE_AT {
+            recent.insert(&i.to_string());
+        }
+        assert_eq!(recent.written.len(), PRUNE_AT);
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!recent.contains("abc123"));
+        recent.insert("fresh");
+        assert_eq!(recent.written.len(), 1);
+    }
+}
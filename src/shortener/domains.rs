@@ -0,0 +1,287 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::prelude::FromRow;
+use tracing::info;
+use url::{Host, Url};
+use utoipa::ToSchema;
+
+use crate::shortener::{auth::AdminUser, AppError, AppState, ErrorResponse};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleKind {
+    Block,
+    Allow,
+}
+
+/// A block or allow rule for a destination domain, it applies to all subdomains too.
+#[derive(Debug, Clone, FromRow, Serialize, ToSchema)]
+pub struct DomainRule {
+    pub domain: String,
+    #[sqlx(try_from = "String")]
+    pub kind: RuleKind,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct DomainRuleReq {
+    pub domain: String,
+    pub kind: RuleKind,
+}
+
+impl RuleKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Block => "block",
+            Self::Allow => "allow",
+        }
+    }
+}
+
+impl TryFrom<String> for RuleKind {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.as_str() {
+            "block" => Ok(Self::Block),
+            "allow" => Ok(Self::Allow),
+            _ => Err(format!("unknown rule kind: {s}")),
+        }
+    }
+}
+
+impl AppState {
+    /// Check destination urls against the domain rules, one result per url.
+    /// Unparsable urls pass here, they are rejected by url validation.
+    pub async fn check_domains(
+        &self,
+        urls: &[String],
+    ) -> Result<Vec<Result<(), String>>, AppError> {
+        let hosts: Vec<Option<Vec<String>>> = urls.iter().map(|url| candidates(url)).collect();
+        let all: Vec<String> = hosts.iter().flatten().flatten().cloned().collect();
+        let rules: Vec<(String, String)> =
+            sqlx::query_as("SELECT domain, kind FROM domain_rules WHERE domain = ANY($1)")
+                .bind(&all)
+                .fetch_all(self.db.primary())
+                .await?;
+        let rules: HashMap<String, RuleKind> = rules
+            .into_iter()
+            .filter_map(|(domain, kind)| Some((domain, RuleKind::try_from(kind).ok()?)))
+            .collect();
+
+        Ok(hosts
+            .iter()
+            .map(|candidates| match candidates {
+                Some(candidates) => evaluate(candidates, &rules, self.config.domain_allowlist),
+                None => Ok(()),
+            })
+            .collect())
+    }
+
+    pub async fn check_domain(&self, url: &str) -> Result<(), AppError> {
+        let ret = self.check_domains(&[url.to_string()]).await?;
+        ret.into_iter()
+            .next()
+            .unwrap_or(Ok(()))
+            .map_err(AppError::Forbidden)
+    }
+
+    async fn list_domain_rules(&self) -> Result<Vec<DomainRule>, AppError> {
+        let rules =
+            sqlx::query_as("SELECT domain, kind, created_at FROM domain_rules ORDER BY domain")
+                .fetch_all(self.db.primary())
+                .await?;
+        Ok(rules)
+    }
+
+    async fn upsert_domain_rule(
+        &self,
+        domain: &str,
+        kind: RuleKind,
+    ) -> Result<DomainRule, AppError> {
+        let rule = sqlx::query_as(
+            r#"
+            INSERT INTO domain_rules (domain, kind) VALUES ($1, $2)
+            ON CONFLICT(domain) DO UPDATE SET kind = excluded.kind
+            RETURNING domain, kind, created_at
+            "#,
+        )
+        .bind(domain)
+        .bind(kind.as_str())
+        .fetch_one(self.db.primary())
+        .await?;
+        Ok(rule)
+    }
+
+    async fn delete_domain_rule(&self, domain: &str) -> Result<bool, AppError> {
+        let ret = sqlx::query("DELETE FROM domain_rules WHERE domain = $1")
+            .bind(domain)
+            .execute(self.db.primary())
+            .await?;
+        Ok(ret.rows_affected() > 0)
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/admin/domains",
+    tag = "admin",
+    responses(
+        (status = 200, description = "all domain rules", body = Vec<DomainRule>),
+        (status = 401, description = "missing or invalid token", body = ErrorResponse),
+        (status = 403, description = "not allowed", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn list_domains_handler(
+    State(state): State<AppState>,
+    _admin: AdminUser,
+) -> Result<Json<Vec<DomainRule>>, AppError> {
+    Ok(Json(state.list_domain_rules().await?))
+}
+
+/// Add a rule, or change the kind of an existing one.
+#[utoipa::path(
+    post,
+    path = "/api/v1/admin/domains",
+    tag = "admin",
+    request_body = DomainRuleReq,
+    responses(
+        (status = 201, description = "rule set", body = DomainRule),
+        (status = 400, description = "invalid request", body = ErrorResponse),
+        (status = 401, description = "missing or invalid token", body = ErrorResponse),
+        (status = 403, description = "not allowed", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn add_domain_handler(
+    State(state): State<AppState>,
+    AdminUser(admin): AdminUser,
+    Json(req): Json<DomainRuleReq>,
+) -> Result<impl IntoResponse, AppError> {
+    let domain = normalize_domain(&req.domain).map_err(AppError::BadRequest)?;
+    let rule = state.upsert_domain_rule(&domain, req.kind).await?;
+    info!(
+        target: "audit",
+        admin = %admin.username,
+        domain,
+        kind = req.kind.as_str(),
+        "domain rule set"
+    );
+    Ok((StatusCode::CREATED, Json(rule)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/admin/domains/{domain}",
+    tag = "admin",
+    params(("domain" = String, Path)),
+    responses(
+        (status = 204, description = "rule removed"),
+        (status = 401, description = "missing or invalid token", body = ErrorResponse),
+        (status = 403, description = "not allowed", body = ErrorResponse),
+        (status = 404, description = "no such rule", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn delete_domain_handler(
+    State(state): State<AppState>,
+    AdminUser(admin): AdminUser,
+    Path(domain): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let domain = normalize_domain(&domain).map_err(AppError::BadRequest)?;
+    if !state.delete_domain_rule(&domain).await? {
+        return Err(AppError::HttpNotFound(domain));
+    }
+    info!(target: "audit", admin = %admin.username, domain, "domain rule removed");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// the host of the url and all its parent domains, `a.b.com` -> [a.b.com, b.com, com]
+pub fn candidates(url: &str) -> Option<Vec<String>> {
+    let url = Url::parse(url).ok()?;
+    match url.host()? {
+        Host::Domain(domain) => {
+            let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+            let parts: Vec<&str> = domain.split('.').collect();
+            Some((0..parts.len()).map(|i| parts[i..].join(".")).collect())
+        }
+        ip => Some(vec![ip.to_string()]),
+    }
+}
+
+// a blocking rule always wins, in allowlist mode some allowing rule must match
+fn evaluate(
+    candidates: &[String],
+    rules: &HashMap<String, RuleKind>,
+    allowlist: bool,
+) -> Result<(), String> {
+    let host = candidates.first().map(String::as_str).unwrap_or_default();
+    let matches = |kind| candidates.iter().any(|d| rules.get(d) == Some(&kind));
+    if matches(RuleKind::Block) {
+        return Err(format!("domain {host} is blocked"));
+    }
+    if allowlist && !matches(RuleKind::Allow) {
+        return Err(format!("domain {host} is not on the allowlist"));
+    }
+    Ok(())
+}
+
+pub fn normalize_domain(domain: &str) -> Result<String, String> {
+    let domain = domain.trim().trim_matches('.').to_ascii_lowercase();
+    let valid = !domain.is_empty()
+        && domain
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+    if !valid {
+        return Err(format!("invalid domain: {domain}"));
+    }
+    Ok(domain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn candidates_should_include_parent_domains() {
+        assert_eq!(
+            candidates("https://A.b.example.com./path").unwrap(),
+            ["a.b.example.com", "b.example.com", "example.com", "com"]
+        );
+        assert_eq!(candidates("http://127.0.0.1:8080/").unwrap(), ["127.0.0.1"]);
+        assert!(candidates("not a url").is_none());
+    }
+
+    #[test]
+    fn evaluate_should_apply_rules() {
+        let rules = HashMap::from([
+            ("evil.com".to_string(), RuleKind::Block),
+            ("good.com".to_string(), RuleKind::Allow),
+            ("bad.good.com".to_string(), RuleKind::Block),
+        ]);
+        let check = |url, allowlist| evaluate(&candidates(url).unwrap(), &rules, allowlist);
+
+        assert!(check("https://www.evil.com/", false).is_err());
+        assert!(check("https://www.other.com/", false).is_ok());
+        assert!(check("https://www.other.com/", true).is_err());
+        assert!(check("https://www.good.com/", true).is_ok());
+        // block wins over a broader allow
+        assert!(check("https://x.bad.good.com/", true).is_err());
+    }
+
+    #[test]
+    fn normalize_domain_should_work() {
+        assert_eq!(normalize_domain(" Example.COM. ").unwrap(), "example.com");
+        assert!(normalize_domain("").is_err());
+        assert!(normalize_domain("example.com/path").is_err());
+    }
+}
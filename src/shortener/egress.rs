@@ -0,0 +1,199 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Result;
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect::Policy,
+    Method, RequestBuilder, Url,
+};
+
+const MAX_REDIRECTS: usize = 5;
+
+/// Outgoing requests to urls of users: link previews, webhook deliveries and
+/// liveness checks.
+///
+/// Unless private networks are allowed, hosts only resolve to public addresses and
+/// urls naming a private address are refused, on every redirect too. Otherwise any
+/// short link could be used to probe the network of the server.
+#[derive(Debug, Clone)]
+pub struct Egress {
+    client: reqwest::Client,
+    allow_private: bool,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("{0} is not a public address")]
+pub struct Refused(String);
+
+impl Egress {
+    pub fn new(timeout: Duration, allow_private: bool) -> Result<Self> {
+        let builder = reqwest::Client::builder().timeout(timeout);
+        let builder = match allow_private {
+            true => builder.redirect(Policy::limited(MAX_REDIRECTS)),
+            false => builder
+                .dns_resolver(Arc::new(PublicResolver))
+                .redirect(Policy::custom(|attempt| {
+                    if attempt.previous().len() >= MAX_REDIRECTS {
+                        return attempt.error("too many redirects");
+                    }
+                    match check(attempt.url()) {
+                        Ok(()) => attempt.follow(),
+                        Err(e) => attempt.error(e),
+                    }
+                })),
+        };
+        Ok(Self {
+            client: builder.build()?,
+            allow_private,
+        })
+    }
+
+    /// The underlying client, for requests to fixed urls of the operator.
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    // hostnames are checked once resolved, addresses in the url right away
+    pub fn request(&self, method: Method, url: &str) -> Result<RequestBuilder, Refused> {
+        if !self.allow_private {
+            if let Ok(parsed) = Url::parse(url) {
+                check(&parsed)?;
+            }
+        }
+        Ok(self.client.request(method, url))
+    }
+
+    pub fn get(&self, url: &str) -> Result<RequestBuilder, Refused> {
+        self.request(Method::GET, url)
+    }
+
+    pub fn post(&self, url: &str) -> Result<RequestBuilder, Refused> {
+        self.request(Method::POST, url)
+    }
+}
+
+// a url naming an address must name a public one
+fn check(url: &Url) -> Result<(), Refused> {
+    let ip: IpAddr = match url.host() {
+        Some(url::Host::Ipv4(ip)) => ip.into(),
+        Some(url::Host::Ipv6(ip)) => ip.into(),
+        _ => return Ok(()),
+    };
+    match is_public(ip) {
+        true => Ok(()),
+        false => Err(Refused(ip.to_string())),
+    }
+}
+
+// drops private addresses, a host with none left does not resolve
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(Refused(name.as_str().to_string()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Not loopback, private, link-local, unique-local or otherwise not routable.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    // 100.64.0.0/10 is shared by carrier-grade nat
+    let shared = a == 100 && (b & 0xc0) == 64;
+    !(ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || shared)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    // fc00::/7 unique local, fe80::/10 link local
+    let unique_local = (first & 0xfe00) == 0xfc00;
+    let link_local = (first & 0xffc0) == 0xfe80;
+    !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || unique_local || link_local)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_public_addresses_should_be_public() {
+        let private = [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "100.64.0.1",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:10.0.0.1",
+        ];
+        for ip in private {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["93.184.216.34", "1.1.1.1", "2606:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[tokio::test]
+    async fn private_destinations_should_be_refused() {
+        let egress = Egress::new(Duration::from_secs(1), false).unwrap();
+        for url in [
+            "http://127.0.0.1:1/",
+            "http://[::1]:1/",
+            "http://169.254.169.254/latest/meta-data",
+            // other spellings of 127.0.0.1
+            "http://2130706433/",
+            "http://0x7f.1/",
+        ] {
+            assert!(egress.get(url).is_err(), "{url}");
+        }
+
+        // names resolving to private addresses fail once resolved
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let routes = axum::Router::new().route("/", axum::routing::get(|| async { "ok" }));
+        tokio::spawn(async move { axum::serve(listener, routes).await });
+        let url = format!("http://localhost:{port}/");
+        let ret = egress.get(&url).unwrap().send().await;
+        assert!(ret.is_err());
+
+        // unless private networks are allowed
+        let egress = Egress::new(Duration::from_secs(1), true).unwrap();
+        let res = egress.get(&url).unwrap().send().await.unwrap();
+        assert!(res.status().is_success());
+    }
+}
@@ -0,0 +1,129 @@
+use axum::{
+    body::{to_bytes, Body},
+    extract::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http::{
+    header::{CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, ETAG, IF_NONE_MATCH},
+    HeaderValue, Method, StatusCode,
+};
+use tracing::warn;
+
+/// Tag successful JSON `GET` responses with a weak ETag of the body and answer
+/// `304 Not Modified` when the client already has it. Weak, since the body may
+/// still be compressed on the way out.
+pub async fn etag(req: Request, next: Next) -> Response {
+    if req.method() != Method::GET {
+        return next.run(req).await;
+    }
+    let if_none_match = req.headers().get(IF_NONE_MATCH).cloned();
+    let res = next.run(req).await;
+    if res.status() != StatusCode::OK || !is_json(&res) {
+        return res;
+    }
+
+    let (mut parts, body) = res.into_parts();
+    let bytes = match to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            warn!("failed to buffer response for etag: {e}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let hash = blake3::hash(&bytes);
+    let tag = format!("W/\"{}\"", &hash.to_hex()[..32]);
+    let value = HeaderValue::from_str(&tag).expect("etag is a valid header value");
+    parts.headers.insert(ETAG, value);
+    // responses depend on the caller's token, only the client may keep them
+    parts
+        .headers
+        .entry(CACHE_CONTROL)
+        .or_insert(HeaderValue::from_static("private, no-cache"));
+
+    if if_none_match.is_some_and(|v| matches(&v, &tag)) {
+        parts.status = StatusCode::NOT_MODIFIED;
+        parts.headers.remove(CONTENT_TYPE);
+        parts.headers.remove(CONTENT_LENGTH);
+        return Response::from_parts(parts, Body::empty());
+    }
+    Response::from_parts(parts, Body::from(bytes))
+}
+
+fn is_json(res: &Response) -> bool {
+    res.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"))
+}
+
+// weak comparison, as If-None-Match requires
+fn matches(if_none_match: &HeaderValue, tag: &str) -> bool {
+    let Ok(value) = if_none_match.to_str() else {
+        return false;
+    };
+    let opaque = |t: &str| t.trim().trim_start_matches("W/").to_string();
+    value.trim() == "*"
+        || value
+            .split(',')
+            .any(|candidate| opaque(candidate) == opaque(tag))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{middleware, routing::get, Json, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn call(req: http::Request<Body>) -> Response {
+        let app = Router::new()
+            .route(
+                "/json",
+                get(|| async { Json(vec!["a", "b"]) }).post(|| async { Json(1) }),
+            )
+            .route("/text", get(|| async { "plain" }))
+            .layer(middleware::from_fn(etag));
+        app.oneshot(req).await.unwrap()
+    }
+
+    fn get_req(uri: &str, if_none_match: Option<&str>) -> http::Request<Body> {
+        let mut req = http::Request::get(uri);
+        if let Some(tag) = if_none_match {
+            req = req.header(IF_NONE_MATCH, tag);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn etag_should_answer_not_modified() {
+        let res = call(get_req("/json", None)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let tag = res.headers()[ETAG].to_str().unwrap().to_string();
+        assert!(tag.starts_with("W/\""));
+
+        let res = call(get_req("/json", Some(&tag))).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers()[ETAG], tag.as_str());
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert!(body.is_empty());
+
+        // strong form and lists of tags match too
+        let strong = tag.trim_start_matches("W/");
+        let res = call(get_req("/json", Some(&format!("\"other\", {strong}")))).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+        let res = call(get_req("/json", Some("\"other\""))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn etag_should_skip_other_responses() {
+        let res = call(get_req("/text", None)).await;
+        assert!(res.headers().get(ETAG).is_none());
+
+        let req = http::Request::post("/json").body(Body::empty()).unwrap();
+        let res = call(req).await;
+        assert!(res.headers().get(ETAG).is_none());
+    }
+}
@@ -0,0 +1,206 @@
+use std::future::Future;
+
+use anyhow::Result;
+use chrono::Utc;
+use http::StatusCode;
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{metadata::MetadataMap, Code, Request, Response, Status};
+use tracing::info;
+
+use crate::shortener::{
+    auth::{AuthUser, MaybeAuthUser},
+    hosts::RequestHost,
+    shorten_request,
+    stats::GroupCount,
+    window::{ActiveWindow, Phase},
+    AppError, AppState, ShortenReq, DEFAULT_TENANT,
+};
+
+/// Messages and the client generated from `proto/shortener.proto`.
+pub mod proto {
+    tonic::include_proto!("shortener.v1");
+}
+
+use proto::{
+    shortener_server::{Shortener, ShortenerServer},
+    ResolveRequest, ResolveResponse, ShortenRequest, ShortenResponse, StatsRequest, StatsResponse,
+};
+
+/// The gRPC service, over the same state as the http api.
+#[derive(Debug, Clone)]
+pub struct GrpcService {
+    state: AppState,
+}
+
+impl From<AppError> for Status {
+    fn from(e: AppError) -> Self {
+        let code = match e.status_code() {
+            StatusCode::BAD_REQUEST => Code::InvalidArgument,
+            StatusCode::UNAUTHORIZED => Code::Unauthenticated,
+            StatusCode::FORBIDDEN => Code::PermissionDenied,
+            StatusCode::NOT_FOUND => Code::NotFound,
+            StatusCode::CONFLICT => Code::AlreadyExists,
+            StatusCode::GONE => Code::FailedPrecondition,
+            StatusCode::TOO_MANY_REQUESTS => Code::ResourceExhausted,
+            StatusCode::SERVICE_UNAVAILABLE => Code::Unavailable,
+            _ => Code::Internal,
+        };
+        Status::new(code, e.to_string())
+    }
+}
+
+pub fn service(state: AppState) -> ShortenerServer<GrpcService> {
+    ShortenerServer::new(GrpcService { state })
+}
+
+/// Serve the gRPC api on `listener` until `shutdown` resolves.
+pub async fn serve(
+    state: AppState,
+    listener: TcpListener,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    info!("Serving gRPC on {}", listener.local_addr()?);
+    tonic::transport::Server::builder()
+        .add_service(service(state))
+        .serve_with_incoming_shutdown(TcpListenerStream::new(listener), shutdown)
+        .await?;
+    Ok(())
+}
+
+impl GrpcService {
+    // the caller of a token in the metadata, anonymous without one
+    fn caller(&self, metadata: &MetadataMap) -> Result<MaybeAuthUser, AppError> {
+        let Some(value) = metadata.get("authorization") else {
+            return Ok(MaybeAuthUser(None));
+        };
+        let token = value
+            .to_str()
+            .ok()
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or_else(|| AppError::Unauthorized("expect a bearer token".to_string()))?;
+        let claims = self.state.keys.verify(token)?;
+        Ok(MaybeAuthUser(Some(AuthUser {
+            id: claims.sub,
+            username: claims.username,
+        })))
+    }
+
+    // links of unknown tenants are never found
+    async fn tenant(&self, tenant: String) -> Result<String, AppError> {
+        if tenant.is_empty() {
+            return Ok(DEFAULT_TENANT.to_string());
+        }
+        if !self.state.tenant_exists(&tenant).await? {
+            return Err(AppError::HttpNotFound(format!("tenant {tenant}")));
+        }
+        Ok(tenant)
+    }
+
+    // requests without a domain are for the default one
+    fn host(&self, domain: &str) -> RequestHost {
+        match domain {
+            "" => RequestHost::new(&self.state.config.listen_addr, false),
+            domain => RequestHost::new(domain, false),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Shortener for GrpcService {
+    async fn shorten(
+        &self,
+        req: Request<ShortenRequest>,
+    ) -> Result<Response<ShortenResponse>, Status> {
+        let user = self.caller(req.metadata())?;
+        let req = req.into_inner();
+        let tenant = self.tenant(req.tenant).await?;
+        let shorten = ShortenReq {
+            url: req.url,
+            redirect_status: None,
+            max_clicks: req.max_clicks,
+            password: None,
+            tags: req.tags,
+            window: ActiveWindow::default(),
+        };
+        let id = shorten_request(&self.state, user, &self.host(""), tenant, shorten).await?;
+        Ok(Response::new(ShortenResponse { id }))
+    }
+
+    async fn resolve(
+        &self,
+        req: Request<ResolveRequest>,
+    ) -> Result<Response<ResolveResponse>, Status> {
+        let req = req.into_inner();
+        let tenant = self.tenant(req.tenant).await?;
+        let record = self
+            .state
+            .get_url(&tenant, &req.id, &self.host(&req.domain))
+            .await
+            .map_err(AppError::from)?;
+        let Some(record) = record else {
+            return Err(AppError::HttpNotFound(req.id).into());
+        };
+        let window = ActiveWindow {
+            active_from: record.active_from,
+            active_until: record.active_until,
+        };
+        let available = record.disabled_status.is_none()
+            && window.phase(Utc::now()) == Phase::Active
+            && record.max_clicks.is_none_or(|max| record.clicks < max);
+        Ok(Response::new(ResolveResponse {
+            url: record.url,
+            redirect_status: record.redirect_status.unwrap_or_default() as u32,
+            available,
+            protected: record.password_hash.is_some(),
+        }))
+    }
+
+    async fn stats(&self, req: Request<StatsRequest>) -> Result<Response<StatsResponse>, Status> {
+        let user = self.caller(req.metadata())?;
+        let Some(user) = user.0 else {
+            return Err(
+                AppError::Unauthorized("missing authorization metadata".to_string()).into(),
+            );
+        };
+        let req = req.into_inner();
+        let tenant = self.tenant(req.tenant).await?;
+        let Some(stats) = self.state.link_stats(&tenant, &req.id, user.id).await? else {
+            return Err(AppError::HttpNotFound(req.id).into());
+        };
+        let groups = |groups: Vec<GroupCount>| {
+            groups
+                .into_iter()
+                .map(|g| proto::GroupCount {
+                    name: g.name,
+                    clicks: g.clicks,
+                })
+                .collect()
+        };
+        Ok(Response::new(StatsResponse {
+            id: stats.id,
+            clicks: stats.clicks,
+            clicks_24h: stats.clicks_24h,
+            clicks_7d: stats.clicks_7d,
+            last_click_at: stats.last_click_at.map(|at| at.timestamp()),
+            countries: groups(stats.breakdown.countries),
+            browsers: groups(stats.breakdown.browsers),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_should_map_to_codes() {
+        let status = Status::from(AppError::HttpNotFound("abc123".to_string()));
+        assert_eq!(status.code(), Code::NotFound);
+        let status = Status::from(AppError::BadRequest("invalid url".to_string()));
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(status.message(), "bad request: invalid url");
+        let status = Status::from(AppError::DbBusy);
+        assert_eq!(status.code(), Code::Unavailable);
+    }
+}
@@ -0,0 +1,111 @@
+use std::collections::BTreeMap;
+
+use axum::{extract::State, response::IntoResponse, Json};
+use http::StatusCode;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::shortener::AppState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Degraded,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Check {
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Readiness {
+    pub status: Status,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+impl Check {
+    fn from_result<E: ToString>(ret: Result<(), E>) -> Self {
+        match ret {
+            Ok(()) => Self {
+                status: Status::Ok,
+                error: None,
+            },
+            Err(e) => Self {
+                status: Status::Degraded,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+impl Readiness {
+    // overall status is degraded as soon as one dependency is
+    fn new(checks: BTreeMap<&'static str, Check>) -> Self {
+        let status = if checks.values().all(|c| c.status == Status::Ok) {
+            Status::Ok
+        } else {
+            Status::Degraded
+        };
+        Self { status, checks }
+    }
+}
+
+// liveness: the process is up and serving requests
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "health",
+    responses((status = 200, description = "the process is up", body = String))
+)]
+pub async fn healthz_handler() -> &'static str {
+    "ok"
+}
+
+// readiness: every dependency we need to serve traffic is reachable
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "health",
+    responses(
+        (status = 200, description = "ready to serve", body = Readiness),
+        (status = 503, description = "some dependency is degraded", body = Readiness),
+    )
+)]
+pub async fn readyz_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let mut checks = BTreeMap::new();
+    checks.insert("db", Check::from_result(state.ping().await));
+
+    let readiness = Readiness::new(checks);
+    let code = match readiness.status {
+        Status::Ok => StatusCode::OK,
+        Status::Degraded => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (code, Json(readiness))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readiness_should_degrade_on_any_failed_check() {
+        let mut checks = BTreeMap::new();
+        checks.insert("db", Check::from_result(Ok::<_, String>(())));
+        assert_eq!(Readiness::new(checks).status, Status::Ok);
+
+        let mut checks = BTreeMap::new();
+        checks.insert("db", Check::from_result(Ok::<_, String>(())));
+        checks.insert("cache", Check::from_result(Err("connection refused")));
+        let readiness = Readiness::new(checks);
+        assert_eq!(readiness.status, Status::Degraded);
+
+        let json = serde_json::to_value(&readiness).unwrap();
+        assert_eq!(json["status"], "degraded");
+        assert_eq!(json["checks"]["cache"]["error"], "connection refused");
+        assert!(json["checks"]["db"].get("error").is_none());
+    }
+}
@@ -0,0 +1,247 @@
+use std::{collections::HashMap, mem, sync::Mutex, time::Duration};
+
+use chrono::{DateTime, Utc};
+use tokio::{sync::Notify, task::JoinHandle};
+use tracing::warn;
+
+use crate::shortener::{analytics::Visitor, webhooks::LinkEvent, AppError, AppState};
+
+/// Clicks of links without a click limit, counted in memory and written to the db
+/// in batches by [`spawn_flusher`] instead of one insert per redirect.
+///
+/// Delivery is at least once while the process runs: a failed flush is merged back
+/// and retried with the next one, so a flush whose commit was lost in transit counts
+/// twice. Pending clicks are flushed on shutdown by `AppState::close`, a crash loses them.
+#[derive(Debug, Default)]
+pub struct Hits {
+    pending: Mutex<HashMap<HitKey, Pending>>,
+    full: Notify,
+}
+
+// clicks that only differ in time are counted together
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct HitKey {
+    tenant: String,
+    link_id: String,
+    country: Option<String>,
+    browser: Option<String>,
+    os: Option<String>,
+    arm: Option<i16>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Pending {
+    count: i64,
+    // the clicks of a key are logged at the time of the first one
+    first_at: DateTime<Utc>,
+}
+
+impl Hits {
+    // returns the number of pending clicks
+    fn record(&self, tenant: &str, id: &str, visitor: &Visitor, at: DateTime<Utc>) -> i64 {
+        let key = HitKey {
+            tenant: tenant.to_string(),
+            link_id: id.to_string(),
+            country: visitor.country.clone(),
+            browser: visitor.browser.clone(),
+            os: visitor.os.clone(),
+            arm: visitor.arm,
+        };
+        let mut pending = self.pending.lock().unwrap();
+        pending
+            .entry(key)
+            .and_modify(|p| p.count += 1)
+            .or_insert(Pending {
+                count: 1,
+                first_at: at,
+            });
+        pending.values().map(|p| p.count).sum()
+    }
+
+    fn take(&self) -> HashMap<HitKey, Pending> {
+        mem::take(&mut *self.pending.lock().unwrap())
+    }
+
+    // put back a batch that failed to flush, clicks recorded meanwhile are kept
+    fn restore(&self, batch: HashMap<HitKey, Pending>) {
+        let mut pending = self.pending.lock().unwrap();
+        for (key, p) in batch {
+            pending
+                .entry(key)
+                .and_modify(|q| {
+                    q.count += p.count;
+                    q.first_at = q.first_at.min(p.first_at);
+                })
+                .or_insert(p);
+        }
+    }
+
+    /// Clicks recorded but not yet written.
+    pub fn pending(&self) -> i64 {
+        self.pending.lock().unwrap().values().map(|p| p.count).sum()
+    }
+}
+
+impl AppState {
+    // count a click in memory, the flusher is woken once a batch is full
+    pub fn buffer_click(&self, tenant: &str, id: &str, visitor: &Visitor) {
+        let pending = self.hits.record(tenant, id, visitor, Utc::now());
+        self.metrics.clicks_pending.set(pending);
+        if pending >= self.config.click_flush_hits as i64 {
+            self.hits.full.notify_one();
+        }
+    }
+
+    /// Write the pending clicks in one statement, returns how many were written.
+    /// Clicks of links purged meanwhile are dropped.
+    pub async fn flush_clicks(&self) -> Result<i64, AppError> {
+        let batch = self.hits.take();
+        if batch.is_empty() {
+            return Ok(0);
+        }
+        let mut tenants = Vec::with_capacity(batch.len());
+        let mut ids = Vec::with_capacity(batch.len());
+        let mut countries = Vec::with_capacity(batch.len());
+        let mut browsers = Vec::with_capacity(batch.len());
+        let mut oses = Vec::with_capacity(batch.len());
+        let mut arms = Vec::with_capacity(batch.len());
+        let mut counts = Vec::with_capacity(batch.len());
+        let mut times = Vec::with_capacity(batch.len());
+        for (key, p) in &batch {
+            tenants.push(key.tenant.clone());
+            ids.push(key.link_id.clone());
+            countries.push(key.country.clone());
+            browsers.push(key.browser.clone());
+            oses.push(key.os.clone());
+            arms.push(key.arm);
+            counts.push(p.count);
+            times.push(p.first_at);
+        }
+        // one click row per click, the counter of each link is bumped once per batch.
+        // thresholds crossed by the batch are returned for the webhooks
+        let ret = sqlx::query!(
+            r#"
+            WITH hits AS (
+                SELECT h.* FROM UNNEST($1::TEXT[], $2::VARCHAR[], $3::TEXT[], $4::TEXT[],
+                    $5::TEXT[], $6::BIGINT[], $7::TIMESTAMPTZ[], $8::SMALLINT[])
+                    AS h(tenant, link_id, country, browser, os, n, clicked_at, arm)
+                JOIN urls u ON u.tenant = h.tenant AND u.id = h.link_id
+            ),
+            logged AS (
+                INSERT INTO clicks (tenant, link_id, country, browser, os, clicked_at, arm)
+                SELECT tenant, link_id, country, browser, os, clicked_at, arm
+                FROM hits, generate_series(1, hits.n)
+            ),
+            counts AS (
+                SELECT tenant, link_id, sum(n)::BIGINT AS n FROM hits GROUP BY tenant, link_id
+            )
+            UPDATE urls u SET clicks = u.clicks + c.n
+            FROM counts c
+            WHERE u.tenant = c.tenant AND u.id = c.link_id
+            RETURNING u.id, u.owner_id, c.n AS "n!", ARRAY(
+                SELECT DISTINCT w.click_threshold FROM webhooks w
+                WHERE w.owner_id = u.owner_id
+                    AND w.click_threshold > u.clicks - c.n AND w.click_threshold <= u.clicks
+            ) AS "thresholds!: Vec<i64>"
+            "#,
+            &tenants,
+            &ids,
+            &countries as &[Option<String>],
+            &browsers as &[Option<String>],
+            &oses as &[Option<String>],
+            &counts,
+            &times,
+            &arms as &[Option<i16>],
+        )
+        .fetch_all(self.db.primary())
+        .await;
+        let rows = match ret {
+            Ok(rows) => rows,
+            Err(e) => {
+                self.metrics.db_errors.inc();
+                self.metrics
+                    .click_flushes
+                    .with_label_values(&["error"])
+                    .inc();
+                self.hits.restore(batch);
+                return Err(e.into());
+            }
+        };
+        self.metrics.click_flushes.with_label_values(&["ok"]).inc();
+        self.metrics.clicks_pending.set(self.hits.pending());
+        let mut written = 0;
+        for row in rows {
+            written += row.n;
+            for clicks in row.thresholds {
+                let event = LinkEvent::ClickThreshold {
+                    id: row.id.clone(),
+                    clicks,
+                };
+                self.notifier.notify(row.owner_id, event);
+            }
+        }
+        Ok(written)
+    }
+}
+
+/// Flush the pending clicks every `interval`, or as soon as a batch is full.
+pub fn spawn_flusher(state: AppState, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        // nothing is pending at startup, the first tick is one interval away
+        let start = tokio::time::Instant::now() + interval;
+        let mut ticker = tokio::time::interval_at(start, interval);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = state.hits.full.notified() => {}
+            }
+            if let Err(e) = state.flush_clicks().await {
+                warn!("failed to flush clicks, retrying with the next batch: {e}");
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hits_should_be_counted_per_key() {
+        let hits = Hits::default();
+        let firefox = Visitor {
+            browser: Some("Firefox".to_string()),
+            ..Default::default()
+        };
+        let t0 = Utc::now();
+        assert_eq!(hits.record("default", "abc123", &firefox, t0), 1);
+        hits.record(
+            "default",
+            "abc123",
+            &firefox,
+            t0 + chrono::Duration::seconds(1),
+        );
+        hits.record("default", "abc123", &Visitor::default(), t0);
+        assert_eq!(hits.record("acme", "abc123", &firefox, t0), 4);
+
+        let batch = hits.take();
+        assert_eq!(batch.len(), 3);
+        let key = batch
+            .iter()
+            .find(|(k, _)| k.tenant == "default" && k.browser.is_some())
+            .unwrap()
+            .1;
+        assert_eq!((key.count, key.first_at), (2, t0));
+        assert_eq!(hits.pending(), 0);
+
+        // a failed batch is merged with the clicks recorded meanwhile
+        hits.record(
+            "default",
+            "abc123",
+            &firefox,
+            t0 + chrono::Duration::seconds(2),
+        );
+        hits.restore(batch);
+        assert_eq!(hits.pending(), 5);
+    }
+}
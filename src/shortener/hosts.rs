@@ -0,0 +1,269 @@
+use std::convert::Infallible;
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Host, Path, State},
+    http::request::Parts,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use utoipa::ToSchema;
+
+use crate::shortener::{
+    auth::AdminUser, domains::normalize_domain, tenants::PathTenant, AppError, AppState,
+    ErrorResponse,
+};
+
+/// A short domain links can be created on, besides the default one. Only its
+/// owner can shorten on it.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ShortDomain {
+    pub domain: String,
+    pub owner: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ShortDomainReq {
+    pub domain: String,
+    /// the account allowed to shorten on the domain
+    pub username: String,
+}
+
+/// The host a request was sent to, for building short urls.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestHost {
+    /// as given, with port
+    pub authority: String,
+    pub https: bool,
+    /// `/t/{tenant}` if the request was addressed by tenant path prefix, else empty
+    pub prefix: String,
+}
+
+impl RequestHost {
+    pub fn new(authority: impl Into<String>, https: bool) -> Self {
+        Self {
+            authority: authority.into(),
+            https,
+            prefix: String::new(),
+        }
+    }
+
+    /// The host without port, lowercased, as short domains are stored.
+    pub fn domain(&self) -> String {
+        let host = match self.authority.rsplit_once(':') {
+            // the colons of an ipv6 address are not a port
+            Some((host, port)) if port.bytes().all(|b| b.is_ascii_digit()) => host,
+            _ => &self.authority,
+        };
+        host.trim_end_matches('.').to_ascii_lowercase()
+    }
+
+    /// Url of a short id, on the domain the client used.
+    pub fn short_url(&self, id: &str) -> String {
+        let scheme = if self.https { "https" } else { "http" };
+        format!("{scheme}://{}{}/{id}", self.authority, self.prefix)
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for RequestHost {
+    // without a host header links are addressed on the listen address
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Infallible> {
+        let host = match Host::from_request_parts(parts, state).await {
+            // http/2 sends no host header, the fallback to the uri host drops the port
+            Ok(Host(host)) if parts.uri.host() == Some(host.as_str()) => parts
+                .uri
+                .authority()
+                .map_or(host, |authority| authority.to_string()),
+            Ok(Host(host)) => host,
+            Err(_) => state.config.listen_addr.clone(),
+        };
+        // with TLS, plain http requests are redirected before reaching handlers
+        let mut host = Self::new(host, state.config.tls_enabled());
+        if let Some(PathTenant(tenant)) = parts.extensions.get::<PathTenant>() {
+            host.prefix = format!("/t/{tenant}");
+        }
+        Ok(host)
+    }
+}
+
+impl AppState {
+    /// Short domain new links of `owner` get when shortened on `host`, None for
+    /// the default domain. Someone else's domain is forbidden.
+    pub async fn domain_for(
+        &self,
+        host: &RequestHost,
+        owner: Option<i64>,
+    ) -> Result<Option<String>, AppError> {
+        let domain = host.domain();
+        let domain_owner = self
+            .db
+            .retry("domain_for", || {
+                sqlx::query_scalar!(
+                    "SELECT owner_id FROM short_domains WHERE domain = $1",
+                    domain
+                )
+                .fetch_optional(self.db.primary())
+            })
+            .await?;
+        match domain_owner {
+            None => Ok(None),
+            Some(id) if Some(id) == owner => Ok(Some(domain)),
+            Some(_) => Err(AppError::Forbidden(format!(
+                "{domain} belongs to another account"
+            ))),
+        }
+    }
+
+    async fn list_short_domains(&self) -> Result<Vec<ShortDomain>, AppError> {
+        let domains = sqlx::query_as!(
+            ShortDomain,
+            r#"
+            SELECT d.domain, u.username AS owner, d.created_at
+            FROM short_domains d JOIN users u ON u.id = d.owner_id ORDER BY d.domain
+            "#
+        )
+        .fetch_all(self.db.primary())
+        .await?;
+        Ok(domains)
+    }
+
+    // fails with BadRequest for unknown users, Conflict if the domain is taken
+    pub async fn add_short_domain(
+        &self,
+        domain: &str,
+        username: &str,
+    ) -> Result<ShortDomain, AppError> {
+        let domain = sqlx::query_as!(
+            ShortDomain,
+            r#"
+            INSERT INTO short_domains (domain, owner_id)
+            SELECT $1, id FROM users WHERE username = $2
+            RETURNING domain, $2 AS "owner!", created_at
+            "#,
+            domain,
+            username,
+        )
+        .fetch_optional(self.db.primary())
+        .await?;
+        domain.ok_or_else(|| AppError::BadRequest(format!("unknown user: {username}")))
+    }
+
+    // returns false if there is no such domain, Conflict while links use it
+    pub async fn delete_short_domain(&self, domain: &str) -> Result<bool, AppError> {
+        let ret = sqlx::query!("DELETE FROM short_domains WHERE domain = $1", domain)
+            .execute(self.db.primary())
+            .await;
+        match ret {
+            Ok(ret) => Ok(ret.rows_affected() > 0),
+            Err(sqlx::Error::Database(e)) if e.is_foreign_key_violation() => Err(
+                AppError::Conflict(format!("{domain} still has links, delete them first")),
+            ),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/admin/short-domains",
+    tag = "admin",
+    responses(
+        (status = 200, description = "all short domains", body = Vec<ShortDomain>),
+        (status = 401, description = "missing or invalid token", body = ErrorResponse),
+        (status = 403, description = "not allowed", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn list_short_domains_handler(
+    State(state): State<AppState>,
+    _admin: AdminUser,
+) -> Result<Json<Vec<ShortDomain>>, AppError> {
+    Ok(Json(state.list_short_domains().await?))
+}
+
+/// Register a short domain for an account. Its DNS must point to this server.
+#[utoipa::path(
+    post,
+    path = "/api/v1/admin/short-domains",
+    tag = "admin",
+    request_body = ShortDomainReq,
+    responses(
+        (status = 201, description = "domain registered", body = ShortDomain),
+        (status = 400, description = "invalid domain or unknown user", body = ErrorResponse),
+        (status = 401, description = "missing or invalid token", body = ErrorResponse),
+        (status = 403, description = "not allowed", body = ErrorResponse),
+        (status = 409, description = "domain already registered", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn add_short_domain_handler(
+    State(state): State<AppState>,
+    AdminUser(admin): AdminUser,
+    Json(req): Json<ShortDomainReq>,
+) -> Result<impl IntoResponse, AppError> {
+    let domain = normalize_domain(&req.domain).map_err(AppError::BadRequest)?;
+    let short_domain = state.add_short_domain(&domain, &req.username).await?;
+    info!(
+        target: "audit",
+        admin = %admin.username,
+        domain,
+        owner = %req.username,
+        "short domain registered"
+    );
+    Ok((StatusCode::CREATED, Json(short_domain)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/admin/short-domains/{domain}",
+    tag = "admin",
+    params(("domain" = String, Path)),
+    responses(
+        (status = 204, description = "domain removed"),
+        (status = 401, description = "missing or invalid token", body = ErrorResponse),
+        (status = 403, description = "not allowed", body = ErrorResponse),
+        (status = 404, description = "no such domain", body = ErrorResponse),
+        (status = 409, description = "links still use the domain", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn delete_short_domain_handler(
+    State(state): State<AppState>,
+    AdminUser(admin): AdminUser,
+    Path(domain): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let domain = normalize_domain(&domain).map_err(AppError::BadRequest)?;
+    if !state.delete_short_domain(&domain).await? {
+        return Err(AppError::HttpNotFound(domain));
+    }
+    info!(target: "audit", admin = %admin.username, domain, "short domain removed");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn domain_should_drop_port() {
+        let domain = |host: &str| RequestHost::new(host, false).domain();
+        assert_eq!(domain("Go.Example.com:8080"), "go.example.com");
+        assert_eq!(domain("go.example.com."), "go.example.com");
+        assert_eq!(domain("127.0.0.1:9876"), "127.0.0.1");
+        assert_eq!(domain("[::1]:9876"), "[::1]");
+    }
+
+    #[test]
+    fn short_url_should_follow_scheme() {
+        let host = RequestHost::new("go.example.com:8443", true);
+        assert_eq!(host.short_url("abc"), "https://go.example.com:8443/abc");
+    }
+}
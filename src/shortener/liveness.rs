@@ -0,0 +1,188 @@
+use std::time::{Duration, Instant};
+
+use futures::{stream, StreamExt};
+use http::StatusCode;
+use reqwest::Method;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::shortener::{AppError, AppState};
+
+// how often due destinations are looked for
+const TICK: Duration = Duration::from_secs(60);
+
+/// Outcome of checking a destination.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Liveness {
+    Alive(StatusCode),
+    /// 404 or 410, the destination is gone
+    Dead(StatusCode),
+    /// no response at all, maybe transient
+    Unreachable(String),
+}
+
+impl Liveness {
+    fn of(status: StatusCode) -> Self {
+        match status {
+            StatusCode::NOT_FOUND | StatusCode::GONE => Self::Dead(status),
+            status => Self::Alive(status),
+        }
+    }
+
+    fn status(&self) -> Option<i16> {
+        match self {
+            Self::Alive(status) | Self::Dead(status) => Some(status.as_u16() as i16),
+            Self::Unreachable(_) => None,
+        }
+    }
+
+    // unreachable keeps the previous verdict
+    fn dead(&self) -> Option<bool> {
+        match self {
+            Self::Alive(_) => Some(false),
+            Self::Dead(_) => Some(true),
+            Self::Unreachable(_) => None,
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Alive(_) => "alive",
+            Self::Dead(_) => "dead",
+            Self::Unreachable(_) => "unreachable",
+        }
+    }
+}
+
+impl AppState {
+    /// HEAD the destination, falling back to GET for servers not supporting HEAD.
+    pub async fn probe(&self, url: &str) -> (Liveness, Duration) {
+        let started = Instant::now();
+        let head = match self.http.request(Method::HEAD, url) {
+            Ok(head) => head,
+            Err(e) => return (Liveness::Unreachable(e.to_string()), started.elapsed()),
+        };
+        let mut ret = head.send().await;
+        if let Ok(res) = &ret {
+            let status = res.status();
+            if status == StatusCode::METHOD_NOT_ALLOWED || status == StatusCode::NOT_IMPLEMENTED {
+                // same url, it was just let through
+                ret = self.http.client().get(url).send().await;
+            }
+        }
+        let liveness = match ret {
+            Ok(res) => Liveness::of(res.status()),
+            Err(e) => Liveness::Unreachable(e.to_string()),
+        };
+        (liveness, started.elapsed())
+    }
+
+    /// Check destinations not checked within `liveness_interval_secs`, least
+    /// recently checked first. Returns how many were checked.
+    pub async fn check_links(&self) -> Result<usize, AppError> {
+        let due = sqlx::query!(
+            r#"
+            SELECT tenant, id, url, dead FROM urls
+            WHERE deleted_at IS NULL
+                AND (last_checked_at IS NULL OR last_checked_at < now() - make_interval(secs => $1))
+            ORDER BY last_checked_at NULLS FIRST
+            LIMIT $2
+            "#,
+            self.config.liveness_interval_secs as f64,
+            self.config.liveness_batch,
+        )
+        .fetch_all(self.db.primary())
+        .await?;
+        let checked = due.len();
+
+        let mut results = stream::iter(due)
+            .map(|link| async move {
+                let (liveness, latency) = self.probe(&link.url).await;
+                (link, liveness, latency)
+            })
+            .buffer_unordered(self.config.liveness_concurrency.max(1));
+        while let Some((link, liveness, latency)) = results.next().await {
+            self.metrics
+                .link_checks
+                .with_label_values(&[liveness.label()])
+                .inc();
+            // the url guard skips links repointed while being checked
+            sqlx::query!(
+                r#"
+                UPDATE urls SET last_checked_at = now(), last_check_status = $3,
+                    last_check_latency_ms = $4, dead = coalesce($5, dead)
+                WHERE tenant = $6 AND id = $1 AND url = $2
+                "#,
+                link.id,
+                link.url,
+                liveness.status(),
+                latency.as_millis() as i32,
+                liveness.dead(),
+                link.tenant,
+            )
+            .execute(self.db.primary())
+            .await?;
+            match liveness {
+                Liveness::Dead(status) if !link.dead => info!(
+                    target: "audit",
+                    tenant = %link.tenant,
+                    id = %link.id,
+                    url = %link.url,
+                    %status,
+                    "link destination is dead"
+                ),
+                Liveness::Alive(_) if link.dead => info!(
+                    target: "audit",
+                    tenant = %link.tenant,
+                    id = %link.id,
+                    url = %link.url,
+                    "link destination is back"
+                ),
+                Liveness::Unreachable(e) => {
+                    warn!("destination of {} is unreachable: {e}", link.id)
+                }
+                _ => {}
+            }
+        }
+        Ok(checked)
+    }
+}
+
+/// Check due destinations every minute, None if the checker is disabled.
+pub fn spawn_checker(state: AppState) -> Option<JoinHandle<()>> {
+    if state.config.liveness_interval_secs == 0 {
+        return None;
+    }
+    Some(tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(TICK);
+        loop {
+            ticker.tick().await;
+            match state.check_links().await {
+                Ok(0) => {}
+                Ok(checked) => info!(checked, "link destinations checked"),
+                Err(e) => warn!("failed to check link destinations: {e}"),
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_missing_destinations_should_be_dead() {
+        assert_eq!(
+            Liveness::of(StatusCode::NOT_FOUND),
+            Liveness::Dead(StatusCode::NOT_FOUND)
+        );
+        assert_eq!(Liveness::of(StatusCode::GONE).dead(), Some(true));
+        assert_eq!(Liveness::of(StatusCode::FORBIDDEN).dead(), Some(false));
+        assert_eq!(
+            Liveness::of(StatusCode::SERVICE_UNAVAILABLE).dead(),
+            Some(false)
+        );
+        let unreachable = Liveness::Unreachable("connection refused".to_string());
+        assert_eq!((unreachable.status(), unreachable.dead()), (None, None));
+    }
+}
@@ -0,0 +1,185 @@
+use std::time::Instant;
+
+use anyhow::Result;
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http::{header::CONTENT_TYPE, StatusCode};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use sqlx::PgPool;
+
+use crate::shortener::AppState;
+
+/// Prometheus metrics of the shortener.
+/// The registry is owned by `AppState` instead of the global default registry,
+/// so every state (and every test) gets its own set of counters.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    registry: Registry,
+    pub shortens: IntCounter,
+    pub redirects: IntCounter,
+    pub not_found: IntCounter,
+    // unknown ids answered from the miss cache, see `misses`
+    pub not_found_cached: IntCounter,
+    pub db_errors: IntCounter,
+    pub id_conflicts: IntCounter,
+    pub link_checks: IntCounterVec,
+    // clicks buffered in memory, see `hits`
+    pub clicks_pending: IntGauge,
+    pub click_flushes: IntCounterVec,
+    pub requests: IntCounterVec,
+    pub latency: HistogramVec,
+    // sampled on scrape, see `observe_pool`
+    pool_connections: IntGaugeVec,
+    pool_max_connections: IntGauge,
+}
+
+impl Metrics {
+    pub fn try_new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("shortener".to_string()), None)?;
+
+        let shortens = IntCounter::new("shortens_total", "number of urls shortened")?;
+        let redirects = IntCounter::new("redirects_total", "number of successful redirects")?;
+        let not_found = IntCounter::new("not_found_total", "number of unknown short ids")?;
+        let not_found_cached = IntCounter::new(
+            "not_found_cached_total",
+            "number of unknown short ids answered without a db query",
+        )?;
+        let db_errors = IntCounter::new("db_errors_total", "number of failed db queries")?;
+        let id_conflicts = IntCounter::new(
+            "id_conflicts_total",
+            "number of generated ids that already existed and were retried",
+        )?;
+        let link_checks = IntCounterVec::new(
+            Opts::new("link_checks_total", "number of destination liveness checks"),
+            &["result"],
+        )?;
+        let clicks_pending = IntGauge::new(
+            "clicks_pending",
+            "number of clicks counted in memory and not yet written",
+        )?;
+        let click_flushes = IntCounterVec::new(
+            Opts::new("click_flushes_total", "number of batched click writes"),
+            &["result"],
+        )?;
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "number of http requests"),
+            &["method", "route", "status"],
+        )?;
+        let latency = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "http request latency"),
+            &["method", "route"],
+        )?;
+
+        let pool_connections = IntGaugeVec::new(
+            Opts::new("db_pool_connections", "number of open db connections"),
+            &["state"],
+        )?;
+        let pool_max_connections =
+            IntGauge::new("db_pool_max_connections", "size limit of the db pool")?;
+
+        registry.register(Box::new(shortens.clone()))?;
+        registry.register(Box::new(redirects.clone()))?;
+        registry.register(Box::new(not_found.clone()))?;
+        registry.register(Box::new(not_found_cached.clone()))?;
+        registry.register(Box::new(db_errors.clone()))?;
+        registry.register(Box::new(id_conflicts.clone()))?;
+        registry.register(Box::new(link_checks.clone()))?;
+        registry.register(Box::new(clicks_pending.clone()))?;
+        registry.register(Box::new(click_flushes.clone()))?;
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(latency.clone()))?;
+        registry.register(Box::new(pool_connections.clone()))?;
+        registry.register(Box::new(pool_max_connections.clone()))?;
+
+        Ok(Self {
+            registry,
+            shortens,
+            redirects,
+            not_found,
+            not_found_cached,
+            db_errors,
+            id_conflicts,
+            link_checks,
+            clicks_pending,
+            click_flushes,
+            requests,
+            latency,
+            pool_connections,
+            pool_max_connections,
+        })
+    }
+
+    // in use connections close to the max mean requests are about to queue for one
+    pub fn observe_pool(&self, db: &PgPool) {
+        let size = db.size() as i64;
+        let idle = db.num_idle() as i64;
+        self.pool_connections.with_label_values(&["idle"]).set(idle);
+        self.pool_connections
+            .with_label_values(&["in_use"])
+            .set(size - idle);
+        self.pool_max_connections
+            .set(db.options().get_max_connections() as i64);
+    }
+
+    // render all metrics in the prometheus text format
+    pub fn render(&self) -> Result<String> {
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(String::from_utf8(buf)?)
+    }
+}
+
+// middleware: count requests and observe latency per matched route
+pub async fn track_metrics(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let method = req.method().to_string();
+    // use the route template instead of the raw path to keep label cardinality low
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    let res = next.run(req).await;
+
+    state
+        .metrics
+        .latency
+        .with_label_values(&[&method, &route])
+        .observe(start.elapsed().as_secs_f64());
+    state
+        .metrics
+        .requests
+        .with_label_values(&[&method, &route, res.status().as_str()])
+        .inc();
+    res
+}
+
+pub async fn metrics_handler(State(state): State<AppState>) -> Response {
+    state.metrics.observe_pool(state.db.primary());
+    match state.metrics.render() {
+        Ok(body) => ([(CONTENT_TYPE, TextEncoder::new().format_type())], body).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_should_include_counters() {
+        let metrics = Metrics::try_new().unwrap();
+        metrics.shortens.inc();
+        metrics.not_found.inc_by(2);
+        let text = metrics.render().unwrap();
+        assert!(text.contains("shortener_shortens_total 1"));
+        assert!(text.contains("shortener_not_found_total 2"));
+    }
+}
@@ -0,0 +1,112 @@
+use std::{
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lru::LruCache;
+
+/// Short ids recently looked up and not found, so scans of random ids don't all
+/// reach the db. The least recently missed ids are evicted first.
+///
+/// Optimistic: an id written by another instance, or during the lookup that missed
+/// it, is only found once its entry expires. Writes on this instance drop the entry.
+#[derive(Debug)]
+pub struct Misses {
+    ttl: Duration,
+    // None if disabled
+    entries: Option<Mutex<LruCache<String, Vec<Miss>>>>,
+}
+
+// the lookup of an id depends on the tenant and the domain it was asked on
+#[derive(Debug, Clone)]
+struct Miss {
+    tenant: String,
+    domain: String,
+    at: Instant,
+}
+
+impl Misses {
+    /// At most `size` ids are kept, 0 disables the cache.
+    pub fn new(size: usize, ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: NonZeroUsize::new(size).map(|size| Mutex::new(LruCache::new(size))),
+        }
+    }
+
+    pub fn contains(&self, tenant: &str, domain: &str, id: &str) -> bool {
+        let Some(entries) = &self.entries else {
+            return false;
+        };
+        let mut entries = entries.lock().unwrap();
+        let Some(misses) = entries.get_mut(id) else {
+            return false;
+        };
+        misses.retain(|m| m.at.elapsed() < self.ttl);
+        let found = misses
+            .iter()
+            .any(|m| m.tenant == tenant && m.domain == domain);
+        if misses.is_empty() {
+            entries.pop(id);
+        }
+        found
+    }
+
+    pub fn insert(&self, tenant: &str, domain: &str, id: &str) {
+        let Some(entries) = &self.entries else {
+            return;
+        };
+        let miss = Miss {
+            tenant: tenant.to_string(),
+            domain: domain.to_string(),
+            at: Instant::now(),
+        };
+        let mut entries = entries.lock().unwrap();
+        let misses = entries.get_or_insert_mut(id.to_string(), Vec::new);
+        misses.retain(|m| m.tenant != tenant || m.domain != domain);
+        misses.push(miss);
+    }
+
+    /// Drop the misses of `id` in any tenant, once it may exist.
+    pub fn forget(&self, id: &str) {
+        if let Some(entries) = &self.entries {
+            entries.lock().unwrap().pop(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn misses_should_be_scoped_and_forgotten() {
+        let misses = Misses::new(2, Duration::from_secs(60));
+        misses.insert("default", "127.0.0.1", "abc123");
+        assert!(misses.contains("default", "127.0.0.1", "abc123"));
+        assert!(!misses.contains("acme", "127.0.0.1", "abc123"));
+        assert!(!misses.contains("default", "go.example", "abc123"));
+
+        misses.forget("abc123");
+        assert!(!misses.contains("default", "127.0.0.1", "abc123"));
+
+        // the least recently missed id is evicted
+        misses.insert("default", "127.0.0.1", "a");
+        misses.insert("default", "127.0.0.1", "b");
+        misses.insert("default", "127.0.0.1", "c");
+        assert!(!misses.contains("default", "127.0.0.1", "a"));
+        assert!(misses.contains("default", "127.0.0.1", "c"));
+    }
+
+    #[test]
+    fn misses_should_expire() {
+        let misses = Misses::new(16, Duration::ZERO);
+        misses.insert("default", "127.0.0.1", "abc123");
+        assert!(!misses.contains("default", "127.0.0.1", "abc123"));
+
+        let disabled = Misses::new(0, Duration::from_secs(60));
+        disabled.insert("default", "127.0.0.1", "abc123");
+        assert!(!disabled.contains("default", "127.0.0.1", "abc123"));
+    }
+}
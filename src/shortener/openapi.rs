@@ -0,0 +1,108 @@
+use utoipa::{
+    openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi,
+};
+use utoipa_swagger_ui::SwaggerUi;
+
+use crate::shortener::{
+    auth, domains, health, hosts, reports, split, stats, tenants, transfer, trash, webhooks,
+    window, AppState,
+};
+
+pub const SPEC_PATH: &str = "/api/openapi.json";
+
+/// The JSON API, for generating clients. Html pages are left out.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "myshortener", description = "Url shortener API"),
+    paths(
+        crate::shortener::shorten_handler,
+        crate::shortener::batch_shorten_handler,
+        crate::shortener::list_links_handler,
+        crate::shortener::update_link_handler,
+        crate::shortener::delete_link_handler,
+        trash::list_trash_handler,
+        trash::restore_link_handler,
+        split::set_split_handler,
+        split::get_split_handler,
+        stats::link_stats_handler,
+        stats::clicks_stream_handler,
+        stats::summary_handler,
+        crate::shortener::redirect_handler,
+        reports::report_handler,
+        auth::register_handler,
+        auth::login_handler,
+        domains::list_domains_handler,
+        domains::add_domain_handler,
+        domains::delete_domain_handler,
+        hosts::list_short_domains_handler,
+        hosts::add_short_domain_handler,
+        hosts::delete_short_domain_handler,
+        tenants::list_tenants_handler,
+        tenants::add_tenant_handler,
+        tenants::delete_tenant_handler,
+        window::set_window_handler,
+        reports::list_reports_handler,
+        reports::moderate_handler,
+        webhooks::create_webhook_handler,
+        webhooks::list_webhooks_handler,
+        webhooks::delete_webhook_handler,
+        transfer::export_handler,
+        transfer::import_handler,
+        health::healthz_handler,
+        health::readyz_handler,
+    ),
+    components(schemas(crate::shortener::ErrorResponse, crate::shortener::problem::Problem)),
+    modifiers(&BearerAuth)
+)]
+pub struct ApiDoc;
+
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .build(),
+            ),
+        );
+    }
+}
+
+/// Swagger UI at `/swagger-ui`, serving the spec at [`SPEC_PATH`].
+pub fn swagger_ui() -> axum::Router<AppState> {
+    SwaggerUi::new("/swagger-ui")
+        .url(SPEC_PATH, ApiDoc::openapi())
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spec_should_cover_api() {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        for path in [
+            "/api/v1/links",
+            "/api/v1/links/{id}",
+            "/api/v1/webhooks",
+            "/api/v1/import",
+            "/{id}",
+        ] {
+            assert!(spec["paths"][path].is_object(), "{path}");
+        }
+        let schemas = &spec["components"]["schemas"];
+        assert_eq!(
+            schemas["ErrorResponse"]["properties"]["message"]["type"],
+            "string"
+        );
+        assert!(schemas["LinkRecord"].is_object());
+        assert!(spec["components"]["securitySchemes"]["bearer"].is_object());
+    }
+}
@@ -0,0 +1,76 @@
+use std::time::Duration;
+
+use sqlx::{types::Json, PgPool, Postgres, Transaction};
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use crate::shortener::webhooks::{LinkEvent, Notifier};
+
+// events published per round trip
+const BATCH: i64 = 100;
+
+/// Store `event` in the transaction of the change it is about, so it is published
+/// if and only if the change commits.
+pub async fn push(
+    tx: &mut Transaction<'_, Postgres>,
+    owner: Option<i64>,
+    event: &LinkEvent,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO outbox (owner_id, event) VALUES ($1, $2)",
+        owner,
+        Json(event) as _,
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+/// Hand pending events to the notifier in the order they were written, returns how many.
+/// Events are removed once queued, relays running concurrently skip each other's rows.
+pub async fn relay(db: &PgPool, notifier: &Notifier) -> Result<usize, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let events = sqlx::query!(
+        r#"
+        SELECT id, owner_id, event AS "event: Json<LinkEvent>"
+        FROM outbox ORDER BY id LIMIT $1 FOR UPDATE SKIP LOCKED
+        "#,
+        BATCH,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let mut published = Vec::with_capacity(events.len());
+    for row in events {
+        // the notifier is gone on shutdown, the rest is published after the restart
+        if !notifier.publish(row.owner_id, row.event.0).await {
+            break;
+        }
+        published.push(row.id);
+    }
+    sqlx::query!("DELETE FROM outbox WHERE id = ANY($1)", &published)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(published.len())
+}
+
+/// Relay pending events every `interval`, for as long as the process runs.
+/// A full batch is followed by the next one right away.
+pub fn spawn_relay(db: PgPool, notifier: Notifier, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            loop {
+                match relay(&db, &notifier).await {
+                    Ok(n) if n as i64 == BATCH => {}
+                    Ok(_) => break,
+                    Err(e) => {
+                        warn!("failed to relay outbox events: {e}");
+                        break;
+                    }
+                }
+            }
+        }
+    })
+}
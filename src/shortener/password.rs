@@ -0,0 +1,308 @@
+use std::{
+    convert::Infallible,
+    hash::Hash,
+    net::{IpAddr, Ipv6Addr},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Query},
+    http::request::Parts,
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use dashmap::DashMap;
+use http::{header::AUTHORIZATION, StatusCode};
+use maud::{html, Markup, DOCTYPE};
+use serde::Deserialize;
+
+use crate::shortener::{auth::verify_password, AppError, AppState};
+
+/// Password given for a protected link: `?pw=` or `Authorization: Basic` with any username.
+#[derive(Debug, Clone, Default)]
+pub struct LinkPassword(pub Option<String>);
+
+#[derive(Debug, Deserialize)]
+pub struct UnlockForm {
+    pub password: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct PasswordParams {
+    pw: Option<String>,
+}
+
+/// Failed password attempts per link and client, used to slow down guessing. Per
+/// client, so someone guessing doesn't lock everyone else out of the link, and per
+/// link with a higher limit, so someone guessing from many addresses is held up too.
+#[derive(Debug, Default)]
+pub struct Failures {
+    // (tenant/link id, client) -> (failures, start of the window)
+    attempts: DashMap<Attempter, (u32, Instant)>,
+    // tenant/link id -> failures of all its clients
+    links: DashMap<String, (u32, Instant)>,
+}
+
+// clients of unknown address share one entry per link, and so do those of one /64,
+// which is handed out whole to a single ipv6 host
+type Attempter = (String, Option<IpAddr>);
+
+// clients tracked at most, beyond that only the failures per link count until the
+// expired entries are swept
+const MAX_ATTEMPTERS: usize = 100_000;
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for LinkPassword {
+    // a missing or malformed password is treated as not given
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Infallible> {
+        let Query(params) = Query::<PasswordParams>::from_request_parts(parts, state)
+            .await
+            .unwrap_or_default();
+        if params.pw.is_some() {
+            return Ok(Self(params.pw));
+        }
+        let basic = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Basic "))
+            .and_then(|v| STANDARD.decode(v).ok())
+            .and_then(|v| String::from_utf8(v).ok())
+            .and_then(|v| v.split_once(':').map(|(_, pw)| pw.to_string()));
+        Ok(Self(basic))
+    }
+}
+
+impl Failures {
+    fn is_locked(&self, key: &Attempter, max: u32, link_max: u32, window: Duration) -> bool {
+        is_locked(&self.attempts, key, max, window)
+            || is_locked(&self.links, &key.0, link_max, window)
+    }
+
+    fn record(&self, key: &Attempter, window: Duration) {
+        if !self.attempts.contains_key(key) && self.attempts.len() >= MAX_ATTEMPTERS {
+            self.attempts
+                .retain(|_, (_, since)| since.elapsed() < window);
+            self.links.retain(|_, (_, since)| since.elapsed() < window);
+        }
+        record(&self.links, &key.0);
+        if self.attempts.contains_key(key) || self.attempts.len() < MAX_ATTEMPTERS {
+            record(&self.attempts, key);
+        }
+    }
+
+    // the failures of the link from other clients still count
+    fn clear(&self, key: &Attempter) {
+        self.attempts.remove(key);
+    }
+}
+
+fn is_locked<K: Eq + Hash>(
+    failures: &DashMap<K, (u32, Instant)>,
+    key: &K,
+    max: u32,
+    window: Duration,
+) -> bool {
+    let Some(entry) = failures.get(key) else {
+        return false;
+    };
+    let (count, since) = *entry;
+    // release the shard lock before removing
+    drop(entry);
+    if since.elapsed() >= window {
+        failures.remove(key);
+        return false;
+    }
+    count >= max
+}
+
+fn record<K: Eq + Hash + Clone>(failures: &DashMap<K, (u32, Instant)>, key: &K) {
+    failures
+        .entry(key.clone())
+        .and_modify(|(count, _)| *count += 1)
+        .or_insert((1, Instant::now()));
+}
+
+// the network of an ipv6 client, an ipv4 one as is
+fn attempter(client: IpAddr) -> IpAddr {
+    match client.to_canonical() {
+        IpAddr::V6(ip) => {
+            let network = u128::from(ip) & (u128::MAX << 64);
+            IpAddr::V6(Ipv6Addr::from(network))
+        }
+        ip => ip,
+    }
+}
+
+impl AppState {
+    /// Check the password of a protected link given by `client`. Returns the form to show
+    /// when the password is missing or wrong, `TooManyRequests` while too many attempts
+    /// of the client failed.
+    pub async fn unlock(
+        &self,
+        tenant: &str,
+        id: &str,
+        hash: &str,
+        password: Option<String>,
+        client: Option<IpAddr>,
+    ) -> Result<Option<Response>, AppError> {
+        let Some(password) = password else {
+            return Ok(Some(unlock_page(id, None)));
+        };
+        let window = Duration::from_secs(self.config.password_lockout_secs);
+        let max = self.config.password_max_failures;
+        let link_max = self.config.password_max_link_failures;
+        // the same id in another tenant is another link
+        let key = (format!("{tenant}/{id}"), client.map(attempter));
+        if self
+            .password_failures
+            .is_locked(&key, max, link_max, window)
+        {
+            return Err(AppError::TooManyRequests(format!(
+                "too many wrong passwords for {id}, try again later"
+            )));
+        }
+        if verify_password(password, hash.to_string()).await? {
+            self.password_failures.clear(&key);
+            return Ok(None);
+        }
+        self.password_failures.record(&key, window);
+        Ok(Some(unlock_page(id, Some("Wrong password"))))
+    }
+}
+
+fn unlock_page(id: &str, error: Option<&str>) -> Response {
+    (StatusCode::UNAUTHORIZED, render_unlock(id, error)).into_response()
+}
+
+pub fn render_unlock(id: &str, error: Option<&str>) -> Markup {
+    html! {
+        (DOCTYPE)
+        html {
+            head {
+                meta charset="utf-8";
+                meta name="robots" content="noindex";
+                title { "Protected link" }
+            }
+            body {
+                h1 { "This link is password protected" }
+                @if let Some(error) = error {
+                    p { strong { (error) } }
+                }
+                // relative, so it posts back under the tenant prefix too
+                form method="post" action=(id) {
+                    input type="password" name="password" autofocus required;
+                    button type="submit" { "Continue" }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failures_should_lock_within_window() {
+        let failures = Failures::default();
+        let window = Duration::from_secs(60);
+        let client = Some("203.0.113.7".parse().unwrap());
+        let abc = ("default/abc".to_string(), client);
+        for _ in 0..3 {
+            assert!(!failures.is_locked(&abc, 3, 10, window));
+            failures.record(&abc, window);
+        }
+        assert!(failures.is_locked(&abc, 3, 10, window));
+        let other = ("default/other".to_string(), client);
+        assert!(!failures.is_locked(&other, 3, 10, window));
+        // other clients of the link are not locked out
+        let elsewhere = (
+            "default/abc".to_string(),
+            Some("198.51.100.1".parse().unwrap()),
+        );
+        assert!(!failures.is_locked(&elsewhere, 3, 10, window));
+
+        // an expired window starts over
+        assert!(!failures.is_locked(&abc, 3, 10, Duration::ZERO));
+        assert!(!failures.is_locked(&abc, 3, 10, window));
+    }
+
+    #[test]
+    fn failures_from_many_addresses_should_lock_the_link() {
+        let failures = Failures::default();
+        let window = Duration::from_secs(60);
+        let key = |ip: &str| {
+            (
+                "default/abc".to_string(),
+                Some(attempter(ip.parse().unwrap())),
+            )
+        };
+        // a new address of the same /64 for every guess is the same client
+        for i in 0..3 {
+            let rotated = key(&format!("2001:db8:1:2::{i:x}"));
+            assert!(!failures.is_locked(&rotated, 3, 10, window));
+            failures.record(&rotated, window);
+        }
+        assert!(failures.is_locked(&key("2001:db8:1:2:ffff::1"), 3, 10, window));
+        assert!(!failures.is_locked(&key("2001:db8:1:3::1"), 3, 10, window));
+
+        // and a new network for every guess still runs into the limit of the link
+        for i in 3..10 {
+            let rotated = key(&format!("198.51.100.{i}"));
+            assert!(!failures.is_locked(&rotated, 3, 10, window));
+            failures.record(&rotated, window);
+        }
+        assert!(failures.is_locked(&key("203.0.113.1"), 3, 10, window));
+        assert!(!failures.is_locked(&("default/other".to_string(), None), 3, 10, window));
+    }
+
+    #[test]
+    fn failures_should_not_track_clients_without_bound() {
+        let failures = Failures::default();
+        let window = Duration::from_secs(60);
+        for i in 0..MAX_ATTEMPTERS as u32 {
+            let client = Some(IpAddr::from(i.to_be_bytes()));
+            failures.record(&(format!("default/{}", i % 100), client), window);
+        }
+        assert_eq!(failures.attempts.len(), MAX_ATTEMPTERS);
+        let late = (
+            "default/abc".to_string(),
+            Some("203.0.113.7".parse().unwrap()),
+        );
+        failures.record(&late, window);
+        assert_eq!(failures.attempts.len(), MAX_ATTEMPTERS);
+        assert_eq!(failures.links.get("default/abc").unwrap().0, 1);
+
+        // expired entries make room again
+        failures.record(&late, Duration::ZERO);
+        assert_eq!(failures.attempts.len(), 1);
+        assert_eq!(failures.links.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn password_should_be_extracted() {
+        let extract = |req: http::Request<()>| async move {
+            let (mut parts, _) = req.into_parts();
+            LinkPassword::from_request_parts(&mut parts, &())
+                .await
+                .unwrap()
+                .0
+        };
+        let req = http::Request::get("/abc?pw=secret").body(()).unwrap();
+        assert_eq!(extract(req).await.as_deref(), Some("secret"));
+
+        let basic = format!("Basic {}", STANDARD.encode(":secret"));
+        let req = http::Request::get("/abc")
+            .header(AUTHORIZATION, basic)
+            .body(())
+            .unwrap();
+        assert_eq!(extract(req).await.as_deref(), Some("secret"));
+
+        let req = http::Request::get("/abc").body(()).unwrap();
+        assert_eq!(extract(req).await, None);
+    }
+}
@@ -0,0 +1,184 @@
+use axum::{
+    extract::{Path, State},
+    response::Response,
+};
+use maud::{html, Markup, DOCTYPE};
+use serde::Deserialize;
+
+use crate::shortener::{
+    analytics::Visitor, client_ip::ClientIp, follow_link, hosts::RequestHost,
+    password::LinkPassword, split::StickyArms, tenants::Tenant, AppError, AppState,
+};
+
+// only scan the beginning of the document for the title
+const MAX_SCAN_BYTES: usize = 64 * 1024;
+const MAX_TITLE_CHARS: usize = 200;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RedirectParams {
+    // `?preview=1` shows the preview page instead of redirecting
+    pub preview: Option<String>,
+}
+
+impl RedirectParams {
+    pub fn wants_preview(&self) -> bool {
+        matches!(self.preview.as_deref(), Some(v) if v != "0" && v != "false")
+    }
+}
+
+// show where a short link leads before following it. only to those who may follow it: a
+// protected link asks for its password, and a disabled, used up or inactive one shows no more
+// than following it would
+#[allow(clippy::too_many_arguments)]
+pub async fn preview_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    LinkPassword(password): LinkPassword,
+    ClientIp(ip): ClientIp,
+    host: RequestHost,
+    Tenant(tenant): Tenant,
+    visitor: Visitor,
+    arms: StickyArms,
+) -> Result<Response, AppError> {
+    let params = RedirectParams {
+        preview: Some("1".to_string()),
+    };
+    follow_link(
+        &state, &tenant, id, &params, password, ip, &host, &visitor, &arms, None,
+    )
+    .await
+}
+
+impl AppState {
+    // best effort: any failure just means the page shows no title
+    pub async fn fetch_title(&self, url: &str) -> Option<String> {
+        let mut res = self.http.get(url).ok()?.send().await.ok()?;
+        if !res.status().is_success() {
+            return None;
+        }
+        let mut body = Vec::new();
+        while let Some(chunk) = res.chunk().await.ok()? {
+            body.extend_from_slice(&chunk);
+            if body.len() >= MAX_SCAN_BYTES {
+                break;
+            }
+        }
+        extract_title(&String::from_utf8_lossy(&body))
+    }
+}
+
+pub fn render_preview(short_url: &str, url: &str, title: Option<&str>) -> Markup {
+    html! {
+        (DOCTYPE)
+        html {
+            head {
+                meta charset="utf-8";
+                title { "Preview of " (short_url) }
+            }
+            body {
+                h1 { "This link leads to" }
+                @if let Some(title) = title {
+                    p { strong { (title) } }
+                }
+                p { code { (url) } }
+                p { a href=(url) rel="noreferrer noopener" { "Continue to destination" } }
+            }
+        }
+    }
+}
+
+// shown instead of redirecting to a destination flagged by the url scanner
+pub fn render_warning(short_url: &str, url: &str, reason: &str) -> Markup {
+    html! {
+        (DOCTYPE)
+        html {
+            head {
+                meta charset="utf-8";
+                meta name="robots" content="noindex";
+                title { "Warning: " (short_url) " may be unsafe" }
+            }
+            body {
+                h1 { "This link may be unsafe" }
+                p { "The destination was flagged as " strong { (reason) } "." }
+                p { code { (url) } }
+                p { a href=(url) rel="noreferrer noopener nofollow" { "Continue at your own risk" } }
+            }
+        }
+    }
+}
+
+fn extract_title(html: &str) -> Option<String> {
+    // ascii lowercasing keeps byte offsets, so indexes work on both strings
+    let lower = html.to_ascii_lowercase();
+    let open = lower.find("<title")?;
+    let start = open + lower[open..].find('>')? + 1;
+    let end = start + lower[start..].find("</title")?;
+
+    let title = html[start..end]
+        .replace("&amp;", "&")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'");
+    let title: String = title
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .take(MAX_TITLE_CHARS)
+        .collect();
+    (!title.is_empty()).then_some(title)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extract_title_should_work() {
+        let html = r#"<html><HEAD><Title lang="en">
+            Rust &amp; Cargo
+        </TITLE></HEAD></html>"#;
+        assert_eq!(extract_title(html).as_deref(), Some("Rust & Cargo"));
+
+        assert_eq!(extract_title("<title></title>"), None);
+        assert_eq!(extract_title("<title>unterminated"), None);
+        assert_eq!(extract_title("<p>no title</p>"), None);
+    }
+
+    #[test]
+    fn preview_should_escape_destination() {
+        let page = render_preview(
+            "http://127.0.0.1:9876/abc123",
+            "https://example.com/?q=<script>",
+            Some("<b>title</b>"),
+        )
+        .into_string();
+        assert!(page.contains("https://example.com/?q=&lt;script&gt;"));
+        assert!(page.contains("&lt;b&gt;title&lt;/b&gt;"));
+        assert!(!page.contains("<script>"));
+    }
+
+    #[test]
+    fn warning_should_show_reason() {
+        let page = render_warning(
+            "http://127.0.0.1:9876/abc123",
+            "https://example.com/",
+            "MALWARE",
+        )
+        .into_string();
+        assert!(page.contains("<strong>MALWARE</strong>"));
+        assert!(page.contains(r#"href="https://example.com/""#));
+    }
+
+    #[test]
+    fn preview_param_should_parse() {
+        let params = |v: &str| RedirectParams {
+            preview: Some(v.to_string()),
+        };
+        assert!(params("1").wants_preview());
+        assert!(params("").wants_preview());
+        assert!(!params("0").wants_preview());
+        assert!(!RedirectParams::default().wants_preview());
+    }
+}
@@ -0,0 +1,180 @@
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use http::{header::CONTENT_TYPE, HeaderValue, StatusCode};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::shortener::{request_id, AppError, AppState};
+
+pub const PROBLEM_JSON: HeaderValue = HeaderValue::from_static("application/problem+json");
+
+tokio::task_local! {
+    // whether errors of the current request are answered with problem details
+    static ENABLED: bool;
+}
+
+/// RFC 7807 problem details, the error body when `problem_json` is on.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Problem {
+    /// identifies the kind of problem, e.g. `urn:myshortener:problem:not-found`
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// short summary, the same for every problem of a type
+    pub title: &'static str,
+    pub status: u16,
+    /// what went wrong with this request
+    pub detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl AppError {
+    fn problem_kind(&self) -> (&'static str, &'static str) {
+        use AppError::*;
+        match self {
+            Sqlx(_) => ("database-error", "Database error"),
+            Conflict(_) => ("conflict", "Conflict"),
+            Anyhow(_) | InternalServerError => ("internal-error", "Internal server error"),
+            HttpNotFound(_) => ("not-found", "Not found"),
+            BadRequest(_) => ("bad-request", "Bad request"),
+            Unauthorized(_) => ("unauthorized", "Unauthorized"),
+            Forbidden(_) => ("forbidden", "Forbidden"),
+            Gone(_) => ("click-limit-reached", "Click limit reached"),
+            DeadLink(_) => ("dead-link", "Destination no longer exists"),
+            TooManyRequests(_) => ("too-many-requests", "Too many requests"),
+            Exhausted(_) => ("ids-exhausted", "No free short id"),
+            DbBusy => ("database-busy", "Database busy"),
+        }
+    }
+
+    pub fn to_problem(&self) -> Problem {
+        let (kind, title) = self.problem_kind();
+        Problem {
+            kind: format!("urn:myshortener:problem:{kind}"),
+            title,
+            status: self.status_code().as_u16(),
+            detail: self.to_string(),
+            request_id: request_id::current(),
+        }
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(self) -> Response {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let mut res = (status, Json(self)).into_response();
+        res.headers_mut().insert(CONTENT_TYPE, PROBLEM_JSON);
+        res
+    }
+}
+
+/// Whether errors are answered with problem details, only within `scope_format`.
+pub fn enabled() -> bool {
+    ENABLED.try_with(|enabled| *enabled).unwrap_or(false)
+}
+
+/// Middleware: pick the error format of `problem_json` for the request.
+pub async fn scope_format(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    ENABLED
+        .scope(state.config.problem_json, next.run(req))
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn problem(e: AppError) -> Value {
+        serde_json::to_value(e.to_problem()).unwrap()
+    }
+
+    #[test]
+    fn every_variant_should_serialize_to_problem() {
+        let cases = [
+            (
+                AppError::Sqlx("connection refused".to_string()),
+                "database-error",
+                500,
+            ),
+            (AppError::Conflict("taken".to_string()), "conflict", 409),
+            (
+                AppError::Anyhow(anyhow::anyhow!("boom")),
+                "internal-error",
+                500,
+            ),
+            (AppError::HttpNotFound("abc".to_string()), "not-found", 404),
+            (
+                AppError::BadRequest("no url".to_string()),
+                "bad-request",
+                400,
+            ),
+            (
+                AppError::Unauthorized("no token".to_string()),
+                "unauthorized",
+                401,
+            ),
+            (
+                AppError::Forbidden("not yours".to_string()),
+                "forbidden",
+                403,
+            ),
+            (
+                AppError::Gone("abc".to_string()),
+                "click-limit-reached",
+                410,
+            ),
+            (AppError::DeadLink("abc".to_string()), "dead-link", 410),
+            (
+                AppError::TooManyRequests("slow down".to_string()),
+                "too-many-requests",
+                429,
+            ),
+            (AppError::Exhausted(9), "ids-exhausted", 503),
+            (AppError::DbBusy, "database-busy", 503),
+            (AppError::InternalServerError, "internal-error", 500),
+        ];
+        for (e, kind, status) in cases {
+            let detail = e.to_string();
+            let value = problem(e);
+            assert_eq!(value["type"], format!("urn:myshortener:problem:{kind}"));
+            assert_eq!(value["status"], status);
+            assert_eq!(value["detail"], detail);
+            assert!(value["title"].as_str().is_some_and(|t| !t.is_empty()));
+            // no request id outside of a request
+            assert!(value.get("request_id").is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn format_should_follow_the_flag() {
+        let res = AppError::HttpNotFound("abc".to_string()).into_response();
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/json");
+
+        let res = ENABLED
+            .scope(true, async {
+                AppError::HttpNotFound("abc".to_string()).into_response()
+            })
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(res.headers()[CONTENT_TYPE], PROBLEM_JSON);
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "type": "urn:myshortener:problem:not-found",
+                "title": "Not found",
+                "status": 404,
+                "detail": "not found for abc",
+            })
+        );
+    }
+}
@@ -0,0 +1,355 @@
+use axum::{
+    extract::{Path, Query, State},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use http::StatusCode;
+use maud::{html, DOCTYPE};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::shortener::{
+    auth::AdminUser, client_ip::ClientIp, tenants::Tenant, AppError, AppState, ErrorResponse,
+};
+
+const MAX_REASON_CHARS: usize = 1000;
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ReportReq {
+    /// why the link is malicious, e.g. phishing or malware
+    pub reason: String,
+}
+
+/// A report of a link by a visitor.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Report {
+    pub id: i64,
+    pub link_id: String,
+    /// destination of the reported link
+    pub url: String,
+    pub reason: String,
+    pub created_at: DateTime<Utc>,
+    /// null while the report waits for review
+    pub resolved_at: Option<DateTime<Utc>>,
+    /// `disabled` or `cleared`
+    pub resolution: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ReportFilter {
+    /// reviewed reports instead of the pending ones
+    #[serde(default)]
+    pub resolved: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Moderation {
+    /// the link stops redirecting
+    Disable,
+    /// the reports are dismissed, a disabled link redirects again
+    Clear,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ModerationReq {
+    pub action: Moderation,
+    /// disable for legal reasons, the link answers 451 instead of 410
+    #[serde(default)]
+    pub legal: bool,
+    /// shown to visitors of a disabled link
+    #[serde(default)]
+    pub note: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ModerationRes {
+    pub link_id: String,
+    /// pending reports of the link resolved by this action
+    pub resolved: i64,
+    pub disabled: bool,
+}
+
+impl Moderation {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Disable => "disabled",
+            Self::Clear => "cleared",
+        }
+    }
+}
+
+fn validate_reason(reason: &str) -> Result<String, String> {
+    let reason = reason.trim();
+    if reason.is_empty() || reason.chars().count() > MAX_REASON_CHARS {
+        return Err(format!(
+            "reason must have 1 to {MAX_REASON_CHARS} characters"
+        ));
+    }
+    Ok(reason.to_string())
+}
+
+/// Explanation served instead of the redirect of a disabled link, 451 or 410.
+pub fn disabled_page(short_url: &str, status: i16, note: Option<&str>) -> Response {
+    let status = match status {
+        451 => StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS,
+        _ => StatusCode::GONE,
+    };
+    let heading = match status {
+        StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS => "This link is unavailable for legal reasons",
+        _ => "This link has been disabled",
+    };
+    let page = html! {
+        (DOCTYPE)
+        html {
+            head {
+                meta charset="utf-8";
+                meta name="robots" content="noindex";
+                title { (short_url) " is disabled" }
+            }
+            body {
+                h1 { (heading) }
+                p { "It was reported and disabled after review." }
+                @if let Some(note) = note {
+                    p { strong { (note) } }
+                }
+            }
+        }
+    };
+    (status, page).into_response()
+}
+
+impl AppState {
+    // returns false if there is no such link. a visitor's pending report of a link
+    // is only kept once
+    async fn report_link(
+        &self,
+        tenant: &str,
+        id: &str,
+        reason: &str,
+        ip: Option<String>,
+    ) -> Result<bool, AppError> {
+        let ret = sqlx::query!(
+            r#"
+            INSERT INTO reports (tenant, link_id, reason, reporter_ip)
+            SELECT tenant, id, $3, $4 FROM urls
+            WHERE tenant = $1 AND id = $2 AND deleted_at IS NULL
+            ON CONFLICT (tenant, link_id, reporter_ip) WHERE resolved_at IS NULL DO NOTHING
+            "#,
+            tenant,
+            id,
+            reason,
+            ip,
+        )
+        .execute(self.db.primary())
+        .await?;
+        if ret.rows_affected() > 0 {
+            return Ok(true);
+        }
+        let exists = sqlx::query_scalar!(
+            r#"
+            SELECT EXISTS(
+                SELECT 1 FROM urls WHERE tenant = $1 AND id = $2 AND deleted_at IS NULL
+            ) AS "exists!"
+            "#,
+            tenant,
+            id,
+        )
+        .fetch_one(self.db.primary())
+        .await?;
+        Ok(exists)
+    }
+
+    // the pending queue is served by the partial index on unresolved reports
+    async fn list_reports(
+        &self,
+        tenant: &str,
+        filter: &ReportFilter,
+    ) -> Result<Vec<Report>, AppError> {
+        let reports = sqlx::query_as!(
+            Report,
+            r#"
+            SELECT r.id, r.link_id, u.url, r.reason, r.created_at, r.resolved_at, r.resolution
+            FROM reports r JOIN urls u ON u.tenant = r.tenant AND u.id = r.link_id
+            WHERE r.tenant = $1 AND (r.resolved_at IS NOT NULL) = $2
+            ORDER BY r.created_at
+            "#,
+            tenant,
+            filter.resolved,
+        )
+        .fetch_all(self.db.primary())
+        .await?;
+        Ok(reports)
+    }
+
+    // acts on the link of the report and resolves all its pending reports,
+    // None if there is no such report
+    pub async fn moderate(
+        &self,
+        tenant: &str,
+        report: i64,
+        req: &ModerationReq,
+    ) -> Result<Option<ModerationRes>, AppError> {
+        let mut tx = self.db.primary().begin().await?;
+        let link_id = sqlx::query_scalar!(
+            "SELECT link_id FROM reports WHERE tenant = $1 AND id = $2",
+            tenant,
+            report,
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(link_id) = link_id else {
+            return Ok(None);
+        };
+        let (status, note) = match req.action {
+            Moderation::Disable => (
+                Some(if req.legal { 451i16 } else { 410 }),
+                req.note.as_deref(),
+            ),
+            Moderation::Clear => (None, None),
+        };
+        sqlx::query!(
+            r#"
+            UPDATE urls SET disabled_status = $3, disabled_note = $4
+            WHERE tenant = $1 AND id = $2
+            "#,
+            tenant,
+            link_id,
+            status,
+            note,
+        )
+        .execute(&mut *tx)
+        .await?;
+        let resolved = sqlx::query!(
+            r#"
+            UPDATE reports SET resolved_at = now(), resolution = $3
+            WHERE tenant = $1 AND link_id = $2 AND resolved_at IS NULL
+            "#,
+            tenant,
+            link_id,
+            req.action.as_str(),
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        self.wrote(&link_id);
+        Ok(Some(ModerationRes {
+            link_id,
+            resolved: resolved.rows_affected() as i64,
+            disabled: status.is_some(),
+        }))
+    }
+}
+
+/// Report a link as malicious, it is queued for review by an admin.
+#[utoipa::path(
+    post,
+    path = "/{id}/report",
+    tag = "redirect",
+    params(("id" = String, Path, description = "short id")),
+    request_body = ReportReq,
+    responses(
+        (status = 202, description = "report queued"),
+        (status = 400, description = "invalid reason", body = ErrorResponse),
+        (status = 404, description = "no such link", body = ErrorResponse),
+    )
+)]
+pub async fn report_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Tenant(tenant): Tenant,
+    ClientIp(ip): ClientIp,
+    Json(req): Json<ReportReq>,
+) -> Result<StatusCode, AppError> {
+    let reason = validate_reason(&req.reason).map_err(AppError::BadRequest)?;
+    let ip = ip.map(|ip| ip.to_string());
+    if !state.report_link(&tenant, &id, &reason, ip).await? {
+        return Err(AppError::HttpNotFound(id));
+    }
+    info!(target: "audit", tenant, id, "link reported");
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Reports waiting for review, oldest first.
+#[utoipa::path(
+    get,
+    path = "/api/v1/admin/reports",
+    tag = "admin",
+    params(ReportFilter),
+    responses(
+        (status = 200, description = "reports", body = Vec<Report>),
+        (status = 401, description = "missing or invalid token", body = ErrorResponse),
+        (status = 403, description = "not allowed", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn list_reports_handler(
+    State(state): State<AppState>,
+    _admin: AdminUser,
+    Tenant(tenant): Tenant,
+    Query(filter): Query<ReportFilter>,
+) -> Result<Json<Vec<Report>>, AppError> {
+    Ok(Json(state.list_reports(&tenant, &filter).await?))
+}
+
+/// Disable the reported link or clear its reports. All pending reports of the link
+/// are resolved at once.
+#[utoipa::path(
+    post,
+    path = "/api/v1/admin/reports/{id}",
+    tag = "admin",
+    params(("id" = i64, Path, description = "report id")),
+    request_body = ModerationReq,
+    responses(
+        (status = 200, description = "link moderated", body = ModerationRes),
+        (status = 401, description = "missing or invalid token", body = ErrorResponse),
+        (status = 403, description = "not allowed", body = ErrorResponse),
+        (status = 404, description = "no such report", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn moderate_handler(
+    State(state): State<AppState>,
+    AdminUser(admin): AdminUser,
+    Tenant(tenant): Tenant,
+    Path(report): Path<i64>,
+    Json(req): Json<ModerationReq>,
+) -> Result<Json<ModerationRes>, AppError> {
+    let Some(res) = state.moderate(&tenant, report, &req).await? else {
+        return Err(AppError::HttpNotFound(format!("report {report}")));
+    };
+    info!(
+        target: "audit",
+        admin = %admin.username,
+        tenant,
+        id = %res.link_id,
+        action = req.action.as_str(),
+        legal = req.legal,
+        resolved = res.resolved,
+        "link moderated"
+    );
+    Ok(Json(res))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reasons_should_be_validated() {
+        assert_eq!(validate_reason("  phishing ").unwrap(), "phishing");
+        assert!(validate_reason(" ").is_err());
+        assert!(validate_reason(&"x".repeat(MAX_REASON_CHARS + 1)).is_err());
+    }
+
+    #[test]
+    fn disabled_page_should_use_status() {
+        let res = disabled_page("http://127.0.0.1:9876/abc123", 451, Some("court order"));
+        assert_eq!(res.status(), StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS);
+        let res = disabled_page("http://127.0.0.1:9876/abc123", 410, None);
+        assert_eq!(res.status(), StatusCode::GONE);
+    }
+}
@@ -0,0 +1,99 @@
+use std::time::Instant;
+
+use axum::{
+    extract::{MatchedPath, Request},
+    middleware::Next,
+    response::Response,
+};
+use http::{HeaderName, HeaderValue};
+use nanoid::nanoid;
+use tracing::{info, info_span, Instrument};
+
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// longer incoming ids are replaced by a generated one
+const MAX_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    // the id of the request being served by the current task
+    static REQUEST_ID: String;
+}
+
+/// Id of the request being served, if called within `request_id` middleware.
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// Propagate the caller's `x-request-id` (or generate one), record it on a span around the
+/// request and echo it in the response. Emits one access log event per request, with
+/// the route it matched, empty if none.
+pub async fn request_id(mut req: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let id = req
+        .headers()
+        .get(&X_REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| is_valid(v))
+        .map(String::from)
+        .unwrap_or_else(|| nanoid!());
+    let header = HeaderValue::from_str(&id).expect("request id is a valid header value");
+    req.headers_mut().insert(X_REQUEST_ID, header.clone());
+
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|route| route.as_str().to_string())
+        .unwrap_or_default();
+    let span = info_span!("request", request_id = %id);
+    let mut res = REQUEST_ID
+        .scope(id.clone(), next.run(req).instrument(span.clone()))
+        .await;
+
+    span.in_scope(|| {
+        info!(
+            target: "access",
+            // also on the event, json logs don't flatten span fields
+            request_id = %id,
+            %method,
+            path,
+            route,
+            status = res.status().as_u16(),
+            latency_ms = start.elapsed().as_millis() as u64,
+            "request served"
+        )
+    });
+    res.headers_mut().insert(X_REQUEST_ID, header);
+    res
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_ID_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn incoming_ids_should_be_validated() {
+        assert!(is_valid("3f2b9c1e-5d4a-4b8e-9f6d-2a1c7e8b0d3f"));
+        assert!(!is_valid(""));
+        assert!(!is_valid("with space"));
+        assert!(!is_valid(&"a".repeat(MAX_ID_LEN + 1)));
+    }
+
+    #[tokio::test]
+    async fn current_should_be_scoped() {
+        assert_eq!(current(), None);
+        let id = REQUEST_ID
+            .scope("abc".to_string(), async { current() })
+            .await;
+        assert_eq!(id.as_deref(), Some("abc"));
+    }
+}
@@ -0,0 +1,53 @@
+use nanoid::nanoid;
+
+/// Path segments that must never become short ids: every top level route, plus
+/// names kept for routes yet to come. Add new top level routes here.
+pub const RESERVED: &[&str] = &[
+    "admin",
+    "api",
+    "assets",
+    "docs",
+    "favicon.ico",
+    "health",
+    "healthz",
+    "links",
+    "login",
+    "logout",
+    "metrics",
+    "openapi.json",
+    "qr",
+    "readyz",
+    "robots.txt",
+    "static",
+    "swagger-ui",
+    // tenant prefix, see `tenants`
+    "t",
+];
+
+/// Whether `id` would shadow a route, compared case-insensitively.
+pub fn is_reserved(id: &str) -> bool {
+    RESERVED.iter().any(|word| word.eq_ignore_ascii_case(id))
+}
+
+/// A random id of `len` chars that is not reserved.
+pub fn new_id(len: usize) -> String {
+    loop {
+        let id = nanoid!(len);
+        if !is_reserved(&id) {
+            return id;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserved_should_ignore_case() {
+        assert!(is_reserved("api"));
+        assert!(is_reserved("Metrics"));
+        assert!(!is_reserved("apis"));
+        assert_eq!(new_id(7).len(), 7);
+    }
+}
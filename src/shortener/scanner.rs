@@ -0,0 +1,158 @@
+use std::fmt;
+
+use anyhow::Result;
+use axum::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::warn;
+
+use crate::shortener::{AppError, AppState};
+
+const SAFE_BROWSING_URL: &str = "https://safebrowsing.googleapis.com/v4/threatMatches:find";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Clean,
+    // the link is kept but visitors get a warning page instead of a redirect
+    Suspicious(String),
+    Malicious(String),
+}
+
+/// Reputation check of destination urls before they are stored.
+#[async_trait]
+pub trait UrlScanner: fmt::Debug + Send + Sync {
+    async fn scan(&self, url: &str) -> Result<Verdict>;
+}
+
+/// Default scanner, every url is clean.
+#[derive(Debug, Default)]
+pub struct NoopScanner;
+
+/// Google Safe Browsing v4 lookup api.
+#[derive(Debug)]
+pub struct SafeBrowsingScanner {
+    client: reqwest::Client,
+    api_key: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ThreatMatches {
+    #[serde(default)]
+    matches: Vec<ThreatMatch>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ThreatMatch {
+    threat_type: String,
+}
+
+#[async_trait]
+impl UrlScanner for NoopScanner {
+    async fn scan(&self, _url: &str) -> Result<Verdict> {
+        Ok(Verdict::Clean)
+    }
+}
+
+impl SafeBrowsingScanner {
+    pub fn new(client: reqwest::Client, api_key: impl Into<String>) -> Self {
+        Self {
+            client,
+            api_key: api_key.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl UrlScanner for SafeBrowsingScanner {
+    async fn scan(&self, url: &str) -> Result<Verdict> {
+        let body = json!({
+            "client": { "clientId": "myshortener", "clientVersion": env!("CARGO_PKG_VERSION") },
+            "threatInfo": {
+                "threatTypes": [
+                    "MALWARE",
+                    "SOCIAL_ENGINEERING",
+                    "UNWANTED_SOFTWARE",
+                    "POTENTIALLY_HARMFUL_APPLICATION"
+                ],
+                "platformTypes": ["ANY_PLATFORM"],
+                "threatEntryTypes": ["URL"],
+                "threatEntries": [{ "url": url }]
+            }
+        });
+        let matches: ThreatMatches = self
+            .client
+            .post(SAFE_BROWSING_URL)
+            .query(&[("key", &self.api_key)])
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(verdict_of(&matches))
+    }
+}
+
+// malware and phishing are malicious, the other threat types only suspicious
+fn verdict_of(matches: &ThreatMatches) -> Verdict {
+    let types: Vec<&str> = matches
+        .matches
+        .iter()
+        .map(|m| m.threat_type.as_str())
+        .collect();
+    if let Some(t) = types
+        .iter()
+        .find(|t| matches!(**t, "MALWARE" | "SOCIAL_ENGINEERING"))
+    {
+        return Verdict::Malicious(t.to_string());
+    }
+    match types.first() {
+        Some(t) => Verdict::Suspicious(t.to_string()),
+        None => Verdict::Clean,
+    }
+}
+
+impl AppState {
+    /// Scan a url before storing it: malicious urls are rejected (or flagged if the
+    /// config says so), suspicious ones flagged. Returns the flag reason if any.
+    /// The scanner being unavailable doesn't block shortening.
+    pub async fn screen_url(&self, url: &str) -> Result<Option<String>, AppError> {
+        let verdict = match self.scanner.scan(url).await {
+            Ok(verdict) => verdict,
+            Err(e) => {
+                warn!("failed to scan {url}: {e:?}");
+                return Ok(None);
+            }
+        };
+        match verdict {
+            Verdict::Clean => Ok(None),
+            Verdict::Suspicious(reason) => Ok(Some(reason)),
+            Verdict::Malicious(reason) if self.config.flag_malicious => Ok(Some(reason)),
+            Verdict::Malicious(reason) => Err(AppError::Forbidden(format!(
+                "url is considered malicious: {reason}"
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verdict_should_follow_threat_types() {
+        let parse = |s: &str| verdict_of(&serde_json::from_str(s).unwrap());
+        assert_eq!(parse("{}"), Verdict::Clean);
+        assert_eq!(
+            parse(r#"{"matches": [{"threatType": "UNWANTED_SOFTWARE"}]}"#),
+            Verdict::Suspicious("UNWANTED_SOFTWARE".to_string())
+        );
+        assert_eq!(
+            parse(
+                r#"{"matches": [{"threatType": "UNWANTED_SOFTWARE"}, {"threatType": "MALWARE"}]}"#
+            ),
+            Verdict::Malicious("MALWARE".to_string())
+        );
+    }
+}
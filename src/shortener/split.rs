@@ -0,0 +1,346 @@
+use std::convert::Infallible;
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Path, State},
+    http::request::Parts,
+    Json,
+};
+use http::header::COOKIE;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use utoipa::ToSchema;
+
+use crate::shortener::{
+    auth::AuthUser, hosts::RequestHost, tenants::Tenant, validate_url, AppError, AppState,
+    ErrorResponse,
+};
+
+const MAX_DESTINATIONS: usize = 10;
+// sticky visitors keep their destination for 30 days
+const STICKY_MAX_AGE: u64 = 30 * 24 * 3600;
+const COOKIE_PREFIX: &str = "shortener_arm_";
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct Destination {
+    pub url: String,
+    /// share of the clicks relative to the other destinations
+    #[serde(default = "default_weight")]
+    pub weight: i32,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SplitReq {
+    /// 2 or more destinations, none to redirect to the link's url again
+    pub destinations: Vec<Destination>,
+    /// visitors keep the destination of their first click, by cookie
+    #[serde(default)]
+    pub sticky: bool,
+}
+
+/// The destinations of a split link and the logged clicks each one served.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Split {
+    pub sticky: bool,
+    pub destinations: Vec<Arm>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Arm {
+    /// position in the request, recorded with each click
+    pub arm: i16,
+    pub url: String,
+    pub weight: i32,
+    pub clicks: i64,
+}
+
+/// The arms split links served this visitor before, by short id.
+#[derive(Debug, Clone, Default)]
+pub struct StickyArms(Vec<(String, i16)>);
+
+fn default_weight() -> i32 {
+    1
+}
+
+impl StickyArms {
+    pub fn get(&self, id: &str) -> Option<i16> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == id)
+            .map(|(_, arm)| *arm)
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for StickyArms {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &AppState) -> Result<Self, Infallible> {
+        let arms = parts
+            .headers
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .filter_map(|(key, value)| {
+                let id = key.strip_prefix(COOKIE_PREFIX)?;
+                Some((id.to_string(), value.parse().ok()?))
+            })
+            .collect();
+        Ok(Self(arms))
+    }
+}
+
+/// The arm serving a click: the sticky one if it still exists, else picked by weight.
+pub fn choose(weights: &[i32], sticky: Option<i16>) -> usize {
+    if let Some(arm) = sticky.and_then(|arm| usize::try_from(arm).ok()) {
+        if arm < weights.len() {
+            return arm;
+        }
+    }
+    let total: i64 = weights.iter().map(|w| *w as i64).sum();
+    pick(weights, rand::thread_rng().gen_range(0..total))
+}
+
+// the arm whose share of `0..total` contains `roll`
+fn pick(weights: &[i32], mut roll: i64) -> usize {
+    for (arm, weight) in weights.iter().enumerate() {
+        if roll < *weight as i64 {
+            return arm;
+        }
+        roll -= *weight as i64;
+    }
+    weights.len() - 1
+}
+
+/// Keeps a visitor on `arm` of the link, only sent to the short url.
+pub fn sticky_cookie(host: &RequestHost, id: &str, arm: usize) -> String {
+    let secure = if host.https { "; Secure" } else { "" };
+    format!(
+        "{COOKIE_PREFIX}{id}={arm}; Path={}/{id}; Max-Age={STICKY_MAX_AGE}; HttpOnly; SameSite=Lax{secure}",
+        host.prefix
+    )
+}
+
+fn validate_destinations(destinations: &[Destination]) -> Result<(), String> {
+    if destinations.len() == 1 || destinations.len() > MAX_DESTINATIONS {
+        return Err(format!(
+            "a split needs 2 to {MAX_DESTINATIONS} destinations, or none to remove it"
+        ));
+    }
+    for d in destinations {
+        validate_url(&d.url)?;
+        if d.weight < 1 {
+            return Err(format!("weight of {} must be positive", d.url));
+        }
+    }
+    Ok(())
+}
+
+impl AppState {
+    // destinations are replaced as a whole, returns None if the owner has no such link
+    async fn set_split(
+        &self,
+        tenant: &str,
+        id: &str,
+        owner: i64,
+        req: &SplitReq,
+    ) -> Result<Option<Split>, AppError> {
+        let urls: Vec<String> = req.destinations.iter().map(|d| d.url.clone()).collect();
+        let weights: Vec<i32> = req.destinations.iter().map(|d| d.weight).collect();
+        let mut tx = self.db.primary().begin().await?;
+        let owned = sqlx::query_scalar!(
+            r#"
+            UPDATE urls SET split_sticky = $4, version = version + 1, updated_at = now()
+            WHERE tenant = $1 AND id = $2 AND owner_id = $3 AND deleted_at IS NULL
+            RETURNING id
+            "#,
+            tenant,
+            id,
+            owner,
+            req.sticky,
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if owned.is_none() {
+            return Ok(None);
+        }
+        sqlx::query!(
+            "DELETE FROM destinations WHERE tenant = $1 AND link_id = $2",
+            tenant,
+            id,
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r#"
+            INSERT INTO destinations (tenant, link_id, arm, url, weight)
+            SELECT $1, $2, d.arm - 1, d.url, d.weight
+            FROM UNNEST($3::TEXT[], $4::INTEGER[]) WITH ORDINALITY AS d(url, weight, arm)
+            "#,
+            tenant,
+            id,
+            &urls,
+            &weights,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        self.wrote(id);
+        self.split(tenant, id, owner).await
+    }
+
+    // returns None if the owner has no such link
+    async fn split(&self, tenant: &str, id: &str, owner: i64) -> Result<Option<Split>, AppError> {
+        let db = self.db.reader(id);
+        let sticky = sqlx::query_scalar!(
+            r#"
+            SELECT split_sticky FROM urls
+            WHERE tenant = $1 AND id = $2 AND owner_id = $3 AND deleted_at IS NULL
+            "#,
+            tenant,
+            id,
+            owner,
+        )
+        .fetch_optional(db)
+        .await?;
+        let Some(sticky) = sticky else {
+            return Ok(None);
+        };
+        let destinations = sqlx::query_as!(
+            Arm,
+            r#"
+            SELECT d.arm, d.url, d.weight, (
+                SELECT count(*) FROM clicks c
+                WHERE c.tenant = d.tenant AND c.link_id = d.link_id AND c.arm = d.arm
+            ) AS "clicks!"
+            FROM destinations d
+            WHERE d.tenant = $1 AND d.link_id = $2
+            ORDER BY d.arm
+            "#,
+            tenant,
+            id,
+        )
+        .fetch_all(db)
+        .await?;
+        Ok(Some(Split {
+            sticky,
+            destinations,
+        }))
+    }
+}
+
+/// Split the clicks of a link between destinations by weight. Split links always
+/// answer 302, so browsers come back for every click.
+#[utoipa::path(
+    put,
+    path = "/api/v1/links/{id}/destinations",
+    tag = "links",
+    params(("id" = String, Path, description = "short id")),
+    request_body = SplitReq,
+    responses(
+        (status = 200, description = "destinations replaced", body = Split),
+        (status = 400, description = "invalid destinations", body = ErrorResponse),
+        (status = 401, description = "missing or invalid token", body = ErrorResponse),
+        (status = 403, description = "destination not allowed", body = ErrorResponse),
+        (status = 404, description = "no such link", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn set_split_handler(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Tenant(tenant): Tenant,
+    Path(id): Path<String>,
+    Json(req): Json<SplitReq>,
+) -> Result<Json<Split>, AppError> {
+    validate_destinations(&req.destinations).map_err(AppError::BadRequest)?;
+    for d in &req.destinations {
+        state.check_domain(&d.url).await?;
+        if let Some(reason) = state.screen_url(&d.url).await? {
+            return Err(AppError::Forbidden(format!(
+                "destination {} is flagged: {reason}",
+                d.url
+            )));
+        }
+    }
+    let Some(split) = state.set_split(&tenant, &id, user.id, &req).await? else {
+        return Err(AppError::HttpNotFound(id));
+    };
+    info!(
+        target: "audit",
+        user = %user.username,
+        tenant,
+        id,
+        destinations = split.destinations.len(),
+        "link split updated"
+    );
+    Ok(Json(split))
+}
+
+/// The destinations of a link and the clicks each one served, empty if not split.
+#[utoipa::path(
+    get,
+    path = "/api/v1/links/{id}/destinations",
+    tag = "links",
+    params(("id" = String, Path, description = "short id")),
+    responses(
+        (status = 200, description = "destinations", body = Split),
+        (status = 401, description = "missing or invalid token", body = ErrorResponse),
+        (status = 404, description = "no such link", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn get_split_handler(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Tenant(tenant): Tenant,
+    Path(id): Path<String>,
+) -> Result<Json<Split>, AppError> {
+    match state.split(&tenant, &id, user.id).await? {
+        Some(split) => Ok(Json(split)),
+        None => Err(AppError::HttpNotFound(id)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arms_should_be_picked_by_weight() {
+        let weights = [1, 3, 1];
+        let picked: Vec<usize> = (0..5).map(|roll| pick(&weights, roll)).collect();
+        assert_eq!(picked, [0, 1, 1, 1, 2]);
+        // a sticky arm is kept while it exists
+        assert_eq!(choose(&weights, Some(2)), 2);
+        assert!(choose(&[1, 1], Some(5)) < 2);
+    }
+
+    #[test]
+    fn destinations_should_be_validated() {
+        let d = |url: &str, weight| Destination {
+            url: url.to_string(),
+            weight,
+        };
+        assert!(validate_destinations(&[]).is_ok());
+        assert!(validate_destinations(&[d("https://a.example", 1)]).is_err());
+        assert!(
+            validate_destinations(&[d("https://a.example", 1), d("https://b.example", 2)]).is_ok()
+        );
+        assert!(
+            validate_destinations(&[d("https://a.example", 1), d("https://b.example", 0)]).is_err()
+        );
+        assert!(validate_destinations(&[d("https://a.example", 1), d("b.example", 1)]).is_err());
+    }
+
+    #[test]
+    fn sticky_cookie_should_be_scoped_to_link() {
+        let host = RequestHost::new("127.0.0.1:9876", true);
+        let cookie = sticky_cookie(&host, "abc123", 1);
+        assert!(cookie.starts_with("shortener_arm_abc123=1; Path=/abc123;"));
+        assert!(cookie.ends_with("; Secure"));
+    }
+}
@@ -0,0 +1,455 @@
+use std::{
+    io,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use http::header::CONTENT_TYPE;
+use serde::Serialize;
+use tokio::sync::{mpsc, Mutex};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::shortener::{
+    auth::{AdminUser, AuthUser},
+    tenants::Tenant,
+    AppError, AppState, ErrorResponse,
+};
+
+// links listed per period in the summary
+const TOP_LINKS: i64 = 10;
+// entries per breakdown, the long tail is left out
+const TOP_GROUPS: usize = 10;
+// clicks encoded into one chunk of the stream body
+const STREAM_CHUNK_ROWS: usize = 256;
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct StatsSummary {
+    pub total_links: i64,
+    pub total_clicks: i64,
+    /// links whose destination answered 404 or 410 at the last check
+    pub dead_links: i64,
+    /// most clicked links in the last 24 hours
+    pub top_24h: Vec<TopLink>,
+    /// most clicked links in the last 7 days
+    pub top_7d: Vec<TopLink>,
+    /// clicks of the last 7 days by country and browser
+    pub breakdown: Breakdown,
+    pub generated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TopLink {
+    pub tenant: String,
+    pub id: String,
+    pub url: String,
+    pub clicks: i64,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct LinkStats {
+    pub id: String,
+    /// all time, including clicks from before clicks were logged
+    pub clicks: i64,
+    pub clicks_24h: i64,
+    pub clicks_7d: i64,
+    pub last_click_at: Option<DateTime<Utc>>,
+    pub breakdown: Breakdown,
+    /// when the destination was last checked, null if not yet
+    pub last_checked_at: Option<DateTime<Utc>>,
+    /// http status of the last check, null if the destination was unreachable
+    pub last_check_status: Option<i16>,
+    pub dead: bool,
+}
+
+/// Logged clicks grouped by where they came from, most clicks first.
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct Breakdown {
+    pub countries: Vec<GroupCount>,
+    pub browsers: Vec<GroupCount>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct GroupCount {
+    /// country code or browser name, `unknown` if it couldn't be told
+    pub name: String,
+    pub clicks: i64,
+}
+
+/// One logged click, fields are null when unknown.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Click {
+    pub clicked_at: DateTime<Utc>,
+    pub country: Option<String>,
+    pub browser: Option<String>,
+    pub os: Option<String>,
+    /// destination served by a split link
+    pub arm: Option<i16>,
+}
+
+/// The last computed summary, shared by all requests until it expires.
+#[derive(Debug, Default)]
+pub struct SummaryCache {
+    // held while computing, so concurrent requests wait for one query instead of all running it
+    entry: Mutex<Option<(Instant, Arc<StatsSummary>)>>,
+}
+
+impl SummaryCache {
+    async fn get_or_compute<F, Fut>(
+        &self,
+        ttl: Duration,
+        compute: F,
+    ) -> Result<Arc<StatsSummary>, AppError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = Result<StatsSummary, AppError>>,
+    {
+        let mut entry = self.entry.lock().await;
+        if let Some((at, summary)) = entry.as_ref() {
+            if at.elapsed() < ttl {
+                return Ok(summary.clone());
+            }
+        }
+        let summary = Arc::new(compute().await?);
+        *entry = Some((Instant::now(), summary.clone()));
+        Ok(summary)
+    }
+}
+
+impl AppState {
+    // clicks of one link, given as (tenant, id), or all links, optionally since some time
+    async fn breakdown(
+        &self,
+        link: Option<(&str, &str)>,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<Breakdown, AppError> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT grouping(country) = 0 AS "by_country!",
+                coalesce(CASE WHEN grouping(country) = 0 THEN country ELSE browser END, 'unknown')
+                    AS "name!",
+                count(*) AS "clicks!"
+            FROM clicks
+            WHERE ($1::TEXT IS NULL OR (tenant = $1 AND link_id = $2))
+                AND ($3::TIMESTAMPTZ IS NULL OR clicked_at >= $3)
+            GROUP BY GROUPING SETS ((country), (browser))
+            ORDER BY 3 DESC, 2
+            "#,
+            link.map(|(tenant, _)| tenant),
+            link.map(|(_, id)| id),
+            since,
+        )
+        .fetch_all(match link {
+            Some((_, id)) => self.db.reader(id),
+            None => self.db.replica(),
+        })
+        .await?;
+
+        let mut breakdown = Breakdown::default();
+        for row in rows {
+            let group = match row.by_country {
+                true => &mut breakdown.countries,
+                false => &mut breakdown.browsers,
+            };
+            if group.len() < TOP_GROUPS {
+                group.push(GroupCount {
+                    name: row.name,
+                    clicks: row.clicks,
+                });
+            }
+        }
+        Ok(breakdown)
+    }
+
+    async fn compute_summary(&self) -> Result<StatsSummary, AppError> {
+        let totals = sqlx::query!(
+            r#"
+            SELECT count(*) AS "links!", coalesce(sum(clicks), 0)::BIGINT AS "clicks!",
+                count(*) FILTER (WHERE dead) AS "dead!"
+            FROM urls WHERE deleted_at IS NULL
+            "#
+        )
+        .fetch_one(self.db.replica())
+        .await?;
+
+        // clicks per link and period, ranked within each period
+        let rows = sqlx::query!(
+            r#"
+            WITH periods (period, since) AS (
+                VALUES ('24h', now() - interval '1 day'), ('7d', now() - interval '7 days')
+            ),
+            counts AS (
+                SELECT p.period, c.tenant, c.link_id, count(*) AS clicks
+                FROM periods p
+                JOIN clicks c ON c.clicked_at >= p.since
+                JOIN urls u ON u.tenant = c.tenant AND u.id = c.link_id AND u.deleted_at IS NULL
+                GROUP BY p.period, c.tenant, c.link_id
+            ),
+            ranked AS (
+                SELECT *, row_number()
+                    OVER (PARTITION BY period ORDER BY clicks DESC, tenant, link_id) AS rank
+                FROM counts
+            )
+            SELECT r.period AS "period!", r.tenant AS "tenant!", r.link_id AS "id!", u.url,
+                r.clicks AS "clicks!"
+            FROM ranked r JOIN urls u ON u.tenant = r.tenant AND u.id = r.link_id
+            WHERE r.rank <= $1
+            ORDER BY r.period, r.rank
+            "#,
+            TOP_LINKS,
+        )
+        .fetch_all(self.db.replica())
+        .await?;
+
+        let (mut top_24h, mut top_7d) = (vec![], vec![]);
+        for row in rows {
+            let link = TopLink {
+                tenant: row.tenant,
+                id: row.id,
+                url: row.url,
+                clicks: row.clicks,
+            };
+            match row.period.as_str() {
+                "24h" => top_24h.push(link),
+                _ => top_7d.push(link),
+            }
+        }
+        let breakdown = self
+            .breakdown(None, Some(Utc::now() - chrono::Duration::days(7)))
+            .await?;
+        Ok(StatsSummary {
+            total_links: totals.links,
+            total_clicks: totals.clicks,
+            dead_links: totals.dead,
+            top_24h,
+            top_7d,
+            breakdown,
+            generated_at: Utc::now(),
+        })
+    }
+
+    /// Summary over all links, recomputed at most every `stats_cache_secs`.
+    pub async fn stats_summary(&self) -> Result<Arc<StatsSummary>, AppError> {
+        let ttl = Duration::from_secs(self.config.stats_cache_secs);
+        self.stats_cache
+            .get_or_compute(ttl, || self.compute_summary())
+            .await
+    }
+
+    async fn owns_link(&self, tenant: &str, id: &str, owner: i64) -> Result<bool, AppError> {
+        let owned = sqlx::query_scalar!(
+            r#"
+            SELECT EXISTS(
+                SELECT 1 FROM urls
+                WHERE tenant = $1 AND id = $2 AND owner_id = $3 AND deleted_at IS NULL
+            ) AS "owned!"
+            "#,
+            tenant,
+            id,
+            owner,
+        )
+        .fetch_one(self.db.reader(id))
+        .await?;
+        Ok(owned)
+    }
+
+    // returns None if the owner has no such link
+    pub async fn link_stats(
+        &self,
+        tenant: &str,
+        id: &str,
+        owner: i64,
+    ) -> Result<Option<LinkStats>, AppError> {
+        let row = self
+            .db
+            .retry("link_stats", || {
+                sqlx::query!(
+                    r#"
+                    SELECT u.id, u.clicks,
+                        count(c.id) FILTER (WHERE c.clicked_at >= now() - interval '1 day')
+                            AS "clicks_24h!",
+                        count(c.id) FILTER (WHERE c.clicked_at >= now() - interval '7 days')
+                            AS "clicks_7d!",
+                        max(c.clicked_at) AS last_click_at, u.last_checked_at,
+                        u.last_check_status, u.dead
+                    FROM urls u LEFT JOIN clicks c ON c.tenant = u.tenant AND c.link_id = u.id
+                    WHERE u.tenant = $1 AND u.id = $2 AND u.owner_id = $3 AND u.deleted_at IS NULL
+                    GROUP BY u.tenant, u.id
+                    "#,
+                    tenant,
+                    id,
+                    owner,
+                )
+                .fetch_optional(self.db.reader(id))
+            })
+            .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        Ok(Some(LinkStats {
+            breakdown: self.breakdown(Some((tenant, &row.id)), None).await?,
+            id: row.id,
+            clicks: row.clicks,
+            clicks_24h: row.clicks_24h,
+            clicks_7d: row.clicks_7d,
+            last_click_at: row.last_click_at,
+            last_checked_at: row.last_checked_at,
+            last_check_status: row.last_check_status,
+            dead: row.dead,
+        }))
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/stats/summary",
+    tag = "stats",
+    responses(
+        (status = 200, description = "totals and most clicked links, may be slightly stale", body = StatsSummary),
+        (status = 401, description = "missing or invalid token", body = ErrorResponse),
+        (status = 403, description = "not allowed", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn summary_handler(
+    State(state): State<AppState>,
+    _admin: AdminUser,
+) -> Result<Json<StatsSummary>, AppError> {
+    let summary = state.stats_summary().await?;
+    Ok(Json(StatsSummary::clone(&summary)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/links/{id}/stats",
+    tag = "stats",
+    params(("id" = String, Path, description = "short id")),
+    responses(
+        (status = 200, description = "clicks of the link", body = LinkStats),
+        (status = 401, description = "missing or invalid token", body = ErrorResponse),
+        (status = 404, description = "no such link", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn link_stats_handler(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Tenant(tenant): Tenant,
+    Path(id): Path<String>,
+) -> Result<Json<LinkStats>, AppError> {
+    match state.link_stats(&tenant, &id, user.id).await? {
+        Some(stats) => Ok(Json(stats)),
+        None => Err(AppError::HttpNotFound(id)),
+    }
+}
+
+/// All logged clicks of the link, oldest first. Rows are encoded chunk by chunk
+/// as they come from the db, long histories are never held in memory.
+#[utoipa::path(
+    get,
+    path = "/api/v1/links/{id}/clicks/stream",
+    tag = "stats",
+    params(("id" = String, Path, description = "short id")),
+    responses(
+        (status = 200, description = "one click per line", content_type = "application/x-ndjson", body = Click),
+        (status = 401, description = "missing or invalid token", body = ErrorResponse),
+        (status = 404, description = "no such link", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn clicks_stream_handler(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Tenant(tenant): Tenant,
+    Path(id): Path<String>,
+) -> Result<Response, AppError> {
+    if !state.owns_link(&tenant, &id, user.id).await? {
+        return Err(AppError::HttpNotFound(id));
+    }
+    let (tx, rx) = mpsc::channel::<io::Result<Bytes>>(4);
+    let db = state.db.reader(&id).clone();
+    tokio::spawn(async move {
+        let mut chunks = sqlx::query_as!(
+            Click,
+            r#"
+            SELECT clicked_at, country, browser, os, arm
+            FROM clicks WHERE tenant = $1 AND link_id = $2 ORDER BY clicked_at, id
+            "#,
+            tenant,
+            id,
+        )
+        .fetch(&db)
+        .chunks(STREAM_CHUNK_ROWS);
+
+        while let Some(rows) = chunks.next().await {
+            let chunk = rows
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(io::Error::other)
+                .and_then(|rows| encode_ndjson(&rows));
+            let failed = chunk.is_err();
+            if let Err(e) = &chunk {
+                warn!("streaming clicks of {id} failed: {e}");
+            }
+            // the receiver is gone when the client disconnected
+            if tx.send(chunk).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+    Ok((
+        [(CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(ReceiverStream::new(rx)),
+    )
+        .into_response())
+}
+
+fn encode_ndjson(rows: &[Click]) -> io::Result<Bytes> {
+    let mut buf = Vec::new();
+    for row in rows {
+        serde_json::to_writer(&mut buf, row)?;
+        buf.push(b'\n');
+    }
+    Ok(buf.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(total_links: i64) -> StatsSummary {
+        StatsSummary {
+            total_links,
+            total_clicks: 0,
+            dead_links: 0,
+            top_24h: vec![],
+            top_7d: vec![],
+            breakdown: Breakdown::default(),
+            generated_at: Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_should_expire() {
+        let cache = SummaryCache::default();
+        let ttl = Duration::from_secs(60);
+        let first = cache.get_or_compute(ttl, || async { Ok(summary(1)) }).await;
+        assert_eq!(first.unwrap().total_links, 1);
+        let cached = cache.get_or_compute(ttl, || async { Ok(summary(2)) }).await;
+        assert_eq!(cached.unwrap().total_links, 1);
+
+        let fresh = cache
+            .get_or_compute(Duration::ZERO, || async { Ok(summary(3)) })
+            .await;
+        assert_eq!(fresh.unwrap().total_links, 3);
+    }
+}
@@ -0,0 +1,306 @@
+use axum::{
+    async_trait,
+    body::Body,
+    extract::{FromRequestParts, Path, State},
+    http::request::Parts,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use http::{Request, StatusCode, Uri};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use utoipa::ToSchema;
+
+use crate::shortener::{
+    auth::AdminUser, domains::normalize_domain, hosts::RequestHost, AppError, AppState,
+    ErrorResponse,
+};
+
+/// Tenant of requests not addressed to any other, and of all links created
+/// before there were tenants.
+pub const DEFAULT_TENANT: &str = "default";
+
+// requests to `/t/{tenant}/...` are routed as `/...` of that tenant
+const PATH_PREFIX: &str = "/t/";
+
+/// The namespace of short ids a request is addressed to: the tenant of the
+/// `/t/{tenant}` path prefix, else the one registered for the host, else the default one.
+/// The same id may exist once per tenant.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tenant(pub String);
+
+/// Tenant named by the path prefix, set by [`strip_prefix`].
+#[derive(Debug, Clone)]
+pub struct PathTenant(pub String);
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TenantInfo {
+    pub slug: String,
+    /// requests to this host belong to the tenant, besides those under `/t/{slug}`
+    pub host: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct TenantReq {
+    /// lowercase letters, digits and dashes
+    pub slug: String,
+    #[serde(default)]
+    pub host: Option<String>,
+}
+
+impl Default for Tenant {
+    fn default() -> Self {
+        Self(DEFAULT_TENANT.to_string())
+    }
+}
+
+/// Route `/t/{tenant}/rest` as `/rest`, remembering the tenant as [`PathTenant`].
+pub fn strip_prefix(mut req: Request<Body>) -> Request<Body> {
+    let Some(rest) = req.uri().path().strip_prefix(PATH_PREFIX) else {
+        return req;
+    };
+    let (slug, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    if slug.is_empty() {
+        return req;
+    }
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_string(),
+    };
+    let mut parts = req.uri().clone().into_parts();
+    let Ok(path_and_query) = path_and_query.parse() else {
+        return req;
+    };
+    parts.path_and_query = Some(path_and_query);
+    let Ok(uri) = Uri::from_parts(parts) else {
+        return req;
+    };
+    let slug = slug.to_string();
+    *req.uri_mut() = uri;
+    req.extensions_mut().insert(PathTenant(slug));
+    req
+}
+
+fn validate_slug(slug: &str) -> Result<(), String> {
+    let valid = (1..=32).contains(&slug.len())
+        && slug
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+        && !slug.starts_with('-');
+    if !valid {
+        return Err(format!("invalid tenant: {slug}"));
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Tenant {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, AppError> {
+        if let Some(PathTenant(slug)) = parts.extensions.get::<PathTenant>() {
+            return match state.tenant_exists(slug).await? {
+                true => Ok(Self(slug.clone())),
+                false => Err(AppError::HttpNotFound(format!("tenant {slug}"))),
+            };
+        }
+        let Ok(host) = RequestHost::from_request_parts(parts, state).await;
+        let slug = state.tenant_of_host(&host.domain()).await?;
+        Ok(Self(slug.unwrap_or_else(|| DEFAULT_TENANT.to_string())))
+    }
+}
+
+impl AppState {
+    pub async fn tenant_exists(&self, slug: &str) -> Result<bool, AppError> {
+        let exists = self
+            .db
+            .retry("tenant_exists", || {
+                sqlx::query_scalar!(
+                    r#"SELECT EXISTS(SELECT 1 FROM tenants WHERE slug = $1) AS "exists!""#,
+                    slug
+                )
+                .fetch_one(self.db.replica())
+            })
+            .await?;
+        Ok(exists)
+    }
+
+    async fn tenant_of_host(&self, host: &str) -> Result<Option<String>, AppError> {
+        let slug = self
+            .db
+            .retry("tenant_of_host", || {
+                sqlx::query_scalar!("SELECT slug FROM tenants WHERE host = $1", host)
+                    .fetch_optional(self.db.replica())
+            })
+            .await?;
+        Ok(slug)
+    }
+
+    async fn list_tenants(&self) -> Result<Vec<TenantInfo>, AppError> {
+        let tenants = sqlx::query_as!(
+            TenantInfo,
+            "SELECT slug, host, created_at FROM tenants ORDER BY slug"
+        )
+        .fetch_all(self.db.primary())
+        .await?;
+        Ok(tenants)
+    }
+
+    // Conflict if the slug or the host is taken
+    pub async fn add_tenant(&self, slug: &str, host: Option<&str>) -> Result<TenantInfo, AppError> {
+        let tenant = sqlx::query_as!(
+            TenantInfo,
+            "INSERT INTO tenants (slug, host) VALUES ($1, $2) RETURNING slug, host, created_at",
+            slug,
+            host,
+        )
+        .fetch_one(self.db.primary())
+        .await?;
+        Ok(tenant)
+    }
+
+    // returns false if there is no such tenant, Conflict while it has links
+    pub async fn delete_tenant(&self, slug: &str) -> Result<bool, AppError> {
+        let ret = sqlx::query!("DELETE FROM tenants WHERE slug = $1", slug)
+            .execute(self.db.primary())
+            .await;
+        match ret {
+            Ok(ret) => Ok(ret.rows_affected() > 0),
+            Err(sqlx::Error::Database(e)) if e.is_foreign_key_violation() => Err(
+                AppError::Conflict(format!("tenant {slug} still has links, delete them first")),
+            ),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/admin/tenants",
+    tag = "admin",
+    responses(
+        (status = 200, description = "all tenants", body = Vec<TenantInfo>),
+        (status = 401, description = "missing or invalid token", body = ErrorResponse),
+        (status = 403, description = "not allowed", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn list_tenants_handler(
+    State(state): State<AppState>,
+    _admin: AdminUser,
+) -> Result<Json<Vec<TenantInfo>>, AppError> {
+    Ok(Json(state.list_tenants().await?))
+}
+
+/// Provision a tenant, addressed under `/t/{slug}` and, if given, on its own host.
+#[utoipa::path(
+    post,
+    path = "/api/v1/admin/tenants",
+    tag = "admin",
+    request_body = TenantReq,
+    responses(
+        (status = 201, description = "tenant created", body = TenantInfo),
+        (status = 400, description = "invalid slug or host", body = ErrorResponse),
+        (status = 401, description = "missing or invalid token", body = ErrorResponse),
+        (status = 403, description = "not allowed", body = ErrorResponse),
+        (status = 409, description = "slug or host taken", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn add_tenant_handler(
+    State(state): State<AppState>,
+    AdminUser(admin): AdminUser,
+    Json(req): Json<TenantReq>,
+) -> Result<impl IntoResponse, AppError> {
+    validate_slug(&req.slug).map_err(AppError::BadRequest)?;
+    let host = match req.host.as_deref() {
+        Some(host) => Some(normalize_domain(host).map_err(AppError::BadRequest)?),
+        None => None,
+    };
+    let tenant = state.add_tenant(&req.slug, host.as_deref()).await?;
+    info!(
+        target: "audit",
+        admin = %admin.username,
+        tenant = %tenant.slug,
+        host = ?tenant.host,
+        "tenant created"
+    );
+    Ok((StatusCode::CREATED, Json(tenant)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/admin/tenants/{slug}",
+    tag = "admin",
+    params(("slug" = String, Path)),
+    responses(
+        (status = 204, description = "tenant removed"),
+        (status = 400, description = "the default tenant can't be removed", body = ErrorResponse),
+        (status = 401, description = "missing or invalid token", body = ErrorResponse),
+        (status = 403, description = "not allowed", body = ErrorResponse),
+        (status = 404, description = "no such tenant", body = ErrorResponse),
+        (status = 409, description = "the tenant still has links", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn delete_tenant_handler(
+    State(state): State<AppState>,
+    AdminUser(admin): AdminUser,
+    Path(slug): Path<String>,
+) -> Result<StatusCode, AppError> {
+    if slug == DEFAULT_TENANT {
+        return Err(AppError::BadRequest(
+            "the default tenant can't be removed".to_string(),
+        ));
+    }
+    if !state.delete_tenant(&slug).await? {
+        return Err(AppError::HttpNotFound(slug));
+    }
+    info!(target: "audit", admin = %admin.username, tenant = %slug, "tenant removed");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strip(uri: &str) -> (String, Option<String>) {
+        let req = strip_prefix(Request::get(uri).body(Body::empty()).unwrap());
+        let tenant = req.extensions().get::<PathTenant>().map(|t| t.0.clone());
+        (req.uri().to_string(), tenant)
+    }
+
+    #[test]
+    fn prefix_should_be_stripped() {
+        assert_eq!(
+            strip("/t/acme/abc123?preview=1"),
+            ("/abc123?preview=1".to_string(), Some("acme".to_string()))
+        );
+        assert_eq!(
+            strip("/t/acme/api/v1/links"),
+            ("/api/v1/links".to_string(), Some("acme".to_string()))
+        );
+        assert_eq!(
+            strip("/t/acme"),
+            ("/".to_string(), Some("acme".to_string()))
+        );
+        // not a tenant prefix
+        assert_eq!(strip("/tabc12"), ("/tabc12".to_string(), None));
+        assert_eq!(strip("/t/"), ("/t/".to_string(), None));
+    }
+
+    #[test]
+    fn slugs_should_be_validated() {
+        assert!(validate_slug("acme-2").is_ok());
+        assert!(validate_slug("").is_err());
+        assert!(validate_slug("Acme").is_err());
+        assert!(validate_slug("-acme").is_err());
+        assert!(validate_slug("a/b").is_err());
+        assert!(validate_slug(&"a".repeat(33)).is_err());
+    }
+}
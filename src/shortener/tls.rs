@@ -0,0 +1,180 @@
+use std::{future::Future, net::SocketAddr, net::TcpListener};
+
+use anyhow::{Context, Result};
+use axum::{
+    extract::{Request, State},
+    middleware::{self, Next},
+    response::{IntoResponse, Redirect, Response},
+    Router,
+};
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use http::{header::HOST, uri::Authority, Uri};
+use tracing::info;
+
+use crate::shortener::config::Config;
+
+// probes and scrapers keep working over plain http
+const PLAIN_PATHS: &[&str] = &["/healthz", "/readyz", "/metrics"];
+
+/// The bound listeners: the main one, with TLS if configured, and a plain http
+/// one redirecting to it.
+pub struct Listeners {
+    main: TcpListener,
+    tls: Option<RustlsConfig>,
+    redirect: Option<TcpListener>,
+}
+
+impl Listeners {
+    /// Bind before anything else starts, so a taken port fails fast.
+    pub async fn bind(config: &Config) -> Result<Self> {
+        let main = TcpListener::bind(&config.listen_addr)
+            .with_context(|| format!("failed to bind {}", config.listen_addr))?;
+        if !config.tls_enabled() {
+            info!("Listening on http://{}", config.listen_addr);
+            return Ok(Self {
+                main,
+                tls: None,
+                redirect: None,
+            });
+        }
+
+        let tls = RustlsConfig::from_pem_file(&config.tls_cert, &config.tls_key)
+            .await
+            .with_context(|| format!("failed to load tls cert {}", config.tls_cert))?;
+        info!("Listening on https://{}", config.listen_addr);
+        let redirect = match config.http_redirect_addr.as_str() {
+            "" => None,
+            addr => {
+                let listener =
+                    TcpListener::bind(addr).with_context(|| format!("failed to bind {addr}"))?;
+                info!("Redirecting http://{addr} to https");
+                Some(listener)
+            }
+        };
+        Ok(Self {
+            main,
+            tls: Some(tls),
+            redirect,
+        })
+    }
+
+    /// Serve `app` on all listeners until `shutdown` resolves, then drain
+    /// in-flight requests of both.
+    pub async fn serve(
+        self,
+        app: Router,
+        shutdown: impl Future<Output = ()> + Send + 'static,
+    ) -> Result<()> {
+        let handle = Handle::new();
+        tokio::spawn({
+            let handle = handle.clone();
+            async move {
+                shutdown.await;
+                handle.graceful_shutdown(None);
+            }
+        });
+
+        let https_port = self.main.local_addr()?.port();
+        let redirect = self.redirect.map(|listener| {
+            let app = app.clone().layer(middleware::from_fn_with_state(
+                https_port,
+                redirect_to_https,
+            ));
+            let service = app.into_make_service_with_connect_info::<SocketAddr>();
+            let server = axum_server::from_tcp(listener).handle(handle.clone());
+            tokio::spawn(async move { server.serve(service).await })
+        });
+
+        // peer addresses are needed for click analytics
+        let service = app.into_make_service_with_connect_info::<SocketAddr>();
+        match self.tls {
+            Some(tls) => {
+                axum_server::from_tcp_rustls(self.main, tls)
+                    .handle(handle)
+                    .serve(service)
+                    .await?
+            }
+            None => {
+                axum_server::from_tcp(self.main)
+                    .handle(handle)
+                    .serve(service)
+                    .await?
+            }
+        }
+        if let Some(redirect) = redirect {
+            redirect.await??;
+        }
+        Ok(())
+    }
+}
+
+// send browsers to the same url over https, the method is kept
+async fn redirect_to_https(State(https_port): State<u16>, req: Request, next: Next) -> Response {
+    if PLAIN_PATHS.contains(&req.uri().path()) {
+        return next.run(req).await;
+    }
+    let host = req
+        .headers()
+        .get(HOST)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| req.uri().host());
+    match host.and_then(|host| https_url(host, https_port, req.uri())) {
+        Some(url) => Redirect::permanent(&url).into_response(),
+        None => next.run(req).await,
+    }
+}
+
+fn https_url(host: &str, https_port: u16, uri: &Uri) -> Option<String> {
+    let authority: Authority = host.parse().ok()?;
+    let host = authority.host();
+    let port = match https_port {
+        443 => String::new(),
+        port => format!(":{port}"),
+    };
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
+    Some(format!("https://{host}{port}{path}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, routing::get};
+    use http::{header::LOCATION, StatusCode};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn call(uri: &str, host: &str) -> Response {
+        let app = Router::new()
+            .route("/healthz", get(|| async { "ok" }))
+            .route("/:id", get(|| async { "link" }))
+            .layer(middleware::from_fn_with_state(8443, redirect_to_https));
+        let req = http::Request::get(uri)
+            .header(HOST, host)
+            .body(Body::empty())
+            .unwrap();
+        app.oneshot(req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn plain_http_should_redirect_to_https() {
+        let res = call("/abc?preview=1", "go.example.com:8080").await;
+        assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            res.headers()[LOCATION],
+            "https://go.example.com:8443/abc?preview=1"
+        );
+
+        let res = call("/healthz", "go.example.com:8080").await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn https_url_should_drop_default_port() {
+        let uri = Uri::from_static("/abc");
+        assert_eq!(
+            https_url("[::1]:80", 443, &uri).as_deref(),
+            Some("https://[::1]/abc")
+        );
+        assert_eq!(https_url("bad host/", 443, &uri), None);
+    }
+}
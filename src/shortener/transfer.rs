@@ -0,0 +1,420 @@
+use std::io;
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Query, State},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use http::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use sqlx::prelude::FromRow;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{info, warn};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::shortener::{
+    auth::AdminUser, is_redirect_status, reserved::is_reserved, tenants::Tenant, url_hash,
+    validate_url, AppError, AppState, ErrorResponse,
+};
+
+// rows encoded into one chunk of the export body
+const EXPORT_CHUNK_ROWS: usize = 256;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Ndjson,
+    Csv,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    // keep the existing link
+    #[default]
+    Skip,
+    // replace the existing link with the same id
+    Overwrite,
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+pub struct TransferParams {
+    #[serde(default)]
+    pub format: Format,
+    #[serde(default)]
+    pub on_conflict: ConflictPolicy,
+}
+
+/// One link as exported. Owners are not part of it, user ids are local to an instance.
+#[derive(Debug, Clone, PartialEq, FromRow, Serialize, Deserialize, ToSchema)]
+pub struct LinkExport {
+    pub id: String,
+    pub url: String,
+    #[serde(default)]
+    pub redirect_status: Option<i16>,
+    #[serde(default)]
+    pub flag_reason: Option<String>,
+    #[serde(default)]
+    pub clicks: i64,
+    #[serde(default)]
+    pub max_clicks: Option<i64>,
+    // argon2 hash, protected links stay protected on the new instance
+    #[serde(default)]
+    pub password_hash: Option<String>,
+    // the short domain must be registered on the new instance too
+    #[serde(default)]
+    pub domain: Option<String>,
+    // imported into the tenant the import is addressed to if not given
+    #[serde(default)]
+    pub tenant: Option<String>,
+    // informational, imported links start fresh
+    #[serde(default)]
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub struct ImportRes {
+    pub imported: usize,
+    pub skipped: usize,
+    pub errors: Vec<ImportError>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ImportError {
+    pub line: u64,
+    pub error: String,
+}
+
+impl Format {
+    fn content_type(&self) -> &'static str {
+        match self {
+            Self::Ndjson => "application/x-ndjson",
+            Self::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    // `header` writes the csv header line before the rows
+    fn encode(&self, rows: &[LinkExport], header: bool) -> io::Result<Bytes> {
+        match self {
+            Self::Ndjson => {
+                let mut buf = Vec::new();
+                for row in rows {
+                    serde_json::to_writer(&mut buf, row)?;
+                    buf.push(b'\n');
+                }
+                Ok(buf.into())
+            }
+            Self::Csv => {
+                let mut wtr = csv::WriterBuilder::new()
+                    .has_headers(header)
+                    .from_writer(Vec::new());
+                for row in rows {
+                    wtr.serialize(row)?;
+                }
+                let buf = wtr.into_inner().map_err(|e| e.into_error())?;
+                Ok(buf.into())
+            }
+        }
+    }
+
+    // one result per record, with the line it starts on
+    fn decode(&self, body: &[u8]) -> Vec<(u64, Result<LinkExport, String>)> {
+        match self {
+            Self::Ndjson => body
+                .split(|b| *b == b'\n')
+                .enumerate()
+                .filter(|(_, line)| !line.trim_ascii().is_empty())
+                .map(|(i, line)| {
+                    let row = serde_json::from_slice(line).map_err(|e| e.to_string());
+                    (i as u64 + 1, row)
+                })
+                .collect(),
+            Self::Csv => {
+                let mut rdr = csv::Reader::from_reader(body);
+                let headers = match rdr.headers() {
+                    Ok(headers) => headers.clone(),
+                    Err(e) => return vec![(1, Err(e.to_string()))],
+                };
+                rdr.records()
+                    .map(|record| match record {
+                        Ok(record) => {
+                            let line = record.position().map(|p| p.line()).unwrap_or_default();
+                            let row = record
+                                .deserialize(Some(&headers))
+                                .map_err(|e| e.to_string());
+                            (line, row)
+                        }
+                        Err(e) => {
+                            let line = e.position().map(|p| p.line()).unwrap_or_default();
+                            (line, Err(e.to_string()))
+                        }
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+impl LinkExport {
+    fn validate(&self) -> Result<(), String> {
+        let valid_id = !self.id.is_empty()
+            && self.id.len() <= 16
+            && self
+                .id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !valid_id {
+            return Err(format!("invalid id: {}", self.id));
+        }
+        if is_reserved(&self.id) {
+            return Err(format!("{} is a reserved word", self.id));
+        }
+        validate_url(&self.url)?;
+        match self.redirect_status {
+            Some(code) if !is_redirect_status(code as u16) => {
+                return Err(format!("unsupported redirect status: {code}"))
+            }
+            _ => {}
+        }
+        match self.max_clicks {
+            Some(n) if n <= 0 => Err(format!("max clicks must be positive: {n}")),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl AppState {
+    // returns false if the link was skipped because of a conflict
+    async fn import_link(
+        &self,
+        tenant: &str,
+        link: &LinkExport,
+        policy: ConflictPolicy,
+    ) -> Result<bool, AppError> {
+        let on_conflict = match policy {
+            ConflictPolicy::Skip => "ON CONFLICT DO NOTHING",
+            ConflictPolicy::Overwrite => {
+                r#"
+                ON CONFLICT (tenant, id) DO UPDATE SET url = excluded.url,
+                    url_hash = excluded.url_hash,
+                    redirect_status = excluded.redirect_status, flag_reason = excluded.flag_reason,
+                    clicks = excluded.clicks, max_clicks = excluded.max_clicks,
+                    password_hash = excluded.password_hash, domain = excluded.domain,
+                    deleted_at = NULL, last_checked_at = NULL, dead = false,
+                    version = urls.version + 1, updated_at = now()
+                "#
+            }
+        };
+        let sql = format!(
+            r#"
+            INSERT INTO urls
+                (id, url, redirect_status, flag_reason, clicks, max_clicks, password_hash, domain,
+                tenant, url_hash)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) {on_conflict}
+            "#
+        );
+        let ret = sqlx::query(&sql)
+            .bind(&link.id)
+            .bind(&link.url)
+            .bind(link.redirect_status)
+            .bind(&link.flag_reason)
+            .bind(link.clicks)
+            .bind(link.max_clicks)
+            .bind(&link.password_hash)
+            .bind(&link.domain)
+            .bind(link.tenant.as_deref().unwrap_or(tenant))
+            .bind(url_hash(&link.url))
+            .execute(self.db.primary())
+            .await?;
+        self.wrote(&link.id);
+        Ok(ret.rows_affected() > 0)
+    }
+}
+
+/// Stream all links, rows are encoded chunk by chunk as they come from the db.
+#[utoipa::path(
+    get,
+    path = "/api/v1/export",
+    tag = "admin",
+    params(TransferParams),
+    responses(
+        (
+            status = 200,
+            description = "one link per line",
+            content(
+                (LinkExport = "application/x-ndjson"),
+                (LinkExport = "text/csv"),
+            )
+        ),
+        (status = 401, description = "missing or invalid token", body = ErrorResponse),
+        (status = 403, description = "not allowed", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn export_handler(
+    State(state): State<AppState>,
+    AdminUser(admin): AdminUser,
+    Query(params): Query<TransferParams>,
+) -> Response {
+    info!(target: "audit", admin = %admin.username, "links exported");
+    let format = params.format;
+    let (tx, rx) = mpsc::channel::<io::Result<Bytes>>(4);
+    let db = state.db.primary().clone();
+    tokio::spawn(async move {
+        let mut chunks = sqlx::query_as::<_, LinkExport>(
+            r#"
+            SELECT id, url, redirect_status, flag_reason, clicks, max_clicks, password_hash,
+                domain, tenant, updated_at
+            FROM urls WHERE deleted_at IS NULL ORDER BY tenant, id
+            "#,
+        )
+        .fetch(&db)
+        .chunks(EXPORT_CHUNK_ROWS);
+
+        let mut header = true;
+        while let Some(rows) = chunks.next().await {
+            let chunk = rows
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(io::Error::other)
+                .and_then(|rows| format.encode(&rows, header));
+            header = false;
+            let failed = chunk.is_err();
+            if let Err(e) = &chunk {
+                warn!("export failed: {e}");
+            }
+            // the receiver is gone when the client disconnected
+            if tx.send(chunk).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+    (
+        [(CONTENT_TYPE, format.content_type())],
+        Body::from_stream(ReceiverStream::new(rx)),
+    )
+        .into_response()
+}
+
+/// Import links in the export format. Invalid or failing rows are reported and
+/// don't stop the import.
+#[utoipa::path(
+    post,
+    path = "/api/v1/import",
+    tag = "admin",
+    params(TransferParams),
+    request_body(
+        description = "one link per line, in the given format",
+        content(
+            (LinkExport = "application/x-ndjson"),
+            (LinkExport = "text/csv"),
+        )
+    ),
+    responses(
+        (status = 200, description = "import summary", body = ImportRes),
+        (status = 401, description = "missing or invalid token", body = ErrorResponse),
+        (status = 403, description = "not allowed", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn import_handler(
+    State(state): State<AppState>,
+    AdminUser(admin): AdminUser,
+    Tenant(tenant): Tenant,
+    Query(params): Query<TransferParams>,
+    body: Bytes,
+) -> Result<Json<ImportRes>, AppError> {
+    let mut res = ImportRes::default();
+    for (line, row) in params.format.decode(&body) {
+        let ret = match row.and_then(|link| link.validate().map(|_| link)) {
+            Ok(link) => state
+                .import_link(&tenant, &link, params.on_conflict)
+                .await
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e),
+        };
+        match ret {
+            Ok(true) => res.imported += 1,
+            Ok(false) => res.skipped += 1,
+            Err(error) => res.errors.push(ImportError { line, error }),
+        }
+    }
+    info!(
+        target: "audit",
+        admin = %admin.username,
+        imported = res.imported,
+        skipped = res.skipped,
+        failed = res.errors.len(),
+        "links imported"
+    );
+    Ok(Json(res))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(id: &str, url: &str) -> LinkExport {
+        LinkExport {
+            id: id.to_string(),
+            url: url.to_string(),
+            redirect_status: Some(302),
+            flag_reason: None,
+            clicks: 7,
+            max_clicks: None,
+            password_hash: None,
+            domain: None,
+            tenant: None,
+            updated_at: None,
+        }
+    }
+
+    #[test]
+    fn formats_should_round_trip() {
+        let rows = vec![
+            link("abc123", "https://example.com/?a=1,b=\"2\""),
+            link("def456", "https://example.org/"),
+        ];
+        for format in [Format::Ndjson, Format::Csv] {
+            let body = format.encode(&rows, true).unwrap();
+            let decoded: Vec<_> = format
+                .decode(&body)
+                .into_iter()
+                .map(|(_, row)| row.unwrap())
+                .collect();
+            assert_eq!(decoded, rows, "{format:?}");
+        }
+    }
+
+    #[test]
+    fn decode_should_report_lines() {
+        let body = b"{\"id\":\"abc123\",\"url\":\"https://example.com/\"}\n\nnot json\n";
+        let rows = Format::Ndjson.decode(body);
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].1.as_ref().unwrap().clicks, 0);
+        assert_eq!(rows[1].0, 3);
+        assert!(rows[1].1.is_err());
+
+        let body = b"id,url,redirect_status\nabc123,https://example.com/,\nbad,row,xyz\n";
+        let rows = Format::Csv.decode(body);
+        assert_eq!(rows[0].1.as_ref().unwrap().redirect_status, None);
+        assert_eq!(rows[1].0, 3);
+        assert!(rows[1].1.is_err());
+    }
+
+    #[test]
+    fn validate_should_reject_bad_rows() {
+        assert!(link("abc123", "https://example.com/").validate().is_ok());
+        assert!(link("a/b", "https://example.com/").validate().is_err());
+        assert!(link("api", "https://example.com/").validate().is_err());
+        assert!(link("abc123", "ftp://example.com/").validate().is_err());
+        let mut bad_status = link("abc123", "https://example.com/");
+        bad_status.redirect_status = Some(303);
+        assert!(bad_status.validate().is_err());
+    }
+}
@@ -0,0 +1,141 @@
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::shortener::{
+    auth::AuthUser, tenants::Tenant, AppError, AppState, ErrorResponse, LinkRecord,
+};
+
+/// A deleted link, restorable until it is purged.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TrashedLink {
+    pub id: String,
+    pub url: String,
+    pub deleted_at: DateTime<Utc>,
+}
+
+impl AppState {
+    pub async fn list_trash(&self, tenant: &str, owner: i64) -> Result<Vec<TrashedLink>, AppError> {
+        let links = sqlx::query_as!(
+            TrashedLink,
+            r#"
+            SELECT id, url, deleted_at AS "deleted_at!"
+            FROM urls WHERE tenant = $1 AND owner_id = $2 AND deleted_at IS NOT NULL
+            ORDER BY deleted_at DESC
+            "#,
+            tenant,
+            owner,
+        )
+        .fetch_all(self.db.primary())
+        .await?;
+        Ok(links)
+    }
+
+    // returns None if the owner has no such link in the trash
+    pub async fn restore_link(
+        &self,
+        tenant: &str,
+        id: &str,
+        owner: i64,
+    ) -> Result<Option<LinkRecord>, AppError> {
+        let link = sqlx::query_as!(
+            LinkRecord,
+            r#"
+            UPDATE urls SET deleted_at = NULL, version = version + 1, updated_at = now()
+            WHERE tenant = $1 AND id = $2 AND owner_id = $3 AND deleted_at IS NOT NULL
+            RETURNING id, url, version, updated_at, redirect_status, flag_reason, clicks,
+                max_clicks, password_hash IS NOT NULL AS "protected!", domain, last_checked_at,
+                last_check_status, last_check_latency_ms, dead, tags, active_from, active_until
+            "#,
+            tenant,
+            id,
+            owner,
+        )
+        .fetch_optional(self.db.primary())
+        .await?;
+        if link.is_some() {
+            self.wrote(id);
+            info!(target: "audit", tenant, id, owner, "link restored");
+        }
+        Ok(link)
+    }
+}
+
+/// Permanently remove links deleted more than `retention_days` ago, returns how many.
+pub async fn purge_deleted(db: &PgPool, retention_days: u32) -> Result<u64, sqlx::Error> {
+    let ret = sqlx::query!(
+        "DELETE FROM urls WHERE deleted_at < now() - make_interval(days => $1)",
+        retention_days as i32,
+    )
+    .execute(db)
+    .await?;
+    Ok(ret.rows_affected())
+}
+
+/// Purge the trash every `interval`, for as long as the process runs.
+pub fn spawn_purger(db: PgPool, retention_days: u32, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match purge_deleted(&db, retention_days).await {
+                Ok(0) => {}
+                Ok(purged) => info!(target: "audit", purged, "deleted links purged"),
+                Err(e) => warn!("failed to purge deleted links: {e}"),
+            }
+        }
+    })
+}
+
+/// Deleted links of the caller, most recently deleted first.
+#[utoipa::path(
+    get,
+    path = "/api/v1/links/trash",
+    tag = "links",
+    responses(
+        (status = 200, description = "deleted links, not purged yet", body = Vec<TrashedLink>),
+        (status = 401, description = "missing or invalid token", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn list_trash_handler(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Tenant(tenant): Tenant,
+) -> Result<Json<Vec<TrashedLink>>, AppError> {
+    Ok(Json(state.list_trash(&tenant, user.id).await?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/links/{id}/restore",
+    tag = "links",
+    params(("id" = String, Path, description = "short id")),
+    responses(
+        (status = 200, description = "link restored", body = LinkRecord),
+        (status = 401, description = "missing or invalid token", body = ErrorResponse),
+        (status = 404, description = "no such link in the trash", body = ErrorResponse),
+        (status = 409, description = "the url was shortened again since", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn restore_link_handler(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Tenant(tenant): Tenant,
+    Path(id): Path<String>,
+) -> Result<Json<LinkRecord>, AppError> {
+    match state.restore_link(&tenant, &id, user.id).await? {
+        Some(link) => Ok(Json(link)),
+        None => Err(AppError::HttpNotFound(id)),
+    }
+}
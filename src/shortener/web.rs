@@ -0,0 +1,323 @@
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    handler::Handler,
+    http::request::Parts,
+    response::{IntoResponse, Redirect, Response},
+    Form, Json,
+};
+use http::{
+    header::{ACCEPT, CONTENT_TYPE, COOKIE, SET_COOKIE},
+    HeaderMap, StatusCode,
+};
+use maud::{html, Markup, DOCTYPE};
+use serde::{Deserialize, Serialize};
+
+use crate::shortener::{
+    auth::{AuthUser, MaybeAuthUser},
+    hosts::RequestHost,
+    openapi::SPEC_PATH,
+    shorten_handler, shorten_request,
+    tenants::Tenant,
+    window::ActiveWindow,
+    AppError, AppState, LinkFilter, LinkRecord, ShortenReq,
+};
+
+// the access token of the web pages, the json api only takes it as a bearer token
+const COOKIE_NAME: &str = "shortener_token";
+
+/// The user logged in by the login form, None if not logged in or the session expired.
+#[derive(Debug, Clone)]
+pub struct WebUser(pub Option<AuthUser>);
+
+#[derive(Debug, Deserialize)]
+pub struct ShortenForm {
+    url: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginForm {
+    username: String,
+    password: String,
+}
+
+/// Where to find the json api, for clients asking `GET /` for json.
+#[derive(Debug, Serialize)]
+struct ApiIndex {
+    api: &'static str,
+    openapi: &'static str,
+    docs: &'static str,
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for WebUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, AppError> {
+        let user = cookie(&parts.headers, COOKIE_NAME)
+            .and_then(|token| state.keys.verify(token).ok())
+            .map(|claims| AuthUser {
+                id: claims.sub,
+                username: claims.username,
+            });
+        Ok(Self(user))
+    }
+}
+
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+// browsers list text/html before any json, api clients ask for json or anything
+fn wants_html(headers: &HeaderMap) -> bool {
+    let Some(accept) = headers.get(ACCEPT).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    accept
+        .split(',')
+        .map(|media| media.split(';').next().unwrap_or_default().trim())
+        .find(|media| *media == "text/html" || media.ends_with("/json"))
+        .is_some_and(|media| media == "text/html")
+}
+
+fn session_cookie(token: &str, max_age: u64, https: bool) -> String {
+    let secure = if https { "; Secure" } else { "" };
+    format!("{COOKIE_NAME}={token}; Path=/; Max-Age={max_age}; HttpOnly; SameSite=Lax{secure}")
+}
+
+fn layout(host: &RequestHost, user: Option<&AuthUser>, title: &str, content: Markup) -> Markup {
+    let base = &host.prefix;
+    html! {
+        (DOCTYPE)
+        html {
+            head {
+                meta charset="utf-8";
+                meta name="viewport" content="width=device-width, initial-scale=1";
+                title { (title) }
+            }
+            body {
+                nav {
+                    a href={ (base) "/" } { "Shorten" }
+                    " | "
+                    @if let Some(user) = user {
+                        a href={ (base) "/links" } { "My links" }
+                        " | "
+                        form method="post" action={ (base) "/logout" } style="display:inline" {
+                            button type="submit" { "Log out " (user.username) }
+                        }
+                    } @else {
+                        a href={ (base) "/login" } { "Log in" }
+                    }
+                }
+                main { (content) }
+            }
+        }
+    }
+}
+
+fn render_home(
+    host: &RequestHost,
+    user: Option<&AuthUser>,
+    short_url: Option<&str>,
+    error: Option<&str>,
+) -> Markup {
+    let content = html! {
+        h1 { "Shorten a link" }
+        form method="post" action={ (host.prefix) "/" } {
+            input type="url" name="url" placeholder="https://" autofocus required;
+            button type="submit" { "Shorten" }
+        }
+        @if let Some(short_url) = short_url {
+            p { "Your short link: " a href=(short_url) { (short_url) } }
+        }
+        @if let Some(error) = error {
+            p { strong { (error) } }
+        }
+    };
+    layout(host, user, "Shorten a link", content)
+}
+
+fn render_login(host: &RequestHost, error: Option<&str>) -> Markup {
+    let content = html! {
+        h1 { "Log in" }
+        @if let Some(error) = error {
+            p { strong { (error) } }
+        }
+        form method="post" action={ (host.prefix) "/login" } {
+            input type="text" name="username" placeholder="username" autofocus required;
+            input type="password" name="password" placeholder="password" required;
+            button type="submit" { "Log in" }
+        }
+    };
+    layout(host, None, "Log in", content)
+}
+
+fn render_links(host: &RequestHost, user: &AuthUser, links: &[LinkRecord]) -> Markup {
+    let content = html! {
+        h1 { "My links" }
+        @if links.is_empty() {
+            p { "No links yet." }
+        } @else {
+            table {
+                thead {
+                    tr { th { "Short link" } th { "Destination" } th { "Clicks" } th { "Tags" } }
+                }
+                tbody {
+                    @for link in links {
+                        @let short_url = host.short_url(&link.id);
+                        tr {
+                            td { a href=(short_url) { (short_url) } }
+                            td { (link.url) }
+                            td { (link.clicks) }
+                            td { (link.tags.join(", ")) }
+                        }
+                    }
+                }
+            }
+        }
+    };
+    layout(host, Some(user), "My links", content)
+}
+
+/// The landing page for browsers, where to find the api for everyone else.
+pub async fn home_handler(
+    headers: HeaderMap,
+    host: RequestHost,
+    WebUser(user): WebUser,
+) -> Response {
+    if !wants_html(&headers) {
+        let index = ApiIndex {
+            api: "/api/v1",
+            openapi: SPEC_PATH,
+            docs: "/swagger-ui",
+        };
+        return Json(index).into_response();
+    }
+    render_home(&host, user.as_ref(), None, None).into_response()
+}
+
+/// `POST /` from the landing page form, else the json api.
+pub async fn post_root_handler(State(state): State<AppState>, req: Request) -> Response {
+    let is_form = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/x-www-form-urlencoded"));
+    if is_form {
+        shorten_form_handler.call(req, state).await
+    } else {
+        shorten_handler.call(req, state).await
+    }
+}
+
+async fn shorten_form_handler(
+    State(state): State<AppState>,
+    host: RequestHost,
+    Tenant(tenant): Tenant,
+    WebUser(user): WebUser,
+    Form(form): Form<ShortenForm>,
+) -> Response {
+    let req = ShortenReq {
+        url: form.url.trim().to_string(),
+        redirect_status: None,
+        max_clicks: None,
+        password: None,
+        tags: vec![],
+        window: ActiveWindow::default(),
+    };
+    let owner = MaybeAuthUser(user.clone());
+    match shorten_request(&state, owner, &host, tenant, req).await {
+        Ok(id) => {
+            let short_url = host.short_url(&id);
+            let page = render_home(&host, user.as_ref(), Some(&short_url), None);
+            (StatusCode::CREATED, page).into_response()
+        }
+        Err(e) => {
+            let page = render_home(&host, user.as_ref(), None, Some(&e.to_string()));
+            (e.status_code(), page).into_response()
+        }
+    }
+}
+
+pub async fn login_page_handler(host: RequestHost) -> Markup {
+    render_login(&host, None)
+}
+
+// on success the token is kept in a cookie and the user sent to their links
+pub async fn login_form_handler(
+    State(state): State<AppState>,
+    host: RequestHost,
+    Form(form): Form<LoginForm>,
+) -> Result<Response, AppError> {
+    let user = match state.verify_user(&form.username, &form.password).await {
+        Ok(user) => user,
+        Err(AppError::Unauthorized(e)) => {
+            let page = render_login(&host, Some(&e));
+            return Ok((StatusCode::UNAUTHORIZED, page).into_response());
+        }
+        Err(e) => return Err(e),
+    };
+    let ttl = state.config.token_ttl_secs;
+    let token = state.keys.sign(user.id, &user.username, ttl)?;
+    let cookie = session_cookie(&token, ttl, host.https);
+    let to = format!("{}/links", host.prefix);
+    Ok(([(SET_COOKIE, cookie)], Redirect::to(&to)).into_response())
+}
+
+pub async fn logout_handler(host: RequestHost) -> Response {
+    let cookie = session_cookie("", 0, host.https);
+    let to = format!("{}/", host.prefix);
+    ([(SET_COOKIE, cookie)], Redirect::to(&to)).into_response()
+}
+
+/// The links of the logged in user, the login page if not logged in.
+pub async fn links_page_handler(
+    State(state): State<AppState>,
+    host: RequestHost,
+    Tenant(tenant): Tenant,
+    WebUser(user): WebUser,
+) -> Result<Response, AppError> {
+    let Some(user) = user else {
+        let to = format!("{}/login", host.prefix);
+        return Ok(Redirect::to(&to).into_response());
+    };
+    let links = state
+        .list_links(&tenant, user.id, &LinkFilter::default())
+        .await?;
+    Ok(render_links(&host, &user, &links).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(name: http::HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn html_should_be_negotiated() {
+        let browser = "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+        assert!(wants_html(&headers(ACCEPT, browser)));
+        assert!(!wants_html(&headers(ACCEPT, "application/json, text/html")));
+        assert!(!wants_html(&headers(ACCEPT, "*/*")));
+        assert!(!wants_html(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn cookie_should_be_found() {
+        let headers = headers(COOKIE, "theme=dark; shortener_token=abc.def; lang=en");
+        assert_eq!(cookie(&headers, COOKIE_NAME), Some("abc.def"));
+        assert_eq!(cookie(&headers, "missing"), None);
+        assert!(session_cookie("abc", 60, true).ends_with("; Secure"));
+    }
+}
@@ -0,0 +1,352 @@
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use http::{HeaderName, StatusCode};
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::{prelude::FromRow, PgPool};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::shortener::{
+    auth::AuthUser, egress::Egress, validate_url, AppError, AppState, ErrorResponse,
+};
+
+pub const X_SIGNATURE: HeaderName = HeaderName::from_static("x-shortener-signature");
+
+// first retry after this delay, doubled on every further attempt
+const BASE_BACKOFF: Duration = Duration::from_millis(500);
+
+/// A link event webhooks can subscribe to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event")]
+pub enum LinkEvent {
+    #[serde(rename = "link.created")]
+    Created { id: String, url: String },
+    #[serde(rename = "link.deleted")]
+    Deleted { id: String },
+    // sent once, when the clicks of a link reach the threshold of the webhook
+    #[serde(rename = "link.click_threshold")]
+    ClickThreshold { id: String, clicks: i64 },
+}
+
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    #[serde(flatten)]
+    event: &'a LinkEvent,
+    timestamp: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, ToSchema)]
+pub struct Webhook {
+    pub id: i64,
+    pub url: String,
+    pub click_threshold: Option<i64>,
+    pub created_at: DateTime<Utc>,
+    // only shown once, when the webhook is created
+    #[serde(skip)]
+    #[schema(ignore)]
+    pub secret: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct WebhookCreated {
+    #[serde(flatten)]
+    pub webhook: Webhook,
+    pub secret: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct WebhookReq {
+    pub url: String,
+    #[serde(default)]
+    pub click_threshold: Option<i64>,
+}
+
+/// Handle to enqueue events, delivery happens in a background task.
+#[derive(Debug, Clone)]
+pub struct Notifier {
+    tx: mpsc::Sender<(i64, LinkEvent)>,
+}
+
+struct Delivery {
+    url: String,
+    secret: String,
+    body: String,
+}
+
+impl Notifier {
+    /// Spawn the delivery task. Events are queued up to `queue_size`, further ones
+    /// are dropped until the queue drains.
+    pub fn spawn(db: PgPool, http: Egress, queue_size: usize, attempts: u32) -> Self {
+        let (tx, mut rx) = mpsc::channel::<(i64, LinkEvent)>(queue_size);
+        tokio::spawn(async move {
+            while let Some((owner, event)) = rx.recv().await {
+                let deliveries = match deliveries_for(&db, owner, &event).await {
+                    Ok(deliveries) => deliveries,
+                    Err(e) => {
+                        warn!("failed to load webhooks of user {owner}: {e:?}");
+                        continue;
+                    }
+                };
+                // a slow endpoint must not hold up the others
+                for delivery in deliveries {
+                    tokio::spawn(deliver(http.clone(), delivery, attempts));
+                }
+            }
+        });
+        Self { tx }
+    }
+
+    /// Notify the webhooks of the link owner, anonymous links have none.
+    pub fn notify(&self, owner: Option<i64>, event: LinkEvent) {
+        let Some(owner) = owner else {
+            return;
+        };
+        if let Err(e) = self.tx.try_send((owner, event)) {
+            warn!("webhook queue full, dropping event: {e}");
+        }
+    }
+
+    /// Like `notify`, but waits for room in the queue instead of dropping the event.
+    /// Returns false if the delivery task is gone.
+    pub async fn publish(&self, owner: Option<i64>, event: LinkEvent) -> bool {
+        match owner {
+            Some(owner) => self.tx.send((owner, event)).await.is_ok(),
+            None => true,
+        }
+    }
+
+    /// A notifier queueing into the returned receiver instead of delivering.
+    #[cfg(test)]
+    pub fn channel(queue_size: usize) -> (Self, mpsc::Receiver<(i64, LinkEvent)>) {
+        let (tx, rx) = mpsc::channel(queue_size);
+        (Self { tx }, rx)
+    }
+}
+
+async fn deliveries_for(
+    db: &PgPool,
+    owner: i64,
+    event: &LinkEvent,
+) -> Result<Vec<Delivery>, sqlx::Error> {
+    let hooks: Vec<Webhook> = match event {
+        LinkEvent::ClickThreshold { clicks, .. } => {
+            sqlx::query_as("SELECT * FROM webhooks WHERE owner_id = $1 AND click_threshold = $2")
+                .bind(owner)
+                .bind(clicks)
+                .fetch_all(db)
+                .await?
+        }
+        _ => {
+            sqlx::query_as("SELECT * FROM webhooks WHERE owner_id = $1")
+                .bind(owner)
+                .fetch_all(db)
+                .await?
+        }
+    };
+    let body = serde_json::to_string(&Payload {
+        event,
+        timestamp: Utc::now(),
+    })
+    .expect("payload is serializable");
+    Ok(hooks
+        .into_iter()
+        .map(|hook| Delivery {
+            url: hook.url,
+            secret: hook.secret,
+            body: body.clone(),
+        })
+        .collect())
+}
+
+// retry with exponential backoff until a 2xx or all attempts are used
+async fn deliver(http: Egress, delivery: Delivery, attempts: u32) {
+    let signature = sign(&delivery.secret, delivery.body.as_bytes());
+    for attempt in 0..attempts {
+        if attempt > 0 {
+            tokio::time::sleep(BASE_BACKOFF * 2u32.pow(attempt - 1)).await;
+        }
+        let req = match http.post(&delivery.url) {
+            Ok(req) => req,
+            Err(e) => {
+                warn!("refusing webhook delivery to {}: {e}", delivery.url);
+                return;
+            }
+        };
+        let ret = req
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(X_SIGNATURE, &signature)
+            .body(delivery.body.clone())
+            .send()
+            .await
+            .and_then(|res| res.error_for_status());
+        match ret {
+            Ok(_) => return,
+            Err(e) => warn!(attempt, "webhook delivery to {} failed: {e}", delivery.url),
+        }
+    }
+    warn!("giving up webhook delivery to {}", delivery.url);
+}
+
+/// `sha256=<hex hmac of the body>`, receivers recompute it with their secret.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts any key length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+impl AppState {
+    async fn create_webhook(&self, owner: i64, req: &WebhookReq) -> Result<Webhook, AppError> {
+        let hook = sqlx::query_as(
+            r#"
+            INSERT INTO webhooks (owner_id, url, secret, click_threshold) VALUES ($1, $2, $3, $4)
+            RETURNING *
+            "#,
+        )
+        .bind(owner)
+        .bind(&req.url)
+        .bind(nanoid!(32))
+        .bind(req.click_threshold)
+        .fetch_one(self.db.primary())
+        .await?;
+        Ok(hook)
+    }
+
+    async fn list_webhooks(&self, owner: i64) -> Result<Vec<Webhook>, AppError> {
+        let hooks = sqlx::query_as("SELECT * FROM webhooks WHERE owner_id = $1 ORDER BY id")
+            .bind(owner)
+            .fetch_all(self.db.primary())
+            .await?;
+        Ok(hooks)
+    }
+
+    async fn delete_webhook(&self, id: i64, owner: i64) -> Result<bool, AppError> {
+        let ret = sqlx::query("DELETE FROM webhooks WHERE id = $1 AND owner_id = $2")
+            .bind(id)
+            .bind(owner)
+            .execute(self.db.primary())
+            .await?;
+        Ok(ret.rows_affected() > 0)
+    }
+}
+
+/// Register a webhook for the links of the caller. Payloads are signed with the
+/// returned secret, see `x-shortener-signature`.
+#[utoipa::path(
+    post,
+    path = "/api/v1/webhooks",
+    tag = "webhooks",
+    request_body = WebhookReq,
+    responses(
+        (status = 201, description = "webhook registered", body = WebhookCreated),
+        (status = 400, description = "invalid request", body = ErrorResponse),
+        (status = 401, description = "missing or invalid token", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn create_webhook_handler(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Json(req): Json<WebhookReq>,
+) -> Result<impl IntoResponse, AppError> {
+    validate_url(&req.url).map_err(AppError::BadRequest)?;
+    if matches!(req.click_threshold, Some(n) if n <= 0) {
+        return Err(AppError::BadRequest(
+            "click threshold must be positive".to_string(),
+        ));
+    }
+    let webhook = state.create_webhook(user.id, &req).await?;
+    info!(
+        target: "audit",
+        owner = user.id,
+        id = webhook.id,
+        url = %webhook.url,
+        "webhook registered"
+    );
+    let secret = webhook.secret.clone();
+    Ok((
+        StatusCode::CREATED,
+        Json(WebhookCreated { webhook, secret }),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/webhooks",
+    tag = "webhooks",
+    responses(
+        (status = 200, description = "webhooks of the caller", body = Vec<Webhook>),
+        (status = 401, description = "missing or invalid token", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn list_webhooks_handler(
+    State(state): State<AppState>,
+    user: AuthUser,
+) -> Result<Json<Vec<Webhook>>, AppError> {
+    Ok(Json(state.list_webhooks(user.id).await?))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/webhooks/{id}",
+    tag = "webhooks",
+    params(("id" = i64, Path)),
+    responses(
+        (status = 204, description = "webhook removed"),
+        (status = 401, description = "missing or invalid token", body = ErrorResponse),
+        (status = 404, description = "no such webhook", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn delete_webhook_handler(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, AppError> {
+    if !state.delete_webhook(id, user.id).await? {
+        return Err(AppError::HttpNotFound(id.to_string()));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_should_match_known_value() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn payload_should_be_tagged() {
+        let event = LinkEvent::ClickThreshold {
+            id: "abc123".to_string(),
+            clicks: 100,
+        };
+        let payload = serde_json::to_value(Payload {
+            event: &event,
+            timestamp: Utc::now(),
+        })
+        .unwrap();
+        assert_eq!(payload["event"], "link.click_threshold");
+        assert_eq!(payload["id"], "abc123");
+        assert_eq!(payload["clicks"], 100);
+        assert!(payload["timestamp"].is_string());
+    }
+}
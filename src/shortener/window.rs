@@ -0,0 +1,262 @@
+use std::fs;
+
+use anyhow::{Context, Result};
+use axum::{
+    extract::{Path, State},
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use http::StatusCode;
+use maud::{html, Markup, DOCTYPE};
+use serde::Deserialize;
+use tracing::info;
+use utoipa::ToSchema;
+
+use crate::shortener::{
+    auth::AdminUser, config::Config, tenants::Tenant, AppError, AppState, ErrorResponse, LinkRecord,
+};
+
+/// When a link redirects, either bound may be open.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, ToSchema)]
+pub struct ActiveWindow {
+    #[serde(default)]
+    pub active_from: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub active_until: Option<DateTime<Utc>>,
+}
+
+/// Where `now` is relative to the window of a link.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    NotYet(DateTime<Utc>),
+    Active,
+    Expired(DateTime<Utc>),
+}
+
+/// Pages shown outside the window, the built-in ones if not configured.
+#[derive(Debug, Clone, Default)]
+pub struct WindowPages {
+    not_yet: Option<String>,
+    expired: Option<String>,
+}
+
+impl ActiveWindow {
+    pub fn validate(&self) -> Result<(), String> {
+        match (self.active_from, self.active_until) {
+            (Some(from), Some(until)) if from >= until => Err(format!(
+                "active_from ({from}) must be before active_until ({until})"
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // `active_until` is exclusive
+    pub fn phase(&self, now: DateTime<Utc>) -> Phase {
+        match (self.active_from, self.active_until) {
+            (Some(from), _) if now < from => Phase::NotYet(from),
+            (_, Some(until)) if now >= until => Phase::Expired(until),
+            _ => Phase::Active,
+        }
+    }
+}
+
+impl WindowPages {
+    pub fn load(config: &Config) -> Result<Self> {
+        let read = |path: &str| -> Result<Option<String>> {
+            if path.is_empty() {
+                return Ok(None);
+            }
+            let page =
+                fs::read_to_string(path).with_context(|| format!("failed to read {path}"))?;
+            Ok(Some(page))
+        };
+        Ok(Self {
+            not_yet: read(&config.not_yet_active_page)?,
+            expired: read(&config.expired_page)?,
+        })
+    }
+
+    /// 404 before the window, 410 after it.
+    pub fn render(&self, short_url: &str, phase: Phase) -> Option<Response> {
+        let (status, custom, page) = match phase {
+            Phase::Active => return None,
+            Phase::NotYet(from) => (
+                StatusCode::NOT_FOUND,
+                &self.not_yet,
+                render_not_yet(short_url, from),
+            ),
+            Phase::Expired(until) => (
+                StatusCode::GONE,
+                &self.expired,
+                render_expired(short_url, until),
+            ),
+        };
+        let res = match custom {
+            Some(custom) => (status, Html(custom.clone())).into_response(),
+            None => (status, page).into_response(),
+        };
+        Some(res)
+    }
+}
+
+fn render_not_yet(short_url: &str, from: DateTime<Utc>) -> Markup {
+    html! {
+        (DOCTYPE)
+        html {
+            head {
+                meta charset="utf-8";
+                meta name="robots" content="noindex";
+                title { (short_url) " is not available yet" }
+            }
+            body {
+                h1 { "This link is not available yet" }
+                p { "It becomes available at " time datetime=(from.to_rfc3339()) { (from.format("%Y-%m-%d %H:%M UTC")) } "." }
+            }
+        }
+    }
+}
+
+fn render_expired(short_url: &str, until: DateTime<Utc>) -> Markup {
+    html! {
+        (DOCTYPE)
+        html {
+            head {
+                meta charset="utf-8";
+                meta name="robots" content="noindex";
+                title { (short_url) " has expired" }
+            }
+            body {
+                h1 { "This link has expired" }
+                p { "It was available until " time datetime=(until.to_rfc3339()) { (until.format("%Y-%m-%d %H:%M UTC")) } "." }
+            }
+        }
+    }
+}
+
+impl AppState {
+    // returns None if there is no such link, the window is replaced as a whole
+    pub async fn set_window(
+        &self,
+        tenant: &str,
+        id: &str,
+        window: &ActiveWindow,
+    ) -> Result<Option<LinkRecord>, AppError> {
+        let link = sqlx::query_as!(
+            LinkRecord,
+            r#"
+            UPDATE urls SET active_from = $3, active_until = $4, version = version + 1,
+                updated_at = now()
+            WHERE tenant = $1 AND id = $2 AND deleted_at IS NULL
+            RETURNING id, url, version, updated_at, redirect_status, flag_reason, clicks,
+                max_clicks, password_hash IS NOT NULL AS "protected!", domain, last_checked_at,
+                last_check_status, last_check_latency_ms, dead, tags, active_from, active_until
+            "#,
+            tenant,
+            id,
+            window.active_from,
+            window.active_until,
+        )
+        .fetch_optional(self.db.primary())
+        .await?;
+        if link.is_some() {
+            self.wrote(id);
+        }
+        Ok(link)
+    }
+}
+
+/// Set when a link redirects, of any owner. Outside the window the link answers
+/// with a "not yet available" or "expired" page.
+#[utoipa::path(
+    put,
+    path = "/api/v1/admin/links/{id}/window",
+    tag = "admin",
+    params(("id" = String, Path, description = "short id")),
+    request_body = ActiveWindow,
+    responses(
+        (status = 200, description = "window updated", body = LinkRecord),
+        (status = 400, description = "window ends before it starts", body = ErrorResponse),
+        (status = 401, description = "missing or invalid token", body = ErrorResponse),
+        (status = 403, description = "not allowed", body = ErrorResponse),
+        (status = 404, description = "no such link", body = ErrorResponse),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn set_window_handler(
+    State(state): State<AppState>,
+    AdminUser(admin): AdminUser,
+    Tenant(tenant): Tenant,
+    Path(id): Path<String>,
+    Json(window): Json<ActiveWindow>,
+) -> Result<Json<LinkRecord>, AppError> {
+    window.validate().map_err(AppError::BadRequest)?;
+    let Some(link) = state.set_window(&tenant, &id, &window).await? else {
+        return Err(AppError::HttpNotFound(id));
+    };
+    info!(
+        target: "audit",
+        admin = %admin.username,
+        tenant,
+        id,
+        active_from = ?window.active_from,
+        active_until = ?window.active_until,
+        "link window updated"
+    );
+    Ok(Json(link))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn phase_should_follow_window() {
+        let window = ActiveWindow {
+            active_from: Some(at("2024-01-01T00:00:00Z")),
+            active_until: Some(at("2024-02-01T00:00:00Z")),
+        };
+        assert_eq!(
+            window.phase(at("2023-12-31T23:59:59Z")),
+            Phase::NotYet(at("2024-01-01T00:00:00Z"))
+        );
+        assert_eq!(window.phase(at("2024-01-01T00:00:00Z")), Phase::Active);
+        assert_eq!(
+            window.phase(at("2024-02-01T00:00:00Z")),
+            Phase::Expired(at("2024-02-01T00:00:00Z"))
+        );
+        assert_eq!(
+            ActiveWindow::default().phase(at("2024-01-01T00:00:00Z")),
+            Phase::Active
+        );
+
+        let reversed = ActiveWindow {
+            active_from: window.active_until,
+            active_until: window.active_from,
+        };
+        assert!(reversed.validate().is_err());
+        assert!(window.validate().is_ok());
+    }
+
+    #[test]
+    fn pages_should_be_replaceable() {
+        let pages = WindowPages {
+            not_yet: None,
+            expired: Some("<p>gone for good</p>".to_string()),
+        };
+        let short_url = "http://127.0.0.1:9876/abc123";
+        assert!(pages.render(short_url, Phase::Active).is_none());
+        let res = pages
+            .render(short_url, Phase::NotYet(at("2024-01-01T00:00:00Z")))
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = pages
+            .render(short_url, Phase::Expired(at("2024-01-01T00:00:00Z")))
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::GONE);
+    }
+}